@@ -0,0 +1,611 @@
+//! Creation, removal and resource limits of cgroups within a mounted hierarchy.
+
+use super::{Cgroup, CgroupVersion, DEFAULT_CGROUP_ROOT};
+use anyhow::{bail, Context, Result};
+use getset::{CopyGetters, Getters};
+use log::trace;
+use oci_spec::runtime::{LinuxMemory, LinuxResources};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+/// The controllers of the legacy hierarchies a cgroup gets created in, if they are mounted.
+const V1_CONTROLLERS: &[&str] = &[
+    "blkio",
+    "cpu",
+    "cpuacct",
+    "cpuset",
+    "devices",
+    "freezer",
+    "hugetlb",
+    "memory",
+    "net_cls",
+    "net_prio",
+    "perf_event",
+    "pids",
+];
+
+/// The default CFS period in microseconds used if only a quota is provided.
+const DEFAULT_CPU_PERIOD: u64 = 100_000;
+
+#[derive(Clone, CopyGetters, Debug, Eq, Getters, PartialEq)]
+/// A mounted cgroup hierarchy, which is the unified one for v2 or the set of per controller
+/// hierarchies for v1.
+pub struct Hierarchy {
+    #[get = "pub"]
+    /// The mount point of the hierarchy.
+    root: PathBuf,
+
+    #[get_copy = "pub"]
+    /// The version of the hierarchy.
+    version: CgroupVersion,
+}
+
+impl Default for Hierarchy {
+    fn default() -> Self {
+        Self::new(DEFAULT_CGROUP_ROOT)
+    }
+}
+
+impl Hierarchy {
+    /// Create a new hierarchy for the mount point, whereas the version gets detected.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        let root = root.into();
+        let version = CgroupVersion::detect(&root);
+        Self { root, version }
+    }
+
+    /// Resolve the cgroup, which is a path relative to the root of the hierarchy.
+    pub fn cgroup(&self, cgroup: &Path) -> Result<Cgroup> {
+        let mut relative = PathBuf::new();
+        for component in cgroup.components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::RootDir | Component::CurDir => {}
+                _ => bail!("invalid cgroup {}", cgroup.display()),
+            }
+        }
+        Ok(match self.version {
+            CgroupVersion::V1 => Cgroup::V1(
+                V1_CONTROLLERS
+                    .iter()
+                    .map(|name| (name, self.root.join(name)))
+                    .filter(|(_, mount)| mount.is_dir())
+                    .map(|(name, mount)| (name.to_string(), mount.join(&relative)))
+                    .collect(),
+            ),
+            CgroupVersion::V2 => Cgroup::V2(self.root.join(relative)),
+        })
+    }
+
+    /// Create the cgroup including its parents. The available controllers get enabled for the
+    /// children of every parent in v2, whereas v1 cgroups inherit the cpuset of their parents.
+    pub fn create(&self, cgroup: &Path) -> Result<Cgroup> {
+        let resolved = self.cgroup(cgroup)?;
+        match &resolved {
+            Cgroup::V1(controllers) => {
+                for (name, path) in controllers {
+                    create_dir(path)?;
+                    if name == "cpuset" {
+                        inherit_cpuset(&self.root.join(name), path)?;
+                    }
+                }
+            }
+            Cgroup::V2(path) => {
+                let relative = path.strip_prefix(&self.root)?;
+                let mut dir = self.root.clone();
+                for component in relative.components() {
+                    enable_controllers(&dir)?;
+                    dir.push(component);
+                    create_dir(&dir)?;
+                }
+            }
+        }
+        trace!("Created cgroup {}", cgroup.display());
+        Ok(resolved)
+    }
+
+    /// Remove the cgroup including all of its children. Removing a cgroup which does not exist
+    /// is not an error.
+    pub fn remove(&self, cgroup: &Path) -> Result<()> {
+        let dirs = match self.cgroup(cgroup)? {
+            Cgroup::V1(controllers) => controllers.into_values().collect(),
+            Cgroup::V2(path) => vec![path],
+        };
+        for dir in dirs.iter().filter(|dir| **dir != self.root) {
+            remove_dir(dir)?;
+        }
+        trace!("Removed cgroup {}", cgroup.display());
+        Ok(())
+    }
+
+    /// Validate the resources against the version of the hierarchy, which fails for resources
+    /// the version is not able to represent.
+    pub fn validate(&self, resources: &LinuxResources) -> Result<()> {
+        let unified = resources.unified().iter().flat_map(HashMap::keys);
+        match self.version {
+            CgroupVersion::V1 => {
+                if unified.count() > 0 {
+                    bail!("unified resources require cgroup v2")
+                }
+            }
+            CgroupVersion::V2 => {
+                for key in unified {
+                    unified_controller(key)?;
+                }
+                if let Some(memory) = resources.memory() {
+                    swap_max(memory)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the resource limits to the cgroup by writing its control files.
+    pub fn apply(&self, cgroup: &Path, resources: &LinuxResources) -> Result<()> {
+        self.validate(resources)?;
+        let resolved = self.cgroup(cgroup)?;
+        match self.version {
+            CgroupVersion::V1 => apply_v1(&resolved, resources),
+            CgroupVersion::V2 => apply_v2(&resolved, resources),
+        }
+        .with_context(|| format!("apply resources to cgroup {}", cgroup.display()))
+    }
+}
+
+/// Convert the CPU shares of v1 into the CPU weight of v2, which maps the range `[2, 262144]`
+/// onto `[1, 10000]`.
+pub fn shares_to_weight(shares: u64) -> u64 {
+    if shares == 0 {
+        return 0;
+    }
+    1 + (shares.clamp(2, 262_144) - 2) * 9999 / 262_142
+}
+
+/// Apply the resources to a cgroup of the legacy hierarchies.
+fn apply_v1(cgroup: &Cgroup, resources: &LinuxResources) -> Result<()> {
+    if let Some(cpu) = resources.cpu() {
+        if let Some(shares) = cpu.shares().filter(|s| *s != 0) {
+            write(cgroup, "cpu", "cpu.shares", shares)?;
+        }
+        if let Some(period) = cpu.period().filter(|p| *p != 0) {
+            write(cgroup, "cpu", "cpu.cfs_period_us", period)?;
+        }
+        if let Some(quota) = cpu.quota().filter(|q| *q != 0) {
+            write(cgroup, "cpu", "cpu.cfs_quota_us", quota)?;
+        }
+        if let Some(cpus) = cpu.cpus().as_ref().filter(|c| !c.is_empty()) {
+            write(cgroup, "cpuset", "cpuset.cpus", cpus)?;
+        }
+        if let Some(mems) = cpu.mems().as_ref().filter(|m| !m.is_empty()) {
+            write(cgroup, "cpuset", "cpuset.mems", mems)?;
+        }
+    }
+
+    if let Some(memory) = resources.memory() {
+        if let Some(limit) = memory.limit().filter(|l| *l != 0) {
+            write(cgroup, "memory", "memory.limit_in_bytes", limit)?;
+        }
+        if let Some(swap) = memory.swap().filter(|s| *s != 0) {
+            write(cgroup, "memory", "memory.memsw.limit_in_bytes", swap)?;
+        }
+        if let Some(reservation) = memory.reservation().filter(|r| *r != 0) {
+            write(cgroup, "memory", "memory.soft_limit_in_bytes", reservation)?;
+        }
+    }
+
+    if let Some(pids) = resources.pids() {
+        write(cgroup, "pids", "pids.max", pids_limit(pids.limit()))?;
+    }
+
+    for hugepage in resources.hugepage_limits().iter().flatten() {
+        write(
+            cgroup,
+            "hugetlb",
+            &format!("hugetlb.{}.limit_in_bytes", hugepage.page_size()),
+            hugepage.limit(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Apply the resources to a cgroup of the unified hierarchy.
+fn apply_v2(cgroup: &Cgroup, resources: &LinuxResources) -> Result<()> {
+    if let Some(cpu) = resources.cpu() {
+        if let Some(shares) = cpu.shares().filter(|s| *s != 0) {
+            write(cgroup, "cpu", "cpu.weight", shares_to_weight(shares))?;
+        }
+        let quota = cpu.quota().filter(|q| *q != 0);
+        let period = cpu.period().filter(|p| *p != 0);
+        if quota.is_some() || period.is_some() {
+            let quota = match quota {
+                Some(quota) if quota > 0 => quota.to_string(),
+                _ => "max".into(),
+            };
+            let period = period.unwrap_or(DEFAULT_CPU_PERIOD);
+            write(cgroup, "cpu", "cpu.max", format!("{} {}", quota, period))?;
+        }
+        if let Some(cpus) = cpu.cpus().as_ref().filter(|c| !c.is_empty()) {
+            write(cgroup, "cpuset", "cpuset.cpus", cpus)?;
+        }
+        if let Some(mems) = cpu.mems().as_ref().filter(|m| !m.is_empty()) {
+            write(cgroup, "cpuset", "cpuset.mems", mems)?;
+        }
+    }
+
+    if let Some(memory) = resources.memory() {
+        if let Some(limit) = memory.limit().filter(|l| *l != 0) {
+            write(cgroup, "memory", "memory.max", memory_limit(limit))?;
+        }
+        if let Some(swap) = swap_max(memory)? {
+            write(cgroup, "memory", "memory.swap.max", swap)?;
+        }
+        if let Some(reservation) = memory.reservation().filter(|r| *r != 0) {
+            write(cgroup, "memory", "memory.low", memory_limit(reservation))?;
+        }
+    }
+
+    if let Some(pids) = resources.pids() {
+        write(cgroup, "pids", "pids.max", pids_limit(pids.limit()))?;
+    }
+
+    for hugepage in resources.hugepage_limits().iter().flatten() {
+        write(
+            cgroup,
+            "hugetlb",
+            &format!("hugetlb.{}.max", hugepage.page_size()),
+            hugepage.limit(),
+        )?;
+    }
+
+    for (key, value) in resources.unified().iter().flat_map(HashMap::iter) {
+        write(cgroup, unified_controller(key)?, key, value)?;
+    }
+    Ok(())
+}
+
+/// The value of `memory.swap.max` for the memory resources. The swap of the runtime spec
+/// includes the memory, whereas v2 limits the swap only.
+fn swap_max(memory: &LinuxMemory) -> Result<Option<String>> {
+    let limit = memory.limit().filter(|l| *l > 0);
+    match (memory.swap().filter(|s| *s != 0), limit) {
+        (Some(-1), _) => Ok(Some("max".into())),
+        (Some(swap), Some(limit)) if swap >= limit => Ok(Some((swap - limit).to_string())),
+        (Some(swap), Some(limit)) => {
+            bail!("memory swap {} is lower than memory limit {}", swap, limit)
+        }
+        (Some(_), None) => bail!("memory swap requires a memory limit"),
+        (None, _) => Ok(None),
+    }
+}
+
+/// The controller of the unified resource key, like `memory` for `memory.high`.
+fn unified_controller(key: &str) -> Result<&str> {
+    match key.split_once('.') {
+        Some((controller, _)) if !controller.is_empty() && !key.contains('/') => Ok(controller),
+        _ => bail!("invalid unified resource {:?}", key),
+    }
+}
+
+/// A memory limit of v2, whereas negative values mean unlimited.
+fn memory_limit(limit: i64) -> String {
+    if limit < 0 {
+        "max".into()
+    } else {
+        limit.to_string()
+    }
+}
+
+/// A process limit, whereas values below one mean unlimited.
+fn pids_limit(limit: i64) -> String {
+    if limit > 0 {
+        limit.to_string()
+    } else {
+        "max".into()
+    }
+}
+
+/// Write the control file of the controller.
+fn write<T: Display>(cgroup: &Cgroup, controller: &str, file: &str, value: T) -> Result<()> {
+    let path = cgroup
+        .path(controller)
+        .with_context(|| format!("controller {} is not available", controller))?
+        .join(file);
+    fs::write(&path, value.to_string()).with_context(|| format!("write {}", path.display()))
+}
+
+/// Create the directory including its parents, which is not an error if it already exists.
+fn create_dir(path: &Path) -> Result<()> {
+    fs::create_dir_all(path).with_context(|| format!("create {}", path.display()))
+}
+
+/// Remove the cgroup directory after all of its child cgroups. The control files vanish with
+/// the directory on a mounted hierarchy, whereas directories still containing files after
+/// removing the children are no mounted cgroups and get removed recursively.
+fn remove_dir(path: &Path) -> Result<()> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_dir(&entry.path())?;
+        }
+    }
+    match fs::remove_dir(path) {
+        Err(e) if e.kind() == ErrorKind::DirectoryNotEmpty => {
+            fs::remove_dir_all(path).with_context(|| format!("remove {}", path.display()))
+        }
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Enable all controllers available in the v2 cgroup for its children.
+fn enable_controllers(dir: &Path) -> Result<()> {
+    let path = dir.join("cgroup.controllers");
+    let controllers = match fs::read_to_string(&path) {
+        Ok(controllers) => controllers,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    let enable = controllers
+        .split_whitespace()
+        .map(|c| format!("+{}", c))
+        .collect::<Vec<_>>();
+    if enable.is_empty() {
+        return Ok(());
+    }
+    let path = dir.join("cgroup.subtree_control");
+    fs::write(&path, enable.join(" ")).with_context(|| format!("write {}", path.display()))
+}
+
+/// Copy the CPUs and memory nodes of the parents into the v1 cpuset cgroups below the mount,
+/// which are empty for new cgroups and prevent processes from joining them otherwise.
+fn inherit_cpuset(mount: &Path, path: &Path) -> Result<()> {
+    let mut parent = mount.to_path_buf();
+    for component in path.strip_prefix(mount)?.components() {
+        let dir = parent.join(component);
+        for file in ["cpuset.cpus", "cpuset.mems"] {
+            let current = fs::read_to_string(dir.join(file)).unwrap_or_default();
+            if !current.trim().is_empty() {
+                continue;
+            }
+            if let Ok(value) = fs::read_to_string(parent.join(file)) {
+                let path = dir.join(file);
+                fs::write(&path, value.trim())
+                    .with_context(|| format!("write {}", path.display()))?;
+            }
+        }
+        parent = dir;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::{
+        LinuxCpuBuilder, LinuxHugepageLimitBuilder, LinuxMemoryBuilder, LinuxPidsBuilder,
+        LinuxResourcesBuilder,
+    };
+    use tempfile::TempDir;
+
+    /// Create a fake unified hierarchy in the directory.
+    fn fake_v2(dir: &TempDir) -> Result<Hierarchy> {
+        fs::write(dir.path().join("cgroup.controllers"), "cpu memory pids")?;
+        Ok(Hierarchy::new(dir.path()))
+    }
+
+    /// Create fake legacy hierarchies in the directory.
+    fn fake_v1(dir: &TempDir) -> Result<Hierarchy> {
+        for controller in ["cpu", "cpuset", "memory", "pids"] {
+            fs::create_dir(dir.path().join(controller))?;
+        }
+        fs::write(dir.path().join("cpuset/cpuset.cpus"), "0-3\n")?;
+        fs::write(dir.path().join("cpuset/cpuset.mems"), "0\n")?;
+        Ok(Hierarchy::new(dir.path()))
+    }
+
+    fn test_resources() -> Result<LinuxResources> {
+        Ok(LinuxResourcesBuilder::default()
+            .cpu(
+                LinuxCpuBuilder::default()
+                    .shares(1024u64)
+                    .quota(50_000)
+                    .period(100_000u64)
+                    .cpus("0-1")
+                    .build()?,
+            )
+            .memory(
+                LinuxMemoryBuilder::default()
+                    .limit(1i64 << 30)
+                    .swap(2i64 << 30)
+                    .build()?,
+            )
+            .pids(LinuxPidsBuilder::default().limit(100).build()?)
+            .hugepage_limits(vec![LinuxHugepageLimitBuilder::default()
+                .page_size("2MB")
+                .limit(1i64 << 21)
+                .build()?])
+            .build()?)
+    }
+
+    fn read(path: PathBuf) -> Result<String> {
+        Ok(fs::read_to_string(path)?)
+    }
+
+    #[test]
+    fn shares_weight() {
+        assert_eq!(shares_to_weight(0), 0);
+        assert_eq!(shares_to_weight(2), 1);
+        assert_eq!(shares_to_weight(1024), 39);
+        assert_eq!(shares_to_weight(262_144), 10_000);
+        assert_eq!(shares_to_weight(u64::MAX), 10_000);
+    }
+
+    #[test]
+    fn create_remove_v2() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = fake_v2(&dir)?;
+        assert_eq!(sut.version(), CgroupVersion::V2);
+
+        let cgroup = sut.create(Path::new("/kubepods/pod1"))?;
+        assert_eq!(cgroup, Cgroup::V2(dir.path().join("kubepods/pod1")));
+        assert!(dir.path().join("kubepods/pod1").is_dir());
+        assert_eq!(
+            read(dir.path().join("cgroup.subtree_control"))?,
+            "+cpu +memory +pids"
+        );
+
+        fs::create_dir(dir.path().join("kubepods/pod1/container"))?;
+        sut.remove(Path::new("/kubepods/pod1"))?;
+        assert!(!dir.path().join("kubepods/pod1").exists());
+        assert!(dir.path().join("kubepods").exists());
+        sut.remove(Path::new("/kubepods/pod1"))?;
+
+        assert!(sut.create(Path::new("../escape")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn create_remove_v1() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = fake_v1(&dir)?;
+        assert_eq!(sut.version(), CgroupVersion::V1);
+
+        let cgroup = sut.create(Path::new("kubepods/pod1"))?;
+        for controller in ["cpu", "cpuset", "memory", "pids"] {
+            assert_eq!(
+                cgroup.path(controller),
+                Some(dir.path().join(controller).join("kubepods/pod1").as_path())
+            );
+            assert!(dir.path().join(controller).join("kubepods/pod1").is_dir());
+        }
+        assert!(cgroup.path("hugetlb").is_none());
+        assert_eq!(read(dir.path().join("cpuset/kubepods/cpuset.cpus"))?, "0-3");
+        assert_eq!(
+            read(dir.path().join("cpuset/kubepods/pod1/cpuset.mems"))?,
+            "0"
+        );
+
+        sut.remove(Path::new("kubepods"))?;
+        for controller in ["cpu", "cpuset", "memory", "pids"] {
+            assert!(!dir.path().join(controller).join("kubepods").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn apply_v2() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = fake_v2(&dir)?;
+        let cgroup = Path::new("pod");
+        sut.create(cgroup)?;
+
+        let mut resources = test_resources()?;
+        resources.set_unified(Some(
+            [("memory.high".to_string(), "512M".to_string())].into(),
+        ));
+        sut.apply(cgroup, &resources)?;
+
+        let path = dir.path().join("pod");
+        assert_eq!(read(path.join("cpu.weight"))?, "39");
+        assert_eq!(read(path.join("cpu.max"))?, "50000 100000");
+        assert_eq!(read(path.join("cpuset.cpus"))?, "0-1");
+        assert_eq!(read(path.join("memory.max"))?, (1u64 << 30).to_string());
+        assert_eq!(
+            read(path.join("memory.swap.max"))?,
+            (1u64 << 30).to_string()
+        );
+        assert_eq!(read(path.join("pids.max"))?, "100");
+        assert_eq!(
+            read(path.join("hugetlb.2MB.max"))?,
+            (1u64 << 21).to_string()
+        );
+        assert_eq!(read(path.join("memory.high"))?, "512M");
+
+        let resources = LinuxResourcesBuilder::default()
+            .memory(LinuxMemoryBuilder::default().swap(1024).build()?)
+            .build()?;
+        assert!(sut.apply(cgroup, &resources).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_v1() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = fake_v1(&dir)?;
+        let cgroup = Path::new("pod");
+        sut.create(cgroup)?;
+
+        let mut resources = test_resources()?;
+        resources.set_hugepage_limits(None);
+        sut.apply(cgroup, &resources)?;
+
+        assert_eq!(read(dir.path().join("cpu/pod/cpu.shares"))?, "1024");
+        assert_eq!(read(dir.path().join("cpu/pod/cpu.cfs_quota_us"))?, "50000");
+        assert_eq!(
+            read(dir.path().join("cpu/pod/cpu.cfs_period_us"))?,
+            "100000"
+        );
+        assert_eq!(read(dir.path().join("cpuset/pod/cpuset.cpus"))?, "0-1");
+        assert_eq!(
+            read(dir.path().join("memory/pod/memory.limit_in_bytes"))?,
+            (1u64 << 30).to_string()
+        );
+        assert_eq!(
+            read(dir.path().join("memory/pod/memory.memsw.limit_in_bytes"))?,
+            (2u64 << 30).to_string()
+        );
+        assert_eq!(read(dir.path().join("pids/pod/pids.max"))?, "100");
+
+        // The hugetlb controller is not mounted
+        assert!(sut.apply(cgroup, &test_resources()?).is_err());
+
+        resources.set_unified(Some([("memory.high".into(), "1G".into())].into()));
+        assert!(sut.apply(cgroup, &resources).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validate() -> Result<()> {
+        let (dir_v1, dir_v2) = (TempDir::new()?, TempDir::new()?);
+        let v1 = fake_v1(&dir_v1)?;
+        let v2 = fake_v2(&dir_v2)?;
+        let mut resources = test_resources()?;
+        v1.validate(&resources)?;
+        v2.validate(&resources)?;
+
+        resources.set_unified(Some([("memory.high".into(), "1G".into())].into()));
+        assert!(v1.validate(&resources).is_err());
+        v2.validate(&resources)?;
+
+        for key in ["memory", ".high", "memory.high/../x"] {
+            resources.set_unified(Some([(key.into(), "1G".into())].into()));
+            assert!(v2.validate(&resources).is_err(), "{}", key);
+        }
+        resources.set_unified(None);
+
+        resources.set_memory(Some(
+            LinuxMemoryBuilder::default()
+                .limit(1024)
+                .swap(512)
+                .build()?,
+        ));
+        v1.validate(&resources)?;
+        assert!(v2.validate(&resources).is_err());
+
+        resources.set_memory(Some(LinuxMemoryBuilder::default().swap(-1).build()?));
+        v2.validate(&resources)?;
+        Ok(())
+    }
+}
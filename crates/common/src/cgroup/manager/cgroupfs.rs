@@ -0,0 +1,90 @@
+//! The cgroupfs manager, which uses the cgroup parent as path on the hierarchy.
+
+use super::{CgroupDriver, CgroupManager, CGROUP_PREFIX};
+use crate::cgroup::hierarchy::Hierarchy;
+use anyhow::{bail, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// The parent of the pod cgroups if the kubelet provides none.
+const DEFAULT_PARENT: &str = "/containrs";
+
+#[derive(Clone, Debug, Default)]
+/// Manages the cgroups directly on the hierarchy.
+pub struct CgroupfsManager {
+    /// The hierarchy the cgroups get created in.
+    hierarchy: Hierarchy,
+}
+
+impl CgroupfsManager {
+    /// Create a new cgroupfs manager for the hierarchy.
+    pub fn new(hierarchy: Hierarchy) -> Self {
+        Self { hierarchy }
+    }
+}
+
+impl CgroupManager for CgroupfsManager {
+    fn driver(&self) -> CgroupDriver {
+        CgroupDriver::Cgroupfs
+    }
+
+    fn hierarchy(&self) -> &Hierarchy {
+        &self.hierarchy
+    }
+
+    fn pod_cgroup(&self, parent: &Path, pod_id: &str) -> Result<PathBuf> {
+        if parent.as_os_str().is_empty() {
+            return Ok(Path::new(DEFAULT_PARENT).join(format!("pod{}", pod_id)));
+        }
+        if parent
+            .components()
+            .any(|c| !matches!(c, Component::RootDir | Component::Normal(_)))
+        {
+            bail!("invalid cgroup parent {}", parent.display())
+        }
+        Ok(Path::new("/").join(parent))
+    }
+
+    fn container_cgroups_path(&self, parent: &Path, pod_id: &str, id: &str) -> Result<String> {
+        Ok(self
+            .pod_cgroup(parent, pod_id)?
+            .join(format!("{}-{}", CGROUP_PREFIX, id))
+            .display()
+            .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pod_cgroup() -> Result<()> {
+        let sut = CgroupfsManager::default();
+        assert_eq!(
+            sut.pod_cgroup(Path::new("/kubepods/burstable/pod123"), "123")?,
+            Path::new("/kubepods/burstable/pod123")
+        );
+        assert_eq!(
+            sut.pod_cgroup(Path::new("kubepods"), "123")?,
+            Path::new("/kubepods")
+        );
+        assert_eq!(
+            sut.pod_cgroup(Path::new(""), "123")?,
+            Path::new("/containrs/pod123")
+        );
+        assert!(sut
+            .pod_cgroup(Path::new("/kubepods/../etc"), "123")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn container_cgroups_path() -> Result<()> {
+        let sut = CgroupfsManager::default();
+        assert_eq!(
+            sut.container_cgroups_path(Path::new("/kubepods/pod123"), "123", "ctr")?,
+            "/kubepods/pod123/containrs-ctr"
+        );
+        Ok(())
+    }
+}
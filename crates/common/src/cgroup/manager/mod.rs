@@ -0,0 +1,138 @@
+//! Cgroup managers, which map the cgroup parent of pods onto the hierarchy.
+//!
+//! The kubelet passes the cgroup parent of a pod either as cgroupfs path like
+//! `/kubepods/burstable/pod<uid>` or as systemd slice like
+//! `kubepods-burstable-pod<uid>.slice`, depending on its cgroup driver. The managers resolve it
+//! into the pod cgroup on the hierarchy and name the cgroups of the containers within it.
+
+use crate::cgroup::hierarchy::Hierarchy;
+use anyhow::{Context, Result};
+use dyn_clone::{clone_trait_object, DynClone};
+use oci_spec::runtime::LinuxResources;
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+};
+use strum::{AsRefStr, Display, EnumString, IntoStaticStr};
+
+pub mod cgroupfs;
+pub mod systemd;
+
+/// The prefix of the container cgroups, which identifies them as the ones of this runtime.
+pub const CGROUP_PREFIX: &str = "containrs";
+
+#[derive(
+    AsRefStr, Clone, Copy, Debug, Default, Display, EnumString, Eq, Hash, IntoStaticStr, PartialEq,
+)]
+#[strum(serialize_all = "snake_case")]
+/// The available cgroup managers, which have to match the cgroup driver of the kubelet.
+pub enum CgroupDriver {
+    #[default]
+    /// Manage the cgroups directly on the hierarchy, see [`cgroupfs`].
+    Cgroupfs,
+
+    /// Name the cgroups after systemd slices and scopes, see [`systemd`].
+    Systemd,
+}
+
+impl CgroupDriver {
+    /// Create the manager of the driver for the hierarchy.
+    pub fn manager(self, hierarchy: Hierarchy) -> Box<dyn CgroupManager> {
+        match self {
+            Self::Cgroupfs => Box::new(cgroupfs::CgroupfsManager::new(hierarchy)),
+            Self::Systemd => Box::new(systemd::SystemdManager::new(hierarchy)),
+        }
+    }
+}
+
+/// A cgroup manager creates and removes the cgroups of pods.
+pub trait CgroupManager: Debug + DynClone + Send + Sync {
+    /// The driver implemented by the manager.
+    fn driver(&self) -> CgroupDriver;
+
+    /// The hierarchy the cgroups get managed in.
+    fn hierarchy(&self) -> &Hierarchy;
+
+    /// Resolve the cgroup of the pod into its path relative to the hierarchy root. The cgroup
+    /// parent provided by the kubelet is pod specific already, whereas pods without parent get a
+    /// cgroup of their own.
+    fn pod_cgroup(&self, parent: &Path, pod_id: &str) -> Result<PathBuf>;
+
+    /// The `cgroupsPath` of the runtime spec for the container within the pod.
+    fn container_cgroups_path(&self, parent: &Path, pod_id: &str, id: &str) -> Result<String>;
+
+    /// Create the cgroup of the pod and apply the resources to it, which should include the pod
+    /// overhead. Returns the path of the pod cgroup relative to the hierarchy root.
+    fn create_pod(
+        &self,
+        parent: &Path,
+        pod_id: &str,
+        resources: Option<&LinuxResources>,
+    ) -> Result<PathBuf> {
+        let cgroup = self.pod_cgroup(parent, pod_id)?;
+        self.hierarchy()
+            .create(&cgroup)
+            .with_context(|| format!("create pod cgroup {}", cgroup.display()))?;
+        if let Some(resources) = resources {
+            self.hierarchy().apply(&cgroup, resources)?;
+        }
+        Ok(cgroup)
+    }
+
+    /// Remove the cgroup of the pod including the ones of its containers. Removing a pod
+    /// cgroup which does not exist is not an error.
+    fn remove_pod(&self, parent: &Path, pod_id: &str) -> Result<()> {
+        let cgroup = self.pod_cgroup(parent, pod_id)?;
+        self.hierarchy()
+            .remove(&cgroup)
+            .with_context(|| format!("remove pod cgroup {}", cgroup.display()))
+    }
+}
+
+clone_trait_object!(CgroupManager);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::{LinuxPidsBuilder, LinuxResourcesBuilder};
+    use std::{fs, str::FromStr};
+    use tempfile::TempDir;
+
+    #[test]
+    fn driver() -> Result<()> {
+        let hierarchy = Hierarchy::new(TempDir::new()?.path());
+        assert_eq!(CgroupDriver::default(), CgroupDriver::Cgroupfs);
+        for driver in [CgroupDriver::Cgroupfs, CgroupDriver::Systemd] {
+            assert_eq!(CgroupDriver::from_str(driver.as_ref())?, driver);
+            assert_eq!(driver.manager(hierarchy.clone()).driver(), driver);
+        }
+        assert!(CgroupDriver::from_str("invalid").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn create_remove_pod() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("cgroup.controllers"), "pids")?;
+        let sut = CgroupDriver::Systemd.manager(Hierarchy::new(dir.path()));
+        let parent = Path::new("kubepods-besteffort-pod123.slice");
+        let resources = LinuxResourcesBuilder::default()
+            .pids(LinuxPidsBuilder::default().limit(10).build()?)
+            .build()?;
+
+        let cgroup = sut.create_pod(parent, "123", Some(&resources))?;
+        let path = dir.path().join(&cgroup);
+        assert_eq!(
+            path,
+            dir.path()
+                .join("kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod123.slice")
+        );
+        assert_eq!(fs::read_to_string(path.join("pids.max"))?, "10");
+
+        sut.remove_pod(parent, "123")?;
+        assert!(!path.exists());
+        assert!(dir.path().join("kubepods.slice").exists());
+        sut.remove_pod(parent, "123")?;
+        Ok(())
+    }
+}
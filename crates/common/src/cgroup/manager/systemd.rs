@@ -0,0 +1,254 @@
+//! The systemd manager, which follows the naming of systemd slices and scopes.
+//!
+//! The cgroup parent is a slice like `kubepods-burstable.slice`, which systemd places below all
+//! slices named by its dash separated prefixes, here `kubepods.slice`. Containers run in scopes
+//! within the pod slice, which the runtime creates from the `slice:prefix:name` cgroups path.
+
+use super::{CgroupDriver, CgroupManager, CGROUP_PREFIX};
+use crate::cgroup::hierarchy::Hierarchy;
+use anyhow::{bail, Context, Result};
+use getset::Getters;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The suffix of systemd slice units.
+const SLICE_SUFFIX: &str = ".slice";
+
+/// The root slice, which corresponds to the root of the hierarchy.
+const ROOT_SLICE: &str = "-.slice";
+
+/// The slice of containers without slice, as used by the runtimes.
+const DEFAULT_SLICE: &str = "system.slice";
+
+#[derive(Clone, Debug, Default)]
+/// Manages the cgroups as systemd slices.
+pub struct SystemdManager {
+    /// The hierarchy the cgroups get created in.
+    hierarchy: Hierarchy,
+}
+
+impl SystemdManager {
+    /// Create a new systemd manager for the hierarchy.
+    pub fn new(hierarchy: Hierarchy) -> Self {
+        Self { hierarchy }
+    }
+
+    /// The slice of the pod, which is the cgroup parent or one of its own if there is none.
+    fn pod_slice(parent: &Path, pod_id: &str) -> Result<String> {
+        let parent = parent
+            .to_str()
+            .with_context(|| format!("invalid slice {}", parent.display()))?;
+        if parent.is_empty() {
+            // Dashes would nest the slice
+            return Ok(format!(
+                "{}-pod{}{}",
+                CGROUP_PREFIX,
+                pod_id.replace('-', "_"),
+                SLICE_SUFFIX
+            ));
+        }
+        Ok(parent.into())
+    }
+}
+
+impl CgroupManager for SystemdManager {
+    fn driver(&self) -> CgroupDriver {
+        CgroupDriver::Systemd
+    }
+
+    fn hierarchy(&self) -> &Hierarchy {
+        &self.hierarchy
+    }
+
+    fn pod_cgroup(&self, parent: &Path, pod_id: &str) -> Result<PathBuf> {
+        expand_slice(&Self::pod_slice(parent, pod_id)?)
+    }
+
+    fn container_cgroups_path(&self, parent: &Path, pod_id: &str, id: &str) -> Result<String> {
+        let path = SystemdCgroupPath {
+            slice: Self::pod_slice(parent, pod_id)?,
+            prefix: CGROUP_PREFIX.into(),
+            name: id.into(),
+        };
+        path.path()?;
+        Ok(path.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+/// A cgroups path of the form `slice:prefix:name`, which the runtimes expect in systemd cgroup
+/// mode. The container runs in the scope `<prefix>-<name>.scope` within the slice.
+pub struct SystemdCgroupPath {
+    #[get = "pub"]
+    /// The slice containing the scope, which is the `system.slice` if empty.
+    slice: String,
+
+    #[get = "pub"]
+    /// The prefix of the scope name.
+    prefix: String,
+
+    #[get = "pub"]
+    /// The name of the scope.
+    name: String,
+}
+
+impl SystemdCgroupPath {
+    /// The path of the scope relative to the hierarchy root.
+    pub fn path(&self) -> Result<PathBuf> {
+        let slice = if self.slice.is_empty() {
+            DEFAULT_SLICE
+        } else {
+            &self.slice
+        };
+        if self.name.is_empty() || self.name.contains('/') || self.prefix.contains('/') {
+            bail!("invalid scope name in cgroups path {}", self)
+        }
+        let scope = if self.prefix.is_empty() {
+            format!("{}.scope", self.name)
+        } else {
+            format!("{}-{}.scope", self.prefix, self.name)
+        };
+        Ok(expand_slice(slice)?.join(scope))
+    }
+}
+
+impl FromStr for SystemdCgroupPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s.split(':').collect::<Vec<_>>();
+        match parts.as_slice() {
+            [slice, prefix, name] => Ok(Self {
+                slice: slice.to_string(),
+                prefix: prefix.to_string(),
+                name: name.to_string(),
+            }),
+            _ => bail!(
+                "expected cgroups path of form slice:prefix:name, got {:?}",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for SystemdCgroupPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.slice, self.prefix, self.name)
+    }
+}
+
+/// Expand the slice into its path relative to the hierarchy root, for example
+/// `a-b-c.slice` into `a.slice/a-b.slice/a-b-c.slice`.
+pub fn expand_slice(slice: &str) -> Result<PathBuf> {
+    if slice == ROOT_SLICE {
+        return Ok(PathBuf::new());
+    }
+    let name = slice
+        .strip_suffix(SLICE_SUFFIX)
+        .with_context(|| format!("slice {} does not end with {}", slice, SLICE_SUFFIX))?;
+    if name.is_empty()
+        || name.contains('/')
+        || name.starts_with('-')
+        || name.ends_with('-')
+        || name.contains("--")
+    {
+        bail!("invalid slice name {}", slice)
+    }
+
+    let mut path = PathBuf::new();
+    let mut prefix = String::new();
+    for part in name.split('-') {
+        if !prefix.is_empty() {
+            prefix.push('-');
+        }
+        prefix.push_str(part);
+        path.push(format!("{}{}", prefix, SLICE_SUFFIX));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand() -> Result<()> {
+        assert_eq!(expand_slice("-.slice")?, PathBuf::new());
+        assert_eq!(expand_slice("system.slice")?, Path::new("system.slice"));
+        assert_eq!(
+            expand_slice("kubepods-besteffort-pod1.slice")?,
+            Path::new("kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1.slice")
+        );
+        for invalid in [
+            "",
+            "system",
+            ".slice",
+            "-a.slice",
+            "a-.slice",
+            "a--b.slice",
+            "a/b.slice",
+        ] {
+            assert!(expand_slice(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cgroups_path() -> Result<()> {
+        let path: SystemdCgroupPath = "kubepods-pod1.slice:containrs:ctr".parse()?;
+        assert_eq!(path.slice(), "kubepods-pod1.slice");
+        assert_eq!(path.prefix(), "containrs");
+        assert_eq!(path.name(), "ctr");
+        assert_eq!(path.to_string(), "kubepods-pod1.slice:containrs:ctr");
+        assert_eq!(
+            path.path()?,
+            Path::new("kubepods.slice/kubepods-pod1.slice/containrs-ctr.scope")
+        );
+
+        let path: SystemdCgroupPath = "::ctr".parse()?;
+        assert_eq!(path.path()?, Path::new("system.slice/ctr.scope"));
+
+        assert!("system.slice:ctr".parse::<SystemdCgroupPath>().is_err());
+        assert!("a:b:c:d".parse::<SystemdCgroupPath>().is_err());
+        assert!("system.slice:prefix:"
+            .parse::<SystemdCgroupPath>()?
+            .path()
+            .is_err());
+        assert!("system:prefix:ctr"
+            .parse::<SystemdCgroupPath>()?
+            .path()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pod_cgroup() -> Result<()> {
+        let sut = SystemdManager::default();
+        assert_eq!(
+            sut.pod_cgroup(Path::new("kubepods-burstable-pod1.slice"), "1")?,
+            Path::new("kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod1.slice")
+        );
+        assert_eq!(
+            sut.pod_cgroup(Path::new(""), "a-b")?,
+            Path::new("containrs.slice/containrs-poda_b.slice")
+        );
+        assert!(sut.pod_cgroup(Path::new("/kubepods/pod1"), "1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn container_cgroups_path() -> Result<()> {
+        let sut = SystemdManager::default();
+        assert_eq!(
+            sut.container_cgroups_path(Path::new("kubepods-pod1.slice"), "1", "ctr")?,
+            "kubepods-pod1.slice:containrs:ctr"
+        );
+        assert!(sut
+            .container_cgroups_path(Path::new("kubepods"), "1", "ctr")
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Linux control group handling for the unified (v2) and the legacy (v1) hierarchies.
+
+pub mod hierarchy;
+pub mod manager;
+pub mod stats;
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use strum::{AsRefStr, Display, IntoStaticStr};
+use tokio::fs;
+
+/// The default mount point of the cgroup hierarchies.
+pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The file listing the available controllers, which only exists in the unified hierarchy.
+const CONTROLLERS_FILE: &str = "cgroup.controllers";
+
+#[derive(AsRefStr, Clone, Copy, Debug, Display, Eq, Hash, IntoStaticStr, PartialEq)]
+/// The version of the cgroup hierarchy.
+pub enum CgroupVersion {
+    #[strum(serialize = "v1")]
+    /// The legacy hierarchy with one mount per controller.
+    V1,
+
+    #[strum(serialize = "v2")]
+    /// The unified hierarchy with all controllers in a single mount.
+    V2,
+}
+
+impl CgroupVersion {
+    /// Detect the version of the hierarchy mounted at `root`. Hybrid setups, which mount the
+    /// unified hierarchy below the root, are considered to be v1 since the controllers are
+    /// bound to the legacy hierarchies.
+    pub fn detect<P: AsRef<Path>>(root: P) -> Self {
+        if root.as_ref().join(CONTROLLERS_FILE).exists() {
+            Self::V2
+        } else {
+            Self::V1
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+/// A resolved cgroup on the host.
+pub enum Cgroup {
+    /// The directory of the cgroup in the legacy hierarchies, by controller name.
+    V1(HashMap<String, PathBuf>),
+
+    /// The directory of the cgroup in the unified hierarchy.
+    V2(PathBuf),
+}
+
+impl Cgroup {
+    /// Resolve the cgroup of the process by reading its `/proc/<pid>/cgroup` file. The hierarchy
+    /// is expected to be mounted at `root`.
+    pub async fn of_process<P: AsRef<Path>>(root: P, pid: u32) -> Result<Self> {
+        let path = PathBuf::from(format!("/proc/{}/cgroup", pid));
+        let content = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("read {}", path.display()))?;
+        Self::parse(root, &content)
+    }
+
+    /// Resolve the cgroup from the content of a `/proc/<pid>/cgroup` file, whereas the version
+    /// gets detected from the hierarchy mounted at `root`.
+    pub fn parse<P: AsRef<Path>>(root: P, content: &str) -> Result<Self> {
+        let root = root.as_ref();
+        let version = CgroupVersion::detect(root);
+        let mut controllers = HashMap::new();
+        for line in content.lines().filter(|l| !l.is_empty()) {
+            let mut fields = line.splitn(3, ':');
+            let (id, names, path) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(id), Some(names), Some(path)) => (id, names, path),
+                _ => bail!("invalid cgroup entry {:?}", line),
+            };
+            let path = path.trim_start_matches('/');
+            match version {
+                CgroupVersion::V2 if id == "0" && names.is_empty() => {
+                    return Ok(Self::V2(root.join(path)))
+                }
+                CgroupVersion::V2 => {}
+                CgroupVersion::V1 => {
+                    // Named hierarchies like `name=systemd` have no controllers attached
+                    for name in names
+                        .split(',')
+                        .filter(|n| !n.is_empty() && !n.contains('='))
+                    {
+                        controllers.insert(name.to_string(), root.join(name).join(path));
+                    }
+                }
+            }
+        }
+        match version {
+            CgroupVersion::V1 => Ok(Self::V1(controllers)),
+            CgroupVersion::V2 => bail!("no unified hierarchy entry found"),
+        }
+    }
+
+    /// The version of the hierarchy the cgroup belongs to.
+    pub fn version(&self) -> CgroupVersion {
+        match self {
+            Self::V1(_) => CgroupVersion::V1,
+            Self::V2(_) => CgroupVersion::V2,
+        }
+    }
+
+    /// The directory of the cgroup for the controller, which is `None` if the controller is not
+    /// available. All controllers share the same directory in the unified hierarchy.
+    pub fn path(&self, controller: &str) -> Option<&Path> {
+        match self {
+            Self::V1(controllers) => controllers.get(controller).map(PathBuf::as_path),
+            Self::V2(path) => Some(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn detect_version() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert_eq!(CgroupVersion::detect(dir.path()), CgroupVersion::V1);
+        std::fs::write(dir.path().join(CONTROLLERS_FILE), "cpu memory pids")?;
+        assert_eq!(CgroupVersion::detect(dir.path()), CgroupVersion::V2);
+        assert_eq!(CgroupVersion::V2.to_string(), "v2");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_v1() -> Result<()> {
+        let dir = TempDir::new()?;
+        let cgroup = Cgroup::parse(
+            dir.path(),
+            "12:cpu,cpuacct:/kubepods/pod/ctr\n\
+             4:memory:/kubepods/pod/ctr\n\
+             1:name=systemd:/system.slice\n\
+             0::/system.slice\n",
+        )?;
+        assert_eq!(cgroup.version(), CgroupVersion::V1);
+        assert_eq!(
+            cgroup.path("cpuacct"),
+            Some(dir.path().join("cpuacct/kubepods/pod/ctr").as_path())
+        );
+        assert_eq!(
+            cgroup.path("memory"),
+            Some(dir.path().join("memory/kubepods/pod/ctr").as_path())
+        );
+        assert!(cgroup.path("pids").is_none());
+        assert!(cgroup.path("name=systemd").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_v2() -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::write(dir.path().join(CONTROLLERS_FILE), "")?;
+        let cgroup = Cgroup::parse(dir.path(), "0::/kubepods.slice/crio-ctr.scope\n")?;
+        assert_eq!(
+            cgroup,
+            Cgroup::V2(dir.path().join("kubepods.slice/crio-ctr.scope"))
+        );
+        assert_eq!(cgroup.path("memory"), cgroup.path("cpu"));
+
+        assert!(Cgroup::parse(dir.path(), "4:memory:/ctr\n").is_err());
+        assert!(Cgroup::parse(dir.path(), "invalid\n").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn of_process() -> Result<()> {
+        let dir = TempDir::new()?;
+        let cgroup = Cgroup::of_process(dir.path(), std::process::id()).await?;
+        assert_eq!(cgroup.version(), CgroupVersion::V1);
+        assert!(Cgroup::of_process(dir.path(), u32::MAX).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+//! Resource usage statistics read from the cgroup files.
+
+use super::Cgroup;
+use anyhow::{Context, Result};
+use getset::{CopyGetters, Getters};
+use std::{collections::HashMap, io::ErrorKind, path::Path};
+use tokio::fs;
+
+/// The clock ticks per second the `cpuacct.stat` file is reported in (`USER_HZ`).
+const USER_HZ: u64 = 100;
+
+/// Memory limits of v1 at or above this value mean that there is no limit.
+const V1_UNLIMITED: u64 = 0x7FFF_FFFF_FFFF_F000;
+
+#[derive(Clone, Debug, Default, Eq, Getters, PartialEq)]
+/// The resource usage of a cgroup.
+pub struct CgroupStats {
+    #[get = "pub"]
+    /// CPU usage and throttling.
+    cpu: CpuStats,
+
+    #[get = "pub"]
+    /// Memory usage.
+    memory: MemoryStats,
+
+    #[get = "pub"]
+    /// Number of processes.
+    pids: PidsStats,
+
+    #[get = "pub"]
+    /// Block I/O per device, ordered by device number.
+    io: Vec<IoStats>,
+
+    #[get = "pub"]
+    /// Huge page usage by page size, for example `2MB`.
+    hugetlb: HashMap<String, HugeTlbStats>,
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Eq, PartialEq)]
+#[get_copy = "pub"]
+/// CPU usage and throttling of a cgroup.
+pub struct CpuStats {
+    /// Total CPU time consumed in microseconds.
+    usage_usec: u64,
+
+    /// CPU time consumed in user mode in microseconds.
+    user_usec: u64,
+
+    /// CPU time consumed in kernel mode in microseconds.
+    system_usec: u64,
+
+    /// Number of enforcement periods elapsed.
+    nr_periods: u64,
+
+    /// Number of enforcement periods the cgroup got throttled in.
+    nr_throttled: u64,
+
+    /// Total time the cgroup got throttled for in microseconds.
+    throttled_usec: u64,
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Eq, PartialEq)]
+#[get_copy = "pub"]
+/// Memory usage of a cgroup.
+pub struct MemoryStats {
+    /// Total memory usage in bytes including the page cache.
+    usage_bytes: u64,
+
+    /// Memory usage in bytes without the inactive page cache, which cannot be reclaimed easily.
+    working_set_bytes: u64,
+
+    /// Anonymous memory usage in bytes.
+    rss_bytes: u64,
+
+    /// Number of page faults.
+    page_faults: u64,
+
+    /// Number of major page faults.
+    major_page_faults: u64,
+
+    /// Memory limit in bytes, which is `None` if the cgroup is unlimited.
+    limit_bytes: Option<u64>,
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Eq, PartialEq)]
+#[get_copy = "pub"]
+/// Number of processes in a cgroup.
+pub struct PidsStats {
+    /// Current number of processes.
+    current: u64,
+
+    /// Maximum number of processes, which is `None` if the cgroup is unlimited.
+    limit: Option<u64>,
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Eq, PartialEq)]
+#[get_copy = "pub"]
+/// Block I/O of a cgroup on a single device.
+pub struct IoStats {
+    /// Major number of the device.
+    major: u64,
+
+    /// Minor number of the device.
+    minor: u64,
+
+    /// Bytes read from the device.
+    read_bytes: u64,
+
+    /// Bytes written to the device.
+    write_bytes: u64,
+
+    /// Number of read operations.
+    read_ios: u64,
+
+    /// Number of write operations.
+    write_ios: u64,
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Eq, PartialEq)]
+#[get_copy = "pub"]
+/// Huge page usage of a cgroup for a single page size.
+pub struct HugeTlbStats {
+    /// Current usage in bytes.
+    usage_bytes: u64,
+
+    /// Maximum recorded usage in bytes, which is only available for v1.
+    max_usage_bytes: u64,
+
+    /// Number of allocations failing because of the limit.
+    fail_count: u64,
+}
+
+impl Cgroup {
+    /// Read the resource usage of the cgroup. Statistics of controllers which are not enabled for
+    /// the cgroup are zero.
+    pub async fn stats(&self) -> Result<CgroupStats> {
+        match self {
+            Self::V1(_) => self.stats_v1().await,
+            Self::V2(path) => stats_v2(path).await,
+        }
+    }
+
+    /// Read the resource usage from the legacy hierarchies.
+    async fn stats_v1(&self) -> Result<CgroupStats> {
+        let mut stats = CgroupStats::default();
+
+        if let Some(path) = self.path("cpuacct") {
+            stats.cpu.usage_usec = read_u64(&path.join("cpuacct.usage")).await? / 1000;
+            let cpuacct = read_flat_keyed(&path.join("cpuacct.stat")).await?;
+            let ticks_to_usec =
+                |key| cpuacct.get(key).copied().unwrap_or_default() * 1_000_000 / USER_HZ;
+            stats.cpu.user_usec = ticks_to_usec("user");
+            stats.cpu.system_usec = ticks_to_usec("system");
+        }
+        if let Some(path) = self.path("cpu") {
+            let cpu = read_flat_keyed(&path.join("cpu.stat")).await?;
+            stats.cpu.nr_periods = cpu.get("nr_periods").copied().unwrap_or_default();
+            stats.cpu.nr_throttled = cpu.get("nr_throttled").copied().unwrap_or_default();
+            stats.cpu.throttled_usec =
+                cpu.get("throttled_time").copied().unwrap_or_default() / 1000;
+        }
+
+        if let Some(path) = self.path("memory") {
+            let memory = read_flat_keyed(&path.join("memory.stat")).await?;
+            let get = |key| memory.get(key).copied().unwrap_or_default();
+            stats.memory.usage_bytes = read_u64(&path.join("memory.usage_in_bytes")).await?;
+            stats.memory.working_set_bytes = stats
+                .memory
+                .usage_bytes
+                .saturating_sub(get("total_inactive_file"));
+            stats.memory.rss_bytes = get("total_rss");
+            stats.memory.page_faults = get("total_pgfault");
+            stats.memory.major_page_faults = get("total_pgmajfault");
+            stats.memory.limit_bytes = read_max(&path.join("memory.limit_in_bytes"))
+                .await?
+                .filter(|limit| *limit < V1_UNLIMITED);
+        }
+
+        if let Some(path) = self.path("pids") {
+            stats.pids = read_pids(path).await?;
+        }
+
+        if let Some(path) = self.path("blkio") {
+            let mut devices: HashMap<(u64, u64), IoStats> = HashMap::new();
+            for (file, bytes) in [
+                ("blkio.throttle.io_service_bytes", true),
+                ("blkio.throttle.io_serviced", false),
+            ] {
+                for line in read_optional(&path.join(file)).await?.lines() {
+                    let fields = line.split_whitespace().collect::<Vec<_>>();
+                    let (device, op, value) = match fields.as_slice() {
+                        [device, op, value] => (*device, *op, *value),
+                        _ => continue,
+                    };
+                    let (major, minor) = parse_device(device)?;
+                    let value = parse_u64(value)?;
+                    let entry = devices.entry((major, minor)).or_insert_with(|| IoStats {
+                        major,
+                        minor,
+                        ..Default::default()
+                    });
+                    match (op, bytes) {
+                        ("Read", true) => entry.read_bytes = value,
+                        ("Write", true) => entry.write_bytes = value,
+                        ("Read", false) => entry.read_ios = value,
+                        ("Write", false) => entry.write_ios = value,
+                        _ => {}
+                    }
+                }
+            }
+            stats.io = devices.into_values().collect();
+            stats.io.sort_by_key(|io| (io.major, io.minor));
+        }
+
+        if let Some(path) = self.path("hugetlb") {
+            for size in hugetlb_sizes(path, ".usage_in_bytes").await? {
+                let file = |name| path.join(format!("hugetlb.{}.{}", size, name));
+                let hugetlb = HugeTlbStats {
+                    usage_bytes: read_u64(&file("usage_in_bytes")).await?,
+                    max_usage_bytes: read_u64(&file("max_usage_in_bytes")).await?,
+                    fail_count: read_u64(&file("failcnt")).await?,
+                };
+                stats.hugetlb.insert(size, hugetlb);
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+/// Read the resource usage from the unified hierarchy.
+async fn stats_v2(path: &Path) -> Result<CgroupStats> {
+    let mut stats = CgroupStats::default();
+
+    let cpu = read_flat_keyed(&path.join("cpu.stat")).await?;
+    let get = |key| cpu.get(key).copied().unwrap_or_default();
+    stats.cpu = CpuStats {
+        usage_usec: get("usage_usec"),
+        user_usec: get("user_usec"),
+        system_usec: get("system_usec"),
+        nr_periods: get("nr_periods"),
+        nr_throttled: get("nr_throttled"),
+        throttled_usec: get("throttled_usec"),
+    };
+
+    let memory = read_flat_keyed(&path.join("memory.stat")).await?;
+    let get = |key| memory.get(key).copied().unwrap_or_default();
+    let usage_bytes = read_u64(&path.join("memory.current")).await?;
+    stats.memory = MemoryStats {
+        usage_bytes,
+        working_set_bytes: usage_bytes.saturating_sub(get("inactive_file")),
+        rss_bytes: get("anon"),
+        page_faults: get("pgfault"),
+        major_page_faults: get("pgmajfault"),
+        limit_bytes: read_max(&path.join("memory.max")).await?,
+    };
+
+    stats.pids = read_pids(path).await?;
+
+    for line in read_optional(&path.join("io.stat")).await?.lines() {
+        let mut fields = line.split_whitespace();
+        let (major, minor) = match fields.next() {
+            Some(device) => parse_device(device)?,
+            None => continue,
+        };
+        let mut io = IoStats {
+            major,
+            minor,
+            ..Default::default()
+        };
+        for (key, value) in fields.filter_map(|field| field.split_once('=')) {
+            let value = parse_u64(value)?;
+            match key {
+                "rbytes" => io.read_bytes = value,
+                "wbytes" => io.write_bytes = value,
+                "rios" => io.read_ios = value,
+                "wios" => io.write_ios = value,
+                _ => {}
+            }
+        }
+        stats.io.push(io);
+    }
+    stats.io.sort_by_key(|io| (io.major, io.minor));
+
+    for size in hugetlb_sizes(path, ".current").await? {
+        let events = read_flat_keyed(&path.join(format!("hugetlb.{}.events", size))).await?;
+        let hugetlb = HugeTlbStats {
+            usage_bytes: read_u64(&path.join(format!("hugetlb.{}.current", size))).await?,
+            max_usage_bytes: 0,
+            fail_count: events.get("max").copied().unwrap_or_default(),
+        };
+        stats.hugetlb.insert(size, hugetlb);
+    }
+
+    Ok(stats)
+}
+
+/// Read the process count and limit, which are named the same in both versions.
+async fn read_pids(path: &Path) -> Result<PidsStats> {
+    Ok(PidsStats {
+        current: read_u64(&path.join("pids.current")).await?,
+        limit: read_max(&path.join("pids.max")).await?,
+    })
+}
+
+/// The huge page sizes of the `hugetlb.<size><suffix>` files in the cgroup directory.
+async fn hugetlb_sizes(path: &Path, suffix: &str) -> Result<Vec<String>> {
+    let mut sizes = vec![];
+    let mut entries = match fs::read_dir(path).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(sizes),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        if let Some(size) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("hugetlb."))
+            .and_then(|n| n.strip_suffix(suffix))
+            .filter(|size| !size.contains('.'))
+        {
+            sizes.push(size.to_string());
+        }
+    }
+    Ok(sizes)
+}
+
+/// Read the file, which is empty if it does not exist because the controller is not enabled.
+async fn read_optional(path: &Path) -> Result<String> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e).with_context(|| format!("read {}", path.display())),
+    }
+}
+
+/// Read a file containing a single value, which is zero if the file does not exist.
+async fn read_u64(path: &Path) -> Result<u64> {
+    let content = read_optional(path).await?;
+    match content.trim() {
+        "" => Ok(0),
+        value => parse_u64(value).with_context(|| format!("parse {}", path.display())),
+    }
+}
+
+/// Read a file containing a limit, which is `None` if it is `max` or the file does not exist.
+async fn read_max(path: &Path) -> Result<Option<u64>> {
+    let content = read_optional(path).await?;
+    match content.trim() {
+        "" | "max" => Ok(None),
+        value => parse_u64(value)
+            .map(Some)
+            .with_context(|| format!("parse {}", path.display())),
+    }
+}
+
+/// Read a file consisting of `<key> <value>` lines.
+async fn read_flat_keyed(path: &Path) -> Result<HashMap<String, u64>> {
+    read_optional(path)
+        .await?
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(key, value)| Ok((key.to_string(), parse_u64(value.trim())?)))
+        .collect::<Result<_>>()
+        .with_context(|| format!("parse {}", path.display()))
+}
+
+/// Parse a `<major>:<minor>` device number.
+fn parse_device(device: &str) -> Result<(u64, u64)> {
+    let (major, minor) = device
+        .split_once(':')
+        .with_context(|| format!("invalid device {:?}", device))?;
+    Ok((parse_u64(major)?, parse_u64(minor)?))
+}
+
+/// Parse an unsigned number.
+fn parse_u64(value: &str) -> Result<u64> {
+    value
+        .parse()
+        .with_context(|| format!("invalid number {:?}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) -> Result<()> {
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().context("no parent")?)?;
+            std::fs::write(path, content)?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stats_v2() -> Result<()> {
+        let dir = TempDir::new()?;
+        write_files(
+            dir.path(),
+            &[
+                (
+                    "cpu.stat",
+                    "usage_usec 3000\nuser_usec 2000\nsystem_usec 1000\n\
+                     nr_periods 10\nnr_throttled 2\nthrottled_usec 500\n",
+                ),
+                ("memory.current", "10000\n"),
+                ("memory.max", "max\n"),
+                (
+                    "memory.stat",
+                    "anon 4000\nfile 6000\ninactive_file 2500\npgfault 42\npgmajfault 3\n",
+                ),
+                ("pids.current", "5\n"),
+                ("pids.max", "1024\n"),
+                (
+                    "io.stat",
+                    "8:16 rbytes=1 wbytes=2 rios=3 wios=4 dbytes=0 dios=0\n\
+                     8:0 rbytes=10 wbytes=20 rios=30 wios=40 dbytes=0 dios=0\n",
+                ),
+                ("hugetlb.2MB.current", "2097152\n"),
+                ("hugetlb.2MB.max", "max\n"),
+                ("hugetlb.2MB.events", "max 1\n"),
+                ("hugetlb.2MB.events.local", "max 1\n"),
+            ],
+        )?;
+
+        let stats = Cgroup::V2(dir.path().into()).stats().await?;
+        assert_eq!(stats.cpu().usage_usec(), 3000);
+        assert_eq!(stats.cpu().user_usec(), 2000);
+        assert_eq!(stats.cpu().system_usec(), 1000);
+        assert_eq!(stats.cpu().nr_periods(), 10);
+        assert_eq!(stats.cpu().nr_throttled(), 2);
+        assert_eq!(stats.cpu().throttled_usec(), 500);
+        assert_eq!(stats.memory().usage_bytes(), 10000);
+        assert_eq!(stats.memory().working_set_bytes(), 7500);
+        assert_eq!(stats.memory().rss_bytes(), 4000);
+        assert_eq!(stats.memory().page_faults(), 42);
+        assert_eq!(stats.memory().major_page_faults(), 3);
+        assert_eq!(stats.memory().limit_bytes(), None);
+        assert_eq!(stats.pids().current(), 5);
+        assert_eq!(stats.pids().limit(), Some(1024));
+        assert_eq!(stats.io().len(), 2);
+        assert_eq!((stats.io()[0].major(), stats.io()[0].minor()), (8, 0));
+        assert_eq!(stats.io()[0].read_bytes(), 10);
+        assert_eq!(stats.io()[0].write_bytes(), 20);
+        assert_eq!(stats.io()[0].read_ios(), 30);
+        assert_eq!(stats.io()[0].write_ios(), 40);
+        assert_eq!(stats.io()[1].minor(), 16);
+        assert_eq!(stats.hugetlb().len(), 1);
+        let hugetlb = stats.hugetlb().get("2MB").context("no 2MB huge pages")?;
+        assert_eq!(hugetlb.usage_bytes(), 2097152);
+        assert_eq!(hugetlb.fail_count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stats_v1() -> Result<()> {
+        let dir = TempDir::new()?;
+        write_files(
+            dir.path(),
+            &[
+                ("cpuacct/ctr/cpuacct.usage", "3000000\n"),
+                ("cpuacct/ctr/cpuacct.stat", "user 20\nsystem 10\n"),
+                (
+                    "cpu/ctr/cpu.stat",
+                    "nr_periods 10\nnr_throttled 2\nthrottled_time 500000\n",
+                ),
+                ("memory/ctr/memory.usage_in_bytes", "10000\n"),
+                ("memory/ctr/memory.limit_in_bytes", "9223372036854771712\n"),
+                (
+                    "memory/ctr/memory.stat",
+                    "rss 1\ntotal_rss 4000\ntotal_inactive_file 2500\n\
+                     total_pgfault 42\ntotal_pgmajfault 3\n",
+                ),
+                ("pids/ctr/pids.current", "5\n"),
+                ("pids/ctr/pids.max", "max\n"),
+                (
+                    "blkio/ctr/blkio.throttle.io_service_bytes",
+                    "8:0 Read 10\n8:0 Write 20\n8:0 Sync 30\n8:0 Total 30\nTotal 30\n",
+                ),
+                (
+                    "blkio/ctr/blkio.throttle.io_serviced",
+                    "8:0 Read 30\n8:0 Write 40\nTotal 70\n",
+                ),
+                ("hugetlb/ctr/hugetlb.1GB.usage_in_bytes", "0\n"),
+                ("hugetlb/ctr/hugetlb.1GB.max_usage_in_bytes", "1073741824\n"),
+                ("hugetlb/ctr/hugetlb.1GB.failcnt", "2\n"),
+                ("hugetlb/ctr/hugetlb.1GB.limit_in_bytes", "1073741824\n"),
+            ],
+        )?;
+
+        let cgroup = Cgroup::parse(
+            dir.path(),
+            "5:cpu,cpuacct:/ctr\n4:memory:/ctr\n3:pids:/ctr\n2:blkio:/ctr\n1:hugetlb:/ctr\n",
+        )?;
+        let stats = cgroup.stats().await?;
+        assert_eq!(stats.cpu().usage_usec(), 3000);
+        assert_eq!(stats.cpu().user_usec(), 200_000);
+        assert_eq!(stats.cpu().system_usec(), 100_000);
+        assert_eq!(stats.cpu().nr_periods(), 10);
+        assert_eq!(stats.cpu().nr_throttled(), 2);
+        assert_eq!(stats.cpu().throttled_usec(), 500);
+        assert_eq!(stats.memory().usage_bytes(), 10000);
+        assert_eq!(stats.memory().working_set_bytes(), 7500);
+        assert_eq!(stats.memory().rss_bytes(), 4000);
+        assert_eq!(stats.memory().page_faults(), 42);
+        assert_eq!(stats.memory().major_page_faults(), 3);
+        assert_eq!(stats.memory().limit_bytes(), None);
+        assert_eq!(stats.pids().current(), 5);
+        assert_eq!(stats.pids().limit(), None);
+        assert_eq!(stats.io().len(), 1);
+        assert_eq!(stats.io()[0].read_bytes(), 10);
+        assert_eq!(stats.io()[0].write_bytes(), 20);
+        assert_eq!(stats.io()[0].read_ios(), 30);
+        assert_eq!(stats.io()[0].write_ios(), 40);
+        let hugetlb = stats.hugetlb().get("1GB").context("no 1GB huge pages")?;
+        assert_eq!(hugetlb.usage_bytes(), 0);
+        assert_eq!(hugetlb.max_usage_bytes(), 1073741824);
+        assert_eq!(hugetlb.fail_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stats_missing_controllers() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert_eq!(
+            Cgroup::V2(dir.path().into()).stats().await?,
+            CgroupStats::default()
+        );
+        assert_eq!(
+            Cgroup::V1(HashMap::new()).stats().await?,
+            CgroupStats::default()
+        );
+
+        std::fs::write(dir.path().join("memory.current"), "invalid")?;
+        assert!(Cgroup::V2(dir.path().into()).stats().await.is_err());
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
 pub mod capability;
+pub mod cgroup;
 pub mod seccomp;
 pub mod unix_stream;
 
@@ -0,0 +1,362 @@
+//! OCI bundles of containers.
+//!
+//! Every container gets its bundle directory below `<root>/<id>`, which contains the runtime spec
+//! as `config.json` and a `rootfs` symlink to the prepared root file system. The runtime and
+//! monitor write their pid, exit and log files into the bundle as well, which means that removing
+//! the directory cleans up everything. The bundles are recorded in the storage, which allows
+//! finding them again after a restart of the server.
+
+use anyhow::{bail, Context, Result};
+use getset::{CopyGetters, Getters};
+use log::{debug, trace};
+use oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+use storage::{default_key_value_storage::DefaultKeyValueStorage, KeyValueStorage};
+use tokio::fs;
+
+/// Storage key prefix of the bundle records.
+const BUNDLE_PREFIX: &str = "bundle/";
+
+/// The runtime spec within the bundle.
+const CONFIG_FILE: &str = "config.json";
+
+/// The symlink to the root file system within the bundle.
+const ROOTFS_LINK: &str = "rootfs";
+
+/// The process ID of the container init process written by the runtime.
+const PID_FILE: &str = "pid";
+
+/// The process ID of the monitor supervising the container.
+const MONITOR_PID_FILE: &str = "monitor.pid";
+
+/// The directory containing the exit status of the container init process written by the
+/// monitor, whereas the file is named after the container ID.
+const EXIT_DIR: &str = "exits";
+
+/// The log of the runtime invocations.
+const RUNTIME_LOG_FILE: &str = "runtime.log";
+
+/// The resources passed to the runtime on update.
+const RESOURCES_FILE: &str = "resources.json";
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize)]
+/// The bundle directory of a single container.
+pub struct Bundle {
+    #[get = "pub"]
+    /// Unique identifier of the container.
+    id: String,
+
+    #[get = "pub"]
+    /// Path to the bundle directory.
+    path: PathBuf,
+}
+
+impl Bundle {
+    /// Path to the runtime spec.
+    pub fn config_file(&self) -> PathBuf {
+        self.path.join(CONFIG_FILE)
+    }
+
+    /// Path to the symlink pointing to the root file system.
+    pub fn rootfs(&self) -> PathBuf {
+        self.path.join(ROOTFS_LINK)
+    }
+
+    /// Path to the file containing the process ID of the container.
+    pub fn pid_file(&self) -> PathBuf {
+        self.path.join(PID_FILE)
+    }
+
+    /// Path to the file containing the process ID of the monitor.
+    pub fn monitor_pid_file(&self) -> PathBuf {
+        self.path.join(MONITOR_PID_FILE)
+    }
+
+    /// Path to the directory the monitor writes the exit file to.
+    pub fn exit_dir(&self) -> PathBuf {
+        self.path.join(EXIT_DIR)
+    }
+
+    /// Path to the file containing the exit status of the container.
+    pub fn exit_file(&self) -> PathBuf {
+        self.exit_dir().join(&self.id)
+    }
+
+    /// Path to the log file of the runtime.
+    pub fn runtime_log_file(&self) -> PathBuf {
+        self.path.join(RUNTIME_LOG_FILE)
+    }
+
+    /// Path to the resources passed to the runtime on update.
+    pub fn resources_file(&self) -> PathBuf {
+        self.path.join(RESOURCES_FILE)
+    }
+
+    /// Load the runtime spec of the bundle.
+    pub fn spec(&self) -> Result<Spec> {
+        Spec::load(self.config_file()).context("load runtime spec")
+    }
+
+    /// Replace the runtime spec of the bundle atomically.
+    pub fn save_spec(&self, spec: &Spec) -> Result<()> {
+        let path = self.config_file();
+        let tmp = path.with_extension("tmp");
+        spec.save(&tmp).context("write runtime spec")?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("replace runtime spec {}", path.display()))
+    }
+
+    /// Read the process ID of the container, which is not available before the runtime created
+    /// it.
+    pub async fn pid(&self) -> Result<Option<u32>> {
+        let path = self.pid_file();
+        match fs::read_to_string(&path).await {
+            Ok(content) => {
+                Ok(Some(content.trim().parse().with_context(|| {
+                    format!("parse process ID in {}", path.display())
+                })?))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("read {}", path.display())),
+        }
+    }
+
+    /// Read the exit of the container, which is not available before it exited. The exit time
+    /// is the one the monitor wrote the exit file at.
+    pub async fn exit(&self) -> Result<Option<Exit>> {
+        let path = self.exit_file();
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        let code = content
+            .trim()
+            .parse()
+            .with_context(|| format!("parse exit code in {}", path.display()))?;
+        let time = fs::metadata(&path).await?.modified()?;
+        Ok(Some(Exit { code, time }))
+    }
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Eq, PartialEq)]
+/// The exit of a container init process.
+pub struct Exit {
+    #[get_copy = "pub"]
+    /// The exit code, which is `128 + signal` if the process got killed by a signal.
+    code: i32,
+
+    #[get_copy = "pub"]
+    /// The time the process exited.
+    time: SystemTime,
+}
+
+#[derive(Clone, Debug, Getters)]
+/// Manages the bundle directories of all containers.
+pub struct Bundles {
+    #[get = "pub"]
+    /// The directory containing the bundles.
+    root: PathBuf,
+
+    /// The storage containing the bundle records.
+    storage: Arc<Mutex<DefaultKeyValueStorage>>,
+}
+
+impl Bundles {
+    /// Create a new bundle manager for the root, whereas the bundles get recorded in the
+    /// provided storage.
+    pub fn new<P: Into<PathBuf>>(root: P, storage: DefaultKeyValueStorage) -> Self {
+        Self {
+            root: root.into(),
+            storage: Arc::new(Mutex::new(storage)),
+        }
+    }
+
+    /// Create the bundle of the container by writing its runtime spec and linking the root file
+    /// system into it. The root path of the spec gets replaced by the link.
+    pub async fn create(&self, id: &str, spec: &Spec, rootfs: &Path) -> Result<Bundle> {
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            bail!("invalid container ID {:?}", id)
+        }
+        if self.get(id)?.is_some() {
+            bail!("bundle of container {} already exists", id)
+        }
+
+        let bundle = Bundle {
+            id: id.into(),
+            path: self.root.join(id),
+        };
+        fs::create_dir_all(&self.root)
+            .await
+            .with_context(|| format!("create bundle root {}", self.root.display()))?;
+        fs::create_dir(bundle.path())
+            .await
+            .with_context(|| format!("create bundle {}", bundle.path().display()))?;
+
+        if let Err(e) = Self::write(&bundle, spec, rootfs).await {
+            if let Err(e) = fs::remove_dir_all(bundle.path()).await {
+                debug!(
+                    "Unable to clean up bundle {}: {}",
+                    bundle.path().display(),
+                    e
+                );
+            }
+            return Err(e);
+        }
+        self.storage()
+            .insert(Self::key(id), &bundle)
+            .context("record bundle")?;
+
+        trace!("Created bundle {}", bundle.path().display());
+        Ok(bundle)
+    }
+
+    /// Get the bundle of the container.
+    pub fn get(&self, id: &str) -> Result<Option<Bundle>> {
+        self.storage().get(Self::key(id))
+    }
+
+    /// List the bundles of all containers ordered by their ID.
+    pub fn list(&self) -> Result<Vec<Bundle>> {
+        Ok(self
+            .storage()
+            .scan_prefix(BUNDLE_PREFIX)?
+            .into_iter()
+            .map(|(_, bundle)| bundle)
+            .collect())
+    }
+
+    /// Remove the bundle of the container including all files within it. Removing a bundle which
+    /// does not exist is not an error.
+    pub async fn remove(&self, id: &str) -> Result<()> {
+        let bundle = match self.get(id)? {
+            Some(bundle) => bundle,
+            None => return Ok(()),
+        };
+        match fs::remove_dir_all(bundle.path()).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("remove {}", bundle.path().display()))
+            }
+            _ => {}
+        }
+        self.storage().remove(Self::key(id))?;
+
+        debug!("Removed bundle {}", bundle.path().display());
+        Ok(())
+    }
+
+    /// Link the root file system and write the runtime spec into the bundle.
+    async fn write(bundle: &Bundle, spec: &Spec, rootfs: &Path) -> Result<()> {
+        fs::create_dir(bundle.exit_dir())
+            .await
+            .context("create exit directory")?;
+        fs::symlink(rootfs, bundle.rootfs())
+            .await
+            .with_context(|| format!("link root file system {}", rootfs.display()))?;
+
+        let mut root = spec.root().clone().unwrap_or_default();
+        root.set_path(ROOTFS_LINK.into());
+        let mut spec = spec.clone();
+        spec.set_root(Some(root));
+        spec.save(bundle.config_file())
+            .context("write runtime spec")
+    }
+
+    /// Lock the storage.
+    fn storage(&self) -> MutexGuard<'_, DefaultKeyValueStorage> {
+        self.storage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The storage key of the bundle.
+    fn key(id: &str) -> String {
+        format!("{}{}", BUNDLE_PREFIX, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::RootBuilder;
+    use tempfile::TempDir;
+
+    fn new_bundles(dir: &TempDir) -> Result<Bundles> {
+        Ok(Bundles::new(
+            dir.path().join("bundles"),
+            DefaultKeyValueStorage::open(dir.path().join("storage"))?,
+        ))
+    }
+
+    #[tokio::test]
+    async fn create_and_remove() -> Result<()> {
+        let dir = TempDir::new()?;
+        let rootfs = dir.path().join("merged");
+        std::fs::create_dir(&rootfs)?;
+        let spec = Spec::default()
+            .set_root(Some(
+                RootBuilder::default()
+                    .path(&rootfs)
+                    .readonly(false)
+                    .build()?,
+            ))
+            .clone();
+        let sut = new_bundles(&dir)?;
+
+        let bundle = sut.create("id", &spec, &rootfs).await?;
+        assert_eq!(bundle.path(), &dir.path().join("bundles").join("id"));
+        assert_eq!(std::fs::read_link(bundle.rootfs())?, rootfs);
+        let root = bundle.spec()?.root().clone().context("no root")?;
+        assert_eq!(root.path(), Path::new(ROOTFS_LINK));
+        assert_eq!(root.readonly(), Some(false));
+        assert!(bundle.pid().await?.is_none());
+        std::fs::write(bundle.pid_file(), "123\n")?;
+        assert_eq!(bundle.pid().await?, Some(123));
+        assert!(bundle.exit().await?.is_none());
+        std::fs::write(bundle.exit_file(), "137")?;
+        let exit = bundle.exit().await?.context("no exit")?;
+        assert_eq!(exit.code(), 137);
+        assert!(exit.time() <= SystemTime::now());
+
+        assert_eq!(sut.get("id")?, Some(bundle.clone()));
+        assert_eq!(sut.list()?, vec![bundle.clone()]);
+        assert!(sut.create("id", &spec, &rootfs).await.is_err());
+
+        sut.remove("id").await?;
+        assert!(!bundle.path().exists());
+        assert!(rootfs.exists());
+        assert!(sut.get("id")?.is_none());
+        sut.remove("id").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persisted() -> Result<()> {
+        let dir = TempDir::new()?;
+        let bundle = {
+            let sut = new_bundles(&dir)?;
+            let bundle = sut.create("id", &Spec::default(), dir.path()).await?;
+            sut.storage().persist()?;
+            bundle
+        };
+
+        let sut = new_bundles(&dir)?;
+        assert_eq!(sut.get("id")?, Some(bundle));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_failure() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_bundles(&dir)?;
+        for id in &["", ".", "..", "a/b"] {
+            assert!(sut.create(id, &Spec::default(), dir.path()).await.is_err());
+        }
+        assert!(sut.list()?.is_empty());
+        Ok(())
+    }
+}
@@ -2,23 +2,39 @@
 //!
 //! [0]: https://github.com/containers/conmon
 
-#![allow(dead_code)]
-
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use derive_builder::Builder;
 use dyn_clone::{clone_trait_object, DynClone};
 use getset::{Getters, Setters};
 use log::LevelFilter;
+use nix::{
+    fcntl::{self, FcntlArg, FdFlag, OFlag},
+    unistd,
+};
+use serde::Deserialize;
 use std::{
+    convert::TryFrom,
     fmt::{self, Debug},
+    fs::File,
+    io::{self, BufRead, BufReader},
+    os::unix::io::{FromRawFd, RawFd},
     path::{Path, PathBuf},
-    process::Output,
+    process::{ExitStatus, Output, Stdio},
     string::ToString,
     time::Duration,
 };
 use strum::AsRefStr;
-use tokio::process::Command;
+use tokio::{process::Command, task};
+
+/// The default conmon binary, which gets looked up in `$PATH`.
+pub const DEFAULT_CONMON: &str = "conmon";
+
+/// The environment variable containing the file descriptor of the sync pipe.
+const SYNC_PIPE_ENV: &str = "_OCI_SYNCPIPE";
+
+/// The file descriptor of the sync pipe within conmon.
+const SYNC_PIPE_FD: RawFd = 3;
 
 #[derive(Builder, Debug, Getters, Setters)]
 #[builder(pattern = "owned", setter(into))]
@@ -41,6 +57,35 @@ impl Conmon {
     pub async fn run(&self, args: &[Arg]) -> Result<Output> {
         self.exec().run_output(self.binary(), args).await
     }
+
+    /// Run conmon to create a container and return the process ID of the container, which
+    /// conmon reports via the sync pipe. Conmon detaches afterwards to keep monitoring the
+    /// container and writes its exit code into the exit directory.
+    pub async fn create(&self, args: &[Arg]) -> Result<u32> {
+        let (status, message) = self.exec().run_synced(self.binary(), args).await?;
+        if message.trim().is_empty() {
+            bail!("conmon exited ({}) without creating the container", status)
+        }
+        let message: SyncMessage =
+            serde_json::from_str(&message).context("parse conmon sync message")?;
+        match u32::try_from(message.data) {
+            Ok(pid) if pid > 0 => Ok(pid),
+            _ => bail!(
+                "conmon failed to create the container: {}",
+                message.message.unwrap_or_default().trim()
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+/// A message written by conmon to the sync pipe.
+struct SyncMessage {
+    /// The process ID of the container on success, otherwise negative.
+    data: i32,
+
+    /// The error message on failure.
+    message: Option<String>,
 }
 
 #[derive(Clone, Default, Debug)]
@@ -59,6 +104,44 @@ trait ExecCommand: Debug + DynClone + Send + Sync {
             .await
             .context("run conmon")
     }
+
+    /// Run a command with its standard streams detached and a sync pipe passed via
+    /// `_OCI_SYNCPIPE`. Return the exit status and the first line written to the pipe, which
+    /// is empty if the pipe got closed without any message.
+    async fn run_synced(&self, binary: &Path, args: &[Arg]) -> Result<(ExitStatus, String)> {
+        let (reader, writer) = unistd::pipe2(OFlag::O_CLOEXEC).context("create sync pipe")?;
+        let reader = unsafe { File::from_raw_fd(reader) };
+
+        let mut cmd = Command::new(binary);
+        cmd.args(args.iter().map(ToString::to_string))
+            .env(SYNC_PIPE_ENV, SYNC_PIPE_FD.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        unsafe {
+            cmd.pre_exec(move || {
+                // The duplicate does not inherit the close-on-exec flag of the writer
+                let res = if writer == SYNC_PIPE_FD {
+                    fcntl::fcntl(writer, FcntlArg::F_SETFD(FdFlag::empty()))
+                } else {
+                    unistd::dup2(writer, SYNC_PIPE_FD)
+                };
+                res.map(drop).map_err(io::Error::from)
+            });
+        }
+        let status = cmd.status().await;
+        unistd::close(writer).context("close sync pipe")?;
+        let status = status.context("run conmon")?;
+
+        let message = task::spawn_blocking(move || -> io::Result<String> {
+            let mut line = String::new();
+            BufReader::new(reader).read_line(&mut line)?;
+            Ok(line)
+        })
+        .await?
+        .context("read sync pipe")?;
+        Ok((status, message))
+    }
 }
 
 clone_trait_object!(ExecCommand);
@@ -219,7 +302,7 @@ impl fmt::Display for Arg {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+    use std::os::unix::{fs::PermissionsExt, process::ExitStatusExt};
 
     #[derive(Clone, Debug)]
     struct MockExecCommand(Output);
@@ -262,6 +345,43 @@ mod tests {
         Ok(())
     }
 
+    /// Create a fake conmon which writes the message to the sync pipe.
+    fn fake_conmon(dir: &Path, message: &str) -> Result<Conmon> {
+        let binary = dir.join("conmon");
+        std::fs::write(
+            &binary,
+            format!(
+                "#!/bin/sh\necho '{}' >&\"$_OCI_SYNCPIPE\"\nexit 1\n",
+                message
+            ),
+        )?;
+        std::fs::set_permissions(&binary, PermissionsExt::from_mode(0o755))?;
+        Ok(ConmonBuilder::default().binary(binary).build()?)
+    }
+
+    #[tokio::test]
+    async fn conmon_success_create() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let conmon = fake_conmon(dir.path(), r#"{"data": 123}"#)?;
+        assert_eq!(conmon.create(&[Arg::Cid("id".into())]).await?, 123);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conmon_failure_create() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let conmon = fake_conmon(dir.path(), r#"{"data": -1, "message": "runtime failed"}"#)?;
+        let err = conmon.create(&[]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "conmon failed to create the container: runtime failed"
+        );
+
+        let conmon = fake_conmon(dir.path(), "")?;
+        assert!(conmon.create(&[]).await.is_err());
+        Ok(())
+    }
+
     #[test]
     fn conmon_success() {
         assert!(ConmonBuilder::default()
@@ -289,5 +409,10 @@ mod tests {
         assert_eq!(&Arg::LogTag("test".into()).to_string(), "--log-tag=test");
         assert_eq!(&Arg::NoSyncLog.to_string(), "--no-sync-log");
         assert_eq!(&Arg::ReplaceListenPid.to_string(), "--replace-listen-pid");
+        assert_eq!(
+            &Arg::ExitDir("/exits".into()).to_string(),
+            "--exit-dir=/exits"
+        );
+        assert_eq!(&Arg::LogSizeMax(1024).to_string(), "--log-size-max=1024");
     }
 }
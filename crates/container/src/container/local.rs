@@ -6,17 +6,31 @@
 
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
+use common::cgroup::{Cgroup, DEFAULT_CGROUP_ROOT};
 use derive_builder::Builder;
-use getset::Getters;
+use getset::{CopyGetters, Getters};
+use log::debug;
 use oci_spec::runtime::{LinuxResources, Spec};
 use serde::{Deserialize, Serialize};
-use tokio::{process::Command, signal::unix::SignalKind};
+use tokio::{fs, process::Command, signal::unix::SignalKind};
 
 use super::{Container, ContainerState, ContainerStats};
+use crate::{
+    bundle::Bundle,
+    conmon::{Arg, ConmonBuilder},
+    monitor::{native::NativeMonitorBuilder, Monitor, MonitorConfig},
+    oci_runtime::{
+        CreateArgs, DeleteArgs, GlobalArgs, OCIRuntime, OCIRuntimeBuilder, RuntimeStatus,
+        Subcommand, UpdateArgs,
+    },
+};
 
-#[derive(Debug, Default, Builder, Getters, Serialize, Deserialize)]
+/// The default OCI runtime binary, which gets looked up in `$PATH`.
+pub const DEFAULT_RUNTIME: &str = "runc";
+
+#[derive(Debug, Default, Builder, CopyGetters, Getters, Serialize, Deserialize)]
 #[builder(default, pattern = "owned", setter(into, strip_option))]
 /// A general OCI container implementation.
 pub struct OCIContainer {
@@ -30,70 +44,529 @@ pub struct OCIContainer {
     #[get = "pub"]
     /// OCI Runtime Specification of the container.
     spec: Spec,
+
+    #[get = "pub"]
+    #[builder(default = "DEFAULT_RUNTIME.into()")]
+    /// Path to the OCI runtime binary, like runc or crun.
+    runtime: PathBuf,
+
+    #[get = "pub"]
+    /// Root directory of the runtime for the container state, which uses the default of the
+    /// runtime if not set.
+    runtime_root: Option<PathBuf>,
+
+    #[get_copy = "pub"]
+    /// Let the runtime manage the cgroup through systemd, which requires the cgroups path of the
+    /// spec to be of the form `slice:prefix:name`.
+    systemd_cgroup: bool,
+
+    #[get = "pub"]
+    #[builder(default = "DEFAULT_CGROUP_ROOT.into()")]
+    /// Mount point of the cgroup hierarchies, used to read the container statistics.
+    cgroup_root: PathBuf,
+
+    #[get = "pub"]
+    /// The bundle containing the runtime spec of the container.
+    bundle: Bundle,
+
+    #[get = "pub"]
+    /// The configuration of the monitor supervising the container if set. Otherwise the runtime
+    /// gets invoked directly and the exit of the container is not recorded.
+    monitor: Option<MonitorConfig>,
+
+    #[get_copy = "pub"]
+    /// Keep the standard input of the container open for attach clients.
+    stdin: bool,
+
+    #[get_copy = "pub"]
+    /// Close the standard input once the first attach client disconnected.
+    stdin_once: bool,
+}
+
+impl OCIContainer {
+    /// The runtime for invoking the subcommands of the container.
+    fn oci_runtime(&self) -> Result<OCIRuntime> {
+        OCIRuntimeBuilder::default()
+            .binary(self.runtime())
+            .build()
+            .context("build OCI runtime")
+    }
+
+    /// The global arguments of all runtime invocations.
+    fn global_args(&self) -> Vec<GlobalArgs> {
+        let mut args = vec![GlobalArgs::Log(self.bundle().runtime_log_file())];
+        if let Some(root) = self.runtime_root() {
+            args.push(GlobalArgs::Root(root.clone()));
+        }
+        if self.systemd_cgroup() {
+            args.push(GlobalArgs::SystemdCgroup);
+        }
+        args
+    }
+
+    /// Whether the container process gets a terminal allocated.
+    fn terminal(&self) -> bool {
+        self.spec()
+            .process()
+            .as_ref()
+            .and_then(|p| p.terminal())
+            .unwrap_or_default()
+    }
+
+    /// The arguments of conmon to create the container.
+    fn conmon_args(&self, monitor: &MonitorConfig) -> Vec<Arg> {
+        let mut args = vec![
+            Arg::ApiVersion("1".into()),
+            Arg::Cid(self.id().clone()),
+            Arg::Cuuid(self.id().clone()),
+            Arg::Name(self.id().clone()),
+            Arg::Runtime(self.runtime().clone()),
+            Arg::Bundle(self.bundle().path().clone()),
+            Arg::ContainerPidfile(self.bundle().pid_file()),
+            Arg::ConmonPidfile(self.bundle().monitor_pid_file()),
+            Arg::ExitDir(self.bundle().exit_dir()),
+            Arg::SocketDirPath(monitor.socket_dir().clone()),
+        ];
+        args.extend(
+            self.global_args()
+                .iter()
+                .map(|arg| Arg::RuntimeArg(arg.to_string())),
+        );
+        if !self.log_path().as_os_str().is_empty() {
+            args.push(Arg::LogPath(self.log_path().clone()));
+        }
+        if let Some(size) = monitor.log_size_max() {
+            args.push(Arg::LogSizeMax(size));
+        }
+        if let Some(command) = monitor.exit_command() {
+            args.push(Arg::ExitCommand(command.clone()));
+            args.extend(
+                monitor
+                    .exit_command_args()
+                    .iter()
+                    .map(|arg| Arg::ExitCommandArg(arg.clone())),
+            );
+        }
+        if self.terminal() {
+            args.push(Arg::Terminal);
+        }
+        if self.stdin() {
+            args.push(Arg::Stdin);
+            if !self.stdin_once() {
+                args.push(Arg::LeaveStdinOpen);
+            }
+        }
+        args
+    }
+
+    /// Run the runtime subcommand and return its standard output.
+    async fn run(&self, subcommand: Subcommand) -> Result<Vec<u8>> {
+        let output = self
+            .oci_runtime()?
+            .run_checked(&subcommand, &self.global_args())
+            .await?;
+        Ok(output.stdout)
+    }
 }
 
 #[async_trait]
 impl Container for OCIContainer {
     /// Create a new container, which should be in the `Created` state afterwards.
     async fn create(&mut self) -> Result<()> {
-        Ok(())
+        if !self.bundle().config_file().exists() {
+            bail!("bundle of container {} has no runtime spec", self.id())
+        }
+        if let Some(monitor) = self.monitor() {
+            let pid = match monitor.monitor() {
+                Monitor::Conmon => {
+                    fs::create_dir_all(monitor.socket_dir())
+                        .await
+                        .context("create socket directory")?;
+                    ConmonBuilder::default()
+                        .binary(monitor.conmon())
+                        .build()?
+                        .create(&self.conmon_args(monitor))
+                        .await?
+                }
+                Monitor::Native => {
+                    NativeMonitorBuilder::default()
+                        .runtime(self.runtime())
+                        .global_args(self.global_args())
+                        .bundle(self.bundle().clone())
+                        .config(monitor.clone())
+                        .log_path(self.log_path())
+                        .stdin(self.stdin())
+                        .stdin_once(self.stdin_once())
+                        .terminal(self.terminal())
+                        .build()?
+                        .create()
+                        .await?
+                }
+            };
+            debug!("Created container {} with PID {}", self.id(), pid);
+            return Ok(());
+        }
+        let subcommand = Subcommand::Create((
+            self.id().clone(),
+            vec![
+                CreateArgs::Bundle(self.bundle().path().clone()),
+                CreateArgs::PidFile(self.bundle().pid_file()),
+            ],
+        ));
+        self.oci_runtime()?
+            .run_detached(&subcommand, &self.global_args())
+            .await
     }
 
     /// Execute the user defined process in a created container.
     async fn start(&mut self) -> Result<()> {
-        unimplemented!()
+        self.run(Subcommand::Start(self.id().clone())).await?;
+        Ok(())
     }
 
-    /// Delete any resources held by the container often used with detached container.
+    /// Delete any resources held by the container often used with detached container. Running
+    /// containers get killed.
     async fn delete(&mut self) -> Result<()> {
-        unimplemented!()
+        self.run(Subcommand::Delete((
+            self.id().clone(),
+            vec![DeleteArgs::Force],
+        )))
+        .await?;
+        Ok(())
     }
 
     /// Suspend all processes inside the container.
     async fn pause(&mut self) -> Result<()> {
-        unimplemented!()
+        self.run(Subcommand::Pause(self.id().clone())).await?;
+        Ok(())
     }
 
     /// Resumes all processes that have been previously paused.
     async fn resume(&mut self) -> Result<()> {
-        unimplemented!()
+        self.run(Subcommand::Resume(self.id().clone())).await?;
+        Ok(())
     }
 
     /// Send the specified signal to the container's init process.
-    async fn kill(&mut self, _signal_kind: SignalKind) -> Result<()> {
-        unimplemented!()
+    async fn kill(&mut self, signal_kind: SignalKind) -> Result<()> {
+        self.run(Subcommand::Kill((
+            self.id().clone(),
+            vec![],
+            Some(signal_kind.as_raw_value()),
+        )))
+        .await?;
+        Ok(())
     }
 
     /// Update container resource constraints.
-    async fn update(&mut self, _resources: &LinuxResources) -> Result<()> {
-        unimplemented!()
+    async fn update(&mut self, resources: &LinuxResources) -> Result<()> {
+        let path = self.bundle().resources_file();
+        fs::write(&path, serde_json::to_vec(resources)?)
+            .await
+            .with_context(|| format!("write resources {}", path.display()))?;
+        self.run(Subcommand::Update((
+            self.id().clone(),
+            vec![UpdateArgs::Resources(path)],
+        )))
+        .await?;
+
+        let mut linux = self.spec().linux().clone().unwrap_or_default();
+        linux.set_resources(Some(resources.clone()));
+        self.spec.set_linux(Some(linux));
+
+        // Keep the spec of the bundle in sync for later status requests and updates
+        let mut spec = self.bundle().spec()?;
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        linux.set_resources(Some(resources.clone()));
+        spec.set_linux(Some(linux));
+        self.bundle().save_spec(&spec)?;
+        Ok(())
     }
 
-    /// Execute the provided process inside the container.
+    /// Execute the provided process inside the container, which is not supported yet.
     async fn exec(&self, _command: &Command) -> Result<()> {
-        unimplemented!()
+        Err(anyhow!("exec is not supported for container {}", self.id()))
     }
 
-    /// Retrieve container resource statistics.
+    /// Retrieve container resource statistics from the cgroup of the container init process.
     async fn stats(&self) -> Result<ContainerStats> {
-        unimplemented!()
+        let state = self
+            .oci_runtime()?
+            .state(self.id(), &self.global_args())
+            .await?;
+        if state.pid() == 0 {
+            bail!("container {} is not running", self.id())
+        }
+        let cgroup = Cgroup::of_process(self.cgroup_root(), state.pid())
+            .await
+            .context("resolve container cgroup")?;
+        Ok(ContainerStats::new(cgroup.stats().await?))
     }
 
-    /// Retrieve the state of a container.
+    /// Retrieve the state of a container. The exit code of stopped containers is the one
+    /// recorded by the monitor.
     async fn state(&self) -> Result<ContainerState> {
-        unimplemented!()
+        let state = self
+            .oci_runtime()?
+            .state(self.id(), &self.global_args())
+            .await?;
+        Ok(match state.status() {
+            RuntimeStatus::Creating | RuntimeStatus::Created => ContainerState::Created,
+            RuntimeStatus::Running => ContainerState::Started,
+            RuntimeStatus::Paused => ContainerState::Paused,
+            RuntimeStatus::Stopped => match self.bundle().exit().await? {
+                Some(exit) => ContainerState::Stopped(exit.code()),
+                None => ContainerState::Killed,
+            },
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{bundle::Bundles, monitor::MonitorConfigBuilder, oci_runtime::RuntimeError};
+    use common::cgroup::stats::CgroupStats;
+    use oci_spec::runtime::LinuxPidsBuilder;
+    use std::{os::unix::fs::PermissionsExt, path::Path, time::SystemTime};
+    use storage::{default_key_value_storage::DefaultKeyValueStorage, KeyValueStorage};
+    use tempfile::TempDir;
+
+    /// Create a fake runtime in the directory, which records its arguments in the `calls` file
+    /// and reports the container as running. It fails for containers with the ID `fail` and
+    /// reports the ones with the ID `stopped` as stopped.
+    fn fake_runtime(dir: &Path) -> Result<PathBuf> {
+        let binary = dir.join("runtime");
+        std::fs::write(
+            &binary,
+            format!(
+                r#"#!/bin/sh
+echo "$@" >> {}
+case "$*" in
+    *" fail "* | *" fail") echo "container failed" >&2; exit 1 ;;
+    *" state stopped") echo '{{"id":"stopped","pid":0,"status":"stopped","bundle":"/b"}}' ;;
+    *" state "*) echo '{{"ociVersion":"1.0.2","id":"id","pid":1,"status":"running","bundle":"/b"}}' ;;
+esac
+"#,
+                dir.join("calls").display()
+            ),
+        )?;
+        std::fs::set_permissions(&binary, PermissionsExt::from_mode(0o755))?;
+        Ok(binary)
+    }
+
+    async fn container(dir: &TempDir, id: &str) -> Result<OCIContainer> {
+        let bundles = Bundles::new(
+            dir.path().join("bundles"),
+            DefaultKeyValueStorage::open(dir.path().join("storage"))?,
+        );
+        let bundle = bundles.create(id, &Spec::default(), dir.path()).await?;
+        Ok(OCIContainerBuilder::default()
+            .id(id)
+            .runtime(fake_runtime(dir.path())?)
+            .runtime_root(dir.path().join("root"))
+            .cgroup_root(dir.path().join("cgroup"))
+            .bundle(bundle)
+            .build()?)
+    }
+
+    fn calls(dir: &TempDir) -> Result<Vec<String>> {
+        Ok(std::fs::read_to_string(dir.path().join("calls"))?
+            .lines()
+            .map(Into::into)
+            .collect())
+    }
 
     #[test]
     fn container_create() -> Result<()> {
         let container = OCIContainerBuilder::default().id("id").build()?;
         assert_eq!(container.id(), "id");
         assert_eq!(container.spec(), &Spec::default());
+        assert_eq!(container.runtime(), Path::new(DEFAULT_RUNTIME));
+        assert!(!container.global_args().contains(&GlobalArgs::SystemdCgroup));
+
+        let container = OCIContainerBuilder::default()
+            .id("id")
+            .systemd_cgroup(true)
+            .build()?;
+        assert!(container.global_args().contains(&GlobalArgs::SystemdCgroup));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_lifecycle() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut container = container(&dir, "id").await?;
+        let bundle = container.bundle().clone();
+        let args = format!(
+            "--log={} --root={}",
+            bundle.runtime_log_file().display(),
+            dir.path().join("root").display()
+        );
+
+        container.create().await?;
+        container.start().await?;
+        container.pause().await?;
+        container.resume().await?;
+        container.kill(SignalKind::terminate()).await?;
+        assert_eq!(container.state().await?, ContainerState::Started);
+
+        let resources = LinuxResources::default()
+            .set_pids(Some(LinuxPidsBuilder::default().limit(10).build()?))
+            .clone();
+        container.update(&resources).await?;
+        assert_eq!(
+            container
+                .spec()
+                .linux()
+                .as_ref()
+                .and_then(|l| l.resources().clone()),
+            Some(resources.clone())
+        );
+        assert_eq!(
+            bundle
+                .spec()?
+                .linux()
+                .as_ref()
+                .and_then(|l| l.resources().clone()),
+            Some(resources)
+        );
+
+        container.delete().await?;
+        assert_eq!(
+            calls(&dir)?,
+            vec![
+                format!(
+                    "{} create --bundle={} --pid-file={} id",
+                    args,
+                    bundle.path().display(),
+                    bundle.pid_file().display()
+                ),
+                format!("{} start id", args),
+                format!("{} pause id", args),
+                format!("{} resume id", args),
+                format!("{} kill id 15", args),
+                format!("{} state id", args),
+                format!(
+                    "{} update --resources={} id",
+                    args,
+                    bundle.resources_file().display()
+                ),
+                format!("{} delete --force id", args),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_exec_unsupported() -> Result<()> {
+        let container = OCIContainerBuilder::default().id("id").build()?;
+        assert!(container.exec(&Command::new("true")).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_create_conmon() -> Result<()> {
+        let dir = TempDir::new()?;
+        let binary = dir.path().join("conmon");
+        std::fs::write(
+            &binary,
+            format!(
+                "#!/bin/sh\nfor arg; do echo \"$arg\"; done > {}\necho '{{\"data\": 42}}' >&3\n",
+                dir.path().join("args").display()
+            ),
+        )?;
+        std::fs::set_permissions(&binary, PermissionsExt::from_mode(0o755))?;
+        let monitor = MonitorConfigBuilder::default()
+            .conmon(binary)
+            .socket_dir(dir.path().join("sockets"))
+            .log_size_max(1024u64)
+            .exit_command("/bin/cleanup")
+            .exit_command_args(vec!["id".to_string()])
+            .build()?;
+        let mut container = container(&dir, "id").await?;
+        container.monitor = Some(monitor);
+        container.stdin = true;
+        container.log_path = dir.path().join("container.log");
+        let bundle = container.bundle().clone();
+
+        container.create().await?;
+        assert!(dir.path().join("sockets").is_dir());
+        let args = std::fs::read_to_string(dir.path().join("args"))?;
+        assert_eq!(
+            args.lines().collect::<Vec<_>>(),
+            vec![
+                "--api-version=1".to_string(),
+                "--cid=id".into(),
+                "--cuuid=id".into(),
+                "--name=id".into(),
+                format!("--runtime={}", container.runtime().display()),
+                format!("--bundle={}", bundle.path().display()),
+                format!("--container-pidfile={}", bundle.pid_file().display()),
+                format!("--conmon-pidfile={}", bundle.monitor_pid_file().display()),
+                format!("--exit-dir={}", bundle.exit_dir().display()),
+                format!("--socket-dir-path={}", dir.path().join("sockets").display()),
+                format!(
+                    "--runtime-arg=--log={}",
+                    bundle.runtime_log_file().display()
+                ),
+                format!("--runtime-arg=--root={}", dir.path().join("root").display()),
+                format!("--log-path={}", dir.path().join("container.log").display()),
+                "--log-size-max=1024".into(),
+                "--exit-command=/bin/cleanup".into(),
+                "--exit-command-arg=id".into(),
+                "--stdin".into(),
+                "--leave-stdin-open".into(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_state_stopped() -> Result<()> {
+        let dir = TempDir::new()?;
+        let container = container(&dir, "stopped").await?;
+        assert_eq!(container.state().await?, ContainerState::Killed);
+
+        std::fs::write(container.bundle().exit_file(), "137")?;
+        assert_eq!(container.state().await?, ContainerState::Stopped(137));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_stats() -> Result<()> {
+        let dir = TempDir::new()?;
+        let container = container(&dir, "id").await?;
+        let stats = container.stats().await?;
+        assert!(stats.timestamp() <= SystemTime::now());
+        assert_eq!(stats.cgroup(), &CgroupStats::default());
+
+        let container = OCIContainerBuilder::default()
+            .id("stopped")
+            .runtime(fake_runtime(dir.path())?)
+            .build()?;
+        assert!(container.stats().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_failure_runtime() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut container = container(&dir, "fail").await?;
+
+        let err = container.start().await.unwrap_err();
+        let err = err
+            .downcast_ref::<RuntimeError>()
+            .context("no runtime error")?;
+        assert_eq!(err.subcommand(), "start");
+        assert_eq!(err.stderr(), "container failed");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_failure_no_bundle() -> Result<()> {
+        let mut container = OCIContainerBuilder::default().id("id").build()?;
+        assert!(container.create().await.is_err());
         Ok(())
     }
 }
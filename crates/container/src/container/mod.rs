@@ -2,8 +2,11 @@
 
 use anyhow::Result;
 use async_trait::async_trait;
+use common::cgroup::stats::CgroupStats;
+use getset::{CopyGetters, Getters};
 use oci_spec::runtime::LinuxResources;
 use serde::{de::DeserializeOwned, Serialize};
+use std::time::SystemTime;
 use strum::{AsRefStr, Display, EnumString, IntoStaticStr};
 use tokio::{process::Command, signal::unix::SignalKind};
 
@@ -46,9 +49,27 @@ where
     async fn state(&self) -> Result<ContainerState>;
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, CopyGetters, Debug, Eq, Getters, PartialEq)]
 /// Container resource statistics.
-pub struct ContainerStats;
+pub struct ContainerStats {
+    #[get_copy = "pub"]
+    /// The time the statistics got collected.
+    timestamp: SystemTime,
+
+    #[get = "pub"]
+    /// The resource usage of the container cgroup.
+    cgroup: CgroupStats,
+}
+
+impl ContainerStats {
+    /// Create new statistics from the cgroup usage collected right now.
+    pub fn new(cgroup: CgroupStats) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            cgroup,
+        }
+    }
+}
 
 #[derive(AsRefStr, Clone, Copy, Debug, Display, EnumString, Eq, Hash, IntoStaticStr, PartialEq)]
 #[strum(serialize_all = "snake_case")]
@@ -63,6 +84,11 @@ pub enum ContainerState {
     /// The container is paused, usually after calling its `pause()` trait method.
     Paused,
 
-    /// The container is stopped, usually after calling its `kill()` trait method.
+    /// The container is stopped, usually after calling its `kill()` trait method, but its exit
+    /// code is unknown since no monitor recorded it.
     Killed,
+
+    /// The container is stopped and exited with the provided code, which is `128 + signal` if it
+    /// got killed by a signal.
+    Stopped(i32),
 }
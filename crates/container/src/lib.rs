@@ -1,5 +1,7 @@
 //! Open Container Initiative (OCI) related implementations
 
-mod conmon;
+pub mod bundle;
+pub mod conmon;
 pub mod container;
+pub mod monitor;
 pub mod oci_runtime;
@@ -0,0 +1,171 @@
+//! Attach sockets of containers, which speak the same protocol as the ones of conmon.
+//!
+//! The sockets are of type `SOCK_SEQPACKET`. Every packet sent to a client starts with a byte
+//! identifying the output stream of the container, followed by the output itself. Packets
+//! received from a client are written unmodified to the standard input of the container.
+
+use super::{fd::NonBlockingFd, Stream};
+use anyhow::{bail, Context, Result};
+use nix::sys::socket::{self, AddressFamily, SockFlag, SockType, UnixAddr};
+use std::{
+    convert::TryFrom,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+/// Name of the attach socket within the bundle.
+pub const ATTACH_SOCKET: &str = "attach";
+
+/// Maximum size of a packet including the stream byte.
+pub const PACKET_SIZE: usize = 8192;
+
+/// Maximum number of pending connections.
+const BACKLOG: usize = 10;
+
+impl From<Stream> for u8 {
+    /// The pipe type conmon uses for the stream.
+    fn from(stream: Stream) -> Self {
+        match stream {
+            Stream::Stdout => 2,
+            Stream::Stderr => 3,
+        }
+    }
+}
+
+impl TryFrom<u8> for Stream {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            2 => Ok(Stream::Stdout),
+            3 => Ok(Stream::Stderr),
+            _ => bail!("unknown stream {}", value),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// The listening attach socket of a container.
+pub struct AttachListener(NonBlockingFd);
+
+impl AttachListener {
+    /// Create the socket at the provided path.
+    pub fn bind(path: &Path) -> Result<Self> {
+        let fd = seqpacket_socket()?;
+        let addr = UnixAddr::new(path)?;
+        socket::bind(fd.as_raw_fd(), &addr)
+            .with_context(|| format!("bind attach socket {}", path.display()))?;
+        socket::listen(fd.as_raw_fd(), BACKLOG).context("listen on attach socket")?;
+        Ok(Self(NonBlockingFd::new(fd)?))
+    }
+
+    /// Wait for the next client.
+    pub async fn accept(&self) -> Result<AttachStream> {
+        let fd = self
+            .0
+            .readable(|fd| socket::accept4(fd, SockFlag::SOCK_CLOEXEC))
+            .await
+            .context("accept attach client")?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(AttachStream(NonBlockingFd::new(fd)?))
+    }
+}
+
+#[derive(Debug)]
+/// A connection to an attach socket.
+pub struct AttachStream(NonBlockingFd);
+
+impl AttachStream {
+    /// Connect to the attach socket at the provided path.
+    pub fn connect(path: &Path) -> Result<Self> {
+        let fd = seqpacket_socket()?;
+        let addr = UnixAddr::new(path)?;
+        socket::connect(fd.as_raw_fd(), &addr)
+            .with_context(|| format!("connect to attach socket {}", path.display()))?;
+        Ok(Self(NonBlockingFd::new(fd)?))
+    }
+
+    /// Send the output of the container, which gets split into multiple packets if required.
+    pub async fn send_output(&self, stream: Stream, data: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+        for chunk in data.chunks(PACKET_SIZE - 1) {
+            packet.clear();
+            packet.push(stream.into());
+            packet.extend_from_slice(chunk);
+            self.0.send(&packet).await.context("send output")?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next output packet of the container, which is `None` if the socket got
+    /// closed.
+    pub async fn recv_output(&self) -> Result<Option<(Stream, Vec<u8>)>> {
+        let mut packet = vec![0; PACKET_SIZE];
+        let n = self.0.recv(&mut packet).await.context("receive output")?;
+        if n == 0 {
+            return Ok(None);
+        }
+        let stream = Stream::try_from(packet[0])?;
+        packet.truncate(n);
+        packet.remove(0);
+        Ok(Some((stream, packet)))
+    }
+
+    /// Send input for the container.
+    pub async fn send_input(&self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(PACKET_SIZE) {
+            self.0.send(chunk).await.context("send input")?;
+        }
+        Ok(())
+    }
+
+    /// Receive input for the container into the buffer and return its length, which is zero if
+    /// the client disconnected.
+    pub async fn recv_input(&self, buf: &mut [u8]) -> Result<usize> {
+        self.0.recv(buf).await.context("receive input")
+    }
+}
+
+/// Create a unix socket for sequential packets.
+fn seqpacket_socket() -> Result<OwnedFd> {
+    let fd = socket::socket(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .context("create attach socket")?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn attach_roundtrip() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join(ATTACH_SOCKET);
+        let listener = AttachListener::bind(&path)?;
+        let client = AttachStream::connect(&path)?;
+        let server = listener.accept().await?;
+
+        let output = vec![b'a'; PACKET_SIZE];
+        server.send_output(Stream::Stderr, &output).await?;
+        let (stream, first) = client.recv_output().await?.context("no output")?;
+        assert_eq!(stream, Stream::Stderr);
+        assert_eq!(first.len(), PACKET_SIZE - 1);
+        let (_, second) = client.recv_output().await?.context("no output")?;
+        assert_eq!(second, b"a");
+
+        client.send_input(b"input").await?;
+        let mut buf = [0; PACKET_SIZE];
+        let n = server.recv_input(&mut buf).await?;
+        assert_eq!(&buf[..n], b"input");
+
+        drop(client);
+        assert_eq!(server.recv_input(&mut buf).await?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+//! Container logs in the format defined by the Container Runtime Interface (CRI).
+//!
+//! Every line of output becomes an entry `<time> <stream> <tag> <content>`. The time is in
+//! RFC3339 with nanoseconds and the tag is `F` for full lines or `P` for partial ones, which get
+//! continued by the next entry of the same stream.
+
+use super::Stream;
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use getset::{CopyGetters, Getters};
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+/// Tag of entries containing a full line.
+const TAG_FULL: &str = "F";
+
+/// Tag of entries containing a partial line.
+const TAG_PARTIAL: &str = "P";
+
+#[derive(CopyGetters, Debug, Getters)]
+/// Writes the output of a container into its log file.
+pub struct LogWriter {
+    #[get = "pub"]
+    /// Path to the log file.
+    path: PathBuf,
+
+    #[get_copy = "pub"]
+    /// Maximum size of the log file. The log gets truncated before exceeding it.
+    size_max: Option<u64>,
+
+    #[get_copy = "pub"]
+    /// The number of bytes written since the log got opened or truncated.
+    written: u64,
+
+    /// The opened log file.
+    file: File,
+}
+
+impl LogWriter {
+    /// Open the log file for appending, which gets created if it does not exist.
+    pub async fn open(path: &Path, size_max: Option<u64>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .await
+            .with_context(|| format!("open container log {}", path.display()))?;
+        let written = file.metadata().await?.len();
+        Ok(Self {
+            path: path.into(),
+            size_max,
+            written,
+            file,
+        })
+    }
+
+    /// Write the output of the stream into the log.
+    pub async fn write(&mut self, stream: Stream, data: &[u8]) -> Result<()> {
+        let entries = Self::entries(stream, data);
+        if let Some(size_max) = self.size_max {
+            if self.written + entries.len() as u64 > size_max {
+                self.file
+                    .set_len(0)
+                    .await
+                    .context("truncate container log")?;
+                self.written = 0;
+            }
+        }
+        self.file
+            .write_all(&entries)
+            .await
+            .context("write container log")?;
+        self.file.flush().await.context("flush container log")?;
+        self.written += entries.len() as u64;
+        Ok(())
+    }
+
+    /// Format the output of the stream into log entries, which share the current time.
+    fn entries(stream: Stream, data: &[u8]) -> Vec<u8> {
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let mut entries = vec![];
+        for line in data.split_inclusive(|b| *b == b'\n') {
+            let (content, tag) = match line.strip_suffix(b"\n") {
+                Some(content) => (content, TAG_FULL),
+                None => (line, TAG_PARTIAL),
+            };
+            entries.extend_from_slice(format!("{} {} {} ", time, stream, tag).as_bytes());
+            entries.extend_from_slice(content);
+            entries.push(b'\n');
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn parse(log: &str) -> Vec<(&str, &str, &str)> {
+        log.lines()
+            .map(|line| {
+                let mut fields = line.splitn(4, ' ');
+                let time = fields.next().unwrap_or_default();
+                assert!(chrono::DateTime::parse_from_rfc3339(time).is_ok());
+                (
+                    fields.next().unwrap_or_default(),
+                    fields.next().unwrap_or_default(),
+                    fields.next().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn log_write() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("container.log");
+        let mut sut = LogWriter::open(&path, None).await?;
+
+        sut.write(Stream::Stdout, b"first\nsecond\npart").await?;
+        sut.write(Stream::Stderr, b"error\n").await?;
+        sut.write(Stream::Stdout, b"\n").await?;
+
+        let log = std::fs::read_to_string(&path)?;
+        assert_eq!(
+            parse(&log),
+            vec![
+                ("stdout", "F", "first"),
+                ("stdout", "F", "second"),
+                ("stdout", "P", "part"),
+                ("stderr", "F", "error"),
+                ("stdout", "F", ""),
+            ]
+        );
+        assert_eq!(sut.written(), log.len() as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn log_truncate() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("container.log");
+        std::fs::write(&path, "")?;
+        let mut sut = LogWriter::open(&path, Some(80)).await?;
+
+        sut.write(Stream::Stdout, b"first\n").await?;
+        sut.write(Stream::Stdout, b"second\n").await?;
+        assert_eq!(
+            parse(&std::fs::read_to_string(&path)?),
+            vec![("stdout", "F", "second")]
+        );
+        Ok(())
+    }
+}
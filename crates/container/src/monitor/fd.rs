@@ -0,0 +1,85 @@
+//! Non-blocking file descriptors driven by the tokio reactor.
+
+use nix::{
+    errno::Errno,
+    fcntl::{self, FcntlArg, OFlag},
+    sys::socket::{self, MsgFlags},
+    unistd,
+};
+use std::{
+    io,
+    os::unix::io::{AsRawFd, OwnedFd, RawFd},
+};
+use tokio::io::unix::AsyncFd;
+
+#[derive(Debug)]
+/// A file descriptor like a pipe, terminal or socket, which gets switched to non-blocking mode.
+pub(super) struct NonBlockingFd(AsyncFd<OwnedFd>);
+
+impl NonBlockingFd {
+    /// Switch the file descriptor to non-blocking mode and register it with the reactor.
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        let flags = fcntl::fcntl(fd.as_raw_fd(), FcntlArg::F_GETFL)?;
+        fcntl::fcntl(
+            fd.as_raw_fd(),
+            FcntlArg::F_SETFL(OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK),
+        )?;
+        Ok(Self(AsyncFd::new(fd)?))
+    }
+
+    /// Read into the buffer. A terminal whose other side got closed reports an end of file
+    /// instead of `EIO`.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.readable(|fd| unistd::read(fd, buf)).await {
+            Err(e) if e.raw_os_error() == Some(Errno::EIO as i32) => Ok(0),
+            res => res,
+        }
+    }
+
+    /// Write the whole buffer.
+    pub async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = self.writable(|fd| unistd::write(fd, buf)).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Receive a single packet from a socket.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.readable(|fd| socket::recv(fd, buf, MsgFlags::empty()))
+            .await
+    }
+
+    /// Send a single packet to a socket without raising `SIGPIPE` for disconnected peers.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.writable(|fd| socket::send(fd, buf, MsgFlags::MSG_NOSIGNAL))
+            .await
+    }
+
+    /// Run the operation once the file descriptor is readable.
+    pub async fn readable<T>(&self, mut f: impl FnMut(RawFd) -> nix::Result<T>) -> io::Result<T> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            if let Ok(res) = guard.try_io(|fd| f(fd.as_raw_fd()).map_err(io::Error::from)) {
+                return res;
+            }
+        }
+    }
+
+    /// Run the operation once the file descriptor is writable.
+    pub async fn writable<T>(&self, mut f: impl FnMut(RawFd) -> nix::Result<T>) -> io::Result<T> {
+        loop {
+            let mut guard = self.0.writable().await?;
+            if let Ok(res) = guard.try_io(|fd| f(fd.as_raw_fd()).map_err(io::Error::from)) {
+                return res;
+            }
+        }
+    }
+}
+
+impl AsRawFd for NonBlockingFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
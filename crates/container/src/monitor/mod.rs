@@ -0,0 +1,86 @@
+//! Container monitors, which supervise containers once the runtime created them.
+//!
+//! The monitor holds the standard streams of the container, writes them into the container log,
+//! exposes them via an attach socket and records the exit code of the container. This is either
+//! done by [conmon][0] or natively from within the server.
+//!
+//! [0]: https://github.com/containers/conmon
+
+use crate::conmon::DEFAULT_CONMON;
+use derive_builder::Builder;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use strum::{AsRefStr, Display, EnumString};
+
+pub mod attach;
+pub mod cri_log;
+mod fd;
+pub mod native;
+mod reaper;
+
+#[derive(
+    AsRefStr,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Display,
+    EnumString,
+    Eq,
+    PartialEq,
+    Serialize,
+)]
+#[strum(serialize_all = "snake_case")]
+/// The available container monitors.
+pub enum Monitor {
+    #[default]
+    /// Spawn the external conmon binary per container.
+    Conmon,
+
+    /// Monitor the containers from within the server, see [`native`].
+    Native,
+}
+
+#[derive(AsRefStr, Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+/// The output streams of a container.
+pub enum Stream {
+    /// The standard output.
+    Stdout,
+
+    /// The standard error output.
+    Stderr,
+}
+
+#[derive(Builder, Clone, CopyGetters, Debug, Default, Deserialize, Getters, Serialize)]
+#[builder(default, pattern = "owned", setter(into, strip_option))]
+/// The configuration of the monitor supervising containers.
+pub struct MonitorConfig {
+    #[get_copy = "pub"]
+    /// The monitor to be used.
+    monitor: Monitor,
+
+    #[get = "pub"]
+    #[builder(default = "DEFAULT_CONMON.into()")]
+    /// Path to the conmon binary, which is only used by the conmon monitor.
+    conmon: PathBuf,
+
+    #[get = "pub"]
+    /// Directory for the attach sockets. The monitor links `<socket_dir>/<id>` to the bundle,
+    /// which keeps the socket paths below the length limit of unix sockets.
+    socket_dir: PathBuf,
+
+    #[get_copy = "pub"]
+    /// Maximum size of the container log file in bytes before it gets rotated.
+    log_size_max: Option<u64>,
+
+    #[get = "pub"]
+    /// Program to execute once the container exited.
+    exit_command: Option<PathBuf>,
+
+    #[get = "pub"]
+    /// Arguments passed to the exit command.
+    exit_command_args: Vec<String>,
+}
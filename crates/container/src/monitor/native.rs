@@ -0,0 +1,627 @@
+//! A native monitor, which supervises containers from within the server instead of spawning
+//! conmon for each of them.
+//!
+//! The server becomes a child subreaper, which means that the container process gets reparented
+//! to it once the runtime exited after creating the container. The monitor holds the standard
+//! streams of the container, writes its output into the CRI log, serves the attach socket and
+//! writes the exit code into the exit directory of the bundle, like conmon does.
+//!
+//! Contrary to conmon, the monitoring ends together with the server. A single [`reaper`] thread
+//! reaps the containers and the processes which get orphaned outside of their PID namespace.
+//!
+//! [`reaper`]: super::reaper
+
+use super::{
+    attach::{AttachListener, AttachStream, ATTACH_SOCKET, PACKET_SIZE},
+    cri_log::LogWriter,
+    fd::NonBlockingFd,
+    reaper, MonitorConfig, Stream,
+};
+use crate::{
+    bundle::Bundle,
+    oci_runtime::{CreateArgs, GlobalArgs, OCIRuntimeBuilder, Subcommand},
+};
+use anyhow::{bail, Context, Result};
+use derive_builder::Builder;
+use getset::{CopyGetters, Getters};
+use log::{debug, trace, warn};
+use nix::{
+    fcntl::OFlag,
+    sys::socket::{self, ControlMessageOwned, MsgFlags, UnixAddr},
+    unistd,
+};
+use std::{
+    fs::File,
+    io::{self, ErrorKind, IoSliceMut},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    net::UnixListener,
+    process::Command,
+    sync::{broadcast, Mutex},
+    time,
+};
+
+/// Name of the socket the runtime sends the terminal of the container to.
+const CONSOLE_SOCKET: &str = "console.sock";
+
+/// Time to wait for the runtime to send the terminal.
+const CONSOLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time to wait for the remaining output once the container exited, since processes outside
+/// of its PID namespace may keep the streams open.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of output chunks buffered for slow attach clients before they miss output.
+const OUTPUT_CAPACITY: usize = 256;
+
+/// Output of the container shared with the attach clients.
+type Output = (Stream, Arc<Vec<u8>>);
+
+/// Standard input of the container shared by the attach clients, which is `None` once closed.
+type Input = Arc<Mutex<Option<NonBlockingFd>>>;
+
+#[derive(Builder, CopyGetters, Debug, Getters)]
+#[builder(pattern = "owned", setter(into))]
+/// The native monitor of a single container.
+pub struct NativeMonitor {
+    #[get = "pub"]
+    /// Path to the OCI runtime binary.
+    runtime: PathBuf,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The global arguments of the runtime.
+    global_args: Vec<GlobalArgs>,
+
+    #[get = "pub"]
+    /// The bundle of the container.
+    bundle: Bundle,
+
+    #[get = "pub"]
+    /// The monitor configuration.
+    config: MonitorConfig,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// Path to the CRI log of the container, which is disabled if empty.
+    log_path: PathBuf,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// Keep the standard input of the container open for attach clients.
+    stdin: bool,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// Close the standard input once the first attach client disconnected.
+    stdin_once: bool,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// Allocate a terminal for the container.
+    terminal: bool,
+}
+
+/// The standard streams of the container from the perspective of the monitor.
+struct Streams {
+    /// The writable standard input.
+    stdin: Option<NonBlockingFd>,
+
+    /// The readable output streams.
+    outputs: Vec<(Stream, NonBlockingFd)>,
+}
+
+impl NativeMonitor {
+    /// Create the container via the runtime and start monitoring it in the background. Return
+    /// the process ID of the container.
+    pub async fn create(&self) -> Result<u32> {
+        set_child_subreaper()?;
+
+        let socket_link = self.config().socket_dir().join(self.bundle().id());
+        fs::create_dir_all(self.config().socket_dir())
+            .await
+            .context("create socket directory")?;
+        remove_file(&socket_link).await?;
+        fs::symlink(self.bundle().path(), &socket_link)
+            .await
+            .with_context(|| format!("link socket directory {}", socket_link.display()))?;
+
+        let res = self.create_container(&socket_link).await;
+        if res.is_err() {
+            if let Err(e) = remove_file(&socket_link).await {
+                debug!("Unable to remove {}: {:#}", socket_link.display(), e);
+            }
+        }
+        res
+    }
+
+    /// Create the container, whereas the socket link points to its bundle.
+    async fn create_container(&self, socket_link: &Path) -> Result<u32> {
+        let log = if self.log_path().as_os_str().is_empty() {
+            None
+        } else {
+            Some(LogWriter::open(self.log_path(), self.config().log_size_max()).await?)
+        };
+        let attach_socket = socket_link.join(ATTACH_SOCKET);
+        remove_file(&attach_socket).await?;
+        let listener = AttachListener::bind(&attach_socket)?;
+
+        let streams = if self.terminal() {
+            self.run_terminal(socket_link).await?
+        } else {
+            self.run_piped().await?
+        };
+
+        let pid = self
+            .bundle()
+            .pid()
+            .await?
+            .context("runtime did not write the container PID")?;
+        fs::write(self.bundle().monitor_pid_file(), process::id().to_string())
+            .await
+            .context("write monitor PID file")?;
+
+        let supervisor = Supervisor {
+            pid,
+            bundle: self.bundle().clone(),
+            config: self.config().clone(),
+            socket_link: socket_link.into(),
+            stdin_once: self.stdin_once(),
+        };
+        tokio::spawn(supervisor.run(streams, log, listener));
+
+        debug!(
+            "Monitoring container {} with PID {}",
+            self.bundle().id(),
+            pid
+        );
+        Ok(pid)
+    }
+
+    /// Run the runtime to create the container with pipes as standard streams.
+    async fn run_piped(&self) -> Result<Streams> {
+        let (stdin, container_stdin) = if self.stdin() {
+            let (reader, writer) = pipe()?;
+            (
+                Some(NonBlockingFd::new(writer.into())?),
+                Stdio::from(reader),
+            )
+        } else {
+            (None, Stdio::null())
+        };
+        let (stdout, container_stdout) = pipe()?;
+        let (stderr, container_stderr) = pipe()?;
+
+        self.run_create(
+            vec![],
+            [
+                container_stdin,
+                Stdio::from(container_stdout),
+                Stdio::from(container_stderr),
+            ],
+        )
+        .await?;
+
+        Ok(Streams {
+            stdin,
+            outputs: vec![
+                (Stream::Stdout, NonBlockingFd::new(stdout.into())?),
+                (Stream::Stderr, NonBlockingFd::new(stderr.into())?),
+            ],
+        })
+    }
+
+    /// Run the runtime to create the container with a terminal, which the runtime sends via the
+    /// console socket.
+    async fn run_terminal(&self, socket_link: &Path) -> Result<Streams> {
+        let path = socket_link.join(CONSOLE_SOCKET);
+        remove_file(&path).await?;
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("bind console socket {}", path.display()))?;
+
+        let res = async {
+            self.run_create(
+                vec![CreateArgs::ConsoleSocket(path.clone())],
+                [Stdio::null(), Stdio::null(), Stdio::null()],
+            )
+            .await?;
+            let (stream, _) = time::timeout(CONSOLE_TIMEOUT, listener.accept())
+                .await
+                .context("runtime did not send the terminal")??;
+            stream.readable().await?;
+            receive_fd(stream.as_raw_fd())
+        }
+        .await;
+        remove_file(&path).await?;
+
+        let terminal = res.context("receive terminal")?;
+        let stdin = if self.stdin() {
+            let fd = unistd::dup(terminal.as_raw_fd()).context("duplicate terminal")?;
+            Some(NonBlockingFd::new(unsafe { OwnedFd::from_raw_fd(fd) })?)
+        } else {
+            None
+        };
+        Ok(Streams {
+            stdin,
+            outputs: vec![(Stream::Stdout, NonBlockingFd::new(terminal)?)],
+        })
+    }
+
+    /// Run the runtime `create` subcommand with the additional arguments and standard streams.
+    async fn run_create(&self, args: Vec<CreateArgs>, stdio: [Stdio; 3]) -> Result<()> {
+        let mut create_args = vec![
+            CreateArgs::Bundle(self.bundle().path().clone()),
+            CreateArgs::PidFile(self.bundle().pid_file()),
+        ];
+        create_args.extend(args);
+        OCIRuntimeBuilder::default()
+            .binary(self.runtime())
+            .build()
+            .context("build OCI runtime")?
+            .run_with_stdio(
+                &Subcommand::Create((self.bundle().id().clone(), create_args)),
+                self.global_args(),
+                stdio,
+            )
+            .await
+    }
+}
+
+/// Supervises a created container until it exited.
+struct Supervisor {
+    /// The process ID of the container.
+    pid: u32,
+
+    /// The bundle of the container.
+    bundle: Bundle,
+
+    /// The monitor configuration.
+    config: MonitorConfig,
+
+    /// The link to the bundle within the socket directory.
+    socket_link: PathBuf,
+
+    /// Close the standard input once the first attach client disconnected.
+    stdin_once: bool,
+}
+
+impl Supervisor {
+    /// Forward the output of the container into its log and to the attach clients until it
+    /// exited, afterwards record its exit.
+    async fn run(self, streams: Streams, log: Option<LogWriter>, listener: AttachListener) {
+        let (output, _) = broadcast::channel(OUTPUT_CAPACITY);
+        let log = Arc::new(Mutex::new(log));
+        let readers = streams
+            .outputs
+            .into_iter()
+            .map(|(stream, fd)| tokio::spawn(forward(stream, fd, log.clone(), output.clone())))
+            .collect::<Vec<_>>();
+        let attach = tokio::spawn(serve_attach(
+            listener,
+            output,
+            Arc::new(Mutex::new(streams.stdin)),
+            self.stdin_once,
+        ));
+
+        let code = match reaper::wait(self.pid).await {
+            Ok(code) => code,
+            Err(e) => {
+                warn!("Unable to wait for container {}: {:#}", self.bundle.id(), e);
+                attach.abort();
+                return;
+            }
+        };
+        debug!("Container {} exited with code {}", self.bundle.id(), code);
+
+        for reader in readers {
+            if time::timeout(DRAIN_TIMEOUT, reader).await.is_err() {
+                debug!(
+                    "Stopped waiting for output of container {}",
+                    self.bundle.id()
+                );
+            }
+        }
+        attach.abort();
+
+        if let Err(e) = self.write_exit(code).await {
+            warn!(
+                "Unable to record exit of container {}: {:#}",
+                self.bundle.id(),
+                e
+            );
+        }
+        if let Err(e) = remove_file(&self.socket_link).await {
+            debug!("Unable to remove {}: {:#}", self.socket_link.display(), e);
+        }
+        self.run_exit_command().await;
+    }
+
+    /// Write the exit code into the exit file, which gets replaced atomically.
+    async fn write_exit(&self, code: i32) -> Result<()> {
+        let path = self.bundle.exit_file();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, code.to_string()).await?;
+        fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("write exit file {}", path.display()))
+    }
+
+    /// Run the configured exit command.
+    async fn run_exit_command(&self) {
+        let command = match self.config.exit_command() {
+            Some(command) => command,
+            None => return,
+        };
+        let res = Command::new(command)
+            .args(self.config.exit_command_args())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        match res {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("Exit command {} failed: {}", command.display(), status),
+            Err(e) => warn!("Unable to run exit command {}: {}", command.display(), e),
+        }
+    }
+}
+
+/// Forward the output stream into the log and to the attach clients until it got closed.
+async fn forward(
+    stream: Stream,
+    fd: NonBlockingFd,
+    log: Arc<Mutex<Option<LogWriter>>>,
+    output: broadcast::Sender<Output>,
+) {
+    let mut buf = vec![0; PACKET_SIZE];
+    loop {
+        let n = match fd.read(&mut buf).await {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Unable to read container {}: {}", stream, e);
+                return;
+            }
+        };
+        if let Some(log) = log.lock().await.as_mut() {
+            if let Err(e) = log.write(stream, &buf[..n]).await {
+                warn!("Unable to write {}: {:#}", log.path().display(), e);
+            }
+        }
+        // Sending only fails if no client is attached
+        output.send((stream, Arc::new(buf[..n].to_vec()))).ok();
+    }
+}
+
+/// Accept attach clients, which receive the output and write into the standard input.
+async fn serve_attach(
+    listener: AttachListener,
+    output: broadcast::Sender<Output>,
+    stdin: Input,
+    stdin_once: bool,
+) {
+    loop {
+        match listener.accept().await {
+            Ok(client) => {
+                trace!("Attach client connected");
+                tokio::spawn(serve_client(
+                    client,
+                    output.subscribe(),
+                    stdin.clone(),
+                    stdin_once,
+                ));
+            }
+            Err(e) => {
+                warn!("Unable to accept attach client: {:#}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Serve a single attach client until it disconnected or the output got closed.
+async fn serve_client(
+    client: AttachStream,
+    mut output: broadcast::Receiver<Output>,
+    stdin: Input,
+    stdin_once: bool,
+) {
+    let mut buf = vec![0; PACKET_SIZE];
+    loop {
+        tokio::select! {
+            res = output.recv() => match res {
+                Ok((stream, data)) => {
+                    if client.send_output(stream, &data).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    debug!("Attach client missed {} output chunks", n)
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            res = client.recv_input(&mut buf) => match res {
+                Ok(n) if n > 0 => {
+                    if let Some(stdin) = stdin.lock().await.as_ref() {
+                        if let Err(e) = stdin.write_all(&buf[..n]).await {
+                            debug!("Unable to write container stdin: {}", e);
+                        }
+                    }
+                }
+                _ => break,
+            },
+        }
+    }
+    trace!("Attach client disconnected");
+    if stdin_once {
+        stdin.lock().await.take();
+    }
+}
+
+/// Become the subreaper of all orphaned descendants, which includes containers once the runtime
+/// created them and exited.
+fn set_child_subreaper() -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("become child subreaper");
+    }
+    Ok(())
+}
+
+/// Create a pipe, which does not get inherited by child processes.
+fn pipe() -> Result<(File, File)> {
+    let (reader, writer) = unistd::pipe2(OFlag::O_CLOEXEC).context("create pipe")?;
+    Ok(unsafe { (File::from_raw_fd(reader), File::from_raw_fd(writer)) })
+}
+
+/// Receive a file descriptor via `SCM_RIGHTS` from the socket.
+fn receive_fd(socket: RawFd) -> Result<OwnedFd> {
+    let mut buf = vec![0; PACKET_SIZE];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+    let msg = socket::recvmsg::<UnixAddr>(socket, &mut iov, Some(&mut cmsg), MsgFlags::empty())
+        .context("receive message")?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+            }
+        }
+    }
+    bail!("message contains no file descriptor")
+}
+
+/// Remove the file, which is not an error if it does not exist.
+async fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bundle::Bundles, monitor::MonitorConfigBuilder, oci_runtime::RuntimeError};
+    use oci_spec::runtime::Spec;
+    use std::os::unix::fs::PermissionsExt;
+    use storage::{default_key_value_storage::DefaultKeyValueStorage, KeyValueStorage};
+    use tempfile::TempDir;
+
+    /// Create a fake runtime in the directory. The container process writes some output, echoes
+    /// the first line of its input and exits with code 3. It fails for containers with the ID
+    /// `fail`.
+    fn fake_runtime(dir: &Path) -> Result<PathBuf> {
+        let binary = dir.join("runtime");
+        std::fs::write(
+            &binary,
+            r#"#!/bin/sh
+case "$*" in
+    *" fail") echo "container failed" >> "${1#--log=}"; exit 1 ;;
+esac
+exec 3<&0
+(
+    echo hello
+    echo error >&2
+    read -r line
+    echo "got $line"
+    exit 3
+) <&3 &
+echo $! > "${4#--pid-file=}"
+"#,
+        )?;
+        std::fs::set_permissions(&binary, PermissionsExt::from_mode(0o755))?;
+        Ok(binary)
+    }
+
+    async fn monitor(dir: &TempDir, id: &str) -> Result<NativeMonitor> {
+        let bundle = Bundles::new(
+            dir.path().join("bundles"),
+            DefaultKeyValueStorage::open(dir.path().join("storage"))?,
+        )
+        .create(id, &Spec::default(), dir.path())
+        .await?;
+        let config = MonitorConfigBuilder::default()
+            .socket_dir(dir.path().join("sockets"))
+            .exit_command(which::which("touch")?)
+            .exit_command_args(vec![dir.path().join("exited").display().to_string()])
+            .build()?;
+        Ok(NativeMonitorBuilder::default()
+            .runtime(fake_runtime(dir.path())?)
+            .global_args(vec![GlobalArgs::Log(bundle.runtime_log_file())])
+            .bundle(bundle)
+            .config(config)
+            .log_path(dir.path().join("container.log"))
+            .stdin(true)
+            .build()?)
+    }
+
+    #[tokio::test]
+    async fn native_monitor_create() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = monitor(&dir, "id").await?;
+        let bundle = sut.bundle().clone();
+        let socket_link = dir.path().join("sockets").join("id");
+
+        let pid = sut.create().await?;
+        assert_eq!(bundle.pid().await?, Some(pid));
+        assert_eq!(std::fs::read_link(&socket_link)?, *bundle.path());
+        assert_eq!(
+            std::fs::read_to_string(bundle.monitor_pid_file())?,
+            process::id().to_string()
+        );
+
+        let client = AttachStream::connect(&socket_link.join(ATTACH_SOCKET))?;
+        client.send_input(b"input\n").await?;
+        let mut output = vec![];
+        while let Some((stream, data)) = client.recv_output().await? {
+            if stream == Stream::Stdout {
+                output.extend(data);
+            }
+        }
+        assert!(String::from_utf8(output)?.ends_with("got input\n"));
+
+        for _ in 0..100 {
+            if dir.path().join("exited").exists() {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(dir.path().join("exited").exists());
+        assert_eq!(bundle.exit().await?.context("no exit")?.code(), 3);
+        assert!(!socket_link.exists());
+
+        let log = std::fs::read_to_string(dir.path().join("container.log"))?;
+        let entries = log
+            .lines()
+            .filter_map(|line| line.split_once(' ').map(|(_, entry)| entry))
+            .collect::<Vec<_>>();
+        assert!(entries.contains(&"stdout F hello"));
+        assert!(entries.contains(&"stderr F error"));
+        assert!(entries.contains(&"stdout F got input"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn native_monitor_failure_runtime() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = monitor(&dir, "fail").await?;
+
+        let err = sut.create().await.unwrap_err();
+        let err = err
+            .downcast_ref::<RuntimeError>()
+            .context("no runtime error")?;
+        assert_eq!(err.log(), "container failed");
+        assert!(!dir.path().join("sockets").join("fail").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+//! Reaping of the processes which get reparented to the server as child subreaper.
+//!
+//! A single thread handles `SIGCHLD` for all monitored containers and passes their exit codes on
+//! to the waiters. It also reaps the orphans of the containers. The children spawned by the
+//! server itself, like runtime invocations or the forks for ID-mapped snapshots, are reaped by
+//! their owners and must never be stolen. Those stay within the session of the server, whereas
+//! the runtime moves containers into a new one. Unknown children are therefore only reaped if
+//! they belong to another session.
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error, warn};
+use nix::{
+    errno::Errno,
+    sys::wait::{self, WaitPidFlag, WaitStatus},
+    unistd::{self, Pid},
+};
+use std::{
+    collections::HashMap,
+    fs, io,
+    sync::{Mutex, MutexGuard, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime,
+    signal::unix::{signal, SignalKind},
+    sync::oneshot,
+};
+
+/// Time to keep the exit codes of reaped orphans, which may be containers whose monitor did not
+/// wait for them yet.
+const EXIT_RETENTION: Duration = Duration::from_secs(60);
+
+/// Wait for the child process to exit and return its exit code, which is `128 + signal` if it
+/// got killed.
+pub(super) async fn wait(pid: u32) -> Result<i32> {
+    let pid = Pid::from_raw(pid as i32);
+    reaper()?
+        .register(pid)?
+        .await
+        .with_context(|| format!("stopped waiting for PID {}", pid))
+}
+
+/// The reaper of the process, whose thread gets started on first use and restarted if it
+/// stopped.
+fn reaper() -> Result<&'static Reaper> {
+    static REAPER: OnceLock<Reaper> = OnceLock::new();
+    let reaper = REAPER.get_or_init(Reaper::default);
+    let mut running = reaper
+        .running
+        .lock()
+        .map_err(|_| anyhow!("reaper thread state is poisoned"))?;
+    if !*running {
+        thread::Builder::new()
+            .name("reaper".into())
+            .spawn(move || reaper.run_until_error())
+            .context("spawn reaper thread")?;
+        *running = true;
+    }
+    Ok(reaper)
+}
+
+#[derive(Debug, Default)]
+/// Reaps the children on `SIGCHLD`.
+struct Reaper {
+    /// Whether the reaper thread is running.
+    running: Mutex<bool>,
+
+    /// The waiters and recorded exits.
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+/// The state shared by the reaper thread and the waiters.
+struct State {
+    /// The waiters for the exit code of a process.
+    waiters: HashMap<Pid, oneshot::Sender<i32>>,
+
+    /// The exit codes of reaped orphans and the time they got reaped.
+    exits: HashMap<Pid, (i32, Instant)>,
+}
+
+impl Reaper {
+    /// Run the reaper until it fails, which fails all current waiters.
+    fn run_until_error(&self) {
+        if let Err(e) = self.run() {
+            error!("Reaper stopped: {:#}", e);
+            if let Ok(mut running) = self.running.lock() {
+                *running = false;
+            }
+            // Dropping the waiters fails their wait
+            if let Ok(mut state) = self.state() {
+                state.waiters.clear();
+            }
+        }
+    }
+
+    /// Reap the children whenever a `SIGCHLD` arrives.
+    fn run(&self) -> Result<()> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("build reaper runtime")?;
+        runtime.block_on(async {
+            let mut sigchld = signal(SignalKind::child()).context("handle SIGCHLD")?;
+            loop {
+                self.reap()?;
+                sigchld.recv().await;
+            }
+        })
+    }
+
+    /// Register a waiter for the process, which receives its exit code. The process may have
+    /// already been reaped as orphan or exited without being reaped yet.
+    fn register(&self, pid: Pid) -> Result<oneshot::Receiver<i32>> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state()?;
+        let code = match state.exits.remove(&pid) {
+            Some((code, _)) => Some(code),
+            None => try_wait(pid)?,
+        };
+        match code {
+            Some(code) => {
+                tx.send(code).ok();
+            }
+            None => {
+                state.waiters.insert(pid, tx);
+            }
+        }
+        Ok(rx)
+    }
+
+    /// Reap all exited containers and orphans.
+    fn reap(&self) -> Result<()> {
+        let mut state = self.state()?;
+
+        let pids = state.waiters.keys().copied().collect::<Vec<_>>();
+        for pid in pids {
+            match try_wait(pid) {
+                Ok(None) => {}
+                Ok(Some(code)) => {
+                    if let Some(waiter) = state.waiters.remove(&pid) {
+                        waiter.send(code).ok();
+                    }
+                }
+                Err(e) => {
+                    // Dropping the waiter fails the wait
+                    warn!("Unable to reap PID {}: {:#}", pid, e);
+                    state.waiters.remove(&pid);
+                }
+            }
+        }
+
+        match orphans() {
+            Ok(orphans) => {
+                for pid in orphans {
+                    if state.waiters.contains_key(&pid) {
+                        continue;
+                    }
+                    match try_wait(pid) {
+                        Ok(None) => {}
+                        Ok(Some(code)) => {
+                            debug!("Reaped orphan PID {} with exit code {}", pid, code);
+                            state.exits.insert(pid, (code, Instant::now()));
+                        }
+                        Err(e) => debug!("Unable to reap orphan PID {}: {:#}", pid, e),
+                    }
+                }
+            }
+            Err(e) => warn!("Unable to find orphans: {:#}", e),
+        }
+        state
+            .exits
+            .retain(|_, (_, reaped)| reaped.elapsed() < EXIT_RETENTION);
+        Ok(())
+    }
+
+    /// Lock the state shared with the waiters.
+    fn state(&self) -> Result<MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow!("reaper state is poisoned"))
+    }
+}
+
+/// Reap the process if it exited and return its exit code.
+fn try_wait(pid: Pid) -> Result<Option<i32>> {
+    loop {
+        match wait::waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(_, code)) => return Ok(Some(code)),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(Some(128 + signal as i32)),
+            Ok(_) => return Ok(None),
+            Err(Errno::EINTR) => continue,
+            Err(e) => bail!("wait for PID {}: {}", pid, e),
+        }
+    }
+}
+
+/// The children of the server which exited but have not been reaped yet and do not belong to the
+/// session of the server.
+fn orphans() -> Result<Vec<Pid>> {
+    let session = unistd::getsid(None).context("get session ID")?.as_raw();
+    let mut orphans = vec![];
+    for task in fs::read_dir("/proc/self/task").context("read tasks")? {
+        let children = match fs::read_to_string(task?.path().join("children")) {
+            Ok(children) => children,
+            // The thread exited in the meantime
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).context("read children"),
+        };
+        for pid in children.split_whitespace() {
+            let pid = pid.parse::<i32>().context("parse child PID")?;
+            // The state, parent, process group and session follow the command name, which may
+            // contain any character
+            let orphan = fs::read_to_string(format!("/proc/{}/stat", pid))
+                .ok()
+                .and_then(|stat| {
+                    let (_, fields) = stat.rsplit_once(')')?;
+                    let fields = fields.split_whitespace().collect::<Vec<_>>();
+                    let sid = fields.get(3)?.parse::<i32>().ok()?;
+                    Some(*fields.first()? == "Z" && sid != session)
+                })
+                .unwrap_or_default();
+            if orphan {
+                orphans.push(Pid::from_raw(pid));
+            }
+        }
+    }
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::process::CommandExt, process::Command};
+
+    /// Wait until the child exited, which may have been reaped already.
+    fn wait_exited(pid: u32) -> Result<()> {
+        for _ in 0..500 {
+            let stat = match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+                Ok(stat) => stat,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            if stat
+                .rsplit_once(')')
+                .map(|(_, fields)| fields.trim_start().starts_with('Z'))
+                == Some(true)
+            {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        bail!("PID {} did not exit", pid)
+    }
+
+    #[tokio::test]
+    async fn wait_exit_code() -> Result<()> {
+        let child = Command::new("sh").args(["-c", "exit 3"]).spawn()?;
+        assert_eq!(wait(child.id()).await?, 3);
+
+        let child = Command::new("sh").args(["-c", "kill -9 $$"]).spawn()?;
+        assert_eq!(wait(child.id()).await?, 128 + 9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leave_own_children() -> Result<()> {
+        let mut child = Command::new("true").spawn()?;
+        wait_exited(child.id())?;
+        reaper()?.reap()?;
+        assert!(child.wait()?.success());
+
+        // Like the forks for ID-mapped snapshots
+        let child = match unsafe { unistd::fork() }? {
+            unistd::ForkResult::Child => unsafe { libc::_exit(4) },
+            unistd::ForkResult::Parent { child } => child,
+        };
+        wait_exited(child.as_raw() as u32)?;
+        reaper()?.reap()?;
+        assert_eq!(try_wait(child)?, Some(4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reap_orphans() -> Result<()> {
+        // Like the container processes, which got reparented to the server
+        let mut command = Command::new("sh");
+        command.args(["-c", "exit 5"]);
+        unsafe {
+            command.pre_exec(|| unistd::setsid().map(drop).map_err(io::Error::from));
+        }
+        let child = command.spawn()?;
+        wait_exited(child.id())?;
+        reaper()?.reap()?;
+        assert!(try_wait(Pid::from_raw(child.id() as i32)).is_err());
+        assert_eq!(wait(child.id()).await?, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_fail_no_child() -> Result<()> {
+        assert!(wait(1).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn register_fail_poisoned() -> Result<()> {
+        let sut = Reaper::default();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _state = sut.state.lock();
+                panic!("poison the state")
+            })
+            .join()
+        })
+        .ok();
+        let mut child = Command::new("true").spawn()?;
+        assert!(sut.register(Pid::from_raw(child.id() as i32)).is_err());
+        assert!(sut.reap().is_err());
+        child.wait()?;
+        Ok(())
+    }
+}
@@ -6,17 +6,26 @@
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use derive_builder::Builder;
 use dyn_clone::{clone_trait_object, DynClone};
-use getset::{Getters, Setters};
+use getset::{CopyGetters, Getters, Setters};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fmt::{self, Debug},
+    io::SeekFrom,
     path::{Path, PathBuf},
-    process::Output,
+    process::{ExitStatus, Output, Stdio},
     string::ToString,
 };
 use strum::{AsRefStr, Display};
-use tokio::process::Command;
+use thiserror::Error;
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+    process::Command,
+};
 
 #[derive(Builder, Debug, Getters, Setters)]
 #[builder(pattern = "owned", setter(into))]
@@ -41,6 +50,246 @@ impl OCIRuntime {
             .run_output(self.binary(), &subcommand.build_cmd()[..], args)
             .await
     }
+
+    /// Run OCIRuntime like [`OCIRuntime::run`], but fail with a [`RuntimeError`] if it exits
+    /// unsuccessfully.
+    pub async fn run_checked(
+        &self,
+        subcommand: &Subcommand,
+        args: &[GlobalArgs],
+    ) -> Result<Output> {
+        let log_offset = log_offset(args).await;
+        let output = self.run(subcommand, args).await?;
+        if !output.status.success() {
+            return Err(RuntimeError {
+                subcommand: subcommand.to_string(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().into(),
+                log: read_log(args, log_offset).await,
+            }
+            .into());
+        }
+        Ok(output)
+    }
+
+    /// Run OCIRuntime with its standard streams detached, which is required for subcommands
+    /// spawning the container process like `create`. The process inherits the streams and
+    /// would otherwise keep them open until it exits. Failures are reported by a
+    /// [`RuntimeError`] carrying the log output only.
+    pub async fn run_detached(&self, subcommand: &Subcommand, args: &[GlobalArgs]) -> Result<()> {
+        self.run_with_stdio(
+            subcommand,
+            args,
+            [Stdio::null(), Stdio::null(), Stdio::null()],
+        )
+        .await
+    }
+
+    /// Run OCIRuntime like [`OCIRuntime::run_detached`], but with the provided standard input,
+    /// output and error streams, which get passed on to the container process.
+    pub async fn run_with_stdio(
+        &self,
+        subcommand: &Subcommand,
+        args: &[GlobalArgs],
+        stdio: [Stdio; 3],
+    ) -> Result<()> {
+        let log_offset = log_offset(args).await;
+        let status = self
+            .exec()
+            .run_status(self.binary(), &subcommand.build_cmd()[..], args, stdio)
+            .await?;
+        if !status.success() {
+            return Err(RuntimeError {
+                subcommand: subcommand.to_string(),
+                status,
+                stderr: String::new(),
+                log: read_log(args, log_offset).await,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Retrieve the state of the container via the `state` subcommand.
+    pub async fn state(&self, id: &str, args: &[GlobalArgs]) -> Result<RuntimeState> {
+        self.run_json(&Subcommand::State(id.into()), args).await
+    }
+
+    /// List the state of all containers via the `list` subcommand.
+    pub async fn list(&self, args: &[GlobalArgs]) -> Result<Vec<RuntimeState>> {
+        // runc prints `null` if there are no containers
+        let states: Option<Vec<RuntimeState>> = self
+            .run_json(
+                &Subcommand::List(vec![ListArgs::Format(FormatArgs::Json)]),
+                args,
+            )
+            .await?;
+        Ok(states.unwrap_or_default())
+    }
+
+    /// List the process IDs of all processes within the container via the `ps` subcommand.
+    pub async fn ps(&self, id: &str, args: &[GlobalArgs]) -> Result<Vec<u32>> {
+        let pids: Option<Vec<u32>> = self
+            .run_json(
+                &Subcommand::Ps((id.into(), vec![PsArgs::Format(FormatArgs::Json)])),
+                args,
+            )
+            .await?;
+        Ok(pids.unwrap_or_default())
+    }
+
+    /// Run OCIRuntime like [`OCIRuntime::run_checked`] and parse its JSON output.
+    async fn run_json<T: DeserializeOwned>(
+        &self,
+        subcommand: &Subcommand,
+        args: &[GlobalArgs],
+    ) -> Result<T> {
+        let output = self.run_checked(subcommand, args).await?;
+        serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("parse output of OCI runtime {}", subcommand))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Display, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+/// The status of a container as reported by the runtime.
+pub enum RuntimeStatus {
+    /// The container is being created.
+    Creating,
+
+    /// The runtime finished creating the container, but the user process did not start yet.
+    Created,
+
+    /// The user process of the container is running.
+    Running,
+
+    /// All processes of the container are paused.
+    Paused,
+
+    /// The user process of the container exited.
+    Stopped,
+}
+
+#[derive(Clone, CopyGetters, Debug, Deserialize, Eq, Getters, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+/// The state of a container as reported by the `state` and `list` subcommands.
+pub struct RuntimeState {
+    #[get = "pub"]
+    #[serde(default)]
+    /// Version of the OCI runtime specification the state complies with.
+    oci_version: String,
+
+    #[get = "pub"]
+    /// Unique identifier of the container.
+    id: String,
+
+    #[get_copy = "pub"]
+    /// The runtime status of the container.
+    status: RuntimeStatus,
+
+    #[get_copy = "pub"]
+    #[serde(default)]
+    /// Process ID of the container init process, which is zero if it does not run.
+    pid: u32,
+
+    #[get = "pub"]
+    /// Path to the bundle of the container.
+    bundle: PathBuf,
+
+    #[get_copy = "pub"]
+    #[serde(default)]
+    /// The time the container got created.
+    created: Option<DateTime<Utc>>,
+
+    #[get = "pub"]
+    #[serde(default)]
+    /// Annotations of the container as set in its runtime spec.
+    annotations: HashMap<String, String>,
+}
+
+#[derive(CopyGetters, Debug, Error, Getters)]
+/// The error of an OCIRuntime invocation which exited unsuccessfully.
+pub struct RuntimeError {
+    #[get = "pub"]
+    /// The executed subcommand.
+    subcommand: String,
+
+    #[get_copy = "pub"]
+    /// The exit status of the runtime.
+    status: ExitStatus,
+
+    #[get = "pub"]
+    /// The standard error output of the runtime.
+    stderr: String,
+
+    #[get = "pub"]
+    /// The output written to the log file during the invocation, if the log is enabled via
+    /// [`GlobalArgs::Log`].
+    log: String,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "OCI runtime {} failed ({}): {}",
+            self.subcommand,
+            self.status,
+            self.message()
+        )
+    }
+}
+
+impl RuntimeError {
+    /// The most meaningful output of the runtime, which is the standard error if available and
+    /// the log otherwise.
+    pub fn message(&self) -> &str {
+        if self.stderr.is_empty() {
+            &self.log
+        } else {
+            &self.stderr
+        }
+    }
+}
+
+/// The log file of the runtime within the global arguments.
+fn log_path(args: &[GlobalArgs]) -> Option<&Path> {
+    args.iter().find_map(|arg| match arg {
+        GlobalArgs::Log(path) => Some(path.as_path()),
+        _ => None,
+    })
+}
+
+/// The current size of the runtime log file, which marks the start of the next invocation.
+async fn log_offset(args: &[GlobalArgs]) -> u64 {
+    match log_path(args) {
+        Some(path) => fs::metadata(path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or_default(),
+        None => 0,
+    }
+}
+
+/// Read the runtime log file from the offset on. The log is only supplementary, which is why
+/// read errors result in an empty log.
+async fn read_log(args: &[GlobalArgs], offset: u64) -> String {
+    let path = match log_path(args) {
+        Some(path) => path,
+        None => return String::new(),
+    };
+    let mut content = vec![];
+    let res = async {
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.read_to_end(&mut content).await
+    }
+    .await;
+    if res.is_err() {
+        return String::new();
+    }
+    String::from_utf8_lossy(&content).trim().into()
 }
 
 #[derive(Clone, Default, Debug)]
@@ -59,18 +308,42 @@ trait ExecCommand: Debug + DynClone + Send + Sync {
         global_args: &[GlobalArgs],
     ) -> Result<Output> {
         Command::new(binary)
-            .args(cmd)
             .args(global_args.iter().map(ToString::to_string))
+            .args(cmd)
             .output()
             .await
             .context("run OCIRuntime")
     }
+
+    /// Run a command with the provided standard input, output and error streams and return its
+    /// `ExitStatus`.
+    async fn run_status(
+        &self,
+        binary: &Path,
+        cmd: &[String],
+        global_args: &[GlobalArgs],
+        stdio: [Stdio; 3],
+    ) -> Result<ExitStatus> {
+        let [stdin, stdout, stderr] = stdio;
+        Command::new(binary)
+            .args(global_args.iter().map(ToString::to_string))
+            .args(cmd)
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(stderr)
+            .status()
+            .await
+            .context("run OCIRuntime")
+    }
 }
 
 clone_trait_object!(ExecCommand);
 
 type ContainerId = String;
 
+/// Raw signal number passed to `kill`.
+type Signal = i32;
+
 #[derive(AsRefStr, Clone, Debug, Hash, Eq, PartialEq, Display)]
 #[strum(serialize_all = "lowercase")]
 pub enum Subcommand {
@@ -79,7 +352,7 @@ pub enum Subcommand {
     /// Create a container
     Create((ContainerId, Vec<CreateArgs>)),
     /// Delete any resources held by the container often used with detached container
-    Delete(ContainerId),
+    Delete((ContainerId, Vec<DeleteArgs>)),
     /// Display container events such as OOM notifications, cpu, memory, and IO usage statistics
     Events((ContainerId, Vec<EventsArgs>)),
     /// Execute new process inside the container
@@ -87,7 +360,7 @@ pub enum Subcommand {
     /// Initialize the namespaces and launch the process (do not call it outside of runc)
     Init,
     /// Kill sends the specified signal (default: SIGTERM) to the container's init process
-    Kill((ContainerId, Vec<KillArgs>)),
+    Kill((ContainerId, Vec<KillArgs>, Option<Signal>)),
     /// Lists containers started by runc with the given root
     List(Vec<ListArgs>),
     /// Pause suspends all processes inside the container
@@ -122,21 +395,26 @@ impl Subcommand {
                 args.iter().map(ToString::to_string).collect(),
                 Some(String::from(container_id)),
             ),
-            Delete(container_id) => {
-                self.build_cmd_vec(Vec::new(), Some(String::from(container_id)))
-            }
-            Events((container_id, args)) => self.build_cmd_vec(
+            Delete((container_id, args)) => self.build_cmd_vec(
                 args.iter().map(ToString::to_string).collect(),
                 Some(String::from(container_id)),
             ),
-            Exec((container_id, args)) => self.build_cmd_vec(
+            Events((container_id, args)) => self.build_cmd_vec(
                 args.iter().map(ToString::to_string).collect(),
                 Some(String::from(container_id)),
             ),
-            Kill((container_id, args)) => self.build_cmd_vec(
+            Exec((container_id, args)) => self.build_cmd_vec(
                 args.iter().map(ToString::to_string).collect(),
                 Some(String::from(container_id)),
             ),
+            Kill((container_id, args, signal)) => {
+                let mut cmd = self.build_cmd_vec(
+                    args.iter().map(ToString::to_string).collect(),
+                    Some(String::from(container_id)),
+                );
+                cmd.extend(signal.map(|s| s.to_string()));
+                cmd
+            }
             Pause(container_id) => self.build_cmd_vec(Vec::new(), Some(String::from(container_id))),
             Ps((container_id, args)) => self.build_cmd_vec(
                 args.iter().map(ToString::to_string).collect(),
@@ -169,7 +447,7 @@ impl Subcommand {
     fn build_cmd_vec(&self, args: Vec<String>, container_id: Option<String>) -> Vec<String> {
         let mut res = vec![self.to_string()]
             .into_iter()
-            .chain(args.into_iter())
+            .chain(args)
             .collect::<Vec<_>>();
         if let Some(id) = container_id {
             res.push(id)
@@ -215,7 +493,7 @@ pub enum GlobalArgs {
     Criu(String),
 
     /// Enable systemd cgroup support, expects cgroupsPath to be of form "slice:prefix:name" for e.g. "system.slice:runc:434234"
-    SystemdCgroup(String),
+    SystemdCgroup,
 
     /// Ignore cgroup permission errors ('true', 'false', or 'auto') (default: "auto")
     Rootless(RootlessArgs),
@@ -242,7 +520,6 @@ impl fmt::Display for GlobalArgs {
             Root(path) => write_kv(f, self, path.display()),
             LogFormat(format) => write_kv(f, self, format),
             Criu(criu) => write_kv(f, self, criu),
-            SystemdCgroup(cgroup) => write_kv(f, self, cgroup),
             Rootless(rootless) => write_kv(f, self, rootless),
             _ => write!(f, "{}", self.as_ref()),
         }
@@ -345,6 +622,20 @@ impl fmt::Display for CreateArgs {
     }
 }
 
+#[derive(AsRefStr, Clone, Debug, Hash, Eq, PartialEq)]
+#[strum(serialize_all = "kebab_case")]
+/// Available arguments for 'oci_runtime delete'.
+pub enum DeleteArgs {
+    /// Forcibly deletes the container if it is still running (uses SIGKILL)
+    Force,
+}
+
+impl fmt::Display for DeleteArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "--{}", self.as_ref())
+    }
+}
+
 #[derive(AsRefStr, Clone, Debug, Hash, Eq, PartialEq)]
 #[strum(serialize_all = "kebab_case")]
 /// Available arguments for 'oci_runtime events'.
@@ -635,6 +926,8 @@ pub enum UpdateArgs {
     L3CacheSchema(String),
     /// The string of Intel RDT/MBA memory bandwidth schema
     MemBwSchema(String),
+    /// Path to a JSON file containing the resources to update
+    Resources(PathBuf),
 }
 
 impl fmt::Display for UpdateArgs {
@@ -659,6 +952,7 @@ impl fmt::Display for UpdateArgs {
             PidsLimit(val) => write_kv(f, self, val),
             L3CacheSchema(val) => write_kv(f, self, val),
             MemBwSchema(val) => write_kv(f, self, val),
+            Resources(path) => write_kv(f, self, path.display()),
         }
     }
 }
@@ -667,6 +961,7 @@ impl fmt::Display for UpdateArgs {
 mod tests {
     //TODO
     use super::*;
+    use std::os::unix::process::ExitStatusExt;
 
     #[derive(Clone, Debug)]
     struct MockExecCommand(Output);
@@ -690,12 +985,12 @@ mod tests {
             .binary(which::which("echo")?)
             .build()?;
         let sc = Subcommand::Create((String::from("id"), vec![CreateArgs::NoPivot]));
-        let output = runtime.run(&sc, &vec![GlobalArgs::Debug]).await?;
+        let output = runtime.run(&sc, &[GlobalArgs::Debug]).await?;
         assert!(output.status.success());
         assert!(String::from_utf8(output.stderr)?.is_empty());
         assert_eq!(
             String::from_utf8(output.stdout)?,
-            "create --no-pivot id --debug\n"
+            "--debug create --no-pivot id\n"
         );
         Ok(())
     }
@@ -709,12 +1004,12 @@ mod tests {
             String::from("id"),
             vec![RestoreArgs::ImagePath(PathBuf::from("some/path"))],
         ));
-        let output = runtime.run(&sc, &vec![GlobalArgs::Debug]).await?;
+        let output = runtime.run(&sc, &[GlobalArgs::Debug]).await?;
         assert!(output.status.success());
         assert!(String::from_utf8(output.stderr)?.is_empty());
         assert_eq!(
             String::from_utf8(output.stdout)?,
-            "restore --image-path=some/path id --debug\n"
+            "--debug restore --image-path=some/path id\n"
         );
         Ok(())
     }
@@ -725,12 +1020,166 @@ mod tests {
             .binary(which::which("echo")?)
             .build()?;
         let sc = Subcommand::Run((String::from("id"), vec![RunArgs::Detach]));
-        let output = runtime.run(&sc, &vec![GlobalArgs::Debug]).await?;
+        let output = runtime.run(&sc, &[GlobalArgs::Debug]).await?;
         assert!(output.status.success());
         assert!(String::from_utf8(output.stderr)?.is_empty());
         assert_eq!(
             String::from_utf8(output.stdout)?,
-            "run --detach id --debug\n"
+            "--debug run --detach id\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ociruntime_success_kill() -> Result<()> {
+        let runtime = OCIRuntimeBuilder::default()
+            .binary(which::which("echo")?)
+            .build()?;
+        let sc = Subcommand::Kill((String::from("id"), vec![KillArgs::All], Some(9)));
+        let output = runtime.run_checked(&sc, &[GlobalArgs::Debug]).await?;
+        assert_eq!(
+            String::from_utf8(output.stdout)?,
+            "--debug kill --all id 9\n"
+        );
+        Ok(())
+    }
+
+    fn mocked_runtime(stdout: &str) -> Result<OCIRuntime> {
+        let mut runtime = OCIRuntimeBuilder::default().binary("").build()?;
+        runtime.set_exec(Box::new(MockExecCommand(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.into(),
+            stderr: vec![],
+        })));
+        Ok(runtime)
+    }
+
+    #[tokio::test]
+    async fn ociruntime_success_state() -> Result<()> {
+        let runtime = mocked_runtime(
+            r#"{
+                "ociVersion": "1.0.2",
+                "id": "id",
+                "pid": 1234,
+                "status": "running",
+                "bundle": "/some/bundle",
+                "rootfs": "/some/bundle/rootfs",
+                "created": "2022-10-12T08:32:43.394627218Z",
+                "annotations": {"key": "value"},
+                "owner": ""
+            }"#,
+        )?;
+        let state = runtime.state("id", &[]).await?;
+        assert_eq!(state.oci_version(), "1.0.2");
+        assert_eq!(state.id(), "id");
+        assert_eq!(state.pid(), 1234);
+        assert_eq!(state.status(), RuntimeStatus::Running);
+        assert_eq!(state.bundle(), Path::new("/some/bundle"));
+        assert_eq!(
+            state.created().context("no created")?.to_rfc3339(),
+            "2022-10-12T08:32:43.394627218+00:00"
+        );
+        assert_eq!(state.annotations().get("key"), Some(&"value".to_string()));
+
+        let runtime = mocked_runtime(r#"{"id": "id", "status": "unknown", "bundle": ""}"#)?;
+        assert!(runtime.state("id", &[]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ociruntime_success_list() -> Result<()> {
+        let runtime = mocked_runtime(
+            r#"[
+                {"id": "a", "pid": 1, "status": "created", "bundle": "/a"},
+                {"id": "b", "pid": 0, "status": "stopped", "bundle": "/b"}
+            ]"#,
+        )?;
+        let states = runtime.list(&[]).await?;
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].status(), RuntimeStatus::Created);
+        assert_eq!(states[1].status(), RuntimeStatus::Stopped);
+        assert!(states[1].created().is_none());
+
+        let runtime = mocked_runtime("null\n")?;
+        assert!(runtime.list(&[]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ociruntime_success_ps() -> Result<()> {
+        let runtime = mocked_runtime("[1, 23, 456]\n")?;
+        assert_eq!(runtime.ps("id", &[]).await?, vec![1, 23, 456]);
+
+        let runtime = mocked_runtime("invalid")?;
+        assert!(runtime.ps("id", &[]).await.is_err());
+        Ok(())
+    }
+
+    /// A runtime which fails after writing to its standard error and log file.
+    fn failing_runtime(dir: &Path) -> Result<OCIRuntime> {
+        let binary = dir.join("runtime");
+        std::fs::write(
+            &binary,
+            r#"#!/bin/sh
+echo "stderr output" >&2
+for arg; do
+    case "$arg" in
+        --log=*) echo "log output" >> "${arg#--log=}" ;;
+    esac
+done
+exit 3
+"#,
+        )?;
+        std::fs::set_permissions(&binary, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+        Ok(OCIRuntimeBuilder::default().binary(binary).build()?)
+    }
+
+    #[tokio::test]
+    async fn ociruntime_failure_checked() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log = dir.path().join("log");
+        std::fs::write(&log, "previous output\n")?;
+        let runtime = failing_runtime(dir.path())?;
+
+        let err = runtime
+            .run_checked(
+                &Subcommand::Start(String::from("id")),
+                &[GlobalArgs::Log(log)],
+            )
+            .await
+            .unwrap_err();
+        let err = err
+            .downcast_ref::<RuntimeError>()
+            .context("no runtime error")?;
+        assert_eq!(err.subcommand(), "start");
+        assert_eq!(err.status().code(), Some(3));
+        assert_eq!(err.stderr(), "stderr output");
+        assert_eq!(err.log(), "log output");
+        assert_eq!(err.message(), "stderr output");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ociruntime_failure_detached() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let runtime = failing_runtime(dir.path())?;
+
+        let err = runtime
+            .run_detached(
+                &Subcommand::Create((String::from("id"), vec![])),
+                &[GlobalArgs::Log(dir.path().join("log"))],
+            )
+            .await
+            .unwrap_err();
+        let err = err
+            .downcast_ref::<RuntimeError>()
+            .context("no runtime error")?;
+        assert_eq!(err.subcommand(), "create");
+        assert!(err.stderr().is_empty());
+        assert_eq!(err.message(), "log output");
+        assert_eq!(
+            err.to_string(),
+            "OCI runtime create failed (exit status: 3): log output"
         );
         Ok(())
     }
@@ -761,6 +1210,7 @@ mod tests {
             "--bundle=test"
         );
         assert_eq!(&SpecArgs::Rootless.to_string(), "--rootless");
+        assert_eq!(&DeleteArgs::Force.to_string(), "--force");
         assert_eq!(&RestoreArgs::LazyPages.to_string(), "--lazy-pages");
         assert_eq!(&FormatArgs::Json.to_string(), "json");
         assert_eq!(
@@ -771,5 +1221,9 @@ mod tests {
             &ListArgs::Format(FormatArgs::Table).to_string(),
             "--format=table"
         );
+        assert_eq!(
+            &UpdateArgs::Resources("resources.json".into()).to_string(),
+            "--resources=resources.json"
+        );
     }
 }
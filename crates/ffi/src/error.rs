@@ -6,7 +6,7 @@ use log::{trace, warn};
 use std::{cell::RefCell, ptr, slice};
 
 thread_local! {
-    static LAST_ERROR: RefCell<Option<Error>> = RefCell::new(None);
+    static LAST_ERROR: RefCell<Option<Error>> = const { RefCell::new(None) };
 }
 
 /// Update the last error by the provided one.
@@ -0,0 +1,876 @@
+//! Import and export of images without a registry, either as `docker save` archives or as
+//! [OCI image layouts][0].
+//!
+//! A docker archive lists the configuration and layer files of every image in its
+//! `manifest.json`, whereas an OCI image layout references the images in its `index.json` and
+//! contains all blobs below `blobs/<algorithm>/<encoded>`. Both formats can be imported from
+//! directories and from optionally gzip compressed tarballs.
+//!
+//! [0]: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+
+use crate::{
+    digest::Digest,
+    index::ImageRecord,
+    layer::Compression,
+    manifest::{self, Manifest},
+    reference::Reference,
+    rootfs,
+    store::BlobStore,
+};
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use getset::{CopyGetters, Getters};
+use log::{debug, info, warn};
+use oci_spec::image::{
+    DescriptorBuilder, ImageIndexBuilder, ImageManifest, ImageManifestBuilder, MediaType, Platform,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+use strum::{AsRefStr, Display, EnumString};
+use tokio::io::AsyncReadExt;
+
+/// The file of a docker archive listing its images.
+const DOCKER_MANIFEST_FILE: &str = "manifest.json";
+
+/// The file marking the root of an OCI image layout.
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+
+/// The entry point of an OCI image layout.
+const OCI_INDEX_FILE: &str = "index.json";
+
+/// The supported version of the OCI image layout.
+const OCI_LAYOUT_VERSION: &str = "1.0.0";
+
+/// Annotation of the OCI image layout index containing the tag of an image.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Annotation of the OCI image layout index containing the full reference of an image.
+const IMAGE_NAME_ANNOTATION: &str = "io.containerd.image.name";
+
+/// Maximum depth of nested indexes resolved during an import.
+const MAX_INDEX_DEPTH: usize = 4;
+
+/// Size of the buffer used to copy files into the blob store.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(AsRefStr, Clone, Copy, Debug, Display, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "kebab-case")]
+/// The formats images can be exported to.
+pub enum ArchiveFormat {
+    /// A tarball as written by `docker save`.
+    DockerArchive,
+
+    /// A tarball containing an OCI image layout.
+    OciArchive,
+
+    /// A directory containing an OCI image layout.
+    OciDir,
+}
+
+#[derive(Clone, CopyGetters, Debug, Getters)]
+/// An image which got imported into the blob store.
+pub struct ImportedImage {
+    #[get = "pub"]
+    /// The image ID, which is the digest of the image configuration.
+    id: Digest,
+
+    #[get = "pub"]
+    /// The references the image is known by in the archive.
+    names: Vec<Reference>,
+
+    #[get = "pub"]
+    /// The digest of the manifest or index referenced by the archive.
+    digest: Digest,
+
+    #[get = "pub"]
+    /// The digest of the platform specific image manifest.
+    manifest_digest: Digest,
+
+    #[get = "pub"]
+    /// The image manifest.
+    manifest: ImageManifest,
+
+    #[get_copy = "pub"]
+    /// The total size of the configuration and all layers as stored.
+    size: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+/// A single image entry of the `manifest.json` of a docker archive.
+struct DockerManifest {
+    /// The path of the image configuration.
+    config: String,
+
+    /// The tags of the image.
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+
+    /// The paths of the layers.
+    layers: Vec<String>,
+}
+
+/// Import all images of the docker archive or OCI image layout at `path` into the store. The
+/// path can either be a directory or an optionally gzip compressed tarball. Images of an OCI
+/// image index are resolved to the current platform.
+pub async fn import<P: AsRef<Path>>(store: &BlobStore, path: P) -> Result<Vec<ImportedImage>> {
+    let path = path.as_ref();
+    info!("Importing images from {}", path.display());
+
+    let extracted;
+    let dir = if path.is_dir() {
+        path.to_path_buf()
+    } else {
+        extracted = tempfile::tempdir_in(store.root()).context("create extract dir")?;
+        let (archive, target) = (path.to_path_buf(), extracted.path().to_path_buf());
+        tokio::task::spawn_blocking(move || extract(&archive, &target))
+            .await
+            .context("join extract")??;
+        extracted.path().to_path_buf()
+    };
+
+    let images = if dir.join(DOCKER_MANIFEST_FILE).exists() {
+        import_docker(store, &dir).await
+    } else if dir.join(OCI_LAYOUT_FILE).exists() {
+        import_oci(store, &dir).await
+    } else {
+        bail!(
+            "{} is neither a docker archive nor an OCI image layout",
+            path.display()
+        )
+    }
+    .with_context(|| format!("import {}", path.display()))?;
+
+    for image in &images {
+        debug!("Imported image {} from {}", image.id(), path.display());
+    }
+    Ok(images)
+}
+
+/// Export the images to `path` in the provided format. The images are exported under their
+/// repository tags, whereas docker archives always contain the platform specific image.
+pub async fn export<P: AsRef<Path>>(
+    store: &BlobStore,
+    images: &[ImageRecord],
+    format: ArchiveFormat,
+    path: P,
+) -> Result<()> {
+    let path = path.as_ref();
+    info!("Exporting {} images to {}", images.len(), path.display());
+    let entries = match format {
+        ArchiveFormat::DockerArchive => docker_entries(store, images).await?,
+        ArchiveFormat::OciArchive | ArchiveFormat::OciDir => oci_entries(store, images).await?,
+    };
+
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut sink = match format {
+            ArchiveFormat::OciDir => Sink::Dir(path),
+            _ => Sink::Tar(tar::Builder::new(
+                File::create(&path).with_context(|| format!("create {}", path.display()))?,
+            )),
+        };
+        for (name, entry) in entries {
+            sink.add(&name, entry)
+                .with_context(|| format!("export {}", name))?;
+        }
+        sink.finish()
+    })
+    .await
+    .context("join export")?
+}
+
+/// Extract the optionally gzip compressed tarball into the target directory.
+fn extract(archive: &Path, target: &Path) -> Result<()> {
+    let mut file = File::open(archive).with_context(|| format!("open {}", archive.display()))?;
+    let mut magic = [0; 4];
+    let read = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    let reader: Box<dyn Read> = match Compression::detect(&magic[..read]) {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(GzDecoder::new(file)),
+        Compression::Zstd => bail!("zstd compressed archives are not supported"),
+    };
+    tar::Archive::new(reader)
+        .unpack(target)
+        .with_context(|| format!("extract {}", archive.display()))
+}
+
+/// Import the images of an extracted docker archive. The layers are stored as they are, whereas
+/// a new manifest gets created for every image.
+async fn import_docker(store: &BlobStore, dir: &Path) -> Result<Vec<ImportedImage>> {
+    let entries: Vec<DockerManifest> = serde_json::from_slice(
+        &tokio::fs::read(dir.join(DOCKER_MANIFEST_FILE))
+            .await
+            .context("read docker archive manifest")?,
+    )
+    .context("parse docker archive manifest")?;
+
+    let mut images: Vec<ImportedImage> = vec![];
+    for entry in entries {
+        let (id, config_size) =
+            write_file(store, &rootfs::resolve_path(dir, &entry.config)?, None).await?;
+
+        let mut layers = vec![];
+        let mut size = config_size;
+        for layer in &entry.layers {
+            let path = rootfs::resolve_path(dir, layer)?;
+            let media_type = match Compression::detect(&read_magic(&path).await?) {
+                Compression::None => MediaType::ImageLayer,
+                Compression::Gzip => MediaType::ImageLayerGzip,
+                Compression::Zstd => MediaType::ImageLayerZstd,
+            };
+            let (digest, layer_size) = write_file(store, &path, None).await?;
+            size += layer_size;
+            layers.push(
+                DescriptorBuilder::default()
+                    .media_type(media_type)
+                    .digest(digest.to_string())
+                    .size(layer_size as i64)
+                    .build()?,
+            );
+        }
+
+        let manifest = ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .media_type(MediaType::ImageManifest)
+            .config(
+                DescriptorBuilder::default()
+                    .media_type(MediaType::ImageConfig)
+                    .digest(id.to_string())
+                    .size(config_size as i64)
+                    .build()?,
+            )
+            .layers(layers)
+            .build()?;
+        let digest = store
+            .write(&serde_json::to_vec(&manifest)?, None)
+            .await
+            .context("store manifest")?;
+
+        let names = entry
+            .repo_tags
+            .unwrap_or_default()
+            .iter()
+            .map(|tag| tag.parse())
+            .collect::<Result<Vec<Reference>, _>>()
+            .context("parse repository tags")?;
+        add_imported(
+            &mut images,
+            ImportedImage {
+                id,
+                names,
+                digest: digest.clone(),
+                manifest_digest: digest,
+                manifest,
+                size,
+            },
+        );
+    }
+    Ok(images)
+}
+
+/// Import the images referenced by the index of an OCI image layout, whereas only the blobs of
+/// the current platform get copied into the store.
+async fn import_oci(store: &BlobStore, dir: &Path) -> Result<Vec<ImportedImage>> {
+    let layout: serde_json::Value = serde_json::from_slice(
+        &tokio::fs::read(dir.join(OCI_LAYOUT_FILE))
+            .await
+            .context("read OCI layout file")?,
+    )
+    .context("parse OCI layout file")?;
+    if layout["imageLayoutVersion"].as_str() != Some(OCI_LAYOUT_VERSION) {
+        bail!(
+            "unsupported OCI layout version {}",
+            layout["imageLayoutVersion"]
+        )
+    }
+    let index = match Manifest::parse(None, &tokio::fs::read(dir.join(OCI_INDEX_FILE)).await?)
+        .context("parse OCI layout index")?
+    {
+        Manifest::Index(index) => index,
+        Manifest::Image(_) => bail!("OCI layout index is an image manifest"),
+    };
+
+    let mut images: Vec<ImportedImage> = vec![];
+    for descriptor in index.manifests() {
+        let digest = descriptor.digest().parse::<Digest>()?;
+        let (manifest_digest, manifest) = resolve_oci(store, dir, descriptor).await?;
+
+        let config_digest = manifest.config().digest().parse::<Digest>()?;
+        copy_blob(store, dir, &config_digest).await?;
+        let mut size = manifest.config().size() as u64;
+        for layer in manifest.layers() {
+            copy_blob(store, dir, &layer.digest().parse()?).await?;
+            size += layer.size() as u64;
+        }
+
+        add_imported(
+            &mut images,
+            ImportedImage {
+                id: config_digest,
+                names: oci_name(descriptor.annotations().as_ref())
+                    .into_iter()
+                    .collect(),
+                digest,
+                manifest_digest,
+                manifest,
+                size,
+            },
+        );
+    }
+    Ok(images)
+}
+
+/// Copy the manifest referenced by the descriptor into the store and resolve it to the image
+/// manifest of the current platform.
+async fn resolve_oci(
+    store: &BlobStore,
+    dir: &Path,
+    descriptor: &oci_spec::image::Descriptor,
+) -> Result<(Digest, ImageManifest)> {
+    let mut digest = descriptor.digest().parse::<Digest>()?;
+    let mut media_type = descriptor.media_type().to_string();
+    for _ in 0..=MAX_INDEX_DEPTH {
+        copy_blob(store, dir, &digest).await?;
+        match Manifest::parse(Some(&media_type), &store.read(&digest).await?)? {
+            Manifest::Image(manifest) => return Ok((digest, manifest)),
+            Manifest::Index(index) => {
+                let selected = manifest::select_platform(&index, &Platform::default())?;
+                debug!(
+                    "Selected manifest {} from index {}",
+                    selected.digest(),
+                    digest
+                );
+                digest = selected.digest().parse()?;
+                media_type = selected.media_type().to_string();
+            }
+        }
+    }
+    bail!("too many nested indexes for {}", descriptor.digest())
+}
+
+/// The reference of an image from the annotations of its OCI layout index entry. A plain tag
+/// cannot be turned into a reference and gets ignored.
+fn oci_name(annotations: Option<&HashMap<String, String>>) -> Option<Reference> {
+    let annotations = annotations?;
+    let name = annotations
+        .get(IMAGE_NAME_ANNOTATION)
+        .or_else(|| annotations.get(REF_NAME_ANNOTATION))
+        .filter(|name| name.contains(['/', ':']))?;
+    match name.parse() {
+        Ok(reference) => Some(reference),
+        Err(e) => {
+            warn!("Ignoring invalid image name {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Add the imported image to the list, whereas the names of images which are listed multiple
+/// times get merged.
+fn add_imported(images: &mut Vec<ImportedImage>, image: ImportedImage) {
+    match images
+        .iter_mut()
+        .find(|i| i.id == image.id && i.digest == image.digest)
+    {
+        Some(existing) => {
+            for name in image.names {
+                if !existing.names.contains(&name) {
+                    existing.names.push(name);
+                }
+            }
+        }
+        None => images.push(image),
+    }
+}
+
+/// Copy a blob of an OCI image layout into the store, unless it already exists.
+async fn copy_blob(store: &BlobStore, dir: &Path, digest: &Digest) -> Result<()> {
+    if store.contains(digest).await {
+        return Ok(());
+    }
+    let path = dir
+        .join("blobs")
+        .join(digest.algorithm().as_ref())
+        .join(digest.encoded());
+    write_file(store, &path, Some(digest.clone()))
+        .await
+        .with_context(|| format!("copy blob {}", digest))?;
+    Ok(())
+}
+
+/// Write the content of the file into the store and return its digest and size.
+async fn write_file(
+    store: &BlobStore,
+    path: &Path,
+    expected: Option<Digest>,
+) -> Result<(Digest, u64)> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("open {}", path.display()))?;
+    let mut writer = store.writer(expected)?;
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        writer.write(&buffer[..read]).await?;
+    }
+    let size = writer.size();
+    Ok((writer.commit().await?, size))
+}
+
+/// Read the first bytes of the file for detecting its compression.
+async fn read_magic(path: &Path) -> Result<Vec<u8>> {
+    let mut magic = vec![0; 4];
+    let read = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("open {}", path.display()))?
+        .read(&mut magic)
+        .await?;
+    magic.truncate(read);
+    Ok(magic)
+}
+
+/// The content of a single file of an export.
+enum Entry {
+    /// A blob of the store.
+    Blob(PathBuf),
+
+    /// Generated content.
+    Data(Vec<u8>),
+}
+
+/// The destination of an export.
+enum Sink {
+    /// A directory.
+    Dir(PathBuf),
+
+    /// A tarball.
+    Tar(tar::Builder<File>),
+}
+
+impl Sink {
+    /// Add the entry under the relative `name`.
+    fn add(&mut self, name: &str, entry: Entry) -> Result<()> {
+        match (self, entry) {
+            (Self::Dir(dir), entry) => {
+                let target = dir.join(name);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                match entry {
+                    Entry::Blob(path) => fs::copy(path, target).map(|_| ())?,
+                    Entry::Data(data) => fs::write(target, data)?,
+                }
+            }
+            (Self::Tar(builder), Entry::Blob(path)) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(fs::metadata(&path)?.len());
+                header.set_mode(0o644);
+                builder.append_data(&mut header, name, File::open(path)?)?;
+            }
+            (Self::Tar(builder), Entry::Data(data)) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, name, data.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Complete the export.
+    fn finish(self) -> Result<()> {
+        if let Self::Tar(builder) = self {
+            builder.into_inner()?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of a blob inside an OCI image layout or a docker archive.
+fn blob_name(digest: &Digest) -> String {
+    format!("blobs/{}/{}", digest.algorithm(), digest.encoded())
+}
+
+/// Build the entries of an OCI image layout containing the images. Every repository tag of an
+/// image gets its own index entry, whereas untagged images are referenced without a name.
+async fn oci_entries(store: &BlobStore, images: &[ImageRecord]) -> Result<Vec<(String, Entry)>> {
+    let mut blobs = BTreeSet::new();
+    let mut descriptors = vec![];
+    for image in images {
+        let digest = image
+            .manifests()
+            .first()
+            .with_context(|| format!("image {} has no manifest", image.id()))?;
+        let content = store.read(digest).await?;
+        let media_type = match Manifest::parse(None, &content)? {
+            Manifest::Image(_) => MediaType::ImageManifest,
+            Manifest::Index(_) => MediaType::ImageIndex,
+        };
+        collect_blobs(store, digest, &mut blobs).await?;
+
+        let mut names = image
+            .repo_tags()
+            .iter()
+            .map(|tag| {
+                let mut annotations = HashMap::new();
+                annotations.insert(IMAGE_NAME_ANNOTATION.to_string(), tag.to_string());
+                if let Some(tag) = tag.tag() {
+                    annotations.insert(REF_NAME_ANNOTATION.to_string(), tag.clone());
+                }
+                Some(annotations)
+            })
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            names.push(None);
+        }
+        for annotations in names {
+            let mut builder = DescriptorBuilder::default()
+                .media_type(media_type.clone())
+                .digest(digest.to_string())
+                .size(content.len() as i64);
+            if let Some(annotations) = annotations {
+                builder = builder.annotations(annotations);
+            }
+            descriptors.push(builder.build()?);
+        }
+    }
+
+    let index = ImageIndexBuilder::default()
+        .schema_version(2u32)
+        .media_type(MediaType::ImageIndex)
+        .manifests(descriptors)
+        .build()?;
+    let mut entries = vec![
+        (
+            OCI_LAYOUT_FILE.to_string(),
+            Entry::Data(serde_json::to_vec(
+                &json!({ "imageLayoutVersion": OCI_LAYOUT_VERSION }),
+            )?),
+        ),
+        (
+            OCI_INDEX_FILE.to_string(),
+            Entry::Data(serde_json::to_vec(&index)?),
+        ),
+    ];
+    for digest in blobs {
+        entries.push((blob_name(&digest), Entry::Blob(store.path(&digest))));
+    }
+    Ok(entries)
+}
+
+/// Collect the digests of the manifest and all blobs it references, whereas only the children
+/// of an index which are available in the store are followed.
+async fn collect_blobs(
+    store: &BlobStore,
+    digest: &Digest,
+    blobs: &mut BTreeSet<Digest>,
+) -> Result<()> {
+    let mut pending = vec![digest.clone()];
+    while let Some(digest) = pending.pop() {
+        if !blobs.insert(digest.clone()) {
+            continue;
+        }
+        match Manifest::parse(None, &store.read(&digest).await?)? {
+            Manifest::Image(manifest) => {
+                blobs.insert(manifest.config().digest().parse()?);
+                for layer in manifest.layers() {
+                    blobs.insert(layer.digest().parse()?);
+                }
+            }
+            Manifest::Index(index) => {
+                for child in index.manifests() {
+                    let child = child.digest().parse::<Digest>()?;
+                    if store.contains(&child).await {
+                        pending.push(child);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Build the entries of a docker archive containing the platform specific images.
+async fn docker_entries(store: &BlobStore, images: &[ImageRecord]) -> Result<Vec<(String, Entry)>> {
+    let mut blobs = BTreeSet::new();
+    let mut manifests = vec![];
+    for image in images {
+        let mut manifest = None;
+        for digest in image.manifests().iter().rev() {
+            if let Manifest::Image(m) = Manifest::parse(None, &store.read(digest).await?)? {
+                manifest = Some(m);
+                break;
+            }
+        }
+        let manifest =
+            manifest.with_context(|| format!("image {} has no image manifest", image.id()))?;
+
+        let config = manifest.config().digest().parse::<Digest>()?;
+        let layers = manifest
+            .layers()
+            .iter()
+            .map(|layer| layer.digest().parse::<Digest>())
+            .collect::<Result<Vec<_>, _>>()?;
+        manifests.push(DockerManifest {
+            config: blob_name(&config),
+            repo_tags: Some(image.repo_tags().iter().map(ToString::to_string).collect())
+                .filter(|tags: &Vec<String>| !tags.is_empty()),
+            layers: layers.iter().map(blob_name).collect(),
+        });
+        blobs.insert(config);
+        blobs.extend(layers);
+    }
+
+    let mut entries = vec![(
+        DOCKER_MANIFEST_FILE.to_string(),
+        Entry::Data(serde_json::to_vec(&manifests)?),
+    )];
+    for digest in blobs {
+        entries.push((blob_name(&digest), Entry::Blob(store.path(&digest))));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{index::ImageRecordBuilder, testing};
+    use flate2::{write::GzEncoder, Compression as GzCompression};
+    use oci_spec::image::{Arch, Os};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    async fn new_image(store: &BlobStore, file: &str, tags: &[&str]) -> Result<ImageRecord> {
+        let (digest, manifest) = testing::store_image_with_config(
+            store,
+            json!({"User": "1000"}),
+            &[testing::layer(&[(file, b"content")])?],
+        )
+        .await?;
+        Ok(ImageRecordBuilder::default()
+            .id(manifest.config().digest().parse::<Digest>()?)
+            .repo_tags(
+                tags.iter()
+                    .map(|t| t.parse())
+                    .collect::<Result<Vec<Reference>, _>>()?,
+            )
+            .manifests(vec![digest])
+            .build()?)
+    }
+
+    async fn roundtrip(format: ArchiveFormat) -> Result<Vec<ImportedImage>> {
+        let dir = TempDir::new()?;
+        let source = BlobStore::open(dir.path().join("source"))?;
+        let image = new_image(&source, "tagged", &["app:v1", "app:latest"]).await?;
+        let untagged = new_image(&source, "untagged", &[]).await?;
+
+        let path = dir.path().join("export");
+        export(&source, &[image.clone(), untagged.clone()], format, &path).await?;
+
+        let target = BlobStore::open(dir.path().join("target"))?;
+        let imported = import(&target, &path).await?;
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[1].id(), untagged.id());
+        assert!(imported[1].names().is_empty());
+        assert_eq!(imported[0].id(), image.id());
+        assert_eq!(imported[0].names(), image.repo_tags());
+        assert!(imported[0].size() > 0);
+        for layer in imported[0].manifest().layers() {
+            assert!(target.contains(&layer.digest().parse()?).await);
+        }
+        assert!(target.contains(imported[0].id()).await);
+        assert!(target.contains(imported[0].digest()).await);
+        Ok(imported)
+    }
+
+    #[tokio::test]
+    async fn roundtrip_oci_dir() -> Result<()> {
+        roundtrip(ArchiveFormat::OciDir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roundtrip_oci_archive() -> Result<()> {
+        roundtrip(ArchiveFormat::OciArchive).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roundtrip_docker_archive() -> Result<()> {
+        let imported = roundtrip(ArchiveFormat::DockerArchive).await?;
+        assert_eq!(imported[0].digest(), imported[0].manifest_digest());
+        assert_eq!(
+            imported[0].manifest().layers()[0].media_type(),
+            &MediaType::ImageLayer
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_docker_legacy_gzip() -> Result<()> {
+        let dir = TempDir::new()?;
+        let config = serde_json::to_vec(&json!({
+            "architecture": Arch::default(),
+            "os": Os::default(),
+            "config": {"User": "app"},
+            "rootfs": {"type": "layers", "diff_ids": []},
+        }))?;
+        let mut layer = GzEncoder::new(vec![], GzCompression::default());
+        layer.write_all(&testing::layer(&[("file", b"content")])?)?;
+        let layer = layer.finish()?;
+
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], GzCompression::default()));
+        for (name, data) in [
+            (
+                "manifest.json",
+                serde_json::to_vec(&json!([{
+                    "Config": "config.json",
+                    "RepoTags": ["app:v1", "quay.io/app:v2"],
+                    "Layers": ["abc/layer.tar"],
+                }]))?,
+            ),
+            ("config.json", config.clone()),
+            ("abc/layer.tar", layer.clone()),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, data.as_slice())?;
+        }
+        let archive = dir.path().join("archive.tar.gz");
+        fs::write(&archive, builder.into_inner()?.finish()?)?;
+
+        let store = BlobStore::open(dir.path().join("store"))?;
+        let imported = import(&store, &archive).await?;
+        assert_eq!(imported.len(), 1);
+        let image = &imported[0];
+        assert_eq!(image.id(), &Digest::from_bytes(Default::default(), &config));
+        assert_eq!(
+            image.names(),
+            &[
+                "docker.io/library/app:v1".parse::<Reference>()?,
+                "quay.io/app:v2".parse()?,
+            ]
+        );
+        assert_eq!(image.size(), (config.len() + layer.len()) as u64);
+        assert_eq!(
+            image.manifest().layers()[0].media_type(),
+            &MediaType::ImageLayerGzip
+        );
+
+        let record = ImageRecord::from_imported(&store, image, None).await?;
+        assert_eq!(record.repo_tags(), image.names());
+        assert_eq!(record.repo_digests().len(), 2);
+        assert_eq!(record.username(), "app");
+        assert_eq!(record.manifests(), &[image.digest().clone()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_oci_index_current_platform() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = BlobStore::open(dir.path().join("source"))?;
+        let (current, _) =
+            testing::store_image(&source, &[testing::layer(&[("current", b"content")])?]).await?;
+        let (other, _) =
+            testing::store_image(&source, &[testing::layer(&[("other", b"content")])?]).await?;
+        let index = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": MediaType::ImageIndex,
+            "manifests": [
+                {
+                    "mediaType": MediaType::ImageManifest,
+                    "digest": other,
+                    "size": source.size(&other).await?,
+                    "platform": {"architecture": "other", "os": Os::default()},
+                },
+                {
+                    "mediaType": MediaType::ImageManifest,
+                    "digest": current,
+                    "size": source.size(&current).await?,
+                    "platform": {"architecture": Arch::default(), "os": Os::default()},
+                },
+            ],
+        }))?;
+        let index_digest = source.write(&index, None).await?;
+
+        let layout = dir.path().join("layout");
+        let mut blobs = BTreeSet::new();
+        collect_blobs(&source, &index_digest, &mut blobs).await?;
+        for digest in &blobs {
+            let target = layout.join(blob_name(digest));
+            fs::create_dir_all(target.parent().context("no parent")?)?;
+            fs::copy(source.path(digest), target)?;
+        }
+        fs::write(
+            layout.join(OCI_LAYOUT_FILE),
+            json!({ "imageLayoutVersion": OCI_LAYOUT_VERSION }).to_string(),
+        )?;
+        fs::write(
+            layout.join(OCI_INDEX_FILE),
+            json!({
+                "schemaVersion": 2,
+                "manifests": [{
+                    "mediaType": MediaType::ImageIndex,
+                    "digest": index_digest,
+                    "size": index.len(),
+                    "annotations": {REF_NAME_ANNOTATION: "v1"},
+                }],
+            })
+            .to_string(),
+        )?;
+
+        let target = BlobStore::open(dir.path().join("target"))?;
+        let imported = import(&target, &layout).await?;
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].digest(), &index_digest);
+        assert_eq!(imported[0].manifest_digest(), &current);
+        assert!(imported[0].names().is_empty());
+        assert!(target.contains(&current).await);
+        assert!(!target.contains(&other).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_failure_corrupt_blob() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = BlobStore::open(dir.path().join("source"))?;
+        let image = new_image(&source, "file", &["app:v1"]).await?;
+        let layout = dir.path().join("layout");
+        export(
+            &source,
+            std::slice::from_ref(&image),
+            ArchiveFormat::OciDir,
+            &layout,
+        )
+        .await?;
+        fs::write(layout.join(blob_name(image.id())), "corrupt")?;
+
+        let target = BlobStore::open(dir.path().join("target"))?;
+        assert!(import(&target, &layout).await.is_err());
+        assert!(!target.contains(image.id()).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_failure_unknown_format() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path().join("store"))?;
+        fs::create_dir(dir.path().join("empty"))?;
+        assert!(import(&store, dir.path().join("empty")).await.is_err());
+        fs::write(dir.path().join("file"), "no archive")?;
+        assert!(import(&store, dir.path().join("file")).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! Content digests as defined by the [OCI image specification][0].
+//!
+//! [0]: https://github.com/opencontainers/image-spec/blob/main/descriptor.md#digests
+
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256, Sha512};
+use std::{convert::TryFrom, fmt, str::FromStr};
+use strum::{AsRefStr, Display, EnumIter, EnumString};
+use thiserror::Error;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+/// Errors which can occur on digest parsing.
+pub enum DigestError {
+    #[error("digest {0:?} has no algorithm separator ':'")]
+    MissingSeparator(String),
+
+    #[error("unsupported digest algorithm {0:?}")]
+    UnsupportedAlgorithm(String),
+
+    #[error("invalid {algorithm} digest {encoded:?}: expected {len} lowercase hex characters")]
+    InvalidEncoded {
+        algorithm: Algorithm,
+        encoded: String,
+        len: usize,
+    },
+}
+
+#[derive(
+    AsRefStr,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Display,
+    EnumIter,
+    EnumString,
+    Eq,
+    Hash,
+    Ord,
+    PartialEq,
+    PartialOrd,
+)]
+#[strum(serialize_all = "lowercase")]
+/// The supported digest algorithms.
+pub enum Algorithm {
+    #[default]
+    /// SHA-256, the default algorithm.
+    Sha256,
+
+    /// SHA-512.
+    Sha512,
+}
+
+impl Algorithm {
+    /// The length of the hex encoded hash value.
+    pub fn encoded_len(self) -> usize {
+        match self {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+#[derive(
+    Clone, CopyGetters, Debug, Deserialize, Eq, Getters, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(try_from = "String", into = "String")]
+/// A validated content digest in the form `algorithm:encoded`.
+pub struct Digest {
+    #[get_copy = "pub"]
+    /// The algorithm used to calculate the digest.
+    algorithm: Algorithm,
+
+    #[get = "pub"]
+    /// The lowercase hex encoded hash value.
+    encoded: String,
+}
+
+impl Digest {
+    /// Create a new digest from its algorithm and hex encoded value.
+    pub fn new(algorithm: Algorithm, encoded: impl Into<String>) -> Result<Self, DigestError> {
+        let encoded = encoded.into();
+        if encoded.len() != algorithm.encoded_len()
+            || !encoded
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        {
+            return Err(DigestError::InvalidEncoded {
+                algorithm,
+                encoded,
+                len: algorithm.encoded_len(),
+            });
+        }
+        Ok(Self { algorithm, encoded })
+    }
+}
+
+impl Digest {
+    /// Calculate the digest of the provided data.
+    pub fn from_bytes(algorithm: Algorithm, data: &[u8]) -> Self {
+        let mut digester = Digester::new(algorithm);
+        digester.update(data);
+        digester.finalize()
+    }
+}
+
+#[derive(Clone, Debug)]
+/// An incremental digest calculator.
+pub enum Digester {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Digester {
+    /// Create a new digester for the provided algorithm.
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Self::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Self::Sha512(Sha512::new()),
+        }
+    }
+
+    /// Feed more data into the digester.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Consume the digester and return the resulting digest.
+    pub fn finalize(self) -> Digest {
+        let (algorithm, encoded) = match self {
+            Self::Sha256(hasher) => (Algorithm::Sha256, format!("{:x}", hasher.finalize())),
+            Self::Sha512(hasher) => (Algorithm::Sha512, format!("{:x}", hasher.finalize())),
+        };
+        Digest { algorithm, encoded }
+    }
+}
+
+impl FromStr for Digest {
+    type Err = DigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, encoded) = s
+            .split_once(':')
+            .ok_or_else(|| DigestError::MissingSeparator(s.into()))?;
+        let algorithm = algorithm
+            .parse::<Algorithm>()
+            .map_err(|_| DigestError::UnsupportedAlgorithm(algorithm.into()))?;
+        Self::new(algorithm, encoded)
+    }
+}
+
+impl TryFrom<String> for Digest {
+    type Error = DigestError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Digest> for String {
+    fn from(digest: Digest) -> Self {
+        digest.to_string()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    const SHA256: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    #[test]
+    fn parse_sha256() -> Result<()> {
+        let digest = SHA256.parse::<Digest>()?;
+        assert_eq!(digest.algorithm(), Algorithm::Sha256);
+        assert_eq!(digest.encoded().len(), 64);
+        assert_eq!(digest.to_string(), SHA256);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_sha512() -> Result<()> {
+        let digest = format!("sha512:{}", "a".repeat(128)).parse::<Digest>()?;
+        assert_eq!(digest.algorithm(), Algorithm::Sha512);
+        Ok(())
+    }
+
+    #[test]
+    fn from_bytes() {
+        let digest = Digest::from_bytes(Algorithm::Sha256, b"hello");
+        assert_eq!(
+            digest.to_string(),
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+
+        let mut digester = Digester::new(Algorithm::Sha512);
+        digester.update(b"hel");
+        digester.update(b"lo");
+        assert_eq!(
+            digester.finalize(),
+            Digest::from_bytes(Algorithm::Sha512, b"hello")
+        );
+    }
+
+    #[test]
+    fn parse_failure() {
+        assert_eq!(
+            "sha256".parse::<Digest>(),
+            Err(DigestError::MissingSeparator("sha256".into()))
+        );
+        assert_eq!(
+            "md5:abc".parse::<Digest>(),
+            Err(DigestError::UnsupportedAlgorithm("md5".into()))
+        );
+        assert!("sha256:abc".parse::<Digest>().is_err());
+        assert!(format!("sha256:{}", "A".repeat(64))
+            .parse::<Digest>()
+            .is_err());
+        assert!(format!("sha512:{}", "a".repeat(64))
+            .parse::<Digest>()
+            .is_err());
+    }
+}
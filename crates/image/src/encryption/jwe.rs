@@ -0,0 +1,358 @@
+//! Unwrapping of layer keys from JSON Web Encryption objects as defined in [RFC 7516][0].
+//!
+//! [0]: https://www.rfc-editor.org/rfc/rfc7516
+
+use super::keys::PrivateKey;
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128Gcm, Aes256Gcm, Nonce,
+};
+use aes_kw::KekAes256;
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::DeflateDecoder;
+use log::trace;
+use p256::{ecdh, PublicKey};
+use rsa::Oaep;
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+/// Key management with RSAES-OAEP using SHA-1.
+const ALG_RSA_OAEP: &str = "RSA-OAEP";
+
+/// Key management with RSAES-OAEP using SHA-256.
+const ALG_RSA_OAEP_256: &str = "RSA-OAEP-256";
+
+/// Key agreement with ECDH-ES, whereas the content key is wrapped with AES-256 key wrap.
+const ALG_ECDH_ES_A256KW: &str = "ECDH-ES+A256KW";
+
+#[derive(Debug, Deserialize)]
+/// A JWE in the general or flattened JSON serialization.
+struct Jwe {
+    /// The base64url encoded integrity protected header.
+    #[serde(default)]
+    protected: String,
+
+    /// The shared unprotected header.
+    unprotected: Option<Header>,
+
+    /// The per recipient header of the flattened serialization.
+    header: Option<Header>,
+
+    /// The encrypted key of the flattened serialization.
+    encrypted_key: Option<String>,
+
+    /// The recipients of the general serialization.
+    recipients: Option<Vec<Recipient>>,
+
+    /// The additional authenticated data.
+    aad: Option<String>,
+
+    /// The initialization vector.
+    iv: String,
+
+    /// The encrypted content.
+    ciphertext: String,
+
+    /// The authentication tag.
+    tag: String,
+}
+
+#[derive(Debug, Deserialize)]
+/// A single recipient of the general serialization.
+struct Recipient {
+    /// The per recipient header.
+    header: Option<Header>,
+
+    /// The encrypted content key.
+    encrypted_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+/// The header parameters used for decryption.
+struct Header {
+    /// The key management algorithm.
+    alg: Option<String>,
+
+    /// The content encryption algorithm.
+    enc: Option<String>,
+
+    /// The compression algorithm of the plaintext.
+    zip: Option<String>,
+
+    /// The ephemeral public key of ECDH-ES.
+    epk: Option<Jwk>,
+
+    /// The base64url encoded producer info of ECDH-ES.
+    apu: Option<String>,
+
+    /// The base64url encoded recipient info of ECDH-ES.
+    apv: Option<String>,
+}
+
+impl Header {
+    /// Merge the headers, whereas parameters of `self` take precedence.
+    fn or(self, other: &Self) -> Self {
+        Self {
+            alg: self.alg.or_else(|| other.alg.clone()),
+            enc: self.enc.or_else(|| other.enc.clone()),
+            zip: self.zip.or_else(|| other.zip.clone()),
+            epk: self.epk.or_else(|| other.epk.clone()),
+            apu: self.apu.or_else(|| other.apu.clone()),
+            apv: self.apv.or_else(|| other.apv.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// An elliptic curve public key.
+struct Jwk {
+    /// The curve of the key.
+    crv: String,
+
+    /// The base64url encoded x coordinate.
+    x: String,
+
+    /// The base64url encoded y coordinate.
+    y: String,
+}
+
+/// Decrypt the JWE with the first key which is a valid recipient.
+pub fn decrypt(data: &[u8], keys: &[PrivateKey]) -> Result<Vec<u8>> {
+    let jwe: Jwe = serde_json::from_slice(data).context("parse JWE")?;
+    let protected = if jwe.protected.is_empty() {
+        Header::default()
+    } else {
+        serde_json::from_slice(&decode(&jwe.protected)?).context("parse JWE protected header")?
+    };
+    let shared = jwe.unprotected.clone().unwrap_or_default().or(&protected);
+
+    let recipients = match jwe.recipients {
+        Some(ref recipients) => recipients
+            .iter()
+            .map(|r| (r.header.clone(), r.encrypted_key.clone()))
+            .collect(),
+        None => vec![(jwe.header.clone(), jwe.encrypted_key.clone())],
+    };
+    let iv = decode(&jwe.iv)?;
+    let mut ciphertext = decode(&jwe.ciphertext)?;
+    ciphertext.extend(decode(&jwe.tag)?);
+    let mut aad = jwe.protected.clone().into_bytes();
+    if let Some(extra) = &jwe.aad {
+        aad.push(b'.');
+        aad.extend(extra.as_bytes());
+    }
+
+    for (header, encrypted_key) in recipients {
+        let header = header.unwrap_or_default().or(&shared);
+        let encrypted_key = decode(encrypted_key.as_deref().unwrap_or_default())?;
+        for key in keys {
+            let cek = match unwrap_key(&header, key, &encrypted_key) {
+                Ok(Some(cek)) => cek,
+                Ok(None) => continue,
+                Err(e) => {
+                    trace!("Unable to unwrap JWE key: {:#}", e);
+                    continue;
+                }
+            };
+            match decrypt_content(&header, &cek, &iv, &ciphertext, &aad) {
+                Ok(plaintext) => return decompress(&header, plaintext),
+                Err(e) => trace!("Unable to decrypt JWE content: {:#}", e),
+            }
+        }
+    }
+    bail!("no matching private key found for JWE")
+}
+
+/// Unwrap the content encryption key, which returns `None` if the key does not fit the
+/// algorithm.
+fn unwrap_key(header: &Header, key: &PrivateKey, encrypted_key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let alg = header.alg.as_deref().context("no JWE alg header")?;
+    Ok(match (alg, key) {
+        (ALG_RSA_OAEP, PrivateKey::Rsa(key)) => {
+            Some(key.decrypt(Oaep::new::<Sha1>(), encrypted_key)?)
+        }
+        (ALG_RSA_OAEP_256, PrivateKey::Rsa(key)) => {
+            Some(key.decrypt(Oaep::new::<Sha256>(), encrypted_key)?)
+        }
+        (ALG_ECDH_ES_A256KW, PrivateKey::P256(key)) => {
+            let epk = header.epk.as_ref().context("no JWE epk header")?;
+            if epk.crv != "P-256" {
+                return Ok(None);
+            }
+            let mut point = vec![0x04];
+            point.extend(decode(&epk.x)?);
+            point.extend(decode(&epk.y)?);
+            let epk = PublicKey::from_sec1_bytes(&point).context("parse JWE epk")?;
+            let shared = ecdh::diffie_hellman(key.to_nonzero_scalar(), epk.as_affine());
+            let apu = decode(header.apu.as_deref().unwrap_or_default())?;
+            let apv = decode(header.apv.as_deref().unwrap_or_default())?;
+            let kek = KekAes256::from(concat_kdf(
+                shared.raw_secret_bytes(),
+                ALG_ECDH_ES_A256KW,
+                &apu,
+                &apv,
+            ));
+            Some(
+                kek.unwrap_vec(encrypted_key)
+                    .map_err(|e| anyhow!("unwrap JWE key: {}", e))?,
+            )
+        }
+        (ALG_RSA_OAEP | ALG_RSA_OAEP_256 | ALG_ECDH_ES_A256KW, _) => None,
+        (alg, _) => bail!("unsupported JWE alg {}", alg),
+    })
+}
+
+/// Derive the 256 bit key encryption key from the shared secret as defined in [RFC 7518][0].
+///
+/// [0]: https://www.rfc-editor.org/rfc/rfc7518#section-4.6.2
+fn concat_kdf(z: &[u8], alg: &str, apu: &[u8], apv: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(1u32.to_be_bytes());
+    hasher.update(z);
+    for part in [alg.as_bytes(), apu, apv] {
+        hasher.update((part.len() as u32).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.update(256u32.to_be_bytes());
+    hasher.finalize().into()
+}
+
+/// Decrypt the content with the content encryption key.
+fn decrypt_content(
+    header: &Header,
+    cek: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    if iv.len() != 12 {
+        bail!("invalid JWE iv length {}", iv.len())
+    }
+    let payload = Payload {
+        msg: ciphertext,
+        aad,
+    };
+    let nonce = Nonce::from_slice(iv);
+    let plaintext = match header.enc.as_deref() {
+        Some("A256GCM") => Aes256Gcm::new_from_slice(cek)?.decrypt(nonce, payload),
+        Some("A128GCM") => Aes128Gcm::new_from_slice(cek)?.decrypt(nonce, payload),
+        enc => bail!("unsupported JWE enc {:?}", enc),
+    };
+    plaintext.map_err(|_| anyhow!("JWE content authentication failed"))
+}
+
+/// Decompress the plaintext if the JWE uses compression.
+fn decompress(header: &Header, plaintext: Vec<u8>) -> Result<Vec<u8>> {
+    match header.zip.as_deref() {
+        None => Ok(plaintext),
+        Some("DEF") => {
+            let mut decompressed = vec![];
+            DeflateDecoder::new(plaintext.as_slice())
+                .read_to_end(&mut decompressed)
+                .context("decompress JWE content")?;
+            Ok(decompressed)
+        }
+        Some(zip) => bail!("unsupported JWE zip {}", zip),
+    }
+}
+
+/// Decode base64url without padding.
+fn decode(data: &str) -> Result<Vec<u8>> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).context("decode JWE base64url")
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    use rand::{rngs::OsRng, RngCore};
+    use serde_json::{json, Value};
+
+    fn encode(data: &[u8]) -> String {
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Encrypt the payload with A256GCM for all recipients in the general serialization.
+    pub fn encrypt(payload: &[u8], recipients: &[PrivateKey]) -> Result<Vec<u8>> {
+        let mut cek = [0; 32];
+        OsRng.fill_bytes(&mut cek);
+        let mut iv = [0; 12];
+        OsRng.fill_bytes(&mut iv);
+        let protected = encode(json!({"enc": "A256GCM"}).to_string().as_bytes());
+
+        let mut entries = vec![];
+        for recipient in recipients {
+            let entry = match recipient {
+                PrivateKey::Rsa(key) => json!({
+                    "header": {"alg": ALG_RSA_OAEP},
+                    "encrypted_key": encode(&key.to_public_key().encrypt(
+                        &mut OsRng,
+                        Oaep::new::<Sha1>(),
+                        &cek,
+                    )?),
+                }),
+                PrivateKey::P256(key) => {
+                    let ephemeral = ecdh::EphemeralSecret::random(&mut OsRng);
+                    let shared = ephemeral.diffie_hellman(&key.public_key());
+                    let point = ephemeral.public_key().to_encoded_point(false);
+                    let kek = KekAes256::from(concat_kdf(
+                        shared.raw_secret_bytes(),
+                        ALG_ECDH_ES_A256KW,
+                        &[],
+                        &[],
+                    ));
+                    json!({
+                        "header": {
+                            "alg": ALG_ECDH_ES_A256KW,
+                            "epk": {
+                                "kty": "EC",
+                                "crv": "P-256",
+                                "x": encode(point.x().context("no x")?),
+                                "y": encode(point.y().context("no y")?),
+                            },
+                        },
+                        "encrypted_key": encode(
+                            &kek.wrap_vec(&cek).map_err(|e| anyhow!("{}", e))?
+                        ),
+                    })
+                }
+            };
+            entries.push(entry);
+        }
+
+        let mut sealed = Aes256Gcm::new_from_slice(&cek)?
+            .encrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: payload,
+                    aad: protected.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("encrypt"))?;
+        let tag = sealed.split_off(sealed.len() - 16);
+        Ok(serde_json::to_vec(&json!({
+            "protected": protected,
+            "recipients": Value::Array(entries),
+            "iv": encode(&iv),
+            "ciphertext": encode(&sealed),
+            "tag": encode(&tag),
+        }))?)
+    }
+
+    #[test]
+    fn decrypt_recipients() -> Result<()> {
+        let rsa = PrivateKey::Rsa(Box::new(super::super::keys::tests::rsa_key()?));
+        let ec = PrivateKey::P256(p256::SecretKey::random(&mut OsRng));
+        let other = PrivateKey::P256(p256::SecretKey::random(&mut OsRng));
+
+        let jwe = encrypt(b"options", &[rsa.clone(), ec.clone()])?;
+        assert_eq!(decrypt(&jwe, std::slice::from_ref(&rsa))?, b"options");
+        assert_eq!(decrypt(&jwe, &[other.clone(), ec])?, b"options");
+        assert!(decrypt(&jwe, &[other]).is_err());
+        assert!(decrypt(&jwe, &[]).is_err());
+        assert!(decrypt(b"{}", &[rsa]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Private keys used to unwrap the symmetric keys of encrypted layers.
+
+use anyhow::{bail, Result};
+use log::warn;
+use p256::pkcs8::DecodePrivateKey;
+use rsa::{pkcs1::DecodeRsaPrivateKey, RsaPrivateKey};
+use std::fmt;
+
+/// The PEM label prefix which starts a block.
+const PEM_BEGIN: &str = "-----BEGIN ";
+
+/// The PEM label prefix which ends a block.
+const PEM_END: &str = "-----END ";
+
+#[derive(Clone)]
+/// A private key of a recipient.
+pub enum PrivateKey {
+    /// An RSA key, usable for JWE `RSA-OAEP` and PKCS7 key transport.
+    Rsa(Box<RsaPrivateKey>),
+
+    /// A NIST P-256 key, usable for JWE `ECDH-ES+A256KW`.
+    P256(p256::SecretKey),
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rsa(_) => write!(f, "Rsa"),
+            Self::P256(_) => write!(f, "P256"),
+        }
+    }
+}
+
+impl PrivateKey {
+    /// Parse all private keys of PEM blocks within the data, whereas other blocks like
+    /// certificates are ignored. Data without any PEM block is parsed as a single DER key.
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Self>> {
+        let text = match std::str::from_utf8(data) {
+            Ok(text) if text.contains(PEM_BEGIN) => text,
+            _ => return Ok(vec![Self::from_der(data)?]),
+        };
+
+        let mut keys = vec![];
+        for (label, block) in pem_blocks(text) {
+            let key = match label {
+                "RSA PRIVATE KEY" => RsaPrivateKey::from_pkcs1_pem(block)
+                    .map(|key| Self::Rsa(Box::new(key)))
+                    .map_err(anyhow::Error::from),
+                "EC PRIVATE KEY" => p256::SecretKey::from_sec1_pem(block)
+                    .map(Self::P256)
+                    .map_err(anyhow::Error::from),
+                "PRIVATE KEY" => pem_to_der(block).and_then(|der| Self::from_der(&der)),
+                "ENCRYPTED PRIVATE KEY" => {
+                    warn!("Skipping password protected private key");
+                    continue;
+                }
+                _ => continue,
+            };
+            keys.push(key?);
+        }
+        if keys.is_empty() {
+            bail!("no private keys found")
+        }
+        Ok(keys)
+    }
+
+    /// Parse a DER encoded PKCS8, PKCS1 or SEC1 private key.
+    fn from_der(der: &[u8]) -> Result<Self> {
+        if let Ok(key) = RsaPrivateKey::from_pkcs8_der(der) {
+            return Ok(Self::Rsa(Box::new(key)));
+        }
+        if let Ok(key) = RsaPrivateKey::from_pkcs1_der(der) {
+            return Ok(Self::Rsa(Box::new(key)));
+        }
+        if let Ok(key) = p256::SecretKey::from_pkcs8_der(der) {
+            return Ok(Self::P256(key));
+        }
+        if let Ok(key) = p256::SecretKey::from_sec1_der(der) {
+            return Ok(Self::P256(key));
+        }
+        bail!("unsupported private key format")
+    }
+}
+
+/// Split the text into its PEM blocks, which are pairs of the label and the whole block.
+fn pem_blocks(text: &str) -> Vec<(&str, &str)> {
+    let mut blocks = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        let block = &rest[start..];
+        let label = match block[PEM_BEGIN.len()..].split_once("-----") {
+            Some((label, _)) => label,
+            None => break,
+        };
+        let end_marker = format!("{}{}-----", PEM_END, label);
+        let end = match block.find(&end_marker) {
+            Some(end) => end + end_marker.len(),
+            None => break,
+        };
+        blocks.push((label, &block[..end]));
+        rest = &block[end..];
+    }
+    blocks
+}
+
+/// Decode the base64 body of a PEM block.
+fn pem_to_der(block: &str) -> Result<Vec<u8>> {
+    let body = block
+        .lines()
+        .filter(|line| !line.starts_with("-----") && !line.contains(':'))
+        .collect::<String>();
+    Ok(base64::decode(body.trim())?)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use p256::pkcs8::{EncodePrivateKey, LineEnding};
+    use rand::rngs::OsRng;
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+
+    pub fn rsa_key() -> Result<RsaPrivateKey> {
+        Ok(RsaPrivateKey::new(&mut OsRng, 1024)?)
+    }
+
+    #[test]
+    fn parse_keys() -> Result<()> {
+        let rsa = rsa_key()?;
+        let ec = p256::SecretKey::random(&mut OsRng);
+        let pem = format!(
+            "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n{}{}{}",
+            rsa.to_pkcs1_pem(LineEnding::LF)?.as_str(),
+            ec.to_sec1_pem(LineEnding::LF)?.as_str(),
+            ec.to_pkcs8_pem(LineEnding::LF)?.as_str(),
+        );
+        let keys = PrivateKey::parse_all(pem.as_bytes())?;
+        assert_eq!(keys.len(), 3);
+        assert!(matches!(&keys[0], PrivateKey::Rsa(key) if **key == rsa));
+        assert!(matches!(&keys[1], PrivateKey::P256(key) if *key == ec));
+        assert!(matches!(&keys[2], PrivateKey::P256(key) if *key == ec));
+
+        let der = rsa.to_pkcs8_der()?;
+        assert_eq!(PrivateKey::parse_all(der.as_bytes())?.len(), 1);
+
+        assert!(PrivateKey::parse_all(b"invalid").is_err());
+        assert!(PrivateKey::parse_all(
+            b"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n"
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+//! Decryption of layers encrypted according to the [ocicrypt][0] specification.
+//!
+//! The content of an encrypted layer is symmetrically encrypted with AES-256-CTR and
+//! authenticated by HMAC-SHA256. The symmetric key is part of the private options, which are
+//! wrapped for every recipient and stored in the layer annotations, either as JWE or as PKCS7
+//! enveloped data. A layer can only be decrypted if one of the configured private keys is a
+//! recipient, otherwise unpacking fails.
+//!
+//! [0]: https://github.com/containers/ocicrypt/blob/main/docs/spec.md
+
+use crate::digest::{Digest, Digester};
+use aes::Aes256;
+use anyhow::{anyhow, bail, format_err, Context, Result};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use log::{debug, trace, warn};
+use oci_spec::image::Descriptor;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{ErrorKind, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+mod jwe;
+mod keys;
+mod pkcs7;
+
+pub use keys::PrivateKey;
+
+/// The media type suffix of encrypted layers.
+pub const ENCRYPTED_SUFFIX: &str = "+encrypted";
+
+/// The annotation containing the JWE wrapped private options.
+const JWE_KEYS_ANNOTATION: &str = "org.opencontainers.image.enc.keys.jwe";
+
+/// The annotation containing the PKCS7 wrapped private options.
+const PKCS7_KEYS_ANNOTATION: &str = "org.opencontainers.image.enc.keys.pkcs7";
+
+/// The annotation containing the base64 encoded public options.
+const PUBLIC_OPTIONS_ANNOTATION: &str = "org.opencontainers.image.enc.pubopts";
+
+/// The only layer cipher defined by the specification.
+const CIPHER_AES_256_CTR_HMAC_SHA256: &str = "AES_256_CTR_HMAC_SHA256";
+
+/// The buffer size used while decrypting layers.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// AES-256 in counter mode with a 128 bit big endian counter.
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+/// Check if the layer media type denotes an encrypted layer.
+pub fn is_encrypted(media_type: &str) -> bool {
+    media_type.ends_with(ENCRYPTED_SUFFIX)
+}
+
+#[derive(Debug, Default, Deserialize)]
+/// The public options of the layer cipher, which are stored unencrypted.
+struct PublicOptions {
+    /// The cipher used to encrypt the layer.
+    cipher: Option<String>,
+
+    /// The base64 encoded HMAC of the encrypted layer.
+    hmac: Option<String>,
+
+    #[serde(default, rename = "cipheroptions")]
+    /// Base64 encoded cipher specific options.
+    cipher_options: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+/// The private options of the layer cipher, which are wrapped for every recipient.
+struct PrivateOptions {
+    /// The base64 encoded symmetric key.
+    symkey: String,
+
+    #[serde(default)]
+    /// The digest of the unencrypted layer.
+    digest: String,
+
+    #[serde(default, rename = "cipheroptions")]
+    /// Base64 encoded cipher specific options.
+    cipher_options: HashMap<String, String>,
+
+    #[serde(flatten)]
+    /// Public options embedded by older encoders.
+    public: PublicOptions,
+}
+
+#[derive(Clone, Default)]
+/// Decrypts encrypted layers with the configured private keys.
+pub struct Decryptor {
+    /// All private keys which may be recipients of layers.
+    keys: Arc<Vec<PrivateKey>>,
+}
+
+impl fmt::Debug for Decryptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Decryptor")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+impl Decryptor {
+    /// Create a new decryptor using the provided private keys.
+    pub fn new(keys: Vec<PrivateKey>) -> Self {
+        Self {
+            keys: Arc::new(keys),
+        }
+    }
+
+    /// Load all private keys from the files within the directory and its subdirectories. Files
+    /// which do not contain private keys are skipped, whereas a missing directory results in a
+    /// decryptor without keys.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut keys = vec![];
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(current) = dirs.pop() {
+            let entries = match fs::read_dir(&current) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound && current == dir => {
+                    debug!("Decryption keys path {} does not exist", dir.display());
+                    break;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("read keys dir {}", current.display()))
+                }
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let data =
+                    fs::read(&path).with_context(|| format!("read key file {}", path.display()))?;
+                match PrivateKey::parse_all(&data) {
+                    Ok(parsed) => {
+                        trace!("Loaded {} keys from {}", parsed.len(), path.display());
+                        keys.extend(parsed)
+                    }
+                    Err(e) => warn!("Skipping key file {}: {:#}", path.display(), e),
+                }
+            }
+        }
+
+        debug!(
+            "Loaded {} decryption keys from {}",
+            keys.len(),
+            dir.display()
+        );
+        Ok(Self::new(keys))
+    }
+
+    /// The number of loaded private keys.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Check if no private keys are loaded.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Decrypt the encrypted layer of the descriptor from the input into the output. The output
+    /// must not be trusted if an error is returned, because the integrity of the layer can only
+    /// be verified after it has been read completely.
+    pub fn decrypt<R: Read, W: Write>(
+        &self,
+        descriptor: &Descriptor,
+        mut input: R,
+        mut output: W,
+    ) -> Result<()> {
+        let digest = descriptor.digest();
+        if self.is_empty() {
+            bail!(
+                "no decryption keys configured for encrypted layer {}",
+                digest
+            )
+        }
+        let annotations = descriptor.annotations().clone().unwrap_or_default();
+        let private = self
+            .unwrap_options(&annotations)
+            .with_context(|| format!("no matching decryption key for layer {}", digest))?;
+        let public = match annotations.get(PUBLIC_OPTIONS_ANNOTATION) {
+            Some(public) => serde_json::from_slice(&base64::decode(public)?)
+                .context("parse public layer options")?,
+            None => PublicOptions::default(),
+        };
+
+        let cipher = public
+            .cipher
+            .as_deref()
+            .or(private.public.cipher.as_deref())
+            .unwrap_or(CIPHER_AES_256_CTR_HMAC_SHA256);
+        if cipher != CIPHER_AES_256_CTR_HMAC_SHA256 {
+            bail!("unsupported layer cipher {}", cipher)
+        }
+        let symkey = base64::decode(&private.symkey).context("decode symmetric key")?;
+        let nonce = private
+            .cipher_options
+            .get("nonce")
+            .or_else(|| public.cipher_options.get("nonce"))
+            .context("no nonce in layer options")?;
+        let nonce = base64::decode(nonce).context("decode nonce")?;
+        let expected_hmac = public
+            .hmac
+            .as_ref()
+            .or(private.public.hmac.as_ref())
+            .context("no HMAC in layer options")?;
+        let expected_hmac = base64::decode(expected_hmac).context("decode HMAC")?;
+        let expected_digest = match private.digest.as_str() {
+            "" => None,
+            digest => Some(digest.parse::<Digest>()?),
+        };
+
+        let mut cipher = Aes256Ctr::new_from_slices(&symkey, &nonce)
+            .map_err(|_| anyhow!("invalid symmetric key or nonce length"))?;
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&symkey)?;
+        let mut digester = expected_digest
+            .as_ref()
+            .map(|digest| Digester::new(digest.algorithm()));
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("read encrypted layer"),
+            };
+            hmac.update(&buf[..n]);
+            cipher.apply_keystream(&mut buf[..n]);
+            if let Some(digester) = digester.as_mut() {
+                digester.update(&buf[..n]);
+            }
+            output
+                .write_all(&buf[..n])
+                .context("write decrypted layer")?;
+        }
+
+        hmac.verify_slice(&expected_hmac)
+            .map_err(|_| format_err!("HMAC mismatch for encrypted layer {}", digest))?;
+        if let (Some(expected), Some(digester)) = (expected_digest, digester) {
+            let decrypted = digester.finalize();
+            if decrypted != expected {
+                bail!(
+                    "decrypted layer {} has digest {}, expected {}",
+                    digest,
+                    decrypted,
+                    expected
+                )
+            }
+        }
+        trace!("Decrypted layer {}", digest);
+        Ok(())
+    }
+
+    /// Unwrap the private options with the first matching key.
+    fn unwrap_options(&self, annotations: &HashMap<String, String>) -> Result<PrivateOptions> {
+        type Unwrap = fn(&[u8], &[PrivateKey]) -> Result<Vec<u8>>;
+        let schemes: [(&str, Unwrap); 2] = [
+            (JWE_KEYS_ANNOTATION, jwe::decrypt),
+            (PKCS7_KEYS_ANNOTATION, pkcs7::decrypt),
+        ];
+
+        let mut wrapped = false;
+        for (annotation, unwrap) in schemes {
+            let values = match annotations.get(annotation) {
+                Some(values) => values,
+                None => continue,
+            };
+            for value in values.split(',').filter(|v| !v.is_empty()) {
+                wrapped = true;
+                let data = base64::decode(value)
+                    .with_context(|| format!("decode annotation {}", annotation))?;
+                match unwrap(&data, &self.keys) {
+                    Ok(options) => {
+                        return serde_json::from_slice(&options)
+                            .context("parse private layer options")
+                    }
+                    Err(e) => trace!("Unable to unwrap {}: {:#}", annotation, e),
+                }
+            }
+        }
+        if !wrapped {
+            bail!("layer contains no supported wrapped keys")
+        }
+        bail!(
+            "none of the {} private keys is a recipient",
+            self.keys.len()
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use oci_spec::image::{DescriptorBuilder, MediaType};
+    use rand::{rngs::OsRng, RngCore};
+    use serde_json::json;
+
+    /// Encrypt the layer for the JWE and PKCS7 recipients and return the encrypted content and
+    /// its descriptor.
+    pub fn encrypt(
+        layer: &[u8],
+        jwe_recipients: &[PrivateKey],
+        pkcs7_recipients: &[PrivateKey],
+    ) -> Result<(Vec<u8>, Descriptor)> {
+        let mut symkey = [0; 32];
+        OsRng.fill_bytes(&mut symkey);
+        let mut nonce = [0; 16];
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut encrypted = layer.to_vec();
+        Aes256Ctr::new_from_slices(&symkey, &nonce)
+            .map_err(|e| anyhow!("{}", e))?
+            .apply_keystream(&mut encrypted);
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&symkey)?;
+        hmac.update(&encrypted);
+
+        let private = serde_json::to_vec(&json!({
+            "symkey": base64::encode(symkey),
+            "digest": Digest::from_bytes(Default::default(), layer).to_string(),
+            "cipheroptions": {"nonce": base64::encode(nonce)},
+        }))?;
+        let public = serde_json::to_vec(&json!({
+            "cipher": CIPHER_AES_256_CTR_HMAC_SHA256,
+            "hmac": base64::encode(hmac.finalize().into_bytes()),
+            "cipheroptions": {},
+        }))?;
+
+        let mut annotations = HashMap::new();
+        annotations.insert(PUBLIC_OPTIONS_ANNOTATION.into(), base64::encode(public));
+        if !jwe_recipients.is_empty() {
+            annotations.insert(
+                JWE_KEYS_ANNOTATION.into(),
+                base64::encode(jwe::tests::encrypt(&private, jwe_recipients)?),
+            );
+        }
+        let pkcs7_recipients = pkcs7_recipients
+            .iter()
+            .filter_map(|key| match key {
+                PrivateKey::Rsa(key) => Some(key.to_public_key()),
+                PrivateKey::P256(_) => None,
+            })
+            .collect::<Vec<_>>();
+        if !pkcs7_recipients.is_empty() {
+            annotations.insert(
+                PKCS7_KEYS_ANNOTATION.into(),
+                base64::encode(pkcs7::tests::encrypt(&private, &pkcs7_recipients)?),
+            );
+        }
+
+        let descriptor = DescriptorBuilder::default()
+            .media_type(MediaType::Other(format!(
+                "{}{}",
+                MediaType::ImageLayer,
+                ENCRYPTED_SUFFIX
+            )))
+            .digest(Digest::from_bytes(Default::default(), &encrypted).to_string())
+            .size(encrypted.len() as i64)
+            .annotations(annotations)
+            .build()?;
+        Ok((encrypted, descriptor))
+    }
+
+    pub fn rsa_key() -> Result<PrivateKey> {
+        Ok(PrivateKey::Rsa(Box::new(keys::tests::rsa_key()?)))
+    }
+
+    #[test]
+    fn decrypt_layer() -> Result<()> {
+        let rsa = rsa_key()?;
+        let ec = PrivateKey::P256(p256::SecretKey::random(&mut OsRng));
+        let other = rsa_key()?;
+
+        for (jwe, pkcs7) in [(vec![ec.clone()], vec![]), (vec![], vec![rsa.clone()])] {
+            let (encrypted, descriptor) = encrypt(b"layer", &jwe, &pkcs7)?;
+            assert!(is_encrypted(&descriptor.media_type().to_string()));
+
+            let decryptor = Decryptor::new(vec![other.clone(), rsa.clone(), ec.clone()]);
+            let mut decrypted = vec![];
+            decryptor.decrypt(&descriptor, &encrypted[..], &mut decrypted)?;
+            assert_eq!(decrypted, b"layer");
+
+            let err = Decryptor::new(vec![other.clone()])
+                .decrypt(&descriptor, &encrypted[..], &mut vec![])
+                .unwrap_err();
+            assert!(format!("{:#}", err).contains("no matching decryption key"));
+            assert!(Decryptor::default()
+                .decrypt(&descriptor, &encrypted[..], &mut vec![])
+                .is_err());
+
+            let mut tampered = encrypted.clone();
+            tampered[0] ^= 1;
+            let err = decryptor
+                .decrypt(&descriptor, &tampered[..], &mut vec![])
+                .unwrap_err();
+            assert!(err.to_string().contains("HMAC mismatch"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn load_keys() -> Result<()> {
+        use p256::pkcs8::{EncodePrivateKey, LineEnding};
+
+        let dir = tempfile::TempDir::new()?;
+        assert!(Decryptor::load(dir.path().join("missing"))?.is_empty());
+
+        fs::create_dir(dir.path().join("nested"))?;
+        let key = p256::SecretKey::random(&mut OsRng);
+        fs::write(
+            dir.path().join("nested/key.pem"),
+            key.to_pkcs8_pem(LineEnding::LF)?.as_bytes(),
+        )?;
+        fs::write(dir.path().join("cert.pem"), "invalid")?;
+        assert_eq!(Decryptor::load(dir.path())?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+//! Unwrapping of layer keys from PKCS7 enveloped data as defined in [RFC 5652][0].
+//!
+//! The DER parsing is intentionally lenient, because common encoders wrap the content encryption
+//! parameters in non canonical ways.
+//!
+//! [0]: https://www.rfc-editor.org/rfc/rfc5652#section-6
+
+use super::keys::PrivateKey;
+use aes::{Aes128, Aes256};
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes128Gcm, Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use log::trace;
+use rsa::{Oaep, Pkcs1v15Encrypt, RsaPrivateKey};
+use sha1::Sha1;
+
+/// DER tag of an INTEGER.
+const TAG_INTEGER: u8 = 0x02;
+
+/// DER tag of an OCTET STRING.
+const TAG_OCTET_STRING: u8 = 0x04;
+
+/// DER tag of an OBJECT IDENTIFIER.
+const TAG_OID: u8 = 0x06;
+
+/// DER tag of a SEQUENCE.
+const TAG_SEQUENCE: u8 = 0x30;
+
+/// DER tag of a SET.
+const TAG_SET: u8 = 0x31;
+
+/// DER tag of the explicit context specific tag 0.
+const TAG_CONTEXT_0: u8 = 0xa0;
+
+/// DER tag of the implicit primitive context specific tag 0.
+const TAG_CONTEXT_0_PRIMITIVE: u8 = 0x80;
+
+/// OID 1.2.840.113549.1.7.3 of enveloped data.
+const OID_ENVELOPED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x03];
+
+/// OID 1.2.840.113549.1.1.1 of RSAES-PKCS1-v1_5.
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// OID 1.2.840.113549.1.1.7 of RSAES-OAEP.
+const OID_RSAES_OAEP: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x07];
+
+/// OID 2.16.840.1.101.3.4.1.2 of AES-128-CBC.
+const OID_AES128_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x02];
+
+/// OID 2.16.840.1.101.3.4.1.42 of AES-256-CBC.
+const OID_AES256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+
+/// OID 2.16.840.1.101.3.4.1.6 of AES-128-GCM.
+const OID_AES128_GCM: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x06];
+
+/// OID 2.16.840.1.101.3.4.1.46 of AES-256-GCM.
+const OID_AES256_GCM: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2e];
+
+/// The only supported length of GCM authentication tags.
+const GCM_TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+/// A DER encoded type, length and value.
+struct Tlv<'a> {
+    /// The identifier octet.
+    tag: u8,
+
+    /// The content octets.
+    value: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    /// Read the next element from the data.
+    fn read(data: &mut &'a [u8]) -> Result<Self> {
+        let (&tag, rest) = data.split_first().context("unexpected end of DER data")?;
+        if tag & 0x1f == 0x1f {
+            bail!("unsupported DER high tag number")
+        }
+        let (&first, mut rest) = rest.split_first().context("missing DER length")?;
+        let len = if first & 0x80 == 0 {
+            usize::from(first)
+        } else {
+            let count = usize::from(first & 0x7f);
+            if count == 0 || count > 4 || rest.len() < count {
+                bail!("unsupported DER length encoding")
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0, |len, &b| (len << 8) | usize::from(b));
+            rest = &rest[count..];
+            len
+        };
+        if rest.len() < len {
+            bail!("DER element exceeds the available data")
+        }
+        *data = &rest[len..];
+        Ok(Self {
+            tag,
+            value: &rest[..len],
+        })
+    }
+
+    /// Parse the single element of the data.
+    fn parse(mut data: &'a [u8]) -> Result<Self> {
+        Self::read(&mut data)
+    }
+
+    /// All elements within the content octets.
+    fn children(&self) -> Result<Vec<Tlv<'a>>> {
+        let mut data = self.value;
+        let mut children = vec![];
+        while !data.is_empty() {
+            children.push(Self::read(&mut data)?);
+        }
+        Ok(children)
+    }
+
+    /// Ensure that the element has the expected tag.
+    fn expect(self, tag: u8) -> Result<Self> {
+        if self.tag != tag {
+            bail!("unexpected DER tag {:#x}, expected {:#x}", self.tag, tag)
+        }
+        Ok(self)
+    }
+}
+
+/// A recipient which received the content encryption key via key transport.
+struct KeyTransRecipient<'a> {
+    /// The OID of the key encryption algorithm.
+    algorithm: &'a [u8],
+
+    /// The encrypted content encryption key.
+    encrypted_key: &'a [u8],
+}
+
+/// Decrypt the enveloped data with the first key which is a valid recipient.
+pub fn decrypt(data: &[u8], keys: &[PrivateKey]) -> Result<Vec<u8>> {
+    let content_info = Tlv::parse(data)?.expect(TAG_SEQUENCE)?.children()?;
+    match content_info.as_slice() {
+        [oid, content] if oid.tag == TAG_OID && oid.value == OID_ENVELOPED_DATA => {
+            let enveloped = content.expect(TAG_CONTEXT_0)?.children()?;
+            let enveloped = enveloped
+                .first()
+                .context("no enveloped data")?
+                .expect(TAG_SEQUENCE)?
+                .children()?;
+            let recipients = enveloped
+                .iter()
+                .find(|e| e.tag == TAG_SET)
+                .context("no recipient infos")?;
+            let encrypted_content = enveloped
+                .iter()
+                .skip_while(|e| e.tag != TAG_SET)
+                .find(|e| e.tag == TAG_SEQUENCE)
+                .context("no encrypted content info")?;
+
+            let recipients = recipients
+                .children()?
+                .into_iter()
+                .filter(|r| r.tag == TAG_SEQUENCE)
+                .map(key_trans_recipient)
+                .collect::<Result<Vec<_>>>()?;
+            for recipient in &recipients {
+                for key in keys {
+                    let key = match key {
+                        PrivateKey::Rsa(key) => key,
+                        PrivateKey::P256(_) => continue,
+                    };
+                    let result = unwrap_key(recipient, key)
+                        .and_then(|cek| decrypt_content(encrypted_content, &cek));
+                    match result {
+                        Ok(plaintext) => return Ok(plaintext),
+                        Err(e) => trace!("Unable to decrypt PKCS7 recipient: {:#}", e),
+                    }
+                }
+            }
+            bail!("no matching private key found for PKCS7")
+        }
+        _ => bail!("PKCS7 content is not enveloped data"),
+    }
+}
+
+/// Parse a key transport recipient info.
+fn key_trans_recipient(info: Tlv<'_>) -> Result<KeyTransRecipient<'_>> {
+    match info.children()?.as_slice() {
+        [_, _, algorithm, encrypted_key] => Ok(KeyTransRecipient {
+            algorithm: algorithm
+                .expect(TAG_SEQUENCE)?
+                .children()?
+                .first()
+                .context("no key encryption algorithm")?
+                .expect(TAG_OID)?
+                .value,
+            encrypted_key: encrypted_key.expect(TAG_OCTET_STRING)?.value,
+        }),
+        _ => bail!("invalid key transport recipient info"),
+    }
+}
+
+/// Decrypt the content encryption key of the recipient.
+fn unwrap_key(recipient: &KeyTransRecipient<'_>, key: &RsaPrivateKey) -> Result<Vec<u8>> {
+    Ok(match recipient.algorithm {
+        OID_RSA_ENCRYPTION => key.decrypt(Pkcs1v15Encrypt, recipient.encrypted_key)?,
+        OID_RSAES_OAEP => key.decrypt(Oaep::new::<Sha1>(), recipient.encrypted_key)?,
+        _ => bail!("unsupported PKCS7 key encryption algorithm"),
+    })
+}
+
+/// Decrypt the encrypted content info with the content encryption key.
+fn decrypt_content(info: &Tlv<'_>, cek: &[u8]) -> Result<Vec<u8>> {
+    let children = info.children()?;
+    let (algorithm, content) = match children.as_slice() {
+        [_, algorithm, content] => (algorithm.expect(TAG_SEQUENCE)?.children()?, content),
+        _ => bail!("no encrypted content"),
+    };
+    let ciphertext = match content.tag {
+        TAG_CONTEXT_0_PRIMITIVE => content.value.to_vec(),
+        TAG_CONTEXT_0 => content
+            .children()?
+            .iter()
+            .map(|c| c.expect(TAG_OCTET_STRING).map(|c| c.value))
+            .collect::<Result<Vec<_>>>()?
+            .concat(),
+        tag => bail!("unexpected encrypted content tag {:#x}", tag),
+    };
+    let (oid, params) = match algorithm.as_slice() {
+        [oid, params, ..] => (oid.expect(TAG_OID)?.value, params),
+        _ => bail!("no content encryption parameters"),
+    };
+
+    match oid {
+        OID_AES128_CBC | OID_AES256_CBC => {
+            let iv = params.expect(TAG_OCTET_STRING)?.value;
+            let plaintext = if oid == OID_AES128_CBC {
+                cbc::Decryptor::<Aes128>::new_from_slices(cek, iv)
+                    .map_err(|e| anyhow!("{}", e))?
+                    .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+            } else {
+                cbc::Decryptor::<Aes256>::new_from_slices(cek, iv)
+                    .map_err(|e| anyhow!("{}", e))?
+                    .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+            };
+            plaintext.map_err(|_| anyhow!("invalid PKCS7 content padding"))
+        }
+        OID_AES128_GCM | OID_AES256_GCM => {
+            let (nonce, tag_len) = gcm_parameters(*params)?;
+            if nonce.len() != 12 || tag_len != GCM_TAG_LEN {
+                bail!("unsupported AES-GCM nonce or tag length")
+            }
+            let nonce = Nonce::from_slice(nonce);
+            let plaintext = if oid == OID_AES128_GCM {
+                Aes128Gcm::new_from_slice(cek)?.decrypt(nonce, ciphertext.as_slice())
+            } else {
+                Aes256Gcm::new_from_slice(cek)?.decrypt(nonce, ciphertext.as_slice())
+            };
+            plaintext.map_err(|_| anyhow!("PKCS7 content authentication failed"))
+        }
+        _ => bail!("unsupported PKCS7 content encryption algorithm"),
+    }
+}
+
+/// Find the nonce and tag length of the GCM parameters, which may be wrapped into additional
+/// sequences and use a context specific tag for the nonce.
+fn gcm_parameters<'a>(mut params: Tlv<'a>) -> Result<(&'a [u8], usize)> {
+    loop {
+        let children = params.children()?;
+        match children.first() {
+            Some(first) if first.tag & 0x1f == 0x10 => params = *first,
+            Some(first) if first.tag & 0x1f == TAG_OCTET_STRING => {
+                let tag_len = match children.get(1) {
+                    Some(len) if len.tag == TAG_INTEGER => len
+                        .value
+                        .iter()
+                        .fold(0, |len, &b| (len << 8) | usize::from(b)),
+                    _ => 12,
+                };
+                return Ok((first.value, tag_len));
+            }
+            _ => bail!("invalid AES-GCM parameters"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use aes_gcm::aead::OsRng;
+    use rand::RngCore;
+    use rsa::RsaPublicKey;
+
+    fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut data = vec![tag];
+        match value.len() {
+            len if len < 0x80 => data.push(len as u8),
+            len if len < 0x100 => data.extend([0x81, len as u8]),
+            len => data.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        data.extend(value);
+        data
+    }
+
+    /// Encrypt the payload with AES-128-GCM for all recipients, encoding the parameters like the
+    /// Go PKCS7 implementation used by ocicrypt.
+    pub fn encrypt(payload: &[u8], recipients: &[RsaPublicKey]) -> Result<Vec<u8>> {
+        let mut cek = [0; 16];
+        OsRng.fill_bytes(&mut cek);
+        let mut nonce = [0; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = Aes128Gcm::new_from_slice(&cek)?
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow!("encrypt"))?;
+
+        let mut infos = vec![];
+        for recipient in recipients {
+            infos.extend(tlv(
+                TAG_SEQUENCE,
+                &[
+                    tlv(TAG_INTEGER, &[0]),
+                    tlv(
+                        TAG_SEQUENCE,
+                        &[tlv(TAG_SEQUENCE, &[]), tlv(TAG_INTEGER, &[1])].concat(),
+                    ),
+                    tlv(
+                        TAG_SEQUENCE,
+                        &[tlv(TAG_OID, OID_RSA_ENCRYPTION), tlv(0x05, &[])].concat(),
+                    ),
+                    tlv(
+                        TAG_OCTET_STRING,
+                        &recipient.encrypt(&mut OsRng, Pkcs1v15Encrypt, &cek)?,
+                    ),
+                ]
+                .concat(),
+            ));
+        }
+        let params = tlv(
+            0x10,
+            &tlv(
+                TAG_SEQUENCE,
+                &[tlv(0x84, &nonce), tlv(TAG_INTEGER, &[16])].concat(),
+            ),
+        );
+        let content_info = tlv(
+            TAG_SEQUENCE,
+            &[
+                tlv(
+                    TAG_OID,
+                    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01],
+                ),
+                tlv(
+                    TAG_SEQUENCE,
+                    &[tlv(TAG_OID, OID_AES128_GCM), params].concat(),
+                ),
+                tlv(TAG_CONTEXT_0, &tlv(TAG_OCTET_STRING, &ciphertext)),
+            ]
+            .concat(),
+        );
+        let enveloped = tlv(
+            TAG_SEQUENCE,
+            &[tlv(TAG_INTEGER, &[0]), tlv(TAG_SET, &infos), content_info].concat(),
+        );
+        Ok(tlv(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_OID, OID_ENVELOPED_DATA),
+                tlv(TAG_CONTEXT_0, &enveloped),
+            ]
+            .concat(),
+        ))
+    }
+
+    #[test]
+    fn decrypt_gcm() -> Result<()> {
+        let key = super::super::keys::tests::rsa_key()?;
+        let other = super::super::keys::tests::rsa_key()?;
+        let data = encrypt(b"options", &[other.to_public_key(), key.to_public_key()])?;
+
+        let keys = [PrivateKey::Rsa(Box::new(key))];
+        assert_eq!(decrypt(&data, &keys)?, b"options");
+        assert!(decrypt(&data, &[]).is_err());
+        assert!(decrypt(b"\x30\x00", &keys).is_err());
+        assert!(decrypt(&data[..data.len() - 1], &keys).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decrypt_cbc_content() -> Result<()> {
+        use cbc::cipher::BlockEncryptMut;
+        let cek = [7; 32];
+        let iv = [9; 16];
+        let ciphertext = cbc::Encryptor::<Aes256>::new_from_slices(&cek, &iv)
+            .map_err(|e| anyhow!("{}", e))?
+            .encrypt_padded_vec_mut::<Pkcs7>(b"options");
+        let info = tlv(
+            TAG_SEQUENCE,
+            &[
+                tlv(
+                    TAG_OID,
+                    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01],
+                ),
+                tlv(
+                    TAG_SEQUENCE,
+                    &[tlv(TAG_OID, OID_AES256_CBC), tlv(TAG_OCTET_STRING, &iv)].concat(),
+                ),
+                tlv(TAG_CONTEXT_0_PRIMITIVE, &ciphertext),
+            ]
+            .concat(),
+        );
+        let info = Tlv::parse(&info)?;
+        assert_eq!(decrypt_content(&info, &cek)?, b"options");
+        assert!(decrypt_content(&info, &[8; 32]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,619 @@
+//! Garbage collection of image content.
+//!
+//! The collector marks every blob and snapshot which is reachable from the provided images and
+//! the active snapshots of containers, then removes everything else from the blob store and the
+//! snapshotter. The [`GcPolicy`] selects the images to remove if the disk usage exceeds its high
+//! threshold, similar to the image garbage collection of the kubelet.
+
+use crate::{
+    digest::Digest,
+    manifest::Manifest,
+    snapshot::{self, SnapshotKind, Snapshotter},
+    store::{BlobStore, StoreError},
+    usage::DiskUsage,
+};
+use anyhow::{Context, Result};
+use derive_builder::Builder;
+use getset::{CopyGetters, Getters};
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+#[derive(Builder, Clone, CopyGetters, Debug, Getters)]
+#[builder(pattern = "owned", setter(into))]
+/// An image which roots its content for the garbage collection.
+pub struct GcImage {
+    #[get = "pub"]
+    /// The image ID, which is the digest of the image configuration.
+    id: Digest,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The digests of the manifests and indexes referencing the image.
+    manifests: Vec<Digest>,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// Pinned images are never selected for removal by the policy.
+    pinned: bool,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// Images used by containers are never selected for removal by the policy.
+    in_use: bool,
+
+    #[get_copy = "pub"]
+    #[builder(default = "SystemTime::UNIX_EPOCH")]
+    /// The last time the image has been pulled or used by a container.
+    last_used: SystemTime,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// The size of the image content in bytes.
+    size: u64,
+}
+
+#[derive(Builder, Clone, Copy, CopyGetters, Debug)]
+#[builder(pattern = "owned", setter(into), build_fn(validate = "Self::validate"))]
+/// The disk usage watermarks which trigger the removal of images.
+pub struct GcPolicy {
+    #[get_copy = "pub"]
+    #[builder(default = "85")]
+    /// Images get removed if the disk usage exceeds this percentage.
+    high_threshold_percent: u8,
+
+    #[get_copy = "pub"]
+    #[builder(default = "80")]
+    /// Images get removed until the disk usage drops to this percentage.
+    low_threshold_percent: u8,
+}
+
+impl Default for GcPolicy {
+    fn default() -> Self {
+        Self {
+            high_threshold_percent: 85,
+            low_threshold_percent: 80,
+        }
+    }
+}
+
+impl GcPolicyBuilder {
+    /// Ensure that the low threshold does not exceed the high one.
+    fn validate(&self) -> Result<(), String> {
+        let high = self.high_threshold_percent.unwrap_or(85);
+        let low = self.low_threshold_percent.unwrap_or(80);
+        if high > 100 {
+            return Err(format!("high threshold {}% exceeds 100%", high));
+        }
+        if low > high {
+            return Err(format!(
+                "low threshold {}% exceeds high threshold {}%",
+                low, high
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl GcPolicy {
+    /// Select the images to remove. Nothing is selected as long as the disk usage stays below
+    /// the high threshold. Otherwise, the least recently used images which are neither pinned nor
+    /// in use are selected until their size brings the usage down to the low threshold.
+    pub fn select<'a>(&self, images: &'a [GcImage], usage: DiskUsage) -> Vec<&'a GcImage> {
+        if usage.percent() < u64::from(self.high_threshold_percent) {
+            return vec![];
+        }
+        let target = usage.capacity() as u128 * u128::from(self.low_threshold_percent) / 100;
+        let mut to_free = (usage.used() as u128).saturating_sub(target);
+        debug!(
+            "Disk usage {}% exceeds high threshold {}%, freeing {} bytes",
+            usage.percent(),
+            self.high_threshold_percent,
+            to_free
+        );
+
+        let mut candidates = images
+            .iter()
+            .filter(|image| !image.pinned && !image.in_use)
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|image| image.last_used);
+
+        let mut selected = vec![];
+        for image in candidates {
+            if to_free == 0 {
+                break;
+            }
+            to_free = to_free.saturating_sub(u128::from(image.size));
+            selected.push(image);
+        }
+        selected
+    }
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Eq, PartialEq)]
+/// The result of a garbage collection run.
+pub struct GcStats {
+    #[get_copy = "pub"]
+    /// The number of removed blobs.
+    removed_blobs: usize,
+
+    #[get_copy = "pub"]
+    /// The number of removed snapshots.
+    removed_snapshots: usize,
+
+    #[get_copy = "pub"]
+    /// The size of the removed blobs in bytes.
+    freed_bytes: u64,
+}
+
+#[derive(Builder, Clone, Debug, Getters)]
+#[builder(pattern = "owned", setter(into))]
+/// Removes image content which is not referenced any more.
+pub struct GarbageCollector {
+    #[get = "pub"]
+    /// The blob store to collect.
+    store: BlobStore,
+
+    #[get = "pub"]
+    /// The snapshotter to collect.
+    snapshotter: Snapshotter,
+
+    #[get = "pub"]
+    #[builder(default, setter(strip_option))]
+    /// The policy for selecting images to remove, whereas nothing gets selected without one.
+    policy: Option<GcPolicy>,
+
+    #[builder(default)]
+    /// Collections hold the write lock, whereas paused operations hold read locks.
+    lock: Arc<RwLock<()>>,
+}
+
+impl GarbageCollector {
+    /// Remove all blobs and committed snapshots which are not reachable from the `images`.
+    /// Active snapshots and their parents are always kept, as well as blobs with a positive
+    /// reference count. Pulls which are not part of the `images` yet have to [`pause`] the
+    /// collection or acquire their blobs to protect them from being removed.
+    ///
+    /// [`pause`]: GarbageCollector::pause
+    pub async fn collect(&self, images: &[GcImage]) -> Result<GcStats> {
+        let _lock = self.lock.write().await;
+        let (blobs, chain_ids) = self.mark(images).await?;
+        let mut stats = GcStats::default();
+
+        // Snapshots
+        let snapshots = self
+            .snapshotter
+            .list()?
+            .into_iter()
+            .map(|info| (info.key().clone(), info))
+            .collect::<HashMap<_, _>>();
+        let mut keep = HashSet::new();
+        for info in snapshots.values() {
+            if info.kind() != SnapshotKind::Active && !chain_ids.contains(info.key()) {
+                continue;
+            }
+            let mut next = Some(info);
+            while let Some(current) = next {
+                if !keep.insert(current.key().clone()) {
+                    break;
+                }
+                next = current.parent().as_ref().and_then(|p| snapshots.get(p));
+            }
+        }
+        let depth = |key: &String| {
+            let mut depth = 0;
+            let mut next = snapshots.get(key).and_then(|i| i.parent().as_ref());
+            while let Some(parent) = next {
+                depth += 1;
+                next = snapshots.get(parent).and_then(|i| i.parent().as_ref());
+            }
+            depth
+        };
+        let mut unreferenced = snapshots
+            .keys()
+            .filter(|key| !keep.contains(*key))
+            .collect::<Vec<_>>();
+        // Children first, because parents cannot be removed before them
+        unreferenced.sort_by_key(|key| std::cmp::Reverse(depth(key)));
+        for key in unreferenced {
+            self.snapshotter
+                .remove(key)
+                .await
+                .with_context(|| format!("remove snapshot {}", key))?;
+            stats.removed_snapshots += 1;
+        }
+
+        // Blobs
+        for digest in self.store.list().await? {
+            if blobs.contains(&digest) || self.store.ref_count(&digest)? > 0 {
+                continue;
+            }
+            let size = self.store.size(&digest).await.unwrap_or_default();
+            match self.store.remove(&digest) {
+                Ok(()) => {
+                    stats.removed_blobs += 1;
+                    stats.freed_bytes += size;
+                }
+                // Acquired in the meantime
+                Err(StoreError::InUse { .. }) => {}
+                Err(e) => return Err(e).with_context(|| format!("remove blob {}", digest)),
+            }
+        }
+
+        info!(
+            "Garbage collection removed {} blobs ({} bytes) and {} snapshots",
+            stats.removed_blobs, stats.freed_bytes, stats.removed_snapshots
+        );
+        Ok(stats)
+    }
+
+    /// Prevent collections until the returned guard gets dropped. This protects content which is
+    /// not yet referenced by any image, for example during a pull.
+    pub async fn pause(&self) -> OwnedRwLockReadGuard<()> {
+        self.lock.clone().read_owned().await
+    }
+
+    /// Wait for all paused operations and prevent new ones until the returned guard gets dropped.
+    /// This allows to remove images without racing with their users, whereas collections must
+    /// not happen before the guard got dropped.
+    pub async fn exclusive(&self) -> OwnedRwLockWriteGuard<()> {
+        self.lock.clone().write_owned().await
+    }
+
+    /// Retrieve the disk usage of the file system containing the blob store.
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        DiskUsage::of(self.store.root())
+    }
+
+    /// Select the images to remove according to the policy and the current disk usage.
+    pub fn select<'a>(&self, images: &'a [GcImage]) -> Result<Vec<&'a GcImage>> {
+        match &self.policy {
+            Some(policy) => Ok(policy.select(images, self.disk_usage()?)),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Collect the digests of all blobs and the chain IDs of all snapshots reachable from the
+    /// images.
+    async fn mark(&self, images: &[GcImage]) -> Result<(HashSet<Digest>, HashSet<String>)> {
+        let mut blobs = HashSet::new();
+        let mut chain_ids = HashSet::new();
+        for image in images {
+            blobs.insert(image.id.clone());
+            let mut pending = image.manifests.clone();
+            while let Some(digest) = pending.pop() {
+                if !blobs.insert(digest.clone()) {
+                    continue;
+                }
+                let content = match self.store.read(&digest).await {
+                    Ok(content) => content,
+                    Err(StoreError::NotFound(_)) => {
+                        warn!("Manifest {} of image {} is missing", digest, image.id);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                match Manifest::parse(None, &content)
+                    .with_context(|| format!("parse manifest {}", digest))?
+                {
+                    Manifest::Image(manifest) => {
+                        for descriptor in
+                            std::iter::once(manifest.config()).chain(manifest.layers())
+                        {
+                            blobs.insert(descriptor.digest().parse()?);
+                        }
+                        match snapshot::diff_ids(&self.store, &manifest).await {
+                            Ok(diff_ids) => chain_ids.extend(
+                                snapshot::chain_ids(&diff_ids)
+                                    .iter()
+                                    .map(ToString::to_string),
+                            ),
+                            Err(e) => warn!("Unable to get diff IDs of {}: {:#}", digest, e),
+                        }
+                    }
+                    Manifest::Index(index) => {
+                        // Only the platform specific manifests which got pulled are available
+                        for descriptor in index.manifests() {
+                            let child: Digest = descriptor.digest().parse()?;
+                            if self.store.contains(&child).await {
+                                pending.push(child);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok((blobs, chain_ids))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{snapshot::Driver, testing};
+    use serde_json::json;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    struct TestImage {
+        manifest_digest: Digest,
+        id: Digest,
+        layers: Vec<Digest>,
+        top: Option<String>,
+    }
+
+    async fn add_image(sut: &GarbageCollector, layers: &[Vec<u8>]) -> Result<TestImage> {
+        let (manifest_digest, manifest) = testing::store_image(sut.store(), layers).await?;
+        let top = sut.snapshotter().unpack(sut.store(), &manifest).await?;
+        Ok(TestImage {
+            manifest_digest,
+            id: manifest.config().digest().parse()?,
+            layers: manifest
+                .layers()
+                .iter()
+                .map(|l| l.digest().parse())
+                .collect::<Result<_, _>>()?,
+            top,
+        })
+    }
+
+    fn gc_image(image: &TestImage) -> Result<GcImage> {
+        Ok(GcImageBuilder::default()
+            .id(image.id.clone())
+            .manifests(vec![image.manifest_digest.clone()])
+            .build()?)
+    }
+
+    fn new_collector(dir: &TempDir) -> Result<GarbageCollector> {
+        Ok(GarbageCollectorBuilder::default()
+            .store(BlobStore::open(dir.path().join("store"))?)
+            .snapshotter(Snapshotter::open(
+                dir.path().join("snapshots"),
+                Driver::Vfs,
+            )?)
+            .build()?)
+    }
+
+    #[tokio::test]
+    async fn collect_unreferenced() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_collector(&dir)?;
+        let shared = testing::layer(&[("shared", b"shared")])?;
+        let kept = add_image(&sut, &[shared.clone(), testing::layer(&[("a", b"a")])?]).await?;
+        let removed = add_image(&sut, &[shared, testing::layer(&[("b", b"b")])?]).await?;
+        assert_eq!(sut.snapshotter().list()?.len(), 3);
+
+        let stats = sut.collect(&[gc_image(&kept)?]).await?;
+        assert_eq!(stats.removed_blobs(), 3);
+        assert_eq!(stats.removed_snapshots(), 1);
+        assert!(stats.freed_bytes() > 0);
+
+        for digest in kept
+            .layers
+            .iter()
+            .chain(vec![&kept.id, &kept.manifest_digest])
+        {
+            assert!(sut.store().contains(digest).await);
+        }
+        assert!(sut.store().contains(&removed.layers[0]).await);
+        for digest in &[&removed.layers[1], &removed.id, &removed.manifest_digest] {
+            assert!(!sut.store().contains(digest).await);
+        }
+        let snapshots = sut.snapshotter().list()?;
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots.iter().any(|s| Some(s.key()) == kept.top.as_ref()));
+
+        // Nothing left to collect
+        assert_eq!(sut.collect(&[gc_image(&kept)?]).await?, GcStats::default());
+
+        // Everything is collectable without images
+        let stats = sut.collect(&[]).await?;
+        assert_eq!(stats.removed_blobs(), 4);
+        assert_eq!(stats.removed_snapshots(), 2);
+        assert!(sut.store().list().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn collect_keeps_active_snapshots() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_collector(&dir)?;
+        let image = add_image(
+            &sut,
+            &[
+                testing::layer(&[("a", b"a")])?,
+                testing::layer(&[("b", b"b")])?,
+            ],
+        )
+        .await?;
+        sut.snapshotter()
+            .prepare("container", image.top.as_deref())
+            .await?;
+
+        let stats = sut.collect(&[]).await?;
+        assert_eq!(stats.removed_snapshots(), 0);
+        assert_eq!(sut.snapshotter().list()?.len(), 3);
+
+        sut.snapshotter().remove("container").await?;
+        let stats = sut.collect(&[]).await?;
+        assert_eq!(stats.removed_snapshots(), 2);
+        assert!(sut.snapshotter().list()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn collect_keeps_acquired_blobs() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_collector(&dir)?;
+        let digest = sut.store().write(b"in flight", None).await?;
+        sut.store().acquire(&digest)?;
+
+        assert_eq!(sut.collect(&[]).await?.removed_blobs(), 0);
+        assert!(sut.store().contains(&digest).await);
+
+        sut.store().release(&digest)?;
+        assert_eq!(sut.collect(&[]).await?.removed_blobs(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn collect_paused() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_collector(&dir)?;
+        sut.store().write(b"in flight", None).await?;
+
+        let guard = sut.pause().await;
+        let collect = tokio::time::timeout(Duration::from_millis(100), sut.collect(&[]));
+        assert!(collect.await.is_err());
+        drop(guard);
+        assert_eq!(sut.collect(&[]).await?.removed_blobs(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exclusive_paused() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_collector(&dir)?;
+
+        let guard = sut.pause().await;
+        let exclusive = tokio::time::timeout(Duration::from_millis(100), sut.exclusive());
+        assert!(exclusive.await.is_err());
+        drop(guard);
+
+        let exclusive = sut.exclusive().await;
+        let pause = tokio::time::timeout(Duration::from_millis(100), sut.pause());
+        assert!(pause.await.is_err());
+        drop(exclusive);
+        sut.pause().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn collect_follows_index() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_collector(&dir)?;
+        let image = add_image(&sut, &[testing::layer(&[("a", b"a")])?]).await?;
+        let index = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": image.manifest_digest,
+                    "size": 1,
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": Digest::from_bytes(Default::default(), b"not pulled"),
+                    "size": 1,
+                },
+            ],
+        }))?;
+        let index_digest = sut.store().write(&index, None).await?;
+
+        let root = GcImageBuilder::default()
+            .id(image.id.clone())
+            .manifests(vec![index_digest.clone()])
+            .build()?;
+        assert_eq!(sut.collect(&[root]).await?, GcStats::default());
+        assert!(sut.store().contains(&image.manifest_digest).await);
+        assert!(sut.store().contains(&image.layers[0]).await);
+        Ok(())
+    }
+
+    #[test]
+    fn policy_select() -> Result<()> {
+        let now = SystemTime::now();
+        let image = |id: &[u8], age: u64, pinned: bool, in_use: bool| -> Result<GcImage> {
+            Ok(GcImageBuilder::default()
+                .id(Digest::from_bytes(Default::default(), id))
+                .pinned(pinned)
+                .in_use(in_use)
+                .last_used(now - Duration::from_secs(age))
+                .size(10u64)
+                .build()?)
+        };
+        let images = vec![
+            image(b"newest", 1, false, false)?,
+            image(b"pinned", 100, true, false)?,
+            image(b"in use", 100, false, true)?,
+            image(b"oldest", 50, false, false)?,
+            image(b"older", 10, false, false)?,
+        ];
+        let sut = GcPolicy::default();
+        let ids = |selected: Vec<&GcImage>| {
+            selected
+                .into_iter()
+                .map(|i| i.id().clone())
+                .collect::<Vec<_>>()
+        };
+
+        // Below the high threshold
+        assert!(sut.select(&images, DiskUsage::new(84, 100)).is_empty());
+
+        // Free 5 bytes
+        assert_eq!(
+            ids(sut.select(&images, DiskUsage::new(85, 100))),
+            vec![images[3].id().clone()]
+        );
+
+        // Free 15 bytes
+        assert_eq!(
+            ids(sut.select(&images, DiskUsage::new(95, 100))),
+            vec![images[3].id().clone(), images[4].id().clone()]
+        );
+
+        // Free everything possible
+        let sut = GcPolicyBuilder::default()
+            .high_threshold_percent(50)
+            .low_threshold_percent(0)
+            .build()?;
+        assert_eq!(sut.select(&images, DiskUsage::new(100, 100)).len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn select_with_policy() -> Result<()> {
+        let dir = TempDir::new()?;
+        let images = vec![GcImageBuilder::default()
+            .id(Digest::from_bytes(Default::default(), b"image"))
+            .build()?];
+
+        let sut = new_collector(&dir)?;
+        assert!(sut.select(&images)?.is_empty());
+
+        let sut = GarbageCollectorBuilder::default()
+            .store(sut.store().clone())
+            .snapshotter(sut.snapshotter().clone())
+            .policy(
+                GcPolicyBuilder::default()
+                    .high_threshold_percent(0)
+                    .low_threshold_percent(0)
+                    .build()?,
+            )
+            .build()?;
+        assert_eq!(sut.select(&images)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn policy_invalid_thresholds() {
+        assert!(GcPolicyBuilder::default()
+            .high_threshold_percent(50)
+            .build()
+            .is_err());
+        assert!(GcPolicyBuilder::default()
+            .high_threshold_percent(101)
+            .low_threshold_percent(90)
+            .build()
+            .is_err());
+        assert!(GcPolicyBuilder::default()
+            .high_threshold_percent(90)
+            .low_threshold_percent(90)
+            .build()
+            .is_ok());
+    }
+}
@@ -0,0 +1,556 @@
+//! A persistent index of the locally available images and the names they are known by.
+//!
+//! Every image is stored by its ID, whereas all repository tags and digests point to that ID.
+//! A name always belongs to exactly one image, which means that tagging a new image removes the
+//! tag from the previous one.
+
+use crate::{
+    archive::ImportedImage,
+    digest::Digest,
+    gc::{GcImage, GcImageBuilder},
+    pull::PulledImage,
+    reference::{ImageIdentifier, Reference},
+    store::BlobStore,
+};
+use anyhow::{Context, Result};
+use derive_builder::Builder;
+use getset::{CopyGetters, Getters};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+use std::{
+    slice,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+use storage::{default_key_value_storage::DefaultKeyValueStorage, KeyValueStorage};
+
+/// Storage key prefix of the image records.
+const IMAGE_PREFIX: &str = "image/";
+
+/// Storage key prefix of the names pointing to image IDs.
+const NAME_PREFIX: &str = "image-name/";
+
+#[derive(Builder, Clone, CopyGetters, Debug, Deserialize, Eq, Getters, PartialEq, Serialize)]
+#[builder(pattern = "owned", setter(into))]
+/// The metadata of a locally available image.
+pub struct ImageRecord {
+    #[get = "pub"]
+    /// The image ID, which is the digest of the image configuration.
+    id: Digest,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The tagged references of the image.
+    repo_tags: Vec<Reference>,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The digested references of the image.
+    repo_digests: Vec<Reference>,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The digests of all manifests and indexes of the image available in the blob store.
+    manifests: Vec<Digest>,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// The size of the configuration and all layers in bytes.
+    size: u64,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// The numeric user ID of the image configuration.
+    uid: Option<i64>,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The user name of the image configuration, if it is not numeric.
+    username: String,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// Pinned images are never removed by the garbage collection.
+    pinned: bool,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The key of the snapshot containing the topmost layer.
+    top_layer: Option<String>,
+
+    #[get_copy = "pub"]
+    #[builder(default = "SystemTime::now()")]
+    /// The last time the image got pulled or used.
+    last_used: SystemTime,
+}
+
+impl ImageRecord {
+    /// Create a new record for the pulled image, whereas the user gets read from the image
+    /// configuration in the store.
+    pub async fn from_pulled(
+        store: &BlobStore,
+        pulled: &PulledImage,
+        top_layer: Option<String>,
+    ) -> Result<Self> {
+        Self::from_content(
+            store,
+            pulled.id(),
+            slice::from_ref(pulled.reference()),
+            pulled.digest(),
+            pulled.manifest_digest(),
+            *pulled.size(),
+            top_layer,
+        )
+        .await
+    }
+
+    /// Create a new record for the imported image, whereas the user gets read from the image
+    /// configuration in the store.
+    pub async fn from_imported(
+        store: &BlobStore,
+        imported: &ImportedImage,
+        top_layer: Option<String>,
+    ) -> Result<Self> {
+        Self::from_content(
+            store,
+            imported.id(),
+            imported.names(),
+            imported.digest(),
+            imported.manifest_digest(),
+            imported.size(),
+            top_layer,
+        )
+        .await
+    }
+
+    /// Create a new record for the image content available in the store under the provided
+    /// references. The `digest` is the one the references resolve to, whereas the
+    /// `manifest_digest` is the one of the platform specific manifest.
+    async fn from_content(
+        store: &BlobStore,
+        id: &Digest,
+        references: &[Reference],
+        digest: &Digest,
+        manifest_digest: &Digest,
+        size: u64,
+        top_layer: Option<String>,
+    ) -> Result<Self> {
+        let config: serde_json::Value =
+            serde_json::from_slice(&store.read(id).await?).context("parse image config")?;
+        let (uid, username) = parse_user(config["config"]["User"].as_str().unwrap_or_default());
+
+        let mut manifests = vec![digest.clone()];
+        if manifest_digest != digest {
+            manifests.push(manifest_digest.clone());
+        }
+        let mut repo_digests: Vec<Reference> = vec![];
+        for reference in references {
+            let repo_digest = reference.with_digest(digest.clone());
+            if !repo_digests.contains(&repo_digest) {
+                repo_digests.push(repo_digest);
+            }
+        }
+        Ok(Self {
+            id: id.clone(),
+            repo_tags: references
+                .iter()
+                .filter(|reference| reference.digest().is_none())
+                .cloned()
+                .collect(),
+            repo_digests,
+            manifests,
+            size,
+            uid,
+            username,
+            pinned: false,
+            top_layer,
+            last_used: SystemTime::now(),
+        })
+    }
+
+    /// Pin the record.
+    pub fn pin(mut self) -> Self {
+        self.pinned = true;
+        self
+    }
+
+    /// The root for the garbage collection of the image content.
+    pub fn gc_image(&self, in_use: bool) -> Result<GcImage> {
+        Ok(GcImageBuilder::default()
+            .id(self.id.clone())
+            .manifests(self.manifests.clone())
+            .pinned(self.pinned)
+            .in_use(in_use)
+            .last_used(self.last_used)
+            .size(self.size)
+            .build()?)
+    }
+
+    /// All names of the image.
+    fn names(&self) -> impl Iterator<Item = &Reference> {
+        self.repo_tags.iter().chain(self.repo_digests.iter())
+    }
+}
+
+#[derive(Clone, Debug)]
+/// The persistent index of all locally available images.
+pub struct ImageIndex {
+    /// The storage containing the records.
+    storage: Arc<Mutex<DefaultKeyValueStorage>>,
+}
+
+impl ImageIndex {
+    /// Create a new index persisted in the provided storage.
+    pub fn new(storage: DefaultKeyValueStorage) -> Self {
+        Self {
+            storage: Arc::new(Mutex::new(storage)),
+        }
+    }
+
+    /// Add the image to the index. If the image already exists, then the names and manifests get
+    /// merged and it gets pinned if either of the records is. Names which belong to other images
+    /// are moved to this one.
+    pub fn add(&self, record: ImageRecord) -> Result<ImageRecord> {
+        let mut storage = self.storage();
+        let mut merged = match Self::get_record(&storage, &record.id)? {
+            Some(mut existing) => {
+                for tag in record.repo_tags {
+                    if !existing.repo_tags.contains(&tag) {
+                        existing.repo_tags.push(tag);
+                    }
+                }
+                for digest in record.repo_digests {
+                    if !existing.repo_digests.contains(&digest) {
+                        existing.repo_digests.push(digest);
+                    }
+                }
+                for manifest in record.manifests {
+                    if !existing.manifests.contains(&manifest) {
+                        existing.manifests.push(manifest);
+                    }
+                }
+                existing.pinned |= record.pinned;
+                existing.top_layer = record.top_layer.or(existing.top_layer);
+                existing.last_used = record.last_used;
+                existing
+            }
+            None => record,
+        };
+
+        for name in merged.names() {
+            let previous = storage.get::<_, Digest>(Self::name_key(name))?;
+            match previous {
+                Some(previous) if previous != merged.id => {
+                    if let Some(mut other) = Self::get_record(&storage, &previous)? {
+                        debug!("Moving {} from image {} to {}", name, previous, merged.id);
+                        other.repo_tags.retain(|n| n != name);
+                        other.repo_digests.retain(|n| n != name);
+                        storage.insert(Self::image_key(&previous), &other)?;
+                    }
+                }
+                _ => {}
+            }
+            storage.insert(Self::name_key(name), &merged.id)?;
+        }
+        merged.repo_tags.sort_by_key(ToString::to_string);
+        merged.repo_digests.sort_by_key(ToString::to_string);
+        storage.insert(Self::image_key(&merged.id), &merged)?;
+
+        trace!("Added image {} to the index", merged.id);
+        Ok(merged)
+    }
+
+    /// Get the image by its ID, tag or digest.
+    pub fn get(&self, identifier: &ImageIdentifier) -> Result<Option<ImageRecord>> {
+        let storage = self.storage();
+        let id = match identifier {
+            ImageIdentifier::Id(id) => id.clone(),
+            ImageIdentifier::Reference(reference) => {
+                match storage.get(Self::name_key(&Self::normalize(reference)))? {
+                    Some(id) => id,
+                    None => return Ok(None),
+                }
+            }
+        };
+        Self::get_record(&storage, &id)
+    }
+
+    /// List all images ordered by their ID.
+    pub fn list(&self) -> Result<Vec<ImageRecord>> {
+        Ok(self
+            .storage()
+            .scan_prefix(IMAGE_PREFIX)?
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect())
+    }
+
+    /// Remove the image including all of its names and return its record. Removing an image
+    /// which does not exist is not an error.
+    pub fn remove(&self, identifier: &ImageIdentifier) -> Result<Option<ImageRecord>> {
+        let record = match self.get(identifier)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let mut storage = self.storage();
+        for name in record.names() {
+            if storage.get::<_, Digest>(Self::name_key(name))?.as_ref() == Some(&record.id) {
+                storage.remove(Self::name_key(name))?;
+            }
+        }
+        if storage
+            .get::<_, ImageRecord>(Self::image_key(&record.id))?
+            .is_some()
+        {
+            storage.remove(Self::image_key(&record.id))?;
+        }
+        debug!("Removed image {} from the index", record.id);
+        Ok(Some(record))
+    }
+
+    /// Update the last usage time of the image to now.
+    pub fn touch(&self, id: &Digest) -> Result<()> {
+        let mut storage = self.storage();
+        if let Some(mut record) = Self::get_record(&storage, id)? {
+            record.last_used = SystemTime::now();
+            storage.insert(Self::image_key(id), &record)?;
+        }
+        Ok(())
+    }
+
+    /// Lock the storage.
+    fn storage(&self) -> MutexGuard<'_, DefaultKeyValueStorage> {
+        self.storage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get the record from the locked storage.
+    fn get_record(storage: &DefaultKeyValueStorage, id: &Digest) -> Result<Option<ImageRecord>> {
+        storage.get(Self::image_key(id))
+    }
+
+    /// The storage key of an image record.
+    fn image_key(id: &Digest) -> String {
+        format!("{}{}", IMAGE_PREFIX, id)
+    }
+
+    /// The storage key of an image name.
+    fn name_key(name: &Reference) -> String {
+        format!("{}{}", NAME_PREFIX, name)
+    }
+
+    /// Digested references are looked up by their digest only.
+    fn normalize(reference: &Reference) -> Reference {
+        match reference.digest() {
+            Some(digest) => reference.with_digest(digest.clone()),
+            None => reference.clone(),
+        }
+    }
+}
+
+/// Split the `User` of an image configuration into the numeric user ID or the user name, which
+/// are mutually exclusive. The group is ignored.
+fn parse_user(user: &str) -> (Option<i64>, String) {
+    let user = user.split(':').next().unwrap_or_default();
+    match user.parse::<i64>() {
+        Ok(uid) => (Some(uid), String::new()),
+        Err(_) => (None, user.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pull::PullerBuilder, registry::Client, testing::TestRegistry};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    fn digest(data: &str) -> Digest {
+        Digest::from_bytes(Default::default(), data.as_bytes())
+    }
+
+    fn record(id: &str, tags: &[&str]) -> Result<ImageRecord> {
+        Ok(ImageRecordBuilder::default()
+            .id(digest(id))
+            .repo_tags(
+                tags.iter()
+                    .map(|t| t.parse())
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+            .repo_digests(vec![format!("app@{}", digest(id)).parse::<Reference>()?])
+            .manifests(vec![digest(id)])
+            .size(10u64)
+            .build()?)
+    }
+
+    fn new_index(dir: &TempDir) -> Result<ImageIndex> {
+        Ok(ImageIndex::new(DefaultKeyValueStorage::open(dir.path())?))
+    }
+
+    #[test]
+    fn add_get_list() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_index(&dir)?;
+        let added = sut.add(record("a", &["app:v1", "app:latest"])?)?;
+        sut.add(record("b", &["other"])?)?;
+
+        for identifier in &[
+            added.id().to_string(),
+            added.id().encoded().to_string(),
+            "app:v1".into(),
+            "docker.io/library/app:latest".into(),
+            format!("app@{}", added.id()),
+            format!("app:v1@{}", added.id()),
+        ] {
+            let found = sut.get(&identifier.parse()?)?;
+            assert_eq!(found.as_ref(), Some(&added), "{}", identifier);
+        }
+        assert!(sut.get(&"app:v2".parse()?)?.is_none());
+        assert!(sut.get(&digest("c").to_string().parse()?)?.is_none());
+
+        let list = sut.list()?;
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            added.repo_tags(),
+            &[
+                "app:latest".parse::<Reference>()?,
+                "app:v1".parse::<Reference>()?
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn add_merge() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_index(&dir)?;
+        sut.add(record("a", &["app:v1"])?)?;
+        let merged = sut.add(record("a", &["app:v2"])?.pin())?;
+        assert_eq!(merged.repo_tags().len(), 2);
+        assert_eq!(merged.repo_digests().len(), 1);
+        assert_eq!(merged.manifests().len(), 1);
+        assert!(merged.pinned());
+
+        // Pinning is kept
+        assert!(sut.add(record("a", &[])?)?.pinned());
+        assert_eq!(sut.list()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn add_moves_tag() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_index(&dir)?;
+        sut.add(record("a", &["app:latest", "app:v1"])?)?;
+        sut.add(record("b", &["app:latest"])?)?;
+
+        let a = sut
+            .get(&digest("a").to_string().parse()?)?
+            .context("no image")?;
+        assert_eq!(a.repo_tags(), &["app:v1".parse::<Reference>()?]);
+        let latest = sut.get(&"app:latest".parse()?)?.context("no image")?;
+        assert_eq!(latest.id(), &digest("b"));
+        Ok(())
+    }
+
+    #[test]
+    fn remove() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_index(&dir)?;
+        sut.add(record("a", &["app:v1", "app:v2"])?)?;
+        sut.add(record("b", &["other"])?)?;
+
+        let removed = sut.remove(&"app:v1".parse()?)?.context("not removed")?;
+        assert_eq!(removed.id(), &digest("a"));
+        assert!(sut.get(&"app:v2".parse()?)?.is_none());
+        assert!(sut.get(&format!("app@{}", digest("a")).parse()?)?.is_none());
+        assert_eq!(sut.list()?.len(), 1);
+
+        // Not existing
+        assert!(sut.remove(&"app:v1".parse()?)?.is_none());
+        assert!(sut.remove(&digest("a").to_string().parse()?)?.is_none());
+
+        sut.remove(&format!("app@{}", digest("b")).parse()?)?;
+        assert!(sut.list()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn touch() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = new_index(&dir)?;
+        let added = sut.add(record("a", &[])?)?;
+        sut.touch(added.id())?;
+        sut.touch(&digest("missing"))?;
+        let touched = sut
+            .get(&added.id().to_string().parse()?)?
+            .context("no image")?;
+        assert!(touched.last_used() >= added.last_used());
+        Ok(())
+    }
+
+    #[test]
+    fn persisted() -> Result<()> {
+        let dir = TempDir::new()?;
+        new_index(&dir)?.add(record("a", &["app"])?)?;
+        assert!(new_index(&dir)?.get(&"app".parse()?)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn gc_image() -> Result<()> {
+        let image = record("a", &[])?.pin().gc_image(true)?;
+        assert_eq!(image.id(), &digest("a"));
+        assert_eq!(image.manifests(), &[digest("a")]);
+        assert!(image.pinned());
+        assert!(image.in_use());
+        assert_eq!(image.size(), 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_pulled() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image_with_config(
+            "app",
+            Some("v1"),
+            json!({"User": "nobody:nogroup"}),
+            &[b"layer"],
+        )?;
+        let dir = TempDir::new()?;
+        let puller = PullerBuilder::default()
+            .client(Client::default())
+            .store(BlobStore::open(dir.path())?)
+            .build()?;
+        let reference: Reference = format!("{}/app:v1", registry.host()).parse()?;
+        let pulled = puller.pull(&reference, None).await?;
+
+        let record = ImageRecord::from_pulled(puller.store(), &pulled, Some("top".into())).await?;
+        assert_eq!(record.id(), image.config_digest());
+        assert_eq!(record.repo_tags().len(), 1);
+        assert_eq!(record.repo_tags()[0], reference);
+        assert_eq!(
+            record.repo_digests(),
+            &[reference.with_digest(image.manifest_digest().clone())]
+        );
+        assert_eq!(record.manifests(), &[image.manifest_digest().clone()]);
+        assert_eq!(record.size(), *pulled.size());
+        assert!(record.uid().is_none());
+        assert_eq!(record.username(), "nobody");
+        assert!(!record.pinned());
+        assert_eq!(record.top_layer().as_deref(), Some("top"));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_users() {
+        for (input, uid, username) in &[
+            ("", None, ""),
+            ("1000", Some(1000), ""),
+            ("1000:1000", Some(1000), ""),
+            ("nobody", None, "nobody"),
+            ("nobody:nogroup", None, "nobody"),
+        ] {
+            assert_eq!(parse_user(input), (*uid, username.to_string()), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,870 @@
+//! Applying image layers to a directory, including the handling of [OCI whiteouts][0].
+//!
+//! [0]: https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+
+use crate::{
+    digest::{Digest, Digester},
+    encryption::{self, Decryptor},
+    store::BlobStore,
+};
+use anyhow::{bail, Context, Result};
+use derive_builder::Builder;
+use flate2::read::GzDecoder;
+use getset::{CopyGetters, Getters};
+use log::{debug, trace, warn};
+use nix::{
+    sys::stat::{self, Mode, SFlag},
+    unistd,
+};
+use oci_spec::image::{Descriptor, ImageManifest};
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    ffi::OsString,
+    fs::{self, File, Permissions},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom},
+    os::unix::fs::{lchown, PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+use strum::{AsRefStr, Display};
+use tar::{Archive, Entry, EntryType};
+
+/// File name prefix of whiteouts, which mark the removal of a path of a lower layer.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// File name marking a directory as opaque, which hides all contents of lower layers.
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+/// PAX extension prefix of extended attributes.
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Prefix of extended attributes which can be set without privileges.
+const XATTR_USER_PREFIX: &str = "user.";
+
+/// Extended attribute marking an overlay directory as opaque.
+const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+/// Maximum number of symlinks followed while resolving a single path.
+const MAX_SYMLINKS: usize = 255;
+
+#[derive(AsRefStr, Clone, Copy, Debug, Display, Eq, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+/// The compression of a layer tar archive.
+pub enum Compression {
+    /// Uncompressed tar archive.
+    None,
+
+    /// Gzip compressed tar archive.
+    Gzip,
+
+    /// Zstandard compressed tar archive.
+    Zstd,
+}
+
+impl Compression {
+    /// Detect the compression from the magic bytes at the start of the layer.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+/// Defines how whiteouts of a layer are applied.
+pub enum WhiteoutFormat {
+    #[default]
+    /// Remove the whiteout paths, which flattens the layer onto the directory.
+    Remove,
+
+    /// Convert whiteouts into overlayfs whiteout devices and opaque directory attributes, which
+    /// is used to apply a layer into an empty upper directory.
+    Overlay,
+}
+
+#[derive(Builder, Clone, CopyGetters, Debug, Getters)]
+#[builder(default, pattern = "owned", setter(into))]
+/// Applies layer tar archives on top of a directory.
+pub struct LayerApplier {
+    #[get_copy = "pub"]
+    /// Apply layers without privileges, which skips ownership changes, device nodes and extended
+    /// attributes outside of the `user` namespace. Defaults to true if not running as root.
+    rootless: bool,
+
+    #[get_copy = "pub"]
+    /// How whiteouts get applied.
+    whiteouts: WhiteoutFormat,
+
+    #[get = "pub"]
+    /// Decrypts layers with an encrypted media type before they get applied.
+    decryptor: Decryptor,
+}
+
+impl Default for LayerApplier {
+    fn default() -> Self {
+        Self {
+            rootless: !unistd::geteuid().is_root(),
+            whiteouts: WhiteoutFormat::default(),
+            decryptor: Decryptor::default(),
+        }
+    }
+}
+
+/// Reader which digests all data passing through it.
+struct DigestReader<R> {
+    inner: R,
+    digester: Digester,
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digester.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl LayerApplier {
+    /// Apply the layer to the destination directory and return the diff ID of the layer, which is
+    /// the digest of the uncompressed tar archive. Entries can never be written outside of the
+    /// destination, whereas symlinks are resolved as if the destination would be the root.
+    pub fn apply<R: Read, P: AsRef<Path>>(&self, layer: R, dest: P) -> Result<Digest> {
+        let root = dest
+            .as_ref()
+            .canonicalize()
+            .with_context(|| format!("resolve layer destination {}", dest.as_ref().display()))?;
+
+        let mut reader = BufReader::new(layer);
+        let compression = Compression::detect(reader.fill_buf().context("read layer")?);
+        trace!("Applying {} layer to {}", compression, root.display());
+        let decoder: Box<dyn Read> = match compression {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(GzDecoder::new(reader)),
+            Compression::Zstd => {
+                Box::new(zstd::Decoder::with_buffer(reader).context("create zstd decoder")?)
+            }
+        };
+        let mut reader = DigestReader {
+            inner: decoder,
+            digester: Digester::new(Default::default()),
+        };
+
+        let mut archive = Archive::new(&mut reader);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_preserve_ownerships(!self.rootless);
+        archive.set_unpack_xattrs(false);
+
+        let mut written = HashSet::new();
+        for entry in archive.entries().context("read layer entries")? {
+            let mut entry = entry.context("read layer entry")?;
+            self.apply_entry(&mut entry, &root, &mut written)
+                .with_context(|| {
+                    format!(
+                        "apply layer entry {}",
+                        String::from_utf8_lossy(&entry.path_bytes())
+                    )
+                })?;
+        }
+
+        // The diff ID covers the trailing blocks of the archive as well
+        io::copy(&mut reader, &mut io::sink()).context("read layer trailer")?;
+        Ok(reader.digester.finalize())
+    }
+
+    /// Apply the layer blob of the descriptor from the store to the destination directory and
+    /// return its diff ID. Encrypted layers are decrypted into a temporary file first, which
+    /// ensures that they are authenticated before any entry gets written.
+    pub async fn apply_blob<P: AsRef<Path>>(
+        &self,
+        store: &BlobStore,
+        layer: &Descriptor,
+        dest: P,
+    ) -> Result<Digest> {
+        let applier = self.clone();
+        let digest = layer.digest().parse::<Digest>()?;
+        let path = store.path(&digest);
+        let tmp_dir = store.root().clone();
+        let dest = dest.as_ref().to_path_buf();
+        let layer = layer.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = File::open(&path).with_context(|| format!("open layer {}", digest))?;
+            if encryption::is_encrypted(&layer.media_type().to_string()) {
+                let mut decrypted = tempfile::tempfile_in(&tmp_dir)
+                    .context("create temporary file for decrypted layer")?;
+                applier
+                    .decryptor
+                    .decrypt(&layer, &file, &decrypted)
+                    .with_context(|| format!("decrypt layer {}", digest))?;
+                decrypted.seek(SeekFrom::Start(0))?;
+                file = decrypted;
+            }
+            let diff_id = applier
+                .apply(file, &dest)
+                .with_context(|| format!("apply layer {}", digest))?;
+            debug!("Applied layer {} to {}", digest, dest.display());
+            Ok(diff_id)
+        })
+        .await
+        .context("join layer applier")?
+    }
+
+    /// Apply all layers of the image manifest in order and return their diff IDs.
+    pub async fn apply_manifest<P: AsRef<Path>>(
+        &self,
+        store: &BlobStore,
+        manifest: &ImageManifest,
+        dest: P,
+    ) -> Result<Vec<Digest>> {
+        let mut diff_ids = vec![];
+        for layer in manifest.layers() {
+            diff_ids.push(self.apply_blob(store, layer, dest.as_ref()).await?);
+        }
+        Ok(diff_ids)
+    }
+
+    /// Apply a single archive entry. All paths written by the current layer are tracked in
+    /// `written` to exclude them from opaque directories.
+    fn apply_entry<R: Read>(
+        &self,
+        entry: &mut Entry<R>,
+        root: &Path,
+        written: &mut HashSet<PathBuf>,
+    ) -> Result<()> {
+        let kind = entry.header().entry_type();
+        if kind.is_pax_global_extensions() {
+            return Ok(());
+        }
+
+        let path = normalize(&entry.path()?)?;
+        let (parent, file_name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(file_name)) => (parent.to_path_buf(), file_name.to_os_string()),
+            // The root directory itself is never modified by layers
+            _ => return Ok(()),
+        };
+
+        if let Some(whiteout) = file_name
+            .to_str()
+            .and_then(|n| n.strip_prefix(WHITEOUT_PREFIX))
+        {
+            return self.apply_whiteout(root, &parent, whiteout, written);
+        }
+
+        let parent_dir =
+            resolve_dir(root, &parent, true)?.context("parent directory does not exist")?;
+        let target = parent_dir.join(&file_name);
+        match fs::symlink_metadata(&target) {
+            Ok(m) if m.is_dir() && kind.is_dir() => {}
+            Ok(m) if m.is_dir() => fs::remove_dir_all(&target)?,
+            Ok(_) => fs::remove_file(&target)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if kind.is_hard_link() {
+            let link_name = normalize(&entry.link_name()?.context("hard link without target")?)?;
+            let source = match (link_name.parent(), link_name.file_name()) {
+                (Some(parent), Some(file_name)) => resolve_dir(root, parent, false)?
+                    .context("hard link target does not exist")?
+                    .join(file_name),
+                _ => bail!("invalid hard link target {}", link_name.display()),
+            };
+            fs::hard_link(&source, &target)
+                .with_context(|| format!("link {}", link_name.display()))?;
+        } else if kind.is_character_special() || kind.is_block_special() || kind.is_fifo() {
+            if !self.create_special(entry, kind, &target)? {
+                return Ok(());
+            }
+        } else {
+            entry.unpack(&target)?;
+        }
+
+        if !kind.is_symlink() && !kind.is_hard_link() {
+            self.set_xattrs(entry, &target)?;
+        }
+        written.extend(path.ancestors().map(Path::to_path_buf));
+        Ok(())
+    }
+
+    /// Remove the path of the whiteout or all contents of the directory if it is opaque. Paths
+    /// which have been written by the current layer are kept. For overlay whiteouts the removal
+    /// is recorded instead.
+    fn apply_whiteout(
+        &self,
+        root: &Path,
+        parent: &Path,
+        whiteout: &str,
+        written: &HashSet<PathBuf>,
+    ) -> Result<()> {
+        if whiteout.is_empty() || whiteout == "." || whiteout == ".." || whiteout.contains('/') {
+            bail!("invalid whiteout {}", parent.join(whiteout).display())
+        }
+        let opaque = format!("{}{}", WHITEOUT_PREFIX, whiteout) == WHITEOUT_OPAQUE;
+        if !opaque && whiteout.starts_with(WHITEOUT_PREFIX) {
+            trace!("Skipping whiteout metadata {}", whiteout);
+            return Ok(());
+        }
+
+        let create = self.whiteouts == WhiteoutFormat::Overlay;
+        let dir = match resolve_dir(root, parent, create)? {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        match (self.whiteouts, opaque) {
+            (WhiteoutFormat::Remove, true) => {
+                trace!("Clearing opaque directory {}", parent.display());
+                for child in fs::read_dir(&dir)? {
+                    let child = child?;
+                    if !written.contains(&parent.join(child.file_name())) {
+                        remove_all(&child.path())?;
+                    }
+                }
+            }
+            (WhiteoutFormat::Remove, false) => {
+                if !written.contains(&parent.join(whiteout)) {
+                    trace!("Removing whiteout {}", parent.join(whiteout).display());
+                    remove_all(&dir.join(whiteout))?;
+                }
+            }
+            (WhiteoutFormat::Overlay, true) => {
+                xattr::set(&dir, OVERLAY_OPAQUE_XATTR, b"y")
+                    .with_context(|| format!("mark {} as opaque", parent.display()))?;
+            }
+            (WhiteoutFormat::Overlay, false) => {
+                let target = dir.join(whiteout);
+                remove_all(&target)?;
+                stat::mknod(&target, SFlag::S_IFCHR, Mode::empty(), stat::makedev(0, 0))
+                    .with_context(|| format!("create whiteout {}", target.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a device node or FIFO, which returns `false` if the entry got skipped.
+    fn create_special<R: Read>(
+        &self,
+        entry: &Entry<R>,
+        kind: EntryType,
+        target: &Path,
+    ) -> Result<bool> {
+        let header = entry.header();
+        let flag = if kind.is_fifo() {
+            SFlag::S_IFIFO
+        } else if self.rootless {
+            warn!(
+                "Skipping device node {} in rootless mode",
+                String::from_utf8_lossy(&entry.path_bytes())
+            );
+            return Ok(false);
+        } else if kind.is_block_special() {
+            SFlag::S_IFBLK
+        } else {
+            SFlag::S_IFCHR
+        };
+
+        let mode = header.mode()?;
+        let dev = stat::makedev(
+            header.device_major()?.unwrap_or_default().into(),
+            header.device_minor()?.unwrap_or_default().into(),
+        );
+        stat::mknod(target, flag, Mode::from_bits_truncate(mode), dev)?;
+
+        if !self.rootless {
+            lchown(
+                target,
+                Some(u32::try_from(header.uid()?)?),
+                Some(u32::try_from(header.gid()?)?),
+            )?;
+        }
+        fs::set_permissions(target, Permissions::from_mode(mode))?;
+        Ok(true)
+    }
+
+    /// Set the extended attributes from the PAX extensions of the entry.
+    fn set_xattrs<R: Read>(&self, entry: &mut Entry<R>, target: &Path) -> Result<()> {
+        let extensions = match entry.pax_extensions()? {
+            Some(extensions) => extensions,
+            None => return Ok(()),
+        };
+        for extension in extensions {
+            let extension = extension?;
+            let key = match extension.key()?.strip_prefix(PAX_XATTR_PREFIX) {
+                Some(key) => key.to_string(),
+                None => continue,
+            };
+            if self.rootless && !key.starts_with(XATTR_USER_PREFIX) {
+                trace!("Skipping xattr {} in rootless mode", key);
+                continue;
+            }
+            xattr::set(target, &key, extension.value_bytes())
+                .with_context(|| format!("set xattr {}", key))?;
+        }
+        Ok(())
+    }
+}
+
+/// Turn the archive path into a relative one and reject any path traversal.
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => bail!("path {} escapes the layer root", path.display()),
+        }
+    }
+    Ok(normalized)
+}
+
+/// Resolve the relative directory path within the root, whereas symlinks are followed as if the
+/// root would be the file system root. Missing directories are created if `create` is set,
+/// otherwise `None` is returned if the path does not resolve to a directory.
+fn resolve_dir(root: &Path, path: &Path, create: bool) -> Result<Option<PathBuf>> {
+    let mut resolved = PathBuf::new();
+    let mut pending = components(path).collect::<VecDeque<_>>();
+    let mut symlinks = 0;
+
+    while let Some(component) = pending.pop_front() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&component);
+        let full = root.join(&candidate);
+        match fs::symlink_metadata(&full) {
+            Ok(m) if m.file_type().is_symlink() => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    bail!("too many levels of symlinks in {}", path.display())
+                }
+                let target = fs::read_link(&full)?;
+                if target.is_absolute() {
+                    resolved.clear();
+                }
+                for component in components(&target).collect::<Vec<_>>().into_iter().rev() {
+                    pending.push_front(component);
+                }
+            }
+            Ok(m) if m.is_dir() => resolved = candidate,
+            Ok(_) if create => {
+                // Files of lower layers are replaced by implicitly created directories
+                fs::remove_file(&full)?;
+                fs::create_dir(&full)?;
+                resolved = candidate;
+            }
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound && create => {
+                fs::create_dir(&full)?;
+                resolved = candidate;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(root.join(resolved)))
+}
+
+/// The normal and parent components of the path.
+fn components(path: &Path) -> impl Iterator<Item = OsString> + '_ {
+    path.components().filter_map(|c| match c {
+        Component::Normal(_) | Component::ParentDir => Some(c.as_os_str().to_os_string()),
+        _ => None,
+    })
+}
+
+/// Remove the path regardless of its type, which succeeds if it does not exist.
+fn remove_all(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        os::unix::fs::{FileTypeExt, MetadataExt},
+    };
+    use tar::{Builder, Header};
+    use tempfile::TempDir;
+
+    /// A single entry of a test layer.
+    enum TestEntry<'a> {
+        Dir(&'a str),
+        File(&'a str, &'a [u8]),
+        Symlink(&'a str, &'a str),
+        Hardlink(&'a str, &'a str),
+        Xattr(&'a str, &'a str, &'a [u8]),
+        Device(&'a str, EntryType, u32, u32),
+        Owned(&'a str, u64, u64),
+        Raw(&'a str),
+    }
+
+    fn layer(entries: &[TestEntry]) -> Result<Vec<u8>> {
+        let mut builder = Builder::new(vec![]);
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(0);
+            header.set_mtime(1);
+            header.set_uid(0);
+            header.set_gid(0);
+            match entry {
+                TestEntry::Dir(path) => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_mode(0o755);
+                    builder.append_data(&mut header, path, io::empty())?;
+                }
+                TestEntry::File(path, content) => {
+                    header.set_size(content.len() as u64);
+                    builder.append_data(&mut header, path, *content)?;
+                }
+                TestEntry::Symlink(path, target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    builder.append_link(&mut header, path, target)?;
+                }
+                TestEntry::Hardlink(path, target) => {
+                    header.set_entry_type(EntryType::Link);
+                    builder.append_link(&mut header, path, target)?;
+                }
+                TestEntry::Xattr(path, key, value) => {
+                    let key = format!("{}{}", PAX_XATTR_PREFIX, key);
+                    builder.append_pax_extensions(vec![(key.as_str(), *value)])?;
+                    builder.append_data(&mut header, path, io::empty())?;
+                }
+                TestEntry::Device(path, kind, major, minor) => {
+                    header.set_entry_type(*kind);
+                    header.set_device_major(*major)?;
+                    header.set_device_minor(*minor)?;
+                    builder.append_data(&mut header, path, io::empty())?;
+                }
+                TestEntry::Owned(path, uid, gid) => {
+                    header.set_uid(*uid);
+                    header.set_gid(*gid);
+                    builder.append_data(&mut header, path, io::empty())?;
+                }
+                TestEntry::Raw(path) => {
+                    // Bypass the path validation of the builder
+                    header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+                    header.set_cksum();
+                    builder.append(&header, io::empty())?;
+                }
+            }
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    fn apply(dir: &TempDir, entries: &[TestEntry]) -> Result<Digest> {
+        LayerApplier::default().apply(&layer(entries)?[..], dir.path())
+    }
+
+    #[test]
+    fn detect_compression() {
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 0x08]), Compression::Gzip);
+        assert_eq!(
+            Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::detect(b"file.txt"), Compression::None);
+        assert_eq!(Compression::detect(&[]), Compression::None);
+    }
+
+    #[test]
+    fn apply_compressed() -> Result<()> {
+        let tar = layer(&[
+            TestEntry::Dir("etc"),
+            TestEntry::File("etc/hostname", b"host"),
+        ])?;
+        let diff_id = Digest::from_bytes(Default::default(), &tar);
+
+        let mut gzip = flate2::write::GzEncoder::new(vec![], Default::default());
+        gzip.write_all(&tar)?;
+        let zstd = zstd::encode_all(&tar[..], 0)?;
+
+        for content in &[tar.clone(), gzip.finish()?, zstd] {
+            let dir = TempDir::new()?;
+            assert_eq!(
+                LayerApplier::default().apply(&content[..], dir.path())?,
+                diff_id
+            );
+            assert_eq!(fs::read(dir.path().join("etc/hostname"))?, b"host");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_manifest() -> Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = BlobStore::open(store_dir.path())?;
+        let lower = layer(&[TestEntry::File("a", b"lower"), TestEntry::File("b", b"")])?;
+        let upper = layer(&[
+            TestEntry::File("a", b"upper"),
+            TestEntry::File(".wh.b", b""),
+        ])?;
+
+        let mut layers = vec![];
+        for content in &[&lower, &upper] {
+            let digest = store.write(content, None).await?;
+            layers.push(serde_json::json!({
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "digest": digest.to_string(),
+                "size": content.len(),
+            }));
+        }
+        let manifest: ImageManifest = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": Digest::from_bytes(Default::default(), b"{}").to_string(),
+                "size": 2,
+            },
+            "layers": layers,
+        }))?;
+
+        let dir = TempDir::new()?;
+        let diff_ids = LayerApplier::default()
+            .apply_manifest(&store, &manifest, dir.path())
+            .await?;
+        assert_eq!(
+            diff_ids,
+            vec![
+                Digest::from_bytes(Default::default(), &lower),
+                Digest::from_bytes(Default::default(), &upper)
+            ]
+        );
+        assert_eq!(fs::read(dir.path().join("a"))?, b"upper");
+        assert!(!dir.path().join("b").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_whiteouts() -> Result<()> {
+        let dir = TempDir::new()?;
+        apply(
+            &dir,
+            &[
+                TestEntry::File("a/x", b""),
+                TestEntry::File("a/y", b""),
+                TestEntry::File("b", b""),
+                TestEntry::File("c/z", b""),
+            ],
+        )?;
+        apply(
+            &dir,
+            &[
+                TestEntry::File(".wh.b", b""),
+                TestEntry::File("a/.wh.x", b""),
+                TestEntry::File(".wh.c", b""),
+                TestEntry::File("missing/.wh.file", b""),
+                TestEntry::File(".wh..wh.plnk", b""),
+            ],
+        )?;
+
+        assert!(!dir.path().join("a/x").exists());
+        assert!(dir.path().join("a/y").exists());
+        assert!(!dir.path().join("b").exists());
+        assert!(!dir.path().join("c").exists());
+        assert!(!dir.path().join("missing").exists());
+        assert!(!dir.path().join(".wh..wh.plnk").exists());
+
+        // Whiteouts of the directory itself or its parent must not escape the root
+        for whiteout in ["a/.wh..", "a/.wh...", "a/.wh."] {
+            assert!(
+                apply(&dir, &[TestEntry::File(whiteout, b"")]).is_err(),
+                "{}",
+                whiteout
+            );
+            assert!(dir.path().join("a/y").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn apply_overlay_whiteouts() -> Result<()> {
+        if !unistd::geteuid().is_root() {
+            return Ok(());
+        }
+        let dir = TempDir::new()?;
+        LayerApplierBuilder::default()
+            .whiteouts(WhiteoutFormat::Overlay)
+            .build()?
+            .apply(
+                &layer(&[
+                    TestEntry::File("a/.wh..wh..opq", b""),
+                    TestEntry::File("a/file", b""),
+                    TestEntry::File("b/.wh.removed", b""),
+                    TestEntry::File(".wh..wh.plnk", b""),
+                ])?[..],
+                dir.path(),
+            )?;
+
+        assert_eq!(
+            xattr::get(dir.path().join("a"), OVERLAY_OPAQUE_XATTR)?,
+            Some(b"y".to_vec())
+        );
+        assert!(dir.path().join("a/file").exists());
+        let whiteout = fs::symlink_metadata(dir.path().join("b/removed"))?;
+        assert!(whiteout.file_type().is_char_device());
+        assert_eq!(whiteout.rdev(), 0);
+        assert!(!dir.path().join(".wh..wh.plnk").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_opaque() -> Result<()> {
+        let dir = TempDir::new()?;
+        apply(
+            &dir,
+            &[TestEntry::File("a/x", b""), TestEntry::File("a/y/z", b"")],
+        )?;
+        apply(
+            &dir,
+            &[
+                TestEntry::File("a/new/file", b""),
+                TestEntry::File("a/.wh..wh..opq", b""),
+            ],
+        )?;
+
+        let mut children = fs::read_dir(dir.path().join("a"))?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        children.sort();
+        assert_eq!(children, vec!["new"]);
+        assert!(dir.path().join("a/new/file").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_replace() -> Result<()> {
+        let dir = TempDir::new()?;
+        apply(
+            &dir,
+            &[
+                TestEntry::File("a/x", b""),
+                TestEntry::File("b", b"old"),
+                TestEntry::File("c", b"old"),
+                TestEntry::Hardlink("d", "c"),
+            ],
+        )?;
+        apply(
+            &dir,
+            &[
+                TestEntry::File("a", b"file"),
+                TestEntry::File("b/x", b""),
+                TestEntry::File("c", b"new"),
+            ],
+        )?;
+
+        assert_eq!(fs::read(dir.path().join("a"))?, b"file");
+        assert!(dir.path().join("b/x").exists());
+        assert_eq!(fs::read(dir.path().join("c"))?, b"new");
+        assert_eq!(fs::read(dir.path().join("d"))?, b"old");
+        Ok(())
+    }
+
+    #[test]
+    fn apply_links() -> Result<()> {
+        let dir = TempDir::new()?;
+        apply(
+            &dir,
+            &[
+                TestEntry::File("usr/bin/tool", b"tool"),
+                TestEntry::Hardlink("usr/bin/alias", "/usr/bin/tool"),
+                TestEntry::Symlink("bin", "usr/bin"),
+                TestEntry::Symlink("abs", "/usr"),
+                TestEntry::File("bin/other", b"other"),
+                TestEntry::File("abs/share/file", b"share"),
+            ],
+        )?;
+
+        let tool = fs::metadata(dir.path().join("usr/bin/tool"))?;
+        let alias = fs::metadata(dir.path().join("usr/bin/alias"))?;
+        assert_eq!(tool.ino(), alias.ino());
+        assert_eq!(fs::read_link(dir.path().join("bin"))?, Path::new("usr/bin"));
+        assert_eq!(fs::read(dir.path().join("usr/bin/other"))?, b"other");
+        assert_eq!(fs::read(dir.path().join("usr/share/file"))?, b"share");
+        Ok(())
+    }
+
+    #[test]
+    fn apply_path_traversal() -> Result<()> {
+        let outside = TempDir::new()?;
+        let dir = TempDir::new()?;
+        let escape = outside.path().display().to_string();
+
+        assert!(apply(&dir, &[TestEntry::Raw("../escape")]).is_err());
+        assert!(apply(&dir, &[TestEntry::Hardlink("link", "../etc/passwd")]).is_err());
+
+        // Symlinks are resolved within the root
+        apply(
+            &dir,
+            &[
+                TestEntry::Symlink("abs", &escape),
+                TestEntry::Symlink("rel", "../../../../.."),
+                TestEntry::File("abs/file", b""),
+                TestEntry::File("rel/file", b""),
+            ],
+        )?;
+        assert_eq!(fs::read_dir(outside.path())?.count(), 0);
+        assert!(dir.path().join(&escape[1..]).join("file").exists());
+        assert!(dir.path().join("file").exists());
+
+        fs::write(outside.path().join("keep"), b"")?;
+        apply(&dir, &[TestEntry::File("abs/.wh.keep", b"")])?;
+        apply(&dir, &[TestEntry::File("rel/.wh..wh..opq", b"")])?;
+        assert!(outside.path().join("keep").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_xattrs() -> Result<()> {
+        let dir = TempDir::new()?;
+        apply(&dir, &[TestEntry::Xattr("file", "user.test", b"value")])?;
+        assert_eq!(
+            xattr::get(dir.path().join("file"), "user.test")?,
+            Some(b"value".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn apply_special_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let entries = [
+            TestEntry::Device("dev/null", EntryType::Char, 1, 3),
+            TestEntry::Device("fifo", EntryType::Fifo, 0, 0),
+            TestEntry::Owned("owned", 1000, 2000),
+        ];
+
+        LayerApplierBuilder::default()
+            .rootless(true)
+            .build()?
+            .apply(&layer(&entries)?[..], dir.path())?;
+        assert!(!dir.path().join("dev/null").exists());
+        assert!(fs::metadata(dir.path().join("fifo"))?.file_type().is_fifo());
+
+        if !unistd::geteuid().is_root() {
+            return Ok(());
+        }
+        LayerApplierBuilder::default()
+            .rootless(false)
+            .build()?
+            .apply(&layer(&entries)?[..], dir.path())?;
+        let null = fs::metadata(dir.path().join("dev/null"))?;
+        assert!(null.file_type().is_char_device());
+        assert_eq!(null.rdev(), stat::makedev(1, 3));
+        let owned = fs::metadata(dir.path().join("owned"))?;
+        assert_eq!((owned.uid(), owned.gid()), (1000, 2000));
+        Ok(())
+    }
+}
@@ -1,7 +1,20 @@
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
-    }
-}
+//! OCI image handling for the container runtime interface.
+
+pub mod archive;
+pub mod digest;
+pub mod encryption;
+pub mod gc;
+pub mod index;
+pub mod layer;
+pub mod manifest;
+pub mod policy;
+pub mod pull;
+pub mod reference;
+pub mod registry;
+pub mod rootfs;
+pub mod snapshot;
+pub mod store;
+pub mod usage;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
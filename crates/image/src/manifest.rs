@@ -0,0 +1,231 @@
+//! Image manifest and index handling, including the Docker schema 2 equivalents.
+
+use anyhow::{bail, Context, Result};
+use oci_spec::image::{Descriptor, ImageIndex, ImageManifest, MediaType, Platform};
+
+/// Media type of the Docker image manifest schema 2.
+pub const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// Media type of the Docker manifest list, the predecessor of the OCI image index.
+pub const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// Media type of the Docker image configuration.
+pub const DOCKER_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+
+/// Media type of a gzip compressed Docker layer.
+pub const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// Media type of a gzip compressed Docker layer which must not be pushed to other registries.
+pub const DOCKER_FOREIGN_LAYER_GZIP: &str =
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+
+/// All manifest media types which are supported on pull, in order of preference.
+pub const SUPPORTED_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    DOCKER_MANIFEST_LIST,
+    DOCKER_MANIFEST,
+];
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+/// A parsed manifest, which is either a single platform image or an index of them.
+pub enum Manifest {
+    /// An image manifest for a single platform.
+    Image(ImageManifest),
+
+    /// An index pointing to image manifests, usually for multiple platforms.
+    Index(ImageIndex),
+}
+
+impl Manifest {
+    /// Parse the manifest content. The `media_type` is usually the `Content-Type` of the registry
+    /// response and takes precedence over the `mediaType` field of the document.
+    pub fn parse(media_type: Option<&str>, content: &[u8]) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_slice(content).context("parse manifest JSON")?;
+
+        let media_type = media_type
+            .map(|m| m.split(';').next().unwrap_or_default().trim().to_string())
+            .filter(|m| !m.is_empty() && m != "application/json")
+            .or_else(|| value["mediaType"].as_str().map(ToString::to_string))
+            .unwrap_or_else(|| {
+                // OCI documents may omit the media type, an index is identified by its manifests
+                if value.get("manifests").is_some() {
+                    MediaType::ImageIndex.to_string()
+                } else {
+                    MediaType::ImageManifest.to_string()
+                }
+            });
+
+        match MediaType::from(media_type.as_str()) {
+            MediaType::ImageManifest => Ok(Self::Image(
+                serde_json::from_value(value).context("parse image manifest")?,
+            )),
+            MediaType::ImageIndex => Ok(Self::Index(
+                serde_json::from_value(value).context("parse image index")?,
+            )),
+            MediaType::Other(m) if m == DOCKER_MANIFEST => Ok(Self::Image(
+                serde_json::from_value(value).context("parse docker manifest")?,
+            )),
+            MediaType::Other(m) if m == DOCKER_MANIFEST_LIST => Ok(Self::Index(
+                serde_json::from_value(value).context("parse docker manifest list")?,
+            )),
+            other => bail!("unsupported manifest media type {}", other),
+        }
+    }
+}
+
+/// Select the best matching manifest for the provided platform from the index.
+///
+/// The operating system and architecture have to match. If the platform has a variant, then an
+/// exact variant match is preferred over manifests without any variant.
+pub fn select_platform<'a>(index: &'a ImageIndex, platform: &Platform) -> Result<&'a Descriptor> {
+    let candidates = index
+        .manifests()
+        .iter()
+        .filter(|d| {
+            d.platform().as_ref().is_some_and(|p| {
+                p.os() == platform.os() && p.architecture() == platform.architecture()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    candidates
+        .iter()
+        .find(|d| d.platform().as_ref().map(Platform::variant) == Some(platform.variant()))
+        .or_else(|| {
+            candidates
+                .iter()
+                .find(|d| d.platform().as_ref().is_none_or(|p| p.variant().is_none()))
+        })
+        .or_else(|| candidates.first())
+        .copied()
+        .with_context(|| {
+            format!(
+                "no manifest found for platform {}/{}",
+                platform.os(),
+                platform.architecture()
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::{Arch, Os, PlatformBuilder};
+    use serde_json::json;
+
+    fn manifest_json(media_type: Option<&str>) -> Vec<u8> {
+        let mut value = json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": DOCKER_CONFIG,
+                "digest": "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b",
+                "size": 10,
+            },
+            "layers": [],
+        });
+        if let Some(media_type) = media_type {
+            value["mediaType"] = media_type.into();
+        }
+        value.to_string().into_bytes()
+    }
+
+    fn index(platforms: &[(&str, &str, Option<&str>)]) -> Result<ImageIndex> {
+        let manifests = platforms
+            .iter()
+            .enumerate()
+            .map(|(i, (os, arch, variant))| {
+                json!({
+                    "mediaType": DOCKER_MANIFEST,
+                    "digest": format!("sha256:{:064x}", i),
+                    "size": 1,
+                    "platform": {"os": os, "architecture": arch, "variant": variant},
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(serde_json::from_value(json!({
+            "schemaVersion": 2,
+            "manifests": manifests,
+        }))?)
+    }
+
+    fn platform(arch: &str, variant: Option<&str>) -> Result<Platform> {
+        let builder = PlatformBuilder::default()
+            .os(Os::Linux)
+            .architecture(Arch::from(arch));
+        Ok(match variant {
+            Some(variant) => builder.variant(variant),
+            None => builder,
+        }
+        .build()?)
+    }
+
+    #[test]
+    fn parse_manifest() -> Result<()> {
+        for (header, field) in &[
+            (Some(DOCKER_MANIFEST), None),
+            (None, Some(DOCKER_MANIFEST)),
+            (Some("application/vnd.oci.image.manifest.v1+json"), None),
+            (Some("application/json"), None),
+            (None, None),
+        ] {
+            let manifest = Manifest::parse(*header, &manifest_json(*field))?;
+            assert!(matches!(manifest, Manifest::Image(_)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_index() -> Result<()> {
+        let content = serde_json::to_vec(&index(&[("linux", "amd64", None)])?)?;
+        assert!(matches!(
+            Manifest::parse(Some(DOCKER_MANIFEST_LIST), &content)?,
+            Manifest::Index(_)
+        ));
+        assert!(matches!(
+            Manifest::parse(None, &content)?,
+            Manifest::Index(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_failure() {
+        assert!(Manifest::parse(None, b"{").is_err());
+        assert!(Manifest::parse(
+            Some("application/vnd.docker.distribution.manifest.v1+prettyjws"),
+            &manifest_json(None)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn select_platform_success() -> Result<()> {
+        let index = index(&[
+            ("linux", "amd64", None),
+            ("linux", "arm", Some("v6")),
+            ("linux", "arm", Some("v7")),
+            ("linux", "arm64", Some("v8")),
+            ("windows", "amd64", None),
+        ])?;
+
+        let selected = select_platform(&index, &platform("amd64", None)?)?;
+        assert_eq!(selected, &index.manifests()[0]);
+
+        let selected = select_platform(&index, &platform("arm", Some("v7"))?)?;
+        assert_eq!(selected, &index.manifests()[2]);
+
+        let selected = select_platform(&index, &platform("arm64", None)?)?;
+        assert_eq!(selected, &index.manifests()[3]);
+        Ok(())
+    }
+
+    #[test]
+    fn select_platform_failure() -> Result<()> {
+        let index = index(&[("linux", "amd64", None)])?;
+        assert!(select_platform(&index, &platform("s390x", None)?).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+//! Verification of simple signing signatures, which are OpenPGP signed messages.
+
+use anyhow::{bail, Context, Result};
+use pgp::{types::PublicKeyTrait, Deserializable, Message, SignedPublicKey};
+
+/// The key type of `signedBy` requirements which is supported.
+pub const KEY_TYPE: &str = "GPGKeys";
+
+/// Parse all public keys of an armored or binary keyring.
+pub fn parse_keys(data: &[u8]) -> Result<Vec<SignedPublicKey>> {
+    let (keys, _) = SignedPublicKey::from_reader_many(data).context("parse GPG keyring")?;
+    let keys = keys
+        .collect::<Result<Vec<_>, _>>()
+        .context("parse GPG key")?;
+    if keys.is_empty() {
+        bail!("no GPG keys found in keyring")
+    }
+    Ok(keys)
+}
+
+/// Verify the signed message with any of the keys or their subkeys and return its content.
+pub fn verify(keys: &[SignedPublicKey], signature: &[u8]) -> Result<Vec<u8>> {
+    let message = Message::from_bytes(signature)
+        .and_then(Message::decompress)
+        .context("parse GPG signature")?;
+    let verified = keys.iter().any(|key| {
+        verify_with(&message, key)
+            || key
+                .public_subkeys
+                .iter()
+                .any(|subkey| verify_with(&message, subkey))
+    });
+    if !verified {
+        bail!("GPG signature is not signed by any accepted key")
+    }
+    message
+        .get_content()
+        .context("read GPG signature content")?
+        .context("GPG signature has no content")
+}
+
+/// Verify the message with a single key.
+fn verify_with(message: &Message, key: &impl PublicKeyTrait) -> bool {
+    message.verify(key).is_ok()
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use pgp::{
+        crypto::hash::HashAlgorithm, ser::Serialize, types::SecretKeyTrait, KeyType,
+        SecretKeyParamsBuilder, SignedSecretKey,
+    };
+    use rand::rngs::OsRng;
+
+    pub fn key() -> Result<SignedSecretKey> {
+        let params = SecretKeyParamsBuilder::default()
+            .key_type(KeyType::EdDSALegacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id("Test <test@example.com>".into())
+            .build()?;
+        Ok(params.generate(OsRng)?.sign(OsRng, String::new)?)
+    }
+
+    pub fn public_key(key: &SignedSecretKey) -> Result<Vec<u8>> {
+        let public = key.public_key().sign(OsRng, key, String::new)?;
+        Ok(public.to_armored_bytes(Default::default())?)
+    }
+
+    pub fn sign(key: &SignedSecretKey, payload: &[u8]) -> Result<Vec<u8>> {
+        let message = Message::new_literal_bytes("", payload).sign(
+            OsRng,
+            key,
+            String::new,
+            HashAlgorithm::SHA2_256,
+        )?;
+        Ok(message.to_bytes()?)
+    }
+
+    #[test]
+    fn verify_signature() -> Result<()> {
+        let other = key()?;
+        let key = key()?;
+        let keys = parse_keys(&public_key(&key)?)?;
+
+        let signature = sign(&key, b"payload")?;
+        assert_eq!(verify(&keys, &signature)?, b"payload");
+
+        assert!(verify(&keys, &sign(&other, b"payload")?).is_err());
+        assert!(verify(&keys, b"invalid").is_err());
+        assert!(parse_keys(b"").is_err());
+        Ok(())
+    }
+}
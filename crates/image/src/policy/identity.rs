@@ -0,0 +1,277 @@
+//! The signed payload and the matching of the identity it claims.
+
+use crate::{digest::Digest, reference::Reference};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// The payload type of simple signing signatures.
+pub const SIMPLE_SIGNING_TYPE: &str = "atomic container signature";
+
+/// The payload type of sigstore signatures.
+pub const SIGSTORE_TYPE: &str = "cosign container image signature";
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+/// The identity a signature has to claim to be accepted for a reference.
+pub enum SignedIdentity {
+    #[default]
+    /// The signed reference has to match the repository if the image is pulled by digest,
+    /// otherwise it has to match exactly.
+    MatchRepoDigestOrExact,
+
+    /// The signed reference has to match exactly.
+    MatchExact,
+
+    /// The signed reference has to match the repository.
+    MatchRepository,
+
+    #[serde(rename_all = "camelCase")]
+    /// The signed reference has to be the provided one.
+    ExactReference {
+        /// The expected reference.
+        docker_reference: String,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    /// The signed reference has to be within the provided repository.
+    ExactRepository {
+        /// The expected repository.
+        docker_repository: String,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    /// References starting with `prefix` are rewritten to start with `signed_prefix`, before
+    /// matching them like `MatchRepoDigestOrExact`.
+    RemapIdentity {
+        /// The prefix of the pulled reference.
+        prefix: String,
+
+        /// The prefix of the signed reference.
+        signed_prefix: String,
+    },
+}
+
+impl SignedIdentity {
+    /// Check if the `signed` reference of a signature is accepted for the pulled `reference`.
+    pub fn matches(&self, reference: &Reference, signed: &Reference) -> Result<bool> {
+        Ok(match self {
+            Self::MatchRepoDigestOrExact => match_repo_digest_or_exact(reference, signed),
+            Self::MatchExact => reference == signed,
+            Self::MatchRepository => reference.name() == signed.name(),
+            Self::ExactReference { docker_reference } => {
+                let expected = docker_reference
+                    .parse::<Reference>()
+                    .with_context(|| format!("parse dockerReference {}", docker_reference))?;
+                &expected == signed
+            }
+            Self::ExactRepository { docker_repository } => {
+                let expected = docker_repository
+                    .parse::<Reference>()
+                    .with_context(|| format!("parse dockerRepository {}", docker_repository))?;
+                expected.name() == signed.name()
+            }
+            Self::RemapIdentity {
+                prefix,
+                signed_prefix,
+            } => {
+                let name = reference.name();
+                let remapped = match name.strip_prefix(prefix.as_str()) {
+                    Some(rest)
+                        if rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/') =>
+                    {
+                        reference
+                            .with_name(&format!("{}{}", signed_prefix, rest))
+                            .with_context(|| format!("remap {} to {}", name, signed_prefix))?
+                    }
+                    _ => reference.clone(),
+                };
+                match_repo_digest_or_exact(&remapped, signed)
+            }
+        })
+    }
+}
+
+/// Match the repository for references by digest, otherwise the whole reference.
+fn match_repo_digest_or_exact(reference: &Reference, signed: &Reference) -> bool {
+    if reference.digest().is_some() {
+        reference.name() == signed.name()
+    } else {
+        reference == signed
+    }
+}
+
+#[derive(Debug, Deserialize)]
+/// The signed content of a signature.
+struct RawPayload {
+    /// The content which has to be understood by every consumer.
+    critical: Critical,
+}
+
+#[derive(Debug, Deserialize)]
+/// The critical section of the payload.
+struct Critical {
+    #[serde(rename = "type")]
+    /// The type of the signature.
+    kind: String,
+
+    /// The identity of the signed image.
+    identity: CriticalIdentity,
+
+    /// The signed image content.
+    image: CriticalImage,
+}
+
+#[derive(Debug, Deserialize)]
+/// The claimed identity of the signed image.
+struct CriticalIdentity {
+    #[serde(rename = "docker-reference")]
+    /// The reference of the signed image.
+    docker_reference: String,
+}
+
+#[derive(Debug, Deserialize)]
+/// The signed manifest.
+struct CriticalImage {
+    #[serde(rename = "docker-manifest-digest")]
+    /// The digest of the signed manifest.
+    docker_manifest_digest: Digest,
+}
+
+#[derive(Debug)]
+/// A parsed and validated signature payload.
+pub struct Payload {
+    /// The claimed reference.
+    reference: Reference,
+
+    /// The signed manifest digest.
+    digest: Digest,
+}
+
+impl Payload {
+    /// Parse the payload, which has to be of the `expected_type`.
+    pub fn parse(payload: &[u8], expected_type: &str) -> Result<Self> {
+        let raw: RawPayload = serde_json::from_slice(payload).context("parse signature payload")?;
+        if raw.critical.kind != expected_type {
+            bail!("unexpected signature type {:?}", raw.critical.kind)
+        }
+        let reference = raw
+            .critical
+            .identity
+            .docker_reference
+            .parse()
+            .context("parse signed reference")?;
+        Ok(Self {
+            reference,
+            digest: raw.critical.image.docker_manifest_digest,
+        })
+    }
+
+    /// Verify that the payload signs the manifest `digest` with an identity accepted for the
+    /// `reference`.
+    pub fn verify(
+        &self,
+        reference: &Reference,
+        digest: &Digest,
+        identity: &SignedIdentity,
+    ) -> Result<()> {
+        if &self.digest != digest {
+            bail!(
+                "signature is for manifest {}, expected {}",
+                self.digest,
+                digest
+            )
+        }
+        if !identity.matches(reference, &self.reference)? {
+            bail!(
+                "signed identity {} does not match {}",
+                self.reference,
+                reference
+            )
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    pub fn payload(kind: &str, reference: &str, digest: &Digest) -> Vec<u8> {
+        json!({
+            "critical": {
+                "type": kind,
+                "identity": {"docker-reference": reference},
+                "image": {"docker-manifest-digest": digest.to_string()},
+            },
+            "optional": {"creator": "test"},
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn signed_identity_matches() -> Result<()> {
+        let digest = super::super::tests::digest("manifest");
+        let tagged = "quay.io/ns/app:v1".parse::<Reference>()?;
+        let other_tag = "quay.io/ns/app:v2".parse::<Reference>()?;
+        let by_digest = tagged.with_digest(digest);
+        let mirror = "mirror.local/app:v1".parse::<Reference>()?;
+
+        let identity = SignedIdentity::MatchRepoDigestOrExact;
+        assert!(identity.matches(&tagged, &tagged)?);
+        assert!(!identity.matches(&tagged, &other_tag)?);
+        assert!(identity.matches(&by_digest, &other_tag)?);
+
+        assert!(!SignedIdentity::MatchExact.matches(&by_digest, &other_tag)?);
+        assert!(SignedIdentity::MatchRepository.matches(&tagged, &other_tag)?);
+        assert!(!SignedIdentity::MatchRepository.matches(&tagged, &mirror)?);
+
+        let identity = SignedIdentity::ExactReference {
+            docker_reference: "quay.io/ns/app:v2".into(),
+        };
+        assert!(identity.matches(&mirror, &other_tag)?);
+        assert!(!identity.matches(&mirror, &tagged)?);
+
+        let identity = SignedIdentity::ExactRepository {
+            docker_repository: "quay.io/ns/app".into(),
+        };
+        assert!(identity.matches(&mirror, &other_tag)?);
+
+        let identity = SignedIdentity::RemapIdentity {
+            prefix: "mirror.local".into(),
+            signed_prefix: "quay.io/ns".into(),
+        };
+        assert!(identity.matches(&mirror, &tagged)?);
+        assert!(!identity.matches(&mirror, &other_tag)?);
+        assert!(!identity.matches(&"mirror.localhost/app:v1".parse()?, &tagged)?);
+        Ok(())
+    }
+
+    #[test]
+    fn payload_verify() -> Result<()> {
+        let digest = super::super::tests::digest("manifest");
+        let reference = "quay.io/app:v1".parse::<Reference>()?;
+        let identity = SignedIdentity::default();
+
+        let parsed = Payload::parse(
+            &payload(SIMPLE_SIGNING_TYPE, "quay.io/app:v1", &digest),
+            SIMPLE_SIGNING_TYPE,
+        )?;
+        parsed.verify(&reference, &digest, &identity)?;
+        assert!(parsed
+            .verify(&reference, &super::super::tests::digest("other"), &identity)
+            .is_err());
+        assert!(parsed
+            .verify(&"quay.io/app:v2".parse()?, &digest, &identity)
+            .is_err());
+
+        assert!(Payload::parse(
+            &payload(SIMPLE_SIGNING_TYPE, "quay.io/app:v1", &digest),
+            SIGSTORE_TYPE
+        )
+        .is_err());
+        assert!(Payload::parse(b"{}", SIGSTORE_TYPE).is_err());
+        Ok(())
+    }
+}
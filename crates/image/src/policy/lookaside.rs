@@ -0,0 +1,266 @@
+//! Locations of simple signing signatures configured in the [containers-registries.d(5)][0]
+//! directory.
+//!
+//! [0]: https://github.com/containers/image/blob/main/docs/containers-registries.d.5.md
+
+use super::scopes;
+use crate::{digest::Digest, reference::Reference};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, trace};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, io::ErrorKind, path::Path};
+
+/// The default location of the lookaside configuration directory.
+pub const DEFAULT_REGISTRIES_DIR: &str = "/etc/containers/registries.d";
+
+/// The maximum number of signatures read for a single image.
+const MAX_SIGNATURES: usize = 128;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// The content of a single configuration file.
+struct LookasideFile {
+    /// The configuration if no namespace matches.
+    default_docker: Option<Namespace>,
+
+    #[serde(default)]
+    /// The configuration per registry, namespace, repository or tag.
+    docker: HashMap<String, Namespace>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+/// The signature storage of a namespace.
+struct Namespace {
+    /// The base URL of the signature storage for reading.
+    lookaside: Option<String>,
+
+    /// The deprecated name of `lookaside`.
+    sigstore: Option<String>,
+}
+
+impl Namespace {
+    /// The configured base URL.
+    fn url(&self) -> Result<Option<Url>> {
+        self.lookaside
+            .as_ref()
+            .or(self.sigstore.as_ref())
+            .map(|url| Url::parse(url).with_context(|| format!("parse URL {}", url)))
+            .transpose()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// Reads simple signing signatures from lookaside storages.
+pub struct Lookaside {
+    /// The base URL if no namespace matches.
+    default: Option<Url>,
+
+    /// The base URLs per namespace.
+    namespaces: HashMap<String, Url>,
+
+    /// The HTTP client for remote storages.
+    http: reqwest::Client,
+}
+
+impl Lookaside {
+    /// Load all YAML files of the directory, whereas a missing directory results in no lookaside
+    /// storages at all.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut files = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("read directory {}", dir.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                trace!("Registries directory {} does not exist", dir.display());
+                vec![]
+            }
+            Err(e) => return Err(e).with_context(|| format!("read directory {}", dir.display())),
+        };
+        files.retain(|p| p.extension().is_some_and(|e| e == "yaml" || e == "yml"));
+        files.sort();
+
+        let mut lookaside = Self::default();
+        for file in files {
+            debug!("Loading lookaside config {}", file.display());
+            let content = fs::read(&file).with_context(|| format!("read {}", file.display()))?;
+            lookaside
+                .add(&content)
+                .with_context(|| format!("parse {}", file.display()))?;
+        }
+        Ok(lookaside)
+    }
+
+    /// Add the configuration of a single file, whereas namespaces must not be configured twice.
+    fn add(&mut self, content: &[u8]) -> Result<()> {
+        let file: LookasideFile = serde_yaml::from_slice(content)?;
+        if let Some(url) = file.default_docker.unwrap_or_default().url()? {
+            if self.default.is_some() {
+                bail!("default-docker configured multiple times")
+            }
+            self.default = Some(url);
+        }
+        for (namespace, config) in file.docker {
+            if let Some(url) = config.url()? {
+                if self.namespaces.contains_key(&namespace) {
+                    bail!("namespace {} configured multiple times", namespace)
+                }
+                self.namespaces.insert(namespace, url);
+            }
+        }
+        Ok(())
+    }
+
+    /// The base URL of the most specific namespace matching the reference.
+    pub fn url(&self, reference: &Reference) -> Option<&Url> {
+        scopes(reference, true)
+            .iter()
+            .find_map(|scope| self.namespaces.get(scope))
+            .or(self.default.as_ref())
+    }
+
+    /// Read all signatures of the manifest `digest` for the reference.
+    pub async fn signatures(&self, reference: &Reference, digest: &Digest) -> Result<Vec<Vec<u8>>> {
+        let base = match self.url(reference) {
+            Some(base) => base,
+            None => {
+                debug!("No lookaside storage configured for {}", reference);
+                return Ok(vec![]);
+            }
+        };
+        let mut signatures = vec![];
+        for index in 1..=MAX_SIGNATURES {
+            let url = Url::parse(&format!(
+                "{}/{}@{}={}/signature-{}",
+                base.as_str().trim_end_matches('/'),
+                reference.repository(),
+                digest.algorithm(),
+                digest.encoded(),
+                index
+            ))?;
+            match self.read(&url).await? {
+                Some(signature) => signatures.push(signature),
+                None => break,
+            }
+        }
+        debug!(
+            "Found {} lookaside signatures for {}",
+            signatures.len(),
+            reference
+        );
+        Ok(signatures)
+    }
+
+    /// Read a single signature, which returns `None` if it does not exist.
+    async fn read(&self, url: &Url) -> Result<Option<Vec<u8>>> {
+        trace!("Reading signature {}", url);
+        if url.scheme() == "file" {
+            let path = url
+                .to_file_path()
+                .map_err(|_| anyhow!("invalid file URL {}", url))?;
+            return match tokio::fs::read(&path).await {
+                Ok(signature) => Ok(Some(signature)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e).with_context(|| format!("read {}", path.display())),
+            };
+        }
+        let response = self
+            .http
+            .get(url.clone())
+            .send()
+            .await
+            .with_context(|| format!("request {}", url))?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(
+                response
+                    .bytes()
+                    .await
+                    .with_context(|| format!("read {}", url))?
+                    .to_vec(),
+            )),
+            status => bail!("request {} failed with status {}", url, status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn load_namespaces() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(
+            dir.path().join("default.yaml"),
+            "default-docker:\n  lookaside: https://sigs.example.com\n",
+        )?;
+        fs::write(
+            dir.path().join("quay.yaml"),
+            "docker:\n  quay.io/ns:\n    sigstore: file:///var/lib/sigs\n  quay.io:\n    lookaside-staging: file:///tmp\n",
+        )?;
+        fs::write(dir.path().join("ignored.txt"), "invalid")?;
+        let lookaside = Lookaside::load(dir.path())?;
+
+        let url = |r: &str| -> Result<Option<String>> {
+            Ok(lookaside.url(&r.parse()?).map(Url::to_string))
+        };
+        assert_eq!(
+            url("quay.io/ns/app:v1")?.as_deref(),
+            Some("file:///var/lib/sigs")
+        );
+        assert_eq!(
+            url("quay.io/app:v1")?.as_deref(),
+            Some("https://sigs.example.com/")
+        );
+
+        fs::write(
+            dir.path().join("duplicate.yml"),
+            "default-docker:\n  lookaside: https://other.example.com\n",
+        )?;
+        assert!(Lookaside::load(dir.path()).is_err());
+        assert!(Lookaside::load(dir.path().join("missing"))?
+            .default
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_file_signatures() -> Result<()> {
+        let dir = TempDir::new()?;
+        let digest = Digest::from_bytes(Default::default(), b"manifest");
+        let signatures = dir.path().join(format!(
+            "ns/app@{}={}",
+            digest.algorithm(),
+            digest.encoded()
+        ));
+        fs::create_dir_all(&signatures)?;
+        fs::write(signatures.join("signature-1"), "first")?;
+        fs::write(signatures.join("signature-2"), "second")?;
+        fs::write(signatures.join("signature-4"), "unreachable")?;
+
+        let mut lookaside = Lookaside::default();
+        lookaside.add(
+            format!(
+                "docker:\n  quay.io:\n    lookaside: file://{}\n",
+                dir.path().display()
+            )
+            .as_bytes(),
+        )?;
+        assert_eq!(
+            lookaside
+                .signatures(&"quay.io/ns/app:v1".parse()?, &digest)
+                .await?,
+            [b"first".to_vec(), b"second".to_vec()]
+        );
+        assert!(lookaside
+            .signatures(&"docker.io/ns/app:v1".parse()?, &digest)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
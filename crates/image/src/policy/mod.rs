@@ -0,0 +1,648 @@
+//! Signature verification of images according to the [containers-policy.json(5)][0] format.
+//!
+//! The policy defines a list of requirements per registry, namespace, repository or tag, which
+//! all have to be satisfied before an image gets pulled. The most specific scope of the `docker`
+//! transport applies, whereas the `default` requirements are used if no scope matches.
+//!
+//! [0]: https://github.com/containers/image/blob/main/docs/containers-policy.json.5.md
+
+use crate::{digest::Digest, reference::Reference};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use getset::Getters;
+use log::{debug, trace, warn};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+mod gpg;
+mod identity;
+pub mod lookaside;
+mod sigstore;
+
+pub use identity::SignedIdentity;
+
+/// The default location of the policy.
+pub const DEFAULT_POLICY_PATH: &str = "/etc/containers/policy.json";
+
+/// The annotation of sigstore signature layers, which contains the base64 encoded signature.
+pub const SIGSTORE_SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// The transport of images pulled from registries.
+const DOCKER_TRANSPORT: &str = "docker";
+
+#[derive(Clone, Debug, Error, Getters)]
+#[error("image {reference} rejected by signature policy: {reason}")]
+/// An image which does not satisfy the requirements of the policy.
+pub struct PolicyViolation {
+    #[get = "pub"]
+    /// The rejected image.
+    reference: Reference,
+
+    #[get = "pub"]
+    /// Why the image got rejected.
+    reason: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+/// A single requirement of the policy.
+pub enum Requirement {
+    /// Accept every image.
+    InsecureAcceptAnything,
+
+    /// Reject every image.
+    Reject,
+
+    /// Require a simple signing signature by one of the GPG keys.
+    SignedBy(SignedBy),
+
+    /// Require a sigstore signature by the public key.
+    SigstoreSigned(SigstoreSigned),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+/// Requires a simple signing signature stored in a lookaside storage.
+pub struct SignedBy {
+    /// The format of the keys, which has to be `GPGKeys`.
+    key_type: String,
+
+    /// The path of a keyring containing the accepted keys.
+    key_path: Option<PathBuf>,
+
+    /// The paths of keyrings containing the accepted keys.
+    key_paths: Option<Vec<PathBuf>>,
+
+    /// The base64 encoded keyring containing the accepted keys.
+    key_data: Option<String>,
+
+    #[serde(default)]
+    /// The identity the signature has to claim.
+    signed_identity: SignedIdentity,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+/// Requires a sigstore signature attached to the image in the registry.
+pub struct SigstoreSigned {
+    /// The path of the PEM encoded public key.
+    key_path: Option<PathBuf>,
+
+    /// The base64 encoded PEM public key.
+    key_data: Option<String>,
+
+    /// Keyless verification via Fulcio certificates, which is not supported.
+    fulcio: Option<serde_json::Value>,
+
+    #[serde(default)]
+    /// The identity the signature has to claim.
+    signed_identity: SignedIdentity,
+}
+
+#[derive(Debug, Deserialize)]
+/// The content of a policy file.
+struct PolicyFile {
+    /// The requirements if no transport scope matches.
+    default: Vec<Requirement>,
+
+    #[serde(default)]
+    /// The requirements per transport and scope.
+    transports: HashMap<String, HashMap<String, Vec<Requirement>>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// The signature verification policy for pulled images.
+pub struct Policy {
+    /// The requirements if no scope matches.
+    default: Vec<Requirement>,
+
+    /// The requirements of the docker transport per scope.
+    scopes: HashMap<String, Vec<Requirement>>,
+}
+
+impl Default for Policy {
+    /// A policy which accepts every image.
+    fn default() -> Self {
+        Self {
+            default: vec![Requirement::InsecureAcceptAnything],
+            scopes: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Getters)]
+/// A sigstore signature attached to an image.
+pub struct SigstoreSignature {
+    #[get = "pub"]
+    /// The signed payload.
+    payload: Vec<u8>,
+
+    #[get = "pub"]
+    /// The base64 encoded signature of the payload.
+    signature: String,
+}
+
+impl SigstoreSignature {
+    /// Create a new sigstore signature.
+    pub fn new(payload: Vec<u8>, signature: String) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[async_trait]
+/// Retrieves the signatures of an image.
+pub trait SignatureSource: Send + Sync {
+    /// The simple signing signatures of the manifest digest.
+    async fn simple_signatures(&self, digest: &Digest) -> Result<Vec<Vec<u8>>>;
+
+    /// The sigstore signatures attached to the manifest digest.
+    async fn sigstore_signatures(&self, digest: &Digest) -> Result<Vec<SigstoreSignature>>;
+}
+
+impl Policy {
+    /// Load the policy file. A missing file results in a policy which accepts every image.
+    /// Relative key paths are resolved against the directory of the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                warn!(
+                    "Signature policy {} does not exist, accepting all images",
+                    path.display()
+                );
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        let base = path.parent().unwrap_or_else(|| Path::new("/"));
+        Self::parse(&content, base).with_context(|| format!("parse policy {}", path.display()))
+    }
+
+    /// Parse the policy, whereas relative key paths are resolved against `base`.
+    pub fn parse(content: &[u8], base: &Path) -> Result<Self> {
+        let file: PolicyFile = serde_json::from_slice(content)?;
+        let mut policy = Self {
+            default: file.default,
+            scopes: file
+                .transports
+                .get(DOCKER_TRANSPORT)
+                .cloned()
+                .unwrap_or_default(),
+        };
+        Self::validate(&mut policy.default, base).context("default requirements")?;
+        for (scope, requirements) in policy.scopes.iter_mut() {
+            Self::validate(requirements, base)
+                .with_context(|| format!("requirements of scope {:?}", scope))?;
+        }
+        Ok(policy)
+    }
+
+    /// Validate the requirements and make their key paths absolute.
+    fn validate(requirements: &mut [Requirement], base: &Path) -> Result<()> {
+        if requirements.is_empty() {
+            bail!("no requirements specified")
+        }
+        for requirement in requirements {
+            match requirement {
+                Requirement::SignedBy(signed_by) => {
+                    if signed_by.key_type != gpg::KEY_TYPE {
+                        bail!("unsupported key type {}", signed_by.key_type)
+                    }
+                    let sources = signed_by.key_path.is_some() as u8
+                        + signed_by.key_paths.is_some() as u8
+                        + signed_by.key_data.is_some() as u8;
+                    if sources != 1 {
+                        bail!("signedBy requires exactly one of keyPath, keyPaths and keyData")
+                    }
+                    for path in signed_by
+                        .key_path
+                        .iter_mut()
+                        .chain(signed_by.key_paths.iter_mut().flatten())
+                    {
+                        *path = base.join(&path);
+                    }
+                }
+                Requirement::SigstoreSigned(sigstore_signed) => {
+                    if sigstore_signed.fulcio.is_some() {
+                        bail!("keyless sigstore verification is not supported")
+                    }
+                    if sigstore_signed.key_path.is_some() == sigstore_signed.key_data.is_some() {
+                        bail!("sigstoreSigned requires exactly one of keyPath and keyData")
+                    }
+                    if let Some(path) = sigstore_signed.key_path.as_mut() {
+                        *path = base.join(&path);
+                    }
+                }
+                Requirement::InsecureAcceptAnything | Requirement::Reject => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The requirements of the most specific scope matching the reference.
+    pub fn requirements(&self, reference: &Reference) -> &[Requirement] {
+        for scope in scopes(reference, true) {
+            if let Some(requirements) = self.scopes.get(&scope) {
+                trace!("Using policy scope {:?} for {}", scope, reference);
+                return requirements;
+            }
+        }
+        self.scopes.get("").unwrap_or(&self.default)
+    }
+
+    /// Verify that the image of the reference, which resolved to the manifest `digest`,
+    /// satisfies all requirements of the policy.
+    pub async fn verify(
+        &self,
+        reference: &Reference,
+        digest: &Digest,
+        source: &dyn SignatureSource,
+    ) -> Result<(), PolicyViolation> {
+        let violation = |reason: String| PolicyViolation {
+            reference: reference.clone(),
+            reason,
+        };
+        for requirement in self.requirements(reference) {
+            match requirement {
+                Requirement::InsecureAcceptAnything => {}
+                Requirement::Reject => return Err(violation("all images are rejected".into())),
+                Requirement::SignedBy(signed_by) => signed_by
+                    .verify(reference, digest, source)
+                    .await
+                    .map_err(|e| violation(format!("{:#}", e)))?,
+                Requirement::SigstoreSigned(sigstore_signed) => sigstore_signed
+                    .verify(reference, digest, source)
+                    .await
+                    .map_err(|e| violation(format!("{:#}", e)))?,
+            }
+        }
+        debug!("Image {} satisfies the signature policy", reference);
+        Ok(())
+    }
+}
+
+impl SignedBy {
+    /// Verify that at least one simple signing signature of the image is valid.
+    async fn verify(
+        &self,
+        reference: &Reference,
+        digest: &Digest,
+        source: &dyn SignatureSource,
+    ) -> Result<()> {
+        let keys = match &self.key_data {
+            Some(data) => gpg::parse_keys(&base64::decode(data).context("decode keyData")?)?,
+            None => {
+                let mut keys = vec![];
+                for path in self.key_path.iter().chain(self.key_paths.iter().flatten()) {
+                    let data =
+                        fs::read(path).with_context(|| format!("read {}", path.display()))?;
+                    keys.extend(gpg::parse_keys(&data)?);
+                }
+                keys
+            }
+        };
+
+        let signatures = source
+            .simple_signatures(digest)
+            .await
+            .context("fetch signatures")?;
+        let mut errors = vec![];
+        for signature in &signatures {
+            let result = gpg::verify(&keys, signature).and_then(|payload| {
+                identity::Payload::parse(&payload, identity::SIMPLE_SIGNING_TYPE)?.verify(
+                    reference,
+                    digest,
+                    &self.signed_identity,
+                )
+            });
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        if errors.is_empty() {
+            bail!("no signature found")
+        }
+        bail!("no valid signature found: {}", errors.join(", "))
+    }
+}
+
+impl SigstoreSigned {
+    /// Verify that at least one sigstore signature of the image is valid.
+    async fn verify(
+        &self,
+        reference: &Reference,
+        digest: &Digest,
+        source: &dyn SignatureSource,
+    ) -> Result<()> {
+        let pem = match (&self.key_data, &self.key_path) {
+            (Some(data), _) => base64::decode(data).context("decode keyData")?,
+            (None, Some(path)) => {
+                fs::read(path).with_context(|| format!("read {}", path.display()))?
+            }
+            (None, None) => bail!("no public key specified"),
+        };
+        let key = sigstore::parse_key(&pem)?;
+
+        let signatures = source
+            .sigstore_signatures(digest)
+            .await
+            .context("fetch signatures")?;
+        let mut errors = vec![];
+        for signature in &signatures {
+            let result = sigstore::verify(&key, signature).and_then(|_| {
+                identity::Payload::parse(signature.payload(), identity::SIGSTORE_TYPE)?.verify(
+                    reference,
+                    digest,
+                    &self.signed_identity,
+                )
+            });
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        if errors.is_empty() {
+            bail!("no signature found")
+        }
+        bail!("no valid signature found: {}", errors.join(", "))
+    }
+}
+
+/// The scopes matching the reference from the most to the least specific one, which are the
+/// reference itself if `with_tag` is set, the repository, its parent namespaces, the registry
+/// and wildcards of the registry's parent domains without the port.
+pub(crate) fn scopes(reference: &Reference, with_tag: bool) -> Vec<String> {
+    let name = reference.name();
+    let mut scopes = vec![];
+    if with_tag {
+        if let Some(digest) = reference.digest() {
+            scopes.push(format!("{}@{}", name, digest));
+        }
+        if let Some(tag) = reference.tag() {
+            scopes.push(format!("{}:{}", name, tag));
+        }
+    }
+    let mut scope = name.as_str();
+    scopes.push(scope.into());
+    while let Some((parent, _)) = scope.rsplit_once('/') {
+        scopes.push(parent.into());
+        scope = parent;
+    }
+    let mut domain = reference.registry().split(':').next().unwrap_or_default();
+    while let Some((_, parent)) = domain.split_once('.') {
+        scopes.push(format!("*.{}", parent));
+        domain = parent;
+    }
+    scopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    pub(super) fn digest(data: &str) -> Digest {
+        Digest::from_bytes(Default::default(), data.as_bytes())
+    }
+
+    #[derive(Default)]
+    struct TestSource {
+        simple: Vec<Vec<u8>>,
+        sigstore: Vec<SigstoreSignature>,
+    }
+
+    #[async_trait]
+    impl SignatureSource for TestSource {
+        async fn simple_signatures(&self, _: &Digest) -> Result<Vec<Vec<u8>>> {
+            Ok(self.simple.clone())
+        }
+
+        async fn sigstore_signatures(&self, _: &Digest) -> Result<Vec<SigstoreSignature>> {
+            Ok(self.sigstore.clone())
+        }
+    }
+
+    fn policy(value: serde_json::Value) -> Result<Policy> {
+        Policy::parse(&serde_json::to_vec(&value)?, Path::new("/etc/containers"))
+    }
+
+    #[test]
+    fn scopes_of_reference() -> Result<()> {
+        let reference = "registry.example.com:5000/ns/app:v1".parse::<Reference>()?;
+        assert_eq!(
+            scopes(&reference, true),
+            [
+                "registry.example.com:5000/ns/app:v1",
+                "registry.example.com:5000/ns/app",
+                "registry.example.com:5000/ns",
+                "registry.example.com:5000",
+                "*.example.com",
+                "*.com",
+            ]
+        );
+        let reference = "app".parse::<Reference>()?;
+        assert_eq!(
+            scopes(&reference, false),
+            [
+                "docker.io/library/app",
+                "docker.io/library",
+                "docker.io",
+                "*.io"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn requirements_most_specific_scope() -> Result<()> {
+        let policy = policy(json!({
+            "default": [{"type": "reject"}],
+            "transports": {
+                "docker": {
+                    "quay.io": [{"type": "insecureAcceptAnything"}],
+                    "quay.io/ns/app:v1": [{"type": "reject"}],
+                    "*.example.com": [{"type": "insecureAcceptAnything"}],
+                },
+                "oci": {"": [{"type": "insecureAcceptAnything"}]},
+            },
+        }))?;
+        let requirements =
+            |r: &str| -> Result<Vec<Requirement>> { Ok(policy.requirements(&r.parse()?).to_vec()) };
+        assert_eq!(
+            requirements("quay.io/ns/app:v2")?,
+            [Requirement::InsecureAcceptAnything]
+        );
+        assert_eq!(requirements("quay.io/ns/app:v1")?, [Requirement::Reject]);
+        assert_eq!(
+            requirements("registry.example.com/app")?,
+            [Requirement::InsecureAcceptAnything]
+        );
+        assert_eq!(requirements("docker.io/app")?, [Requirement::Reject]);
+
+        let policy = self::policy(json!({
+            "default": [{"type": "reject"}],
+            "transports": {"docker": {"": [{"type": "insecureAcceptAnything"}]}},
+        }))?;
+        assert_eq!(
+            policy.requirements(&"app".parse()?),
+            [Requirement::InsecureAcceptAnything]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_resolves_key_paths() -> Result<()> {
+        let policy = policy(json!({
+            "default": [
+                {"type": "signedBy", "keyType": "GPGKeys", "keyPath": "keys/key.gpg"},
+                {"type": "sigstoreSigned", "keyPath": "/abs/key.pub"},
+            ],
+        }))?;
+        match &policy.default[..] {
+            [Requirement::SignedBy(gpg), Requirement::SigstoreSigned(sigstore)] => {
+                assert_eq!(
+                    gpg.key_path.as_deref(),
+                    Some(Path::new("/etc/containers/keys/key.gpg"))
+                );
+                assert_eq!(gpg.signed_identity, SignedIdentity::MatchRepoDigestOrExact);
+                assert_eq!(
+                    sigstore.key_path.as_deref(),
+                    Some(Path::new("/abs/key.pub"))
+                );
+            }
+            other => panic!("unexpected requirements {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_failure() {
+        for invalid in [
+            json!({}),
+            json!({"default": []}),
+            json!({"default": [{"type": "unknown"}]}),
+            json!({"default": [{"type": "signedBy", "keyType": "X509Certificates", "keyPath": "/k"}]}),
+            json!({"default": [{"type": "signedBy", "keyType": "GPGKeys"}]}),
+            json!({"default": [{"type": "sigstoreSigned", "keyPath": "/k", "keyData": "AA=="}]}),
+            json!({"default": [{"type": "sigstoreSigned", "fulcio": {}}]}),
+            json!({"default": [{"type": "reject"}], "transports": {"docker": {"quay.io": []}}}),
+        ] {
+            assert!(policy(invalid.clone()).is_err(), "{} is valid", invalid);
+        }
+    }
+
+    #[test]
+    fn load_missing_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert_eq!(
+            Policy::load(dir.path().join("policy.json"))?,
+            Policy::default()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_accept_and_reject() -> Result<()> {
+        let policy = policy(json!({
+            "default": [{"type": "insecureAcceptAnything"}],
+            "transports": {"docker": {"quay.io": [{"type": "reject"}]}},
+        }))?;
+        let source = TestSource::default();
+        let digest = digest("manifest");
+        policy
+            .verify(&"docker.io/app:v1".parse()?, &digest, &source)
+            .await?;
+
+        let reference = "quay.io/app:v1".parse::<Reference>()?;
+        let violation = policy
+            .verify(&reference, &digest, &source)
+            .await
+            .unwrap_err();
+        assert_eq!(violation.reference(), &reference);
+        assert_eq!(
+            violation.to_string(),
+            "image quay.io/app:v1 rejected by signature policy: all images are rejected"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_signed_by() -> Result<()> {
+        let key = gpg::tests::key()?;
+        let policy = policy(json!({
+            "default": [{
+                "type": "signedBy",
+                "keyType": "GPGKeys",
+                "keyData": base64::encode(gpg::tests::public_key(&key)?),
+            }],
+        }))?;
+        let reference = "quay.io/app:v1".parse::<Reference>()?;
+        let digest = digest("manifest");
+        let payload =
+            identity::tests::payload(identity::SIMPLE_SIGNING_TYPE, "quay.io/app:v1", &digest);
+
+        let mut source = TestSource::default();
+        assert!(policy.verify(&reference, &digest, &source).await.is_err());
+
+        source.simple = vec![
+            gpg::tests::sign(&gpg::tests::key()?, &payload)?,
+            gpg::tests::sign(&key, &payload)?,
+        ];
+        policy.verify(&reference, &digest, &source).await?;
+
+        let violation = policy
+            .verify(&"quay.io/app:v2".parse()?, &digest, &source)
+            .await
+            .unwrap_err();
+        assert!(violation.reason().contains("does not match"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_sigstore_signed() -> Result<()> {
+        let dir = TempDir::new()?;
+        let key = sigstore::tests::key();
+        fs::write(
+            dir.path().join("key.pub"),
+            sigstore::tests::public_key(&key)?,
+        )?;
+        let policy = Policy::parse(
+            &serde_json::to_vec(&json!({
+                "default": [{"type": "reject"}],
+                "transports": {
+                    "docker": {
+                        "quay.io/ns": [{"type": "sigstoreSigned", "keyPath": "key.pub"}],
+                    },
+                },
+            }))?,
+            dir.path(),
+        )?;
+        let reference = "quay.io/ns/app:v1".parse::<Reference>()?;
+        let digest = digest("manifest");
+        let payload =
+            identity::tests::payload(identity::SIGSTORE_TYPE, "quay.io/ns/app:v1", &digest);
+
+        let mut source = TestSource {
+            sigstore: vec![sigstore::tests::sign(&sigstore::tests::key(), &payload)],
+            ..Default::default()
+        };
+        assert!(policy.verify(&reference, &digest, &source).await.is_err());
+
+        source.sigstore.push(sigstore::tests::sign(&key, &payload));
+        policy.verify(&reference, &digest, &source).await?;
+        assert!(policy
+            .verify(&reference, &self::digest("other"), &source)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! Verification of sigstore signatures, which are ECDSA P-256 signatures over the payload.
+
+use super::SigstoreSignature;
+use anyhow::{Context, Result};
+use p256::{
+    ecdsa::{signature::Verifier, DerSignature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+};
+use std::str;
+
+/// Parse the PEM encoded public key.
+pub fn parse_key(pem: &[u8]) -> Result<VerifyingKey> {
+    let pem = str::from_utf8(pem).context("public key is not valid UTF-8")?;
+    VerifyingKey::from_public_key_pem(pem.trim()).context("parse sigstore public key")
+}
+
+/// Verify the base64 encoded signature of the payload.
+pub fn verify(key: &VerifyingKey, signature: &SigstoreSignature) -> Result<()> {
+    let der = base64::decode(signature.signature()).context("decode sigstore signature")?;
+    let der = DerSignature::from_bytes(&der).context("parse sigstore signature")?;
+    key.verify(signature.payload(), &der)
+        .context("sigstore signature is not signed by the accepted key")
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use p256::{
+        ecdsa::{signature::Signer, SigningKey},
+        pkcs8::{EncodePublicKey, LineEnding},
+    };
+    use rand::rngs::OsRng;
+
+    pub fn key() -> SigningKey {
+        SigningKey::random(&mut OsRng)
+    }
+
+    pub fn public_key(key: &SigningKey) -> Result<String> {
+        Ok(key.verifying_key().to_public_key_pem(LineEnding::LF)?)
+    }
+
+    pub fn sign(key: &SigningKey, payload: &[u8]) -> SigstoreSignature {
+        let signature: DerSignature = key.sign(payload);
+        SigstoreSignature::new(payload.to_vec(), base64::encode(signature.as_bytes()))
+    }
+
+    #[test]
+    fn verify_signature() -> Result<()> {
+        let key = self::key();
+        let verifying_key = parse_key(public_key(&key)?.as_bytes())?;
+
+        verify(&verifying_key, &sign(&key, b"payload"))?;
+        assert!(verify(&verifying_key, &sign(&self::key(), b"payload")).is_err());
+
+        let mut tampered = sign(&key, b"payload");
+        tampered.payload = b"other".to_vec();
+        assert!(verify(&verifying_key, &tampered).is_err());
+        assert!(parse_key(b"invalid").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,1321 @@
+//! Pulling images from a registry into the local blob store.
+//!
+//! Concurrent pulls of the same reference with the same credentials are coalesced into a single
+//! one, whose result is shared by all callers. Blobs are downloaded only once even if multiple
+//! images reference them, the number of concurrent downloads is limited and interrupted
+//! downloads are resumed from where they stopped. The garbage collection stays paused while a
+//! pull runs in the background and until all callers dropped the pulled image.
+
+use crate::{
+    digest::Digest,
+    gc::GarbageCollector,
+    manifest::{self, Manifest},
+    policy::{
+        lookaside::Lookaside, Policy, PolicyViolation, SignatureSource, SigstoreSignature,
+        SIGSTORE_SIGNATURE_ANNOTATION,
+    },
+    reference::Reference,
+    registry::{auth::Credentials, Client, FetchedManifest, StatusError},
+    store::BlobStore,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use derive_builder::Builder;
+use futures::{
+    future::{try_join_all, BoxFuture, Shared},
+    FutureExt, StreamExt,
+};
+use getset::{CopyGetters, Getters};
+use log::{debug, info, warn};
+use oci_spec::image::{Descriptor, ImageManifest, Platform};
+use reqwest::StatusCode;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedRwLockReadGuard, Semaphore};
+
+/// The default maximum number of concurrent blob downloads.
+pub const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 3;
+
+/// Maximum number of nested indexes to follow before giving up.
+const MAX_INDEX_DEPTH: usize = 2;
+
+/// Maximum size of a single sigstore signature payload.
+const MAX_SIGNATURE_PAYLOAD_SIZE: i64 = 4 * 1024 * 1024;
+
+/// Maximum number of times an interrupted blob download gets resumed within a single pull.
+const MAX_DOWNLOAD_RETRIES: usize = 3;
+
+/// The interval in which the progress of running pulls gets logged.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Pulls are coalesced per reference and the credentials provided for it.
+type PullKey = (Reference, Option<Credentials>);
+
+/// The result of a running pull, which is shared by all callers waiting for it.
+type SharedPull = Shared<BoxFuture<'static, Result<PulledImage, Arc<anyhow::Error>>>>;
+
+#[derive(Builder, Clone, CopyGetters, Debug, Getters)]
+#[builder(pattern = "owned", setter(into), build_fn(validate = "Self::validate"))]
+/// Pulls images from registries into a blob store.
+pub struct Puller {
+    #[get = "pub"]
+    /// The registry client.
+    client: Client,
+
+    #[get = "pub"]
+    /// The store to write the pulled content into.
+    store: BlobStore,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The platform to select from image indexes, which defaults to the current one.
+    platform: Platform,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The signature policy every pulled image has to satisfy, which accepts all images by
+    /// default.
+    policy: Policy,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The lookaside storages to read simple signing signatures from.
+    lookaside: Lookaside,
+
+    #[get_copy = "pub"]
+    #[builder(default = "DEFAULT_MAX_CONCURRENT_DOWNLOADS")]
+    /// The maximum number of blobs downloaded at the same time across all pulls.
+    max_concurrent_downloads: usize,
+
+    #[get = "pub"]
+    #[builder(default, setter(strip_option))]
+    /// The garbage collector to pause while pulling, which protects the pulled content until
+    /// it is referenced by an image.
+    garbage_collector: Option<GarbageCollector>,
+
+    #[builder(
+        setter(skip),
+        default = "Self::default_state(self.max_concurrent_downloads)"
+    )]
+    /// The pulls and downloads in flight, which are shared by all clones of the puller.
+    state: Arc<PullState>,
+}
+
+impl PullerBuilder {
+    /// Create the initial state, which limits the downloads to the configured maximum.
+    fn default_state(max_concurrent_downloads: Option<usize>) -> Arc<PullState> {
+        Arc::new(PullState::new(
+            max_concurrent_downloads.unwrap_or(DEFAULT_MAX_CONCURRENT_DOWNLOADS),
+        ))
+    }
+
+    /// Ensure that downloads are possible at all.
+    fn validate(&self) -> Result<(), String> {
+        if self.max_concurrent_downloads == Some(0) {
+            return Err("max concurrent downloads must not be zero".into());
+        }
+        Ok(())
+    }
+}
+
+/// The pulls and blob downloads in flight.
+struct PullState {
+    /// Running pulls by their reference and credentials.
+    pulls: Mutex<HashMap<PullKey, RunningPull>>,
+
+    /// Locks which ensure that every blob is only downloaded once at a time.
+    blobs: Mutex<HashMap<Digest, Arc<AsyncMutex<()>>>>,
+
+    /// Limits the number of concurrent downloads.
+    downloads: Semaphore,
+}
+
+impl fmt::Debug for PullState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PullState")
+            .field("pulls", &lock(&self.pulls).len())
+            .field("downloads", &self.downloads.available_permits())
+            .finish()
+    }
+}
+
+impl PullState {
+    /// Create a new state allowing `max_concurrent_downloads`.
+    fn new(max_concurrent_downloads: usize) -> Self {
+        Self {
+            pulls: Mutex::default(),
+            blobs: Mutex::default(),
+            downloads: Semaphore::new(max_concurrent_downloads),
+        }
+    }
+
+    /// The lock for downloading the blob, whereas locks which are no longer used get removed.
+    fn blob_lock(&self, digest: &Digest) -> Arc<AsyncMutex<()>> {
+        let mut blobs = lock(&self.blobs);
+        blobs.retain(|_, lock| Arc::strong_count(lock) > 1);
+        blobs.entry(digest.clone()).or_default().clone()
+    }
+}
+
+#[derive(Clone)]
+/// A pull in flight.
+struct RunningPull {
+    /// The shared result of the pull.
+    result: SharedPull,
+
+    /// The progress of the pull.
+    progress: Arc<Mutex<PullProgress>>,
+}
+
+#[derive(Clone, CopyGetters, Debug, Getters)]
+/// The progress of a pull in flight.
+pub struct PullProgress {
+    #[get = "pub"]
+    /// The pulled reference.
+    reference: Reference,
+
+    #[get_copy = "pub"]
+    /// The time the pull started.
+    started: SystemTime,
+
+    #[get_copy = "pub"]
+    /// The number of blobs of the image, which is zero until the manifest got resolved.
+    total_blobs: usize,
+
+    #[get_copy = "pub"]
+    /// The number of blobs which are available in the store.
+    completed_blobs: usize,
+
+    #[get_copy = "pub"]
+    /// The size of all blobs of the image.
+    total_bytes: u64,
+
+    #[get_copy = "pub"]
+    /// The amount of bytes which are downloaded or have already been available.
+    completed_bytes: u64,
+}
+
+impl fmt::Display for PullProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}/{} blobs, {}/{} bytes",
+            self.reference,
+            self.completed_blobs,
+            self.total_blobs,
+            self.completed_bytes,
+            self.total_bytes
+        )
+    }
+}
+
+impl PullProgress {
+    /// Create the progress of a pull which did not resolve its manifest yet.
+    fn new(reference: Reference) -> Self {
+        Self {
+            reference,
+            started: SystemTime::now(),
+            total_blobs: 0,
+            completed_blobs: 0,
+            total_bytes: 0,
+            completed_bytes: 0,
+        }
+    }
+
+    /// Start downloading the blobs of a resolved manifest.
+    fn start(&mut self, total_blobs: usize, total_bytes: u64) {
+        self.total_blobs = total_blobs;
+        self.total_bytes = total_bytes;
+        self.completed_blobs = 0;
+        self.completed_bytes = 0;
+    }
+}
+
+/// Lock the mutex, regardless if it is poisoned.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Clone, Debug, Getters)]
+/// The result of a successful pull.
+pub struct PulledImage {
+    #[get = "pub"]
+    /// The image ID, which is the digest of the image configuration.
+    id: Digest,
+
+    #[get = "pub"]
+    /// The pulled reference.
+    reference: Reference,
+
+    #[get = "pub"]
+    /// The digest of the manifest or index the reference resolved to, which is the repository
+    /// digest of the image.
+    digest: Digest,
+
+    #[get = "pub"]
+    /// The digest of the platform specific image manifest.
+    manifest_digest: Digest,
+
+    #[get = "pub"]
+    /// The image manifest.
+    manifest: ImageManifest,
+
+    #[get = "pub"]
+    /// The total size of the configuration and all layers as stored.
+    size: u64,
+
+    /// Pauses the garbage collection as long as any clone of the pulled image exists.
+    _pause: Option<Arc<OwnedRwLockReadGuard<()>>>,
+}
+
+impl Puller {
+    /// Pull the image for the reference. The manifest, configuration and all layers are verified
+    /// and written into the blob store, whereas blobs which already exist are not downloaded
+    /// again. The configured mirrors are tried in order before the registry itself. If no
+    /// `credentials` are provided, then they are looked up from the credential store of the
+    /// client for every location. If the image got rejected by the signature policy, then the
+    /// returned error is the `PolicyViolation`.
+    ///
+    /// A pull of the same reference and credentials which is already running gets joined
+    /// instead of starting a new one. Pulls run in the background, which means that they
+    /// complete even if the caller stops waiting for them. The configured garbage collector
+    /// stays paused until the returned image and all its clones got dropped.
+    pub async fn pull(
+        &self,
+        reference: &Reference,
+        credentials: Option<&Credentials>,
+    ) -> Result<PulledImage> {
+        let key = (reference.clone(), credentials.cloned());
+        let result = {
+            let mut pulls = lock(&self.state.pulls);
+            match pulls.get(&key) {
+                Some(running) => {
+                    debug!("Joining running pull of image {}", reference);
+                    running.result.clone()
+                }
+                None => {
+                    let running = self.start(key.clone());
+                    let result = running.result.clone();
+                    pulls.insert(key, running);
+                    result
+                }
+            }
+        };
+
+        result
+            .await
+            .map_err(|e| match e.downcast_ref::<PolicyViolation>() {
+                Some(violation) => violation.clone().into(),
+                None => anyhow!("{:#}", e),
+            })
+    }
+
+    /// The progress of all pulls in flight.
+    pub fn progress(&self) -> Vec<PullProgress> {
+        lock(&self.state.pulls)
+            .values()
+            .map(|running| lock(&running.progress).clone())
+            .collect()
+    }
+
+    /// Start pulling the image in the background. The progress gets logged periodically and the
+    /// pull removes itself from the running ones once it is done. The garbage collection is
+    /// paused by the pull itself, because it has to complete even if every caller stopped
+    /// waiting for it.
+    fn start(&self, key: PullKey) -> RunningPull {
+        let progress = Arc::new(Mutex::new(PullProgress::new(key.0.clone())));
+        let puller = self.clone();
+        let task_progress = progress.clone();
+        let task = tokio::spawn(async move {
+            let pause = match &puller.garbage_collector {
+                Some(garbage_collector) => Some(Arc::new(garbage_collector.pause().await)),
+                None => None,
+            };
+            let (reference, credentials) = &key;
+            let pull = puller.pull_sources(reference, credentials.as_ref(), &task_progress);
+            tokio::pin!(pull);
+            let mut interval = tokio::time::interval(PROGRESS_LOG_INTERVAL);
+            interval.tick().await;
+            let result = loop {
+                tokio::select! {
+                    result = &mut pull => break result,
+                    _ = interval.tick() => info!("Pulling image {}", lock(&task_progress)),
+                }
+            };
+            lock(&puller.state.pulls).remove(&key);
+            result
+                .map(|pulled| PulledImage {
+                    _pause: pause,
+                    ..pulled
+                })
+                .map_err(Arc::new)
+        });
+
+        let result = async move {
+            task.await
+                .unwrap_or_else(|e| Err(Arc::new(anyhow!("join pull: {}", e))))
+        }
+        .boxed()
+        .shared();
+        RunningPull { result, progress }
+    }
+
+    /// Pull the image by trying all locations of the reference in order.
+    async fn pull_sources(
+        &self,
+        reference: &Reference,
+        credentials: Option<&Credentials>,
+        progress: &Mutex<PullProgress>,
+    ) -> Result<PulledImage> {
+        info!("Pulling image {}", reference);
+        let sources = self.client.registries().sources(reference)?;
+
+        let mut errors = vec![];
+        let mut violation = None;
+        for source in &sources {
+            match self
+                .pull_source(reference, source, credentials, progress)
+                .await
+            {
+                Ok(pulled) => return Ok(pulled),
+                Err(e) => {
+                    warn!("Unable to pull {} from {}: {:#}", reference, source, e);
+                    errors.push(format!("{}: {:#}", source, e));
+                    if e.is::<PolicyViolation>() {
+                        violation.get_or_insert(e);
+                    }
+                }
+            }
+        }
+        if let Some(violation) = violation {
+            return Err(violation);
+        }
+        bail!("unable to pull image {}: {}", reference, errors.join(", "))
+    }
+
+    /// Pull the first image which succeeds from the candidates, which are usually the result of
+    /// the short name resolution. The first `PolicyViolation` is returned if no candidate
+    /// could be pulled.
+    pub async fn pull_any(
+        &self,
+        candidates: &[Reference],
+        credentials: Option<&Credentials>,
+    ) -> Result<PulledImage> {
+        let mut errors = vec![];
+        let mut violation = None;
+        for candidate in candidates {
+            match self.pull(candidate, credentials).await {
+                Ok(pulled) => return Ok(pulled),
+                Err(e) => {
+                    errors.push(format!("{:#}", e));
+                    if e.is::<PolicyViolation>() {
+                        violation.get_or_insert(e);
+                    }
+                }
+            }
+        }
+        if let Some(violation) = violation {
+            return Err(violation);
+        }
+        if errors.is_empty() {
+            bail!("no image candidates provided")
+        }
+        bail!("{}", errors.join(", "))
+    }
+
+    /// Pull the image for the reference from a single location.
+    async fn pull_source(
+        &self,
+        reference: &Reference,
+        source: &Reference,
+        credentials: Option<&Credentials>,
+        progress: &Mutex<PullProgress>,
+    ) -> Result<PulledImage> {
+        if source != reference {
+            debug!("Pulling image {} from {}", reference, source);
+        }
+        let credentials = match credentials {
+            Some(credentials) => Some(credentials.clone()),
+            None => self
+                .client
+                .credential_store()
+                .lookup(source)
+                .await
+                .context("lookup credentials")?,
+        };
+        let credentials = credentials.as_ref();
+        let (digest, manifest_digest, manifest, fetched) =
+            self.resolve(source, credentials).await?;
+
+        let signatures = RegistrySignatures {
+            puller: self,
+            reference,
+            source,
+            credentials,
+        };
+        self.policy.verify(reference, &digest, &signatures).await?;
+        for fetched in fetched {
+            self.store
+                .write(fetched.content(), Some(fetched.digest()))
+                .await
+                .context("store manifest")?;
+        }
+
+        let blobs = std::iter::once(manifest.config())
+            .chain(manifest.layers())
+            .collect::<Vec<_>>();
+        let size = blobs.iter().map(|d| d.size().max(0) as u64).sum();
+        lock(progress).start(blobs.len(), size);
+        try_join_all(
+            blobs
+                .into_iter()
+                .map(|descriptor| self.fetch_blob(source, descriptor, credentials, progress)),
+        )
+        .await?;
+
+        let id = manifest.config().digest().parse::<Digest>()?;
+        info!("Pulled image {} with ID {}", reference, id);
+
+        Ok(PulledImage {
+            id,
+            reference: reference.clone(),
+            digest,
+            manifest_digest,
+            manifest,
+            size,
+            _pause: None,
+        })
+    }
+
+    /// Resolve the reference to a platform specific image manifest. Returns the digest the
+    /// reference resolved to, together with the digest of the platform specific manifest, the
+    /// manifest itself and every manifest on the way, which get stored once the image has been
+    /// verified.
+    async fn resolve(
+        &self,
+        reference: &Reference,
+        credentials: Option<&Credentials>,
+    ) -> Result<(Digest, Digest, ImageManifest, Vec<FetchedManifest>)> {
+        let mut current = reference.clone();
+        let mut digest = None;
+        let mut manifests = vec![];
+        for _ in 0..=MAX_INDEX_DEPTH {
+            let fetched = self.client.fetch_manifest(&current, credentials).await?;
+            let digest = digest.get_or_insert_with(|| fetched.digest().clone());
+
+            match Manifest::parse(fetched.media_type().as_deref(), fetched.content())? {
+                Manifest::Image(manifest) => {
+                    let manifest_digest = fetched.digest().clone();
+                    manifests.push(fetched);
+                    return Ok((digest.clone(), manifest_digest, manifest, manifests));
+                }
+                Manifest::Index(index) => {
+                    let descriptor = manifest::select_platform(&index, &self.platform)?;
+                    debug!(
+                        "Selected manifest {} from index {}",
+                        descriptor.digest(),
+                        fetched.digest()
+                    );
+                    current = reference.with_digest(descriptor.digest().parse()?);
+                    manifests.push(fetched);
+                }
+            }
+        }
+        bail!("too many nested indexes for {}", reference)
+    }
+
+    /// Download a single blob into the store if it does not exist yet. Concurrent downloads of
+    /// the same blob wait for each other, whereas partial content of previous downloads gets
+    /// resumed.
+    async fn fetch_blob(
+        &self,
+        reference: &Reference,
+        descriptor: &Descriptor,
+        credentials: Option<&Credentials>,
+        progress: &Mutex<PullProgress>,
+    ) -> Result<()> {
+        let digest = descriptor.digest().parse::<Digest>()?;
+        let size = descriptor.size().max(0) as u64;
+        let blob_lock = self.state.blob_lock(&digest);
+        let _blob_guard = blob_lock.lock().await;
+        if self.store.contains(&digest).await {
+            debug!("Blob {} already exists", digest);
+            let mut progress = lock(progress);
+            progress.completed_blobs += 1;
+            progress.completed_bytes += size;
+            return Ok(());
+        }
+
+        let _permit = self
+            .state
+            .downloads
+            .acquire()
+            .await
+            .context("acquire download permit")?;
+        let mut writer = self.store.resumable_writer(digest.clone()).await?;
+        if writer.size() > size {
+            writer.truncate().await?;
+        }
+        lock(progress).completed_bytes += writer.size();
+
+        let mut retries = 0;
+        while writer.size() < size {
+            let offset = writer.size();
+            let response = self
+                .client
+                .fetch_blob_from(reference, &digest, offset, credentials)
+                .await?;
+            if offset > 0 {
+                if response.status() == StatusCode::PARTIAL_CONTENT {
+                    debug!("Resuming download of blob {} at {} bytes", digest, offset);
+                } else {
+                    debug!("Registry ignored range request for blob {}", digest);
+                    writer.truncate().await?;
+                    lock(progress).completed_bytes -= offset;
+                }
+            }
+
+            let mut stream = response.bytes_stream();
+            let mut interrupted = None;
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        if writer.size() + chunk.len() as u64 > size {
+                            writer.discard().await?;
+                            bail!(
+                                "blob {} exceeds its expected size of {} bytes",
+                                digest,
+                                size
+                            );
+                        }
+                        writer.write(&chunk).await?;
+                        lock(progress).completed_bytes += chunk.len() as u64;
+                    }
+                    Err(e) => {
+                        interrupted = Some(e);
+                        break;
+                    }
+                }
+            }
+            match interrupted {
+                None => break,
+                Some(e) if retries < MAX_DOWNLOAD_RETRIES => {
+                    retries += 1;
+                    warn!(
+                        "Download of blob {} interrupted after {} bytes: {}",
+                        digest,
+                        writer.size(),
+                        e
+                    );
+                }
+                Some(e) => return Err(e).with_context(|| format!("download blob {}", digest)),
+            }
+        }
+
+        if writer.size() != size {
+            let written = writer.size();
+            writer.discard().await?;
+            bail!(
+                "size mismatch for blob {}: expected {} bytes, got {}",
+                digest,
+                size,
+                written
+            );
+        }
+        writer.commit().await?;
+        lock(progress).completed_blobs += 1;
+        debug!("Downloaded blob {}", digest);
+        Ok(())
+    }
+}
+
+/// The signatures of an image pulled from a single location.
+struct RegistrySignatures<'a> {
+    /// The puller of the image.
+    puller: &'a Puller,
+
+    /// The pulled reference.
+    reference: &'a Reference,
+
+    /// The location the image gets pulled from.
+    source: &'a Reference,
+
+    /// The credentials for the location.
+    credentials: Option<&'a Credentials>,
+}
+
+#[async_trait]
+impl SignatureSource for RegistrySignatures<'_> {
+    async fn simple_signatures(&self, digest: &Digest) -> Result<Vec<Vec<u8>>> {
+        self.puller
+            .lookaside
+            .signatures(self.reference, digest)
+            .await
+    }
+
+    async fn sigstore_signatures(&self, digest: &Digest) -> Result<Vec<SigstoreSignature>> {
+        let tag = format!("{}-{}.sig", digest.algorithm(), digest.encoded());
+        let reference = self.source.with_tag(&tag)?;
+        let fetched = match self
+            .puller
+            .client
+            .fetch_manifest(&reference, self.credentials)
+            .await
+        {
+            Ok(fetched) => fetched,
+            Err(e)
+                if e.downcast_ref::<StatusError>()
+                    .is_some_and(|e| e.status() == StatusCode::NOT_FOUND) =>
+            {
+                debug!("No sigstore signatures found for {}", self.source);
+                return Ok(vec![]);
+            }
+            Err(e) => return Err(e),
+        };
+        let manifest = match Manifest::parse(fetched.media_type().as_deref(), fetched.content())? {
+            Manifest::Image(manifest) => manifest,
+            Manifest::Index(_) => bail!("signatures {} are not an image manifest", reference),
+        };
+
+        let mut signatures = vec![];
+        for layer in manifest.layers() {
+            let signature = match layer
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(SIGSTORE_SIGNATURE_ANNOTATION))
+            {
+                Some(signature) => signature.clone(),
+                None => continue,
+            };
+            if layer.size() > MAX_SIGNATURE_PAYLOAD_SIZE {
+                bail!("signature payload of {} bytes is too large", layer.size())
+            }
+            let digest = layer.digest().parse::<Digest>()?;
+            let payload = self
+                .puller
+                .client
+                .fetch_blob(self.source, &digest, self.credentials)
+                .await?
+                .bytes()
+                .await
+                .context("read signature payload")?;
+            if Digest::from_bytes(digest.algorithm(), &payload) != digest {
+                bail!("signature payload does not match digest {}", digest)
+            }
+            signatures.push(SigstoreSignature::new(payload.to_vec(), signature));
+        }
+        debug!(
+            "Found {} sigstore signatures for {}",
+            signatures.len(),
+            self.source
+        );
+        Ok(signatures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gc::GarbageCollectorBuilder,
+        registry::{
+            auth::CredentialStoreBuilder,
+            config::{MirrorBuilder, RegistriesConfigBuilder, RegistryBuilder},
+            ClientBuilder,
+        },
+        snapshot::{Driver, Snapshotter},
+        testing::{TestAuth, TestRegistry},
+    };
+    use oci_spec::image::{Arch, Os, PlatformBuilder};
+    use p256::{
+        ecdsa::{signature::Signer, DerSignature, SigningKey},
+        pkcs8::{EncodePublicKey, LineEnding},
+    };
+    use rand::rngs::OsRng;
+    use serde_json::{json, Value};
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn new_puller(dir: &TempDir) -> Result<Puller> {
+        Ok(PullerBuilder::default()
+            .client(Client::default())
+            .store(BlobStore::open(dir.path())?)
+            .build()?)
+    }
+
+    #[tokio::test]
+    async fn pull_by_tag() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer1", b"layer2"])?;
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        let pulled = puller.pull(&reference, None).await?;
+
+        assert_eq!(pulled.id(), image.config_digest());
+        assert_eq!(pulled.digest(), image.manifest_digest());
+        assert_eq!(pulled.manifest_digest(), image.manifest_digest());
+        assert_eq!(pulled.manifest().layers().len(), 2);
+        for digest in image
+            .layers()
+            .iter()
+            .chain([image.config_digest(), image.manifest_digest()])
+        {
+            assert!(puller.store().contains(digest).await);
+        }
+        assert_eq!(puller.store().read(&image.layers()[1]).await?, b"layer2");
+        Ok(())
+    }
+
+    fn new_puller_with_policy(dir: &TempDir, policy: Value) -> Result<Puller> {
+        Ok(PullerBuilder::default()
+            .client(Client::default())
+            .store(BlobStore::open(dir.path())?)
+            .policy(Policy::parse(
+                &serde_json::to_vec(&policy)?,
+                Path::new("/"),
+            )?)
+            .build()?)
+    }
+
+    #[tokio::test]
+    async fn pull_sigstore_signed() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        let key = SigningKey::random(&mut OsRng);
+        let pem = key.verifying_key().to_public_key_pem(LineEnding::LF)?;
+        let dir = TempDir::new()?;
+        let puller = new_puller_with_policy(
+            &dir,
+            json!({"default": [{"type": "sigstoreSigned", "keyData": base64::encode(pem)}]}),
+        )?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse::<Reference>()?;
+        let err = puller.pull(&reference, None).await.unwrap_err();
+        let violation = err
+            .downcast_ref::<PolicyViolation>()
+            .context("no violation")?;
+        assert_eq!(violation.reference(), &reference);
+        assert!(!puller.store().contains(&image.layers()[0]).await);
+        assert!(!puller.store().contains(image.manifest_digest()).await);
+
+        let payload = json!({
+            "critical": {
+                "type": "cosign container image signature",
+                "identity": {"docker-reference": reference.to_string()},
+                "image": {"docker-manifest-digest": image.manifest_digest().to_string()},
+            },
+            "optional": null,
+        })
+        .to_string();
+        let signature: DerSignature = key.sign(payload.as_bytes());
+        let config = b"{}";
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": registry.add_blob(config).to_string(),
+                "size": config.len(),
+            },
+            "layers": [{
+                "mediaType": "application/vnd.dev.cosign.simplesigning.v1+json",
+                "digest": registry.add_blob(payload.as_bytes()).to_string(),
+                "size": payload.len(),
+                "annotations": {
+                    SIGSTORE_SIGNATURE_ANNOTATION: base64::encode(signature.as_bytes()),
+                },
+            }],
+        });
+        let digest = image.manifest_digest();
+        registry.add_manifest(
+            "app",
+            Some(&format!("{}-{}.sig", digest.algorithm(), digest.encoded())),
+            "application/vnd.oci.image.manifest.v1+json",
+            &serde_json::to_vec(&manifest)?,
+        );
+
+        let pulled = puller.pull(&reference, None).await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        assert!(puller.store().contains(&image.layers()[0]).await);
+
+        let other = format!("{}/other:v1", registry.host()).parse::<Reference>()?;
+        registry.tag("app", "v2", digest)?;
+        let renamed = format!("{}/app:v2", registry.host()).parse::<Reference>()?;
+        assert!(puller
+            .pull(&renamed, None)
+            .await
+            .unwrap_err()
+            .is::<PolicyViolation>());
+        assert!(!puller
+            .pull(&other, None)
+            .await
+            .unwrap_err()
+            .is::<PolicyViolation>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_any_policy_violation() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.add_image("b/app", Some("latest"), &[b"layer"])?;
+        let dir = TempDir::new()?;
+        let puller = new_puller_with_policy(
+            &dir,
+            json!({
+                "default": [{"type": "insecureAcceptAnything"}],
+                "transports": {"docker": {registry.host(): [{"type": "reject"}]}},
+            }),
+        )?;
+
+        let candidates = vec![
+            format!("{}/a/app", registry.host()).parse()?,
+            format!("{}/b/app", registry.host()).parse()?,
+        ];
+        let err = puller.pull_any(&candidates, None).await.unwrap_err();
+        let violation = err
+            .downcast_ref::<PolicyViolation>()
+            .context("no violation")?;
+        assert_eq!(violation.reference(), &candidates[1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_by_digest() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("a/b", None, &[b"layer"])?;
+        let dir = TempDir::new()?;
+
+        let reference = format!("{}/a/b@{}", registry.host(), image.manifest_digest()).parse()?;
+        let pulled = new_puller(&dir)?.pull(&reference, None).await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_index_current_platform() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let current = registry.add_image("app", None, &[b"current"])?;
+        let other = registry.add_image("app", None, &[b"other"])?;
+        let other_platform = PlatformBuilder::default()
+            .os(Os::Other("plan9".into()))
+            .architecture(Arch::default())
+            .build()?;
+        let index = registry.add_index(
+            "app",
+            Some("latest"),
+            &[(&other, other_platform), (&current, Platform::default())],
+        )?;
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        let pulled = puller
+            .pull(&format!("{}/app", registry.host()).parse()?, None)
+            .await?;
+        assert_eq!(pulled.id(), current.config_digest());
+        assert_eq!(pulled.digest(), &index);
+        assert_eq!(pulled.manifest_digest(), current.manifest_digest());
+        assert!(!puller.store().contains(&other.layers()[0]).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_existing_blobs_skipped() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+
+        puller.pull(&reference, None).await?;
+        puller.pull(&reference, None).await?;
+
+        let layer_request = format!("GET /v2/app/blobs/{}", image.layers()[0]);
+        assert_eq!(
+            registry
+                .requests()
+                .iter()
+                .filter(|r| **r == layer_request)
+                .count(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_coalesced() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        registry.set_blob_delay(Duration::from_millis(100));
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+        let reference = format!("{}/app:v1", registry.host()).parse::<Reference>()?;
+
+        let running = tokio::spawn({
+            let puller = puller.clone();
+            let reference = reference.clone();
+            async move { puller.pull(&reference, None).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let progress = puller.progress();
+        assert_eq!(progress.len(), 1);
+        assert_eq!(progress[0].reference(), &reference);
+        assert_eq!(progress[0].total_blobs(), 2);
+
+        let pulled = puller.pull(&reference, None).await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        assert_eq!(running.await??.id(), image.config_digest());
+        assert!(puller.progress().is_empty());
+
+        let manifest_request = "GET /v2/app/manifests/v1".to_string();
+        assert_eq!(
+            registry
+                .requests()
+                .iter()
+                .filter(|r| **r == manifest_request)
+                .count(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_pauses_garbage_collection() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.add_image("app", Some("v1"), &[b"layer1", b"layer2"])?;
+        registry.set_blob_delay(Duration::from_millis(500));
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path().join("store"))?;
+        let gc = GarbageCollectorBuilder::default()
+            .store(store.clone())
+            .snapshotter(Snapshotter::open(
+                dir.path().join("snapshots"),
+                Driver::Vfs,
+            )?)
+            .build()?;
+        let puller = PullerBuilder::default()
+            .client(Client::default())
+            .store(store)
+            .garbage_collector(gc.clone())
+            .build()?;
+        let reference = format!("{}/app:v1", registry.host()).parse::<Reference>()?;
+
+        // The pull continues in the background after the caller stopped waiting
+        let pull = tokio::time::timeout(Duration::from_millis(50), puller.pull(&reference, None));
+        assert!(pull.await.is_err());
+        let collect = tokio::time::timeout(Duration::from_millis(50), gc.collect(&[]));
+        assert!(collect.await.is_err());
+
+        let pulled = puller.pull(&reference, None).await?;
+        let collect = tokio::time::timeout(Duration::from_millis(50), gc.collect(&[]));
+        assert!(collect.await.is_err());
+
+        drop(pulled);
+        assert_eq!(gc.collect(&[]).await?.removed_blobs(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_shared_layers() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let a = registry.add_image("a", Some("latest"), &[b"shared", b"a"])?;
+        registry.add_image("b", Some("latest"), &[b"shared", b"b"])?;
+        registry.set_blob_delay(Duration::from_millis(50));
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        let reference_a = format!("{}/a", registry.host()).parse()?;
+        let reference_b = format!("{}/b", registry.host()).parse()?;
+        let (pulled_a, pulled_b) = tokio::join!(
+            puller.pull(&reference_a, None),
+            puller.pull(&reference_b, None),
+        );
+        pulled_a?;
+        pulled_b?;
+
+        let shared = a.layers()[0].to_string();
+        assert_eq!(
+            registry
+                .requests()
+                .iter()
+                .filter(|r| r.ends_with(&shared))
+                .count(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_max_concurrent_downloads() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"1", b"2", b"3"])?;
+        registry.set_blob_delay(Duration::from_millis(20));
+        let dir = TempDir::new()?;
+        let puller = PullerBuilder::default()
+            .client(Client::default())
+            .store(BlobStore::open(dir.path())?)
+            .max_concurrent_downloads(1usize)
+            .build()?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        let pulled = puller.pull(&reference, None).await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        assert_eq!(registry.max_concurrent_blob_requests(), 1);
+
+        assert!(PullerBuilder::default()
+            .client(Client::default())
+            .store(puller.store().clone())
+            .max_concurrent_downloads(0usize)
+            .build()
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_resume_interrupted() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer content"])?;
+        let layer = &image.layers()[0];
+        registry.interrupt_blob(layer, 5);
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        puller.pull(&reference, None).await?;
+        assert_eq!(puller.store().read(layer).await?, b"layer content");
+        assert!(registry
+            .requests()
+            .contains(&format!("GET /v2/app/blobs/{} bytes=5-", layer)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_resume_partial() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer content"])?;
+        let layer = &image.layers()[0];
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        // Partial content of a previous pull which got interrupted
+        let mut writer = puller.store().resumable_writer(layer.clone()).await?;
+        writer.write(b"layer").await?;
+        drop(writer);
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        puller.pull(&reference, None).await?;
+        assert_eq!(puller.store().read(layer).await?, b"layer content");
+        assert!(registry
+            .requests()
+            .contains(&format!("GET /v2/app/blobs/{} bytes=5-", layer)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_with_credentials() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        registry.set_auth(TestAuth::Bearer {
+            username: "user".into(),
+            password: "pass".into(),
+            identity_token: "refresh".into(),
+        });
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+
+        assert!(puller.pull(&reference, None).await.is_err());
+        let pulled = puller
+            .pull(
+                &reference,
+                Some(&Credentials::IdentityToken("refresh".into())),
+            )
+            .await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_with_credentials_from_auth_file() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        registry.set_auth(TestAuth::Basic {
+            username: "user".into(),
+            password: "pass".into(),
+        });
+        let dir = TempDir::new()?;
+        let auth_file = dir.path().join("auth.json");
+        std::fs::write(
+            &auth_file,
+            format!(
+                r#"{{"auths": {{"{}": {{"auth": "{}"}}}}}}"#,
+                registry.host(),
+                base64::encode("user:pass")
+            ),
+        )?;
+        let client = ClientBuilder::default()
+            .credential_store(
+                CredentialStoreBuilder::default()
+                    .auth_files(vec![auth_file])
+                    .build()?,
+            )
+            .build()?;
+        let puller = PullerBuilder::default()
+            .client(client)
+            .store(BlobStore::open(dir.path().join("store"))?)
+            .build()?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        let pulled = puller.pull(&reference, None).await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        Ok(())
+    }
+
+    fn new_mirrored_puller(
+        dir: &TempDir,
+        registry: &TestRegistry,
+        mirror: &TestRegistry,
+        blocked: bool,
+    ) -> Result<Puller> {
+        let client = ClientBuilder::default()
+            .registries(
+                RegistriesConfigBuilder::default()
+                    .registries(vec![RegistryBuilder::default()
+                        .prefix(registry.host())
+                        .blocked(blocked)
+                        .mirrors(vec![MirrorBuilder::default()
+                            .location(format!("{}/mirror", mirror.host()))
+                            .build()?])
+                        .build()?])
+                    .build()?,
+            )
+            .build()?;
+        Ok(PullerBuilder::default()
+            .client(client)
+            .store(BlobStore::open(dir.path())?)
+            .build()?)
+    }
+
+    #[tokio::test]
+    async fn pull_from_mirror() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let mirror = TestRegistry::start().await?;
+        let image = mirror.add_image("mirror/app", Some("v1"), &[b"layer"])?;
+        let dir = TempDir::new()?;
+        let puller = new_mirrored_puller(&dir, &registry, &mirror, false)?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        let pulled = puller.pull(&reference, None).await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        assert_eq!(pulled.reference(), &reference);
+        assert!(registry.requests().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_mirror_fallback() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let mirror = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        let dir = TempDir::new()?;
+        let puller = new_mirrored_puller(&dir, &registry, &mirror, false)?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        let pulled = puller.pull(&reference, None).await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        assert!(!mirror.requests().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_failure_blocked() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let mirror = TestRegistry::start().await?;
+        registry.add_image("app", Some("v1"), &[b"layer"])?;
+        let dir = TempDir::new()?;
+        let puller = new_mirrored_puller(&dir, &registry, &mirror, true)?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        assert!(puller.pull(&reference, None).await.is_err());
+        assert!(registry.requests().is_empty());
+        assert!(mirror.requests().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_any() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("b/app", Some("latest"), &[b"layer"])?;
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        let candidates = vec![
+            format!("{}/a/app", registry.host()).parse()?,
+            format!("{}/b/app", registry.host()).parse()?,
+        ];
+        let pulled = puller.pull_any(&candidates, None).await?;
+        assert_eq!(pulled.id(), image.config_digest());
+        assert_eq!(pulled.reference(), &candidates[1]);
+
+        assert!(puller.pull_any(&candidates[..1], None).await.is_err());
+        assert!(puller.pull_any(&[], None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_failure_not_found() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let dir = TempDir::new()?;
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        assert!(new_puller(&dir)?.pull(&reference, None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_failure_corrupt_layer() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        registry.replace_blob(&image.layers()[0], b"LAYER");
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        assert!(puller.pull(&reference, None).await.is_err());
+        assert!(!puller.store().contains(&image.layers()[0]).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_failure_oversized_layer() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        registry.replace_blob(&image.layers()[0], &vec![0; 1 << 20]);
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        let err = puller.pull(&reference, None).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("exceeds its expected size"));
+        assert!(!puller.store().contains(&image.layers()[0]).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_failure_missing_layer() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        registry.remove_blob(&image.layers()[0]);
+        let dir = TempDir::new()?;
+        let puller = new_puller(&dir)?;
+
+        let reference = format!("{}/app:v1", registry.host()).parse()?;
+        assert!(puller.pull(&reference, None).await.is_err());
+        assert!(!puller.store().contains(&image.layers()[0]).await);
+        Ok(())
+    }
+}
@@ -0,0 +1,526 @@
+//! Image reference parsing and normalization.
+//!
+//! The grammar follows the [distribution reference][0] implementation, including the Docker
+//! specific normalization of short names, for example `nginx` becomes
+//! `docker.io/library/nginx:latest`.
+//!
+//! [0]: https://github.com/distribution/distribution/blob/main/reference/reference.go
+
+use crate::digest::{Digest, DigestError};
+use getset::Getters;
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt, str::FromStr};
+use thiserror::Error;
+
+/// The registry used if the reference does not contain one.
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// The legacy name of the default registry, which gets normalized to `DEFAULT_REGISTRY`.
+const LEGACY_DEFAULT_REGISTRY: &str = "index.docker.io";
+
+/// The repository prefix of official images on the default registry.
+const OFFICIAL_REPOSITORY_PREFIX: &str = "library/";
+
+/// The tag used if the reference neither contains a tag nor a digest.
+pub const DEFAULT_TAG: &str = "latest";
+
+/// Maximum length of the full name, which is the registry and the repository.
+const NAME_MAX_LEN: usize = 255;
+
+/// Maximum length of a tag.
+const TAG_MAX_LEN: usize = 128;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+/// Errors which can occur on image reference parsing.
+pub enum ReferenceError {
+    #[error("image reference is empty")]
+    Empty,
+
+    #[error("repository name {0:?} must be lowercase")]
+    Uppercase(String),
+
+    #[error("repository name {0:?} must not be longer than {NAME_MAX_LEN} characters")]
+    NameTooLong(String),
+
+    #[error("invalid registry {0:?}")]
+    InvalidRegistry(String),
+
+    #[error("invalid repository path component {0:?}")]
+    InvalidPathComponent(String),
+
+    #[error("invalid tag {0:?}")]
+    InvalidTag(String),
+
+    #[error("invalid digest: {0}")]
+    InvalidDigest(#[from] DigestError),
+
+    #[error("repository name {0:?} cannot be a 64-byte hexadecimal string")]
+    AmbiguousId(String),
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, Getters, Hash, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+/// A fully qualified and normalized image reference.
+pub struct Reference {
+    #[get = "pub"]
+    /// The registry host, including an optional port.
+    registry: String,
+
+    #[get = "pub"]
+    /// The repository path on the registry.
+    repository: String,
+
+    #[get = "pub"]
+    /// The tag of the image, which is always set if no digest is available.
+    tag: Option<String>,
+
+    #[get = "pub"]
+    /// The content digest of the image manifest.
+    digest: Option<Digest>,
+}
+
+impl Reference {
+    /// The full name of the reference, which is the registry and the repository.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.registry, self.repository)
+    }
+
+    /// Return a new reference pointing to the provided digest within the same repository.
+    pub fn with_digest(&self, digest: Digest) -> Self {
+        Self {
+            registry: self.registry.clone(),
+            repository: self.repository.clone(),
+            tag: None,
+            digest: Some(digest),
+        }
+    }
+
+    /// Return a new reference pointing to the provided tag within the same repository.
+    pub fn with_tag(&self, tag: &str) -> Result<Self, ReferenceError> {
+        Self::validate_tag(tag)?;
+        Ok(Self {
+            registry: self.registry.clone(),
+            repository: self.repository.clone(),
+            tag: Some(tag.into()),
+            digest: None,
+        })
+    }
+
+    /// Return a new reference with the provided name, which is a registry and a repository, while
+    /// keeping the tag and digest of this reference.
+    pub fn with_name(&self, name: &str) -> Result<Self, ReferenceError> {
+        let reference = name.parse::<Self>()?;
+        Ok(Self {
+            tag: self.tag.clone(),
+            digest: self.digest.clone(),
+            ..reference
+        })
+    }
+
+    /// Check if the unparsed reference is a short name, which does not contain a registry.
+    pub fn is_short_name(s: &str) -> bool {
+        Self::split_registry(s).1 == s
+    }
+
+    /// Split off the registry from the name if the first path component looks like a host.
+    fn split_registry(name: &str) -> (&str, &str) {
+        match name.split_once('/') {
+            Some((first, rest))
+                if first.contains(['.', ':'])
+                    || first == "localhost"
+                    || first.chars().any(|c| c.is_ascii_uppercase()) =>
+            {
+                (first, rest)
+            }
+            _ => (DEFAULT_REGISTRY, name),
+        }
+    }
+
+    /// Validate a registry host with an optional port.
+    fn validate_registry(registry: &str) -> Result<(), ReferenceError> {
+        let invalid = || ReferenceError::InvalidRegistry(registry.into());
+        let (host, port) = match registry.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (registry, None),
+        };
+        if let Some(port) = port {
+            if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) {
+                return Err(invalid());
+            }
+        }
+        for component in host.split('.') {
+            if component.is_empty()
+                || component.starts_with('-')
+                || component.ends_with('-')
+                || !component
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                return Err(invalid());
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate a single repository path component, which consists of lowercase alpha-numeric
+    /// characters separated by `.`, `_`, `__` or any number of `-`.
+    fn validate_path_component(component: &str) -> Result<(), ReferenceError> {
+        let invalid = || ReferenceError::InvalidPathComponent(component.into());
+        let is_alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+
+        if !component.starts_with(is_alnum) || !component.ends_with(is_alnum) {
+            return Err(invalid());
+        }
+        for separator in component.split(is_alnum).filter(|s| !s.is_empty()) {
+            let valid = separator == "."
+                || separator == "_"
+                || separator == "__"
+                || separator.chars().all(|c| c == '-');
+            if !valid {
+                return Err(invalid());
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate a tag, which may contain up to 128 word characters, dots and dashes and must not
+    /// start with a dot or dash.
+    fn validate_tag(tag: &str) -> Result<(), ReferenceError> {
+        let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        if tag.len() > TAG_MAX_LEN
+            || !tag.starts_with(is_word)
+            || !tag.chars().all(|c| is_word(c) || c == '.' || c == '-')
+        {
+            return Err(ReferenceError::InvalidTag(tag.into()));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Reference {
+    type Err = ReferenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(ReferenceError::Empty);
+        }
+        if is_image_id(s) {
+            return Err(ReferenceError::AmbiguousId(s.into()));
+        }
+
+        let (rest, digest) = match s.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest.parse::<Digest>()?)),
+            None => (s, None),
+        };
+
+        // A colon after the last slash separates the tag, everything else belongs to the port.
+        let (name, tag) = match rest.rfind(':') {
+            Some(i) if !rest[i..].contains('/') => (&rest[..i], Some(&rest[i + 1..])),
+            _ => (rest, None),
+        };
+        if name.is_empty() {
+            return Err(ReferenceError::Empty);
+        }
+        if let Some(tag) = tag {
+            Self::validate_tag(tag)?;
+        }
+
+        let (registry, repository) = Self::split_registry(name);
+        Self::validate_registry(registry)?;
+        if repository.chars().any(|c| c.is_ascii_uppercase()) {
+            return Err(ReferenceError::Uppercase(repository.into()));
+        }
+        for component in repository.split('/') {
+            Self::validate_path_component(component)?;
+        }
+
+        let registry = if registry == LEGACY_DEFAULT_REGISTRY {
+            DEFAULT_REGISTRY
+        } else {
+            registry
+        };
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("{}{}", OFFICIAL_REPOSITORY_PREFIX, repository)
+        } else {
+            repository.into()
+        };
+        if registry.len() + 1 + repository.len() > NAME_MAX_LEN {
+            return Err(ReferenceError::NameTooLong(repository));
+        }
+
+        let tag = match (tag, &digest) {
+            (Some(tag), _) => Some(tag.into()),
+            (None, None) => Some(DEFAULT_TAG.into()),
+            (None, Some(_)) => None,
+        };
+
+        Ok(Self {
+            registry: registry.into(),
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl TryFrom<String> for Reference {
+    type Error = ReferenceError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Reference> for String {
+    fn from(reference: Reference) -> Self {
+        reference.to_string()
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+/// An image identifier as accepted by the CRI image service, which is either an image ID or an
+/// image reference.
+pub enum ImageIdentifier {
+    /// The image ID, which is the digest of the image configuration.
+    Id(Digest),
+
+    /// A reference by tag or digest.
+    Reference(Reference),
+}
+
+impl FromStr for ImageIdentifier {
+    type Err = ReferenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if is_image_id(s) {
+            return Ok(Self::Id(Digest::new(Default::default(), s)?));
+        }
+        if let Ok(digest) = s.parse::<Digest>() {
+            return Ok(Self::Id(digest));
+        }
+        Ok(Self::Reference(s.parse()?))
+    }
+}
+
+impl fmt::Display for ImageIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(digest) => write!(f, "{}", digest),
+            Self::Reference(reference) => write!(f, "{}", reference),
+        }
+    }
+}
+
+/// Check if the provided string is a plain 64 character hex encoded image ID.
+fn is_image_id(s: &str) -> bool {
+    s.len() == 64
+        && s.chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    #[test]
+    fn parse_normalize_short_name() -> Result<()> {
+        let reference = "nginx".parse::<Reference>()?;
+        assert_eq!(reference.registry(), DEFAULT_REGISTRY);
+        assert_eq!(reference.repository(), "library/nginx");
+        assert_eq!(reference.tag().as_deref(), Some(DEFAULT_TAG));
+        assert!(reference.digest().is_none());
+        assert_eq!(reference.to_string(), "docker.io/library/nginx:latest");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_normalize() -> Result<()> {
+        for (input, expected) in &[
+            ("nginx:1.23", "docker.io/library/nginx:1.23"),
+            ("user/app", "docker.io/user/app:latest"),
+            ("index.docker.io/nginx", "docker.io/library/nginx:latest"),
+            ("docker.io/library/nginx", "docker.io/library/nginx:latest"),
+            ("quay.io/coreos/etcd:v3.5.0", "quay.io/coreos/etcd:v3.5.0"),
+            ("quay.io/etcd", "quay.io/etcd:latest"),
+            ("localhost/app", "localhost/app:latest"),
+            ("localhost:5000/a/b/c:tag", "localhost:5000/a/b/c:tag"),
+            ("127.0.0.1:5000/app", "127.0.0.1:5000/app:latest"),
+            ("registry.k8s.io/pause:3.8", "registry.k8s.io/pause:3.8"),
+            ("my_org/my-app__x.y", "docker.io/my_org/my-app__x.y:latest"),
+        ] {
+            assert_eq!(&input.parse::<Reference>()?.to_string(), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_digest() -> Result<()> {
+        let reference = format!("nginx@{}", DIGEST).parse::<Reference>()?;
+        assert!(reference.tag().is_none());
+        assert_eq!(reference.digest(), &Some(DIGEST.parse()?));
+        assert_eq!(
+            reference.to_string(),
+            format!("docker.io/library/nginx@{}", DIGEST)
+        );
+
+        let reference = format!("quay.io/app:v1@{}", DIGEST).parse::<Reference>()?;
+        assert_eq!(reference.tag().as_deref(), Some("v1"));
+        assert!(reference.digest().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_failure() {
+        assert_eq!("".parse::<Reference>(), Err(ReferenceError::Empty));
+        assert_eq!(":tag".parse::<Reference>(), Err(ReferenceError::Empty));
+        assert_eq!(
+            "Nginx".parse::<Reference>(),
+            Err(ReferenceError::Uppercase("Nginx".into()))
+        );
+        assert_eq!(
+            "quay.io/App".parse::<Reference>(),
+            Err(ReferenceError::Uppercase("App".into()))
+        );
+        assert_eq!(
+            "nginx:-tag".parse::<Reference>(),
+            Err(ReferenceError::InvalidTag("-tag".into()))
+        );
+        assert_eq!(
+            format!("nginx:{}", "a".repeat(129)).parse::<Reference>(),
+            Err(ReferenceError::InvalidTag("a".repeat(129)))
+        );
+        assert_eq!(
+            "a/-b".parse::<Reference>(),
+            Err(ReferenceError::InvalidPathComponent("-b".into()))
+        );
+        assert_eq!(
+            "a//b".parse::<Reference>(),
+            Err(ReferenceError::InvalidPathComponent("".into()))
+        );
+        assert_eq!(
+            "a/b..c".parse::<Reference>(),
+            Err(ReferenceError::InvalidPathComponent("b..c".into()))
+        );
+        assert_eq!(
+            "localhost:port/app".parse::<Reference>(),
+            Err(ReferenceError::InvalidRegistry("localhost:port".into()))
+        );
+        assert_eq!(
+            "-registry.io/app".parse::<Reference>(),
+            Err(ReferenceError::InvalidRegistry("-registry.io".into()))
+        );
+        assert!(matches!(
+            "nginx@sha256:abc".parse::<Reference>(),
+            Err(ReferenceError::InvalidDigest(_))
+        ));
+        assert!(matches!(
+            format!("app/{}", "a".repeat(255)).parse::<Reference>(),
+            Err(ReferenceError::NameTooLong(_))
+        ));
+        assert!(matches!(
+            "a".repeat(64).parse::<Reference>(),
+            Err(ReferenceError::AmbiguousId(_))
+        ));
+    }
+
+    #[test]
+    fn serde_roundtrip() -> Result<()> {
+        let reference = "quay.io/app:v1".parse::<Reference>()?;
+        let json = serde_json::to_string(&reference)?;
+        assert_eq!(json, "\"quay.io/app:v1\"");
+        assert_eq!(serde_json::from_str::<Reference>(&json)?, reference);
+        Ok(())
+    }
+
+    #[test]
+    fn with_digest() -> Result<()> {
+        let reference = "nginx".parse::<Reference>()?.with_digest(DIGEST.parse()?);
+        assert_eq!(
+            reference.to_string(),
+            format!("docker.io/library/nginx@{}", DIGEST)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn with_tag() -> Result<()> {
+        let reference = format!("nginx@{}", DIGEST)
+            .parse::<Reference>()?
+            .with_tag("sha256-abc.sig")?;
+        assert_eq!(
+            reference.to_string(),
+            "docker.io/library/nginx:sha256-abc.sig"
+        );
+        assert!(reference.with_tag("-invalid").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn with_name() -> Result<()> {
+        let reference =
+            "quay.io/app@sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b"
+                .parse::<Reference>()?;
+        let mirrored = reference.with_name("localhost:5000/mirror/app")?;
+        assert_eq!(mirrored.registry(), "localhost:5000");
+        assert_eq!(mirrored.repository(), "mirror/app");
+        assert_eq!(mirrored.digest(), reference.digest());
+        assert!(mirrored.tag().is_none());
+
+        let mirrored = "nginx:1.19"
+            .parse::<Reference>()?
+            .with_name("docker.io/nginx")?;
+        assert_eq!(mirrored.to_string(), "docker.io/library/nginx:1.19");
+        assert!(reference.with_name("Invalid::Name").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn is_short_name() {
+        for name in &["nginx", "library/nginx:1.19", "a/b/c", "nginx@sha256:abc"] {
+            assert!(Reference::is_short_name(name), "{}", name);
+        }
+        for name in &[
+            "docker.io/nginx",
+            "localhost/app",
+            "localhost:5000/app",
+            "quay.io/a/b",
+        ] {
+            assert!(!Reference::is_short_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn parse_image_identifier() -> Result<()> {
+        let encoded = DIGEST.trim_start_matches("sha256:");
+        assert_eq!(
+            encoded.parse::<ImageIdentifier>()?,
+            ImageIdentifier::Id(DIGEST.parse()?)
+        );
+        assert_eq!(
+            DIGEST.parse::<ImageIdentifier>()?,
+            ImageIdentifier::Id(DIGEST.parse()?)
+        );
+        assert_eq!(
+            "nginx".parse::<ImageIdentifier>()?,
+            ImageIdentifier::Reference("nginx".parse()?)
+        );
+        assert!("Nginx".parse::<ImageIdentifier>().is_err());
+        Ok(())
+    }
+}
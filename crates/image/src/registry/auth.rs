@@ -0,0 +1,465 @@
+//! Registry credentials and their lookup from containers-style `auth.json` files and
+//! `docker-credential-*` helper binaries.
+
+use crate::reference::{Reference, DEFAULT_REGISTRY};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use derive_builder::Builder;
+use dyn_clone::{clone_trait_object, DynClone};
+use getset::{Getters, Setters};
+use log::{debug, trace};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{self, Debug},
+    path::PathBuf,
+    process::{Output, Stdio},
+};
+use tokio::{fs, io::AsyncWriteExt, process::Command};
+
+/// The user name returned by credential helpers if the secret is an identity token.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// The key used for the default registry by the docker CLI.
+const LEGACY_DEFAULT_REGISTRY_KEY: &str = "https://index.docker.io/v1/";
+
+#[derive(Clone, Eq, Hash, PartialEq)]
+/// Credentials used to authenticate against a registry.
+pub enum Credentials {
+    /// A user name and password, which are used for basic authentication or to retrieve a bearer
+    /// token.
+    Basic { username: String, password: String },
+
+    /// A refresh token used to retrieve a bearer token via OAuth2.
+    IdentityToken(String),
+
+    /// A bearer token which is directly sent to the registry.
+    RegistryToken(String),
+}
+
+impl Credentials {
+    /// Create basic credentials from a base64 encoded `username:password` string.
+    pub fn from_auth(auth: &str) -> Result<Self> {
+        let decoded = base64::decode(auth.trim()).context("decode base64 auth")?;
+        let decoded = String::from_utf8(decoded).context("auth is not valid UTF-8")?;
+        let (username, password) = decoded
+            .split_once(':')
+            .context("auth is not in the format username:password")?;
+        Ok(Self::Basic {
+            username: username.into(),
+            password: password.into(),
+        })
+    }
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never leak any secret into the logs
+        match self {
+            Self::Basic { username, .. } => write!(f, "Basic({}, ***)", username),
+            Self::IdentityToken(_) => write!(f, "IdentityToken(***)"),
+            Self::RegistryToken(_) => write!(f, "RegistryToken(***)"),
+        }
+    }
+}
+
+#[derive(Builder, Clone, Debug, Getters, Setters)]
+#[builder(pattern = "owned", setter(into))]
+/// Credential lookup for registries if no credentials are provided explicitly.
+pub struct CredentialStore {
+    #[get = "pub"]
+    #[builder(default = "CredentialStore::default_auth_files()")]
+    /// The `auth.json` files in order of precedence. Files which do not exist are skipped.
+    auth_files: Vec<PathBuf>,
+
+    #[getset(get, set)]
+    #[builder(private, default = "Box::new(DefaultExecCommand)")]
+    /// The executor for credential helpers.
+    exec: Box<dyn ExecCommand>,
+}
+
+impl Default for CredentialStore {
+    fn default() -> Self {
+        Self {
+            auth_files: Self::default_auth_files(),
+            exec: Box::new(DefaultExecCommand),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+/// The relevant subset of an `auth.json` or docker `config.json` file.
+struct AuthFile {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+/// A single entry in the `auths` section.
+struct AuthEntry {
+    #[serde(default)]
+    auth: String,
+
+    #[serde(default)]
+    identitytoken: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+/// The output of a credential helper `get` invocation.
+struct HelperOutput {
+    username: String,
+    secret: String,
+}
+
+impl CredentialStore {
+    /// The default `auth.json` locations as used by podman, CRI-O and the docker CLI.
+    pub fn default_auth_files() -> Vec<PathBuf> {
+        let mut files = vec![];
+        if let Some(file) = env::var_os("REGISTRY_AUTH_FILE") {
+            files.push(file.into());
+        }
+        if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+            files.push(PathBuf::from(dir).join("containers").join("auth.json"));
+        }
+        if let Some(home) = env::var_os("HOME") {
+            let home = PathBuf::from(home);
+            files.push(home.join(".config").join("containers").join("auth.json"));
+            files.push(home.join(".docker").join("config.json"));
+        }
+        files
+    }
+
+    /// Lookup the credentials for the reference. The first auth file which contains a matching
+    /// entry wins, whereas repository scoped entries take precedence over registry wide ones.
+    pub async fn lookup(&self, reference: &Reference) -> Result<Option<Credentials>> {
+        for path in self.auth_files() {
+            let content = match fs::read(path).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+            };
+            let file: AuthFile = serde_json::from_slice(&content)
+                .with_context(|| format!("parse auth file {}", path.display()))?;
+
+            if let Some(credentials) = self.lookup_file(&file, reference).await? {
+                debug!(
+                    "Using credentials for {} from {}",
+                    reference.registry(),
+                    path.display()
+                );
+                return Ok(Some(credentials));
+            }
+        }
+        trace!("No credentials found for {}", reference);
+        Ok(None)
+    }
+
+    /// Lookup the credentials within a single auth file.
+    async fn lookup_file(
+        &self,
+        file: &AuthFile,
+        reference: &Reference,
+    ) -> Result<Option<Credentials>> {
+        let registry = reference.registry();
+        if let Some(helper) = file.cred_helpers.iter().find_map(|(key, helper)| {
+            (Self::normalize_key(key) == registry.as_str()).then_some(helper)
+        }) {
+            return self.run_helper(helper, registry).await;
+        }
+
+        // Find the most specific key, which is either the registry or a repository prefix
+        let name = reference.name();
+        let entry = file
+            .auths
+            .iter()
+            .map(|(key, entry)| (Self::normalize_key(key), entry))
+            .filter(|(key, _)| {
+                name == *key
+                    || (name.starts_with(key.as_str()) && name[key.len()..].starts_with('/'))
+            })
+            .max_by_key(|(key, _)| key.len())
+            .map(|(_, entry)| entry);
+
+        match entry {
+            Some(entry) if !entry.identitytoken.is_empty() => Ok(Some(Credentials::IdentityToken(
+                entry.identitytoken.clone(),
+            ))),
+            Some(entry) if !entry.auth.is_empty() => Credentials::from_auth(&entry.auth).map(Some),
+            _ => match &file.creds_store {
+                Some(helper) => self.run_helper(helper, registry).await,
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Retrieve the credentials from the `docker-credential-<helper>` binary.
+    async fn run_helper(&self, helper: &str, registry: &str) -> Result<Option<Credentials>> {
+        let binary = format!("docker-credential-{}", helper);
+        let server_url = if registry == DEFAULT_REGISTRY {
+            LEGACY_DEFAULT_REGISTRY_KEY
+        } else {
+            registry
+        };
+
+        let output = self.exec().run_output(&binary, server_url).await?;
+        if !output.status.success() {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            if stdout.contains("credentials not found") {
+                return Ok(None);
+            }
+            bail!(
+                "credential helper {} failed: {}{}",
+                binary,
+                stdout.trim(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let output: HelperOutput = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("parse output of {}", binary))?;
+        Ok(Some(if output.username == IDENTITY_TOKEN_USERNAME {
+            Credentials::IdentityToken(output.secret)
+        } else {
+            Credentials::Basic {
+                username: output.username,
+                password: output.secret,
+            }
+        }))
+    }
+
+    /// Normalize an auth file key, which may be an URL like `https://index.docker.io/v1/`.
+    fn normalize_key(key: &str) -> String {
+        let key = key
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .trim_end_matches('/');
+        let key = key
+            .strip_suffix("/v1")
+            .or_else(|| key.strip_suffix("/v2"))
+            .unwrap_or(key);
+        match key.split_once('/') {
+            Some(("index.docker.io", rest)) | Some(("registry-1.docker.io", rest)) => {
+                format!("{}/{}", DEFAULT_REGISTRY, rest)
+            }
+            None if key == "index.docker.io" || key == "registry-1.docker.io" => {
+                DEFAULT_REGISTRY.into()
+            }
+            _ => key.into(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+/// DefaultExecCommand runs the credential helpers from the `$PATH`.
+struct DefaultExecCommand;
+
+impl ExecCommand for DefaultExecCommand {}
+
+#[async_trait]
+trait ExecCommand: Debug + DynClone + Send + Sync {
+    /// Run the `get` command of the credential helper and return its `Output`.
+    async fn run_output(&self, binary: &str, server_url: &str) -> Result<Output> {
+        let mut child = Command::new(binary)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("spawn {}", binary))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(server_url.as_bytes()).await?;
+        }
+        child
+            .wait_with_output()
+            .await
+            .with_context(|| format!("run {}", binary))
+    }
+}
+
+clone_trait_object!(ExecCommand);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use tempfile::TempDir;
+
+    #[derive(Clone, Debug)]
+    struct MockExecCommand {
+        expected_binary: String,
+        expected_server_url: String,
+        status: i32,
+        stdout: String,
+    }
+
+    #[async_trait]
+    impl ExecCommand for MockExecCommand {
+        async fn run_output(&self, binary: &str, server_url: &str) -> Result<Output> {
+            assert_eq!(binary, self.expected_binary);
+            assert_eq!(server_url, self.expected_server_url);
+            Ok(Output {
+                status: ExitStatus::from_raw(self.status << 8),
+                stdout: self.stdout.as_bytes().to_vec(),
+                stderr: vec![],
+            })
+        }
+    }
+
+    fn new_store(dir: &TempDir, content: &str) -> Result<CredentialStore> {
+        let file = dir.path().join("auth.json");
+        std::fs::write(&file, content)?;
+        Ok(CredentialStoreBuilder::default()
+            .auth_files(vec![dir.path().join("missing.json"), file])
+            .build()?)
+    }
+
+    fn basic(username: &str, password: &str) -> Credentials {
+        Credentials::Basic {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    #[test]
+    fn from_auth() -> Result<()> {
+        assert_eq!(
+            Credentials::from_auth(&base64::encode("user:pass:word"))?,
+            basic("user", "pass:word")
+        );
+        assert!(Credentials::from_auth("!!!").is_err());
+        assert!(Credentials::from_auth(&base64::encode("user")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn debug_redacted() {
+        assert_eq!(format!("{:?}", basic("user", "secret")), "Basic(user, ***)");
+        assert!(!format!("{:?}", Credentials::RegistryToken("secret".into())).contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn lookup_auths() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = new_store(
+            &dir,
+            &format!(
+                r#"{{"auths": {{
+                    "https://index.docker.io/v1/": {{"auth": "{}"}},
+                    "quay.io": {{"auth": "{}"}},
+                    "quay.io/org": {{"auth": "{}"}},
+                    "localhost:5000": {{"identitytoken": "token"}}
+                }}}}"#,
+                base64::encode("docker:pass"),
+                base64::encode("quay:pass"),
+                base64::encode("org:pass"),
+            ),
+        )?;
+
+        for (reference, expected) in &[
+            ("nginx", Some(basic("docker", "pass"))),
+            ("quay.io/other/app", Some(basic("quay", "pass"))),
+            ("quay.io/org/app", Some(basic("org", "pass"))),
+            ("quay.io/organization/app", Some(basic("quay", "pass"))),
+            (
+                "localhost:5000/app",
+                Some(Credentials::IdentityToken("token".into())),
+            ),
+            ("gcr.io/app", None),
+        ] {
+            assert_eq!(&store.lookup(&reference.parse()?).await?, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup_cred_helper() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut store = new_store(&dir, r#"{"credHelpers": {"gcr.io": "gcloud"}}"#)?;
+        store.set_exec(Box::new(MockExecCommand {
+            expected_binary: "docker-credential-gcloud".into(),
+            expected_server_url: "gcr.io".into(),
+            status: 0,
+            stdout: r#"{"ServerURL": "gcr.io", "Username": "user", "Secret": "pass"}"#.into(),
+        }));
+        assert_eq!(
+            store.lookup(&"gcr.io/app".parse()?).await?,
+            Some(basic("user", "pass"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup_creds_store_identity_token() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut store = new_store(&dir, r#"{"credsStore": "desktop"}"#)?;
+        store.set_exec(Box::new(MockExecCommand {
+            expected_binary: "docker-credential-desktop".into(),
+            expected_server_url: LEGACY_DEFAULT_REGISTRY_KEY.into(),
+            status: 0,
+            stdout: r#"{"Username": "<token>", "Secret": "refresh"}"#.into(),
+        }));
+        assert_eq!(
+            store.lookup(&"nginx".parse()?).await?,
+            Some(Credentials::IdentityToken("refresh".into()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup_cred_helper_not_found() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut store = new_store(&dir, r#"{"credsStore": "pass"}"#)?;
+        store.set_exec(Box::new(MockExecCommand {
+            expected_binary: "docker-credential-pass".into(),
+            expected_server_url: "quay.io".into(),
+            status: 1,
+            stdout: "credentials not found in native keychain".into(),
+        }));
+        assert_eq!(store.lookup(&"quay.io/app".parse()?).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup_cred_helper_failure() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut store = new_store(&dir, r#"{"credsStore": "pass"}"#)?;
+        store.set_exec(Box::new(MockExecCommand {
+            expected_binary: "docker-credential-pass".into(),
+            expected_server_url: "quay.io".into(),
+            status: 1,
+            stdout: "gpg: decryption failed".into(),
+        }));
+        assert!(store.lookup(&"quay.io/app".parse()?).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup_failure_invalid_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = new_store(&dir, "{")?;
+        assert!(store.lookup(&"nginx".parse()?).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_key() {
+        for (key, expected) in &[
+            ("https://index.docker.io/v1/", "docker.io"),
+            ("registry-1.docker.io", "docker.io"),
+            ("index.docker.io/org", "docker.io/org"),
+            ("http://localhost:5000/v2/", "localhost:5000"),
+            ("quay.io/org/repo", "quay.io/org/repo"),
+        ] {
+            assert_eq!(&CredentialStore::normalize_key(key), expected);
+        }
+    }
+}
@@ -0,0 +1,741 @@
+//! Registry configuration in the [containers-registries.conf(5)][0] version 2 format, including
+//! the drop-in configuration directory next to the main file.
+//!
+//! [0]: https://github.com/containers/image/blob/main/docs/containers-registries.conf.5.md
+
+use crate::reference::Reference;
+use anyhow::{bail, Context, Result};
+use derive_builder::Builder;
+use getset::{CopyGetters, Getters};
+use log::{debug, trace};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// The default path to the registries configuration.
+pub const DEFAULT_REGISTRIES_CONFIG: &str = "/etc/containers/registries.conf";
+
+/// The file extension of drop-in configuration files.
+const DROP_IN_EXTENSION: &str = "conf";
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+/// Defines how short names without a registry are resolved.
+pub enum ShortNameMode {
+    /// Use an alias if available, otherwise the only unqualified-search registry. Short names are
+    /// rejected as ambiguous if multiple unqualified-search registries are configured.
+    Enforcing,
+
+    #[default]
+    /// Use an alias if available, otherwise try all unqualified-search registries in order.
+    Permissive,
+
+    /// Ignore all aliases and try all unqualified-search registries in order.
+    Disabled,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+/// Defines which references are pulled from a mirror.
+pub enum PullFromMirror {
+    #[default]
+    /// Pull references by tag and digest from the mirror.
+    All,
+
+    /// Only pull references by digest from the mirror.
+    DigestOnly,
+
+    /// Only pull references by tag from the mirror.
+    TagOnly,
+}
+
+#[derive(Builder, Clone, CopyGetters, Debug, Default, Deserialize, Getters, PartialEq)]
+#[builder(default, pattern = "owned", setter(into))]
+#[serde(default, rename_all = "kebab-case")]
+/// A mirror of a registry, which is tried before the registry itself.
+pub struct Mirror {
+    #[get = "pub"]
+    /// The location of the mirror, which replaces the prefix of the registry.
+    location: String,
+
+    #[get_copy = "pub"]
+    /// Skip the certificate verification of the mirror and allow plain HTTP.
+    insecure: bool,
+
+    #[get_copy = "pub"]
+    /// The references which are allowed to be pulled from the mirror.
+    pull_from_mirror: PullFromMirror,
+}
+
+#[derive(Builder, Clone, CopyGetters, Debug, Default, Deserialize, Getters, PartialEq)]
+#[builder(default, pattern = "owned", setter(into))]
+#[serde(default, rename_all = "kebab-case")]
+/// The configuration of all images whose name starts with a prefix.
+pub struct Registry {
+    #[get = "pub"]
+    /// The prefix of image names the configuration applies to, which defaults to the location.
+    /// A prefix like `*.example.com` matches all subdomains.
+    prefix: String,
+
+    #[get = "pub"]
+    /// The location to pull from, which replaces the prefix of the image name and defaults to
+    /// the prefix.
+    location: String,
+
+    #[get_copy = "pub"]
+    /// Skip the certificate verification of the location and allow plain HTTP.
+    insecure: bool,
+
+    #[get_copy = "pub"]
+    /// Refuse to pull any image matching the prefix.
+    blocked: bool,
+
+    #[get_copy = "pub"]
+    /// Only use the mirrors for references by digest.
+    mirror_by_digest_only: bool,
+
+    #[get = "pub"]
+    #[serde(rename = "mirror")]
+    /// Mirrors which are tried in order before the location.
+    mirrors: Vec<Mirror>,
+}
+
+#[derive(Builder, Clone, CopyGetters, Debug, Default, Getters, PartialEq)]
+#[builder(default, pattern = "owned", setter(into))]
+/// The registries configuration, which defaults to pulling everything directly and resolving
+/// short names to the default registry.
+pub struct RegistriesConfig {
+    #[get = "pub"]
+    /// Registries to search for short names in order.
+    unqualified_search_registries: Vec<String>,
+
+    #[get_copy = "pub"]
+    /// How short names are resolved.
+    short_name_mode: ShortNameMode,
+
+    #[get = "pub"]
+    /// Per prefix registry configurations.
+    registries: Vec<Registry>,
+
+    #[get = "pub"]
+    /// Short names mapped to fully qualified image names.
+    aliases: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+/// A single configuration file, where unset fields do not override previous files.
+struct ConfigFile {
+    unqualified_search_registries: Option<Vec<String>>,
+    short_name_mode: Option<ShortNameMode>,
+
+    #[serde(rename = "registry")]
+    registries: Vec<Registry>,
+
+    aliases: HashMap<String, String>,
+
+    #[serde(rename = "registries")]
+    /// The `[registries.*]` tables of the unsupported version 1 format.
+    v1_registries: Option<toml::Value>,
+}
+
+impl Registry {
+    /// The prefix to match image names against.
+    fn match_prefix(&self) -> &str {
+        if self.prefix.is_empty() {
+            &self.location
+        } else {
+            &self.prefix
+        }
+    }
+
+    /// Return the matched part of the image name if the prefix applies to it.
+    fn matches<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let prefix = self.match_prefix();
+        if let Some(domain) = prefix.strip_prefix('*') {
+            let host = name.split('/').next().unwrap_or_default();
+            return host.ends_with(domain).then_some(host);
+        }
+        match name.strip_prefix(prefix) {
+            Some(rest) if !prefix.is_empty() && (rest.is_empty() || rest.starts_with('/')) => {
+                Some(&name[..prefix.len()])
+            }
+            _ => None,
+        }
+    }
+
+    /// Normalize and validate the entry after loading it.
+    fn validate(&mut self) -> Result<()> {
+        self.prefix = self.prefix.trim_end_matches('/').into();
+        self.location = self.location.trim_end_matches('/').into();
+        if self.prefix.starts_with('*') {
+            if !self.prefix.starts_with("*.") || self.prefix.contains('/') {
+                bail!("invalid wildcard prefix {:?}", self.prefix)
+            }
+            if !self.location.is_empty() {
+                bail!("wildcard prefix {:?} must not have a location", self.prefix)
+            }
+        } else if self.prefix.is_empty() && self.location.is_empty() {
+            bail!("registry has neither a prefix nor a location")
+        } else if self.prefix.is_empty() {
+            self.prefix = self.location.clone();
+        } else if self.location.is_empty() {
+            self.location = self.prefix.clone();
+        }
+
+        for mirror in self.mirrors.iter_mut() {
+            mirror.location = mirror.location.trim_end_matches('/').into();
+            if mirror.location.is_empty() {
+                bail!("mirror of registry {:?} has no location", self.prefix)
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RegistriesConfig {
+    /// Load the configuration file and all `*.conf` files of the drop-in directory, which is the
+    /// path with an additional `.d` suffix, like `/etc/containers/registries.conf.d`. Drop-in
+    /// files are applied in lexical order and override registries with the same prefix. Missing
+    /// files result in the default configuration.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut drop_in_dir = OsString::from(path.as_os_str());
+        drop_in_dir.push(".d");
+        let drop_in_dir = PathBuf::from(drop_in_dir);
+
+        let mut files = vec![path.to_path_buf()];
+        match fs::read_dir(&drop_in_dir) {
+            Ok(entries) => {
+                let mut drop_ins = entries
+                    .map(|entry| entry.map(|e| e.path()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("read directory {}", drop_in_dir.display()))?;
+                drop_ins.retain(|p| p.extension().is_some_and(|e| e == DROP_IN_EXTENSION));
+                drop_ins.sort();
+                files.extend(drop_ins);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("read directory {}", drop_in_dir.display()))
+            }
+        }
+
+        let mut config = Self::default();
+        for file in files {
+            let content = match fs::read_to_string(&file) {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    trace!("Registries config {} does not exist", file.display());
+                    continue;
+                }
+                Err(e) => return Err(e).with_context(|| format!("read {}", file.display())),
+            };
+            let parsed = toml::from_str::<ConfigFile>(&content)
+                .with_context(|| format!("parse registries config {}", file.display()))?;
+            config
+                .merge(parsed)
+                .with_context(|| format!("invalid registries config {}", file.display()))?;
+            debug!("Loaded registries config {}", file.display());
+        }
+        Ok(config)
+    }
+
+    /// Apply a configuration file on top of the current configuration.
+    fn merge(&mut self, file: ConfigFile) -> Result<()> {
+        if file.v1_registries.is_some() {
+            bail!("the version 1 format is not supported")
+        }
+
+        if let Some(search) = file.unqualified_search_registries {
+            for registry in &search {
+                if registry.is_empty() || registry.contains('/') {
+                    bail!("invalid unqualified-search registry {:?}", registry)
+                }
+            }
+            self.unqualified_search_registries = search;
+        }
+
+        if let Some(mode) = file.short_name_mode {
+            self.short_name_mode = mode;
+        }
+
+        for mut registry in file.registries {
+            registry.validate()?;
+            self.registries.retain(|r| r.prefix != registry.prefix);
+            self.registries.push(registry);
+        }
+
+        for (short_name, name) in file.aliases {
+            if !Reference::is_short_name(&short_name) || !split_short_name(&short_name).1.is_empty()
+            {
+                bail!("alias {:?} is not a short name without tag", short_name)
+            }
+            if Reference::is_short_name(&name) || !split_short_name(&name).1.is_empty() {
+                bail!(
+                    "alias {:?} must point to a fully qualified name without tag, got {:?}",
+                    short_name,
+                    name
+                )
+            }
+            name.parse::<Reference>()
+                .with_context(|| format!("invalid alias {:?}", short_name))?;
+            self.aliases.insert(short_name, name);
+        }
+        Ok(())
+    }
+
+    /// Resolve an image name as provided by the user to the references which should be tried in
+    /// order. Fully qualified names resolve to themselves, whereas short names are resolved via
+    /// the aliases and unqualified-search registries. The default registry is used if no
+    /// unqualified-search registries are configured.
+    pub fn candidates(&self, name: &str) -> Result<Vec<Reference>> {
+        // Validate the input on its own, which also rejects ambiguous image IDs
+        let reference = name.parse::<Reference>()?;
+        if !Reference::is_short_name(name) {
+            return Ok(vec![reference]);
+        }
+
+        let (short_name, suffix) = split_short_name(name);
+        if self.short_name_mode != ShortNameMode::Disabled {
+            if let Some(alias) = self.aliases.get(short_name) {
+                debug!("Resolved short name {} via alias {}", name, alias);
+                return Ok(vec![format!("{}{}", alias, suffix).parse()?]);
+            }
+        }
+
+        if self.unqualified_search_registries.is_empty() {
+            return Ok(vec![reference]);
+        }
+        if self.short_name_mode == ShortNameMode::Enforcing
+            && self.unqualified_search_registries.len() > 1
+        {
+            bail!(
+                "short name {} is ambiguous, because multiple unqualified-search registries are configured",
+                name
+            )
+        }
+        Ok(self
+            .unqualified_search_registries
+            .iter()
+            .map(|registry| format!("{}/{}", registry, name).parse())
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Return the locations to pull the reference from in order, which are the allowed mirrors
+    /// followed by the registry location. Fails if the registry is blocked.
+    pub fn sources(&self, reference: &Reference) -> Result<Vec<Reference>> {
+        let name = reference.name();
+        let (registry, matched) = match self.find_registry(&name) {
+            Some(found) => found,
+            None => return Ok(vec![reference.clone()]),
+        };
+        if registry.blocked {
+            bail!(
+                "registry {} is blocked for {}",
+                registry.match_prefix(),
+                reference
+            )
+        }
+
+        let rest = &name[matched.len()..];
+        let by_digest = reference.digest().is_some();
+        let mut sources = registry
+            .mirrors
+            .iter()
+            .filter(|m| match m.pull_from_mirror {
+                _ if registry.mirror_by_digest_only => by_digest,
+                PullFromMirror::All => true,
+                PullFromMirror::DigestOnly => by_digest,
+                PullFromMirror::TagOnly => !by_digest,
+            })
+            .map(|m| reference.with_name(&format!("{}{}", m.location, rest)))
+            .collect::<Result<Vec<_>, _>>()
+            .context("rewrite reference for mirror")?;
+
+        let location = if registry.location.is_empty() {
+            matched
+        } else {
+            &registry.location
+        };
+        sources.push(
+            reference
+                .with_name(&format!("{}{}", location, rest))
+                .context("rewrite reference for location")?,
+        );
+        Ok(sources)
+    }
+
+    /// Check if the registry host is insecure, which is the case if any insecure location or
+    /// mirror points to it.
+    pub fn is_insecure(&self, host: &str) -> bool {
+        let location_host = |location: &str| location.split('/').next() == Some(host);
+        self.registries.iter().any(|r| {
+            (r.insecure
+                && if r.location.is_empty() {
+                    r.matches(host).is_some()
+                } else {
+                    location_host(&r.location)
+                })
+                || r.mirrors
+                    .iter()
+                    .any(|m| m.insecure && location_host(&m.location))
+        })
+    }
+
+    /// Find the registry with the longest matching prefix and return it together with the
+    /// matched part of the name.
+    fn find_registry<'a>(&self, name: &'a str) -> Option<(&Registry, &'a str)> {
+        self.registries
+            .iter()
+            .filter_map(|r| r.matches(name).map(|matched| (r, matched)))
+            .max_by_key(|(r, _)| r.match_prefix().len())
+    }
+}
+
+/// Split a short name into the name and the tag or digest suffix including its separator.
+fn split_short_name(name: &str) -> (&str, &str) {
+    let end = name.find('@').unwrap_or(name.len());
+    let end = match name[..end].rfind(':') {
+        Some(i) if !name[i..end].contains('/') => i,
+        _ => end,
+    };
+    name.split_at(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    fn write_config(dir: &TempDir, content: &str) -> Result<PathBuf> {
+        let path = dir.path().join("registries.conf");
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+
+    fn sources(config: &RegistriesConfig, reference: &str) -> Result<Vec<String>> {
+        Ok(config
+            .sources(&reference.parse()?)?
+            .iter()
+            .map(ToString::to_string)
+            .collect())
+    }
+
+    #[test]
+    fn load() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write_config(
+            &dir,
+            r#"
+            unqualified-search-registries = ["quay.io", "docker.io"]
+            short-name-mode = "enforcing"
+
+            [[registry]]
+            location = "quay.io/"
+            insecure = true
+
+            [[registry]]
+            prefix = "docker.io/library"
+            location = "registry.local/library"
+            mirror-by-digest-only = true
+
+            [[registry.mirror]]
+            location = "mirror.local/library"
+            pull-from-mirror = "tag-only"
+
+            [aliases]
+            "fedora" = "registry.fedoraproject.org/fedora"
+            "#,
+        )?;
+
+        let config = RegistriesConfig::load(path)?;
+        assert_eq!(
+            config.unqualified_search_registries(),
+            &["quay.io", "docker.io"]
+        );
+        assert_eq!(config.short_name_mode(), ShortNameMode::Enforcing);
+        assert_eq!(config.registries().len(), 2);
+        assert_eq!(config.registries()[0].prefix(), "quay.io");
+        assert!(config.registries()[0].insecure());
+        assert!(config.registries()[1].mirror_by_digest_only());
+        assert_eq!(
+            config.registries()[1].mirrors()[0].pull_from_mirror(),
+            PullFromMirror::TagOnly
+        );
+        assert_eq!(
+            config.aliases()["fedora"],
+            "registry.fedoraproject.org/fedora"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_missing() -> Result<()> {
+        let dir = TempDir::new()?;
+        let config = RegistriesConfig::load(dir.path().join("registries.conf"))?;
+        assert_eq!(config, RegistriesConfig::default());
+        Ok(())
+    }
+
+    #[test]
+    fn load_drop_ins() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write_config(
+            &dir,
+            r#"
+            unqualified-search-registries = ["quay.io"]
+            short-name-mode = "enforcing"
+
+            [[registry]]
+            location = "quay.io"
+
+            [[registry]]
+            location = "docker.io"
+            "#,
+        )?;
+        let drop_in_dir = dir.path().join("registries.conf.d");
+        fs::create_dir(&drop_in_dir)?;
+        fs::write(
+            drop_in_dir.join("10-a.conf"),
+            r#"
+            unqualified-search-registries = ["docker.io"]
+
+            [[registry]]
+            location = "quay.io"
+            blocked = true
+            "#,
+        )?;
+        fs::write(
+            drop_in_dir.join("20-b.conf"),
+            "[aliases]\n\"app\" = \"quay.io/org/app\"",
+        )?;
+        fs::write(drop_in_dir.join("ignored.txt"), "invalid")?;
+
+        let config = RegistriesConfig::load(path)?;
+        assert_eq!(config.unqualified_search_registries(), &["docker.io"]);
+        assert_eq!(config.short_name_mode(), ShortNameMode::Enforcing);
+        assert_eq!(config.registries().len(), 2);
+        assert!(config
+            .registries()
+            .iter()
+            .any(|r| r.prefix() == "quay.io" && r.blocked()));
+        assert_eq!(config.aliases()["app"], "quay.io/org/app");
+        Ok(())
+    }
+
+    #[test]
+    fn load_failure() -> Result<()> {
+        let dir = TempDir::new()?;
+        for content in &[
+            "invalid",
+            "[registries.search]\nregistries = [\"quay.io\"]",
+            "unqualified-search-registries = [\"quay.io/org\"]",
+            "[[registry]]\ninsecure = true",
+            "[[registry]]\nprefix = \"*.example.com\"\nlocation = \"example.com\"",
+            "[[registry]]\nprefix = \"*example.com\"",
+            "[[registry]]\nlocation = \"quay.io\"\n[[registry.mirror]]\ninsecure = true",
+            "[aliases]\n\"quay.io/app\" = \"quay.io/app\"",
+            "[aliases]\n\"app\" = \"app\"",
+            "[aliases]\n\"app\" = \"quay.io/app:v1\"",
+        ] {
+            let path = write_config(&dir, content)?;
+            assert!(RegistriesConfig::load(path).is_err(), "{}", content);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn candidates() -> Result<()> {
+        let config = RegistriesConfigBuilder::default()
+            .unqualified_search_registries(vec!["quay.io".to_string(), "docker.io".to_string()])
+            .aliases(
+                vec![("app".to_string(), "registry.local/org/app".to_string())]
+                    .into_iter()
+                    .collect::<HashMap<_, _>>(),
+            )
+            .build()?;
+        let candidates = |name: &str| -> Result<Vec<String>> {
+            Ok(config
+                .candidates(name)?
+                .iter()
+                .map(ToString::to_string)
+                .collect())
+        };
+
+        assert_eq!(candidates("localhost/app")?, &["localhost/app:latest"]);
+        assert_eq!(candidates("app:v1")?, &["registry.local/org/app:v1"]);
+        assert_eq!(
+            candidates(&format!("app@{}", DIGEST))?,
+            &[format!("registry.local/org/app@{}", DIGEST)]
+        );
+        assert_eq!(
+            candidates("nginx:1.19")?,
+            &["quay.io/nginx:1.19", "docker.io/library/nginx:1.19"]
+        );
+        assert!(config.candidates("Invalid::Reference").is_err());
+        assert!(config.candidates(&DIGEST[7..]).is_err());
+
+        let config = RegistriesConfig {
+            short_name_mode: ShortNameMode::Disabled,
+            ..config.clone()
+        };
+        assert_eq!(config.candidates("app")?.len(), 2);
+
+        let config = RegistriesConfig {
+            short_name_mode: ShortNameMode::Enforcing,
+            ..config
+        };
+        assert_eq!(config.candidates("app")?.len(), 1);
+        assert!(config.candidates("nginx").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn candidates_default() -> Result<()> {
+        let candidates = RegistriesConfig::default().candidates("nginx")?;
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].to_string(), "docker.io/library/nginx:latest");
+        Ok(())
+    }
+
+    #[test]
+    fn sources_mirrors() -> Result<()> {
+        let config = RegistriesConfigBuilder::default()
+            .registries(vec![
+                RegistryBuilder::default()
+                    .prefix("docker.io")
+                    .location("docker.io")
+                    .mirrors(vec![
+                        MirrorBuilder::default()
+                            .location("mirror-a.local")
+                            .build()?,
+                        MirrorBuilder::default()
+                            .location("mirror-b.local/hub")
+                            .pull_from_mirror(PullFromMirror::DigestOnly)
+                            .build()?,
+                    ])
+                    .build()?,
+                RegistryBuilder::default()
+                    .prefix("docker.io/library/app")
+                    .location("registry.local/app")
+                    .build()?,
+            ])
+            .build()?;
+
+        assert_eq!(
+            sources(&config, "nginx:1.19")?,
+            &[
+                "mirror-a.local/library/nginx:1.19",
+                "docker.io/library/nginx:1.19"
+            ]
+        );
+        assert_eq!(
+            sources(&config, &format!("nginx@{}", DIGEST))?,
+            &[
+                format!("mirror-a.local/library/nginx@{}", DIGEST),
+                format!("mirror-b.local/hub/library/nginx@{}", DIGEST),
+                format!("docker.io/library/nginx@{}", DIGEST),
+            ]
+        );
+        assert_eq!(sources(&config, "app")?, &["registry.local/app:latest"]);
+        assert_eq!(sources(&config, "quay.io/appx")?, &["quay.io/appx:latest"]);
+        Ok(())
+    }
+
+    #[test]
+    fn sources_mirror_by_digest_only() -> Result<()> {
+        let config = RegistriesConfigBuilder::default()
+            .registries(vec![RegistryBuilder::default()
+                .location("quay.io")
+                .mirror_by_digest_only(true)
+                .mirrors(vec![MirrorBuilder::default()
+                    .location("mirror.local")
+                    .pull_from_mirror(PullFromMirror::TagOnly)
+                    .build()?])
+                .build()?])
+            .build()?;
+
+        assert_eq!(sources(&config, "quay.io/app")?, &["quay.io/app:latest"]);
+        assert_eq!(
+            sources(&config, &format!("quay.io/app@{}", DIGEST))?.len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sources_blocked_and_wildcard() -> Result<()> {
+        let config = RegistriesConfigBuilder::default()
+            .registries(vec![
+                RegistryBuilder::default()
+                    .prefix("*.example.com")
+                    .mirrors(vec![MirrorBuilder::default()
+                        .location("mirror.local")
+                        .insecure(true)
+                        .build()?])
+                    .build()?,
+                RegistryBuilder::default()
+                    .prefix("blocked.example.com")
+                    .location("blocked.example.com")
+                    .blocked(true)
+                    .build()?,
+            ])
+            .build()?;
+
+        assert_eq!(
+            sources(&config, "registry.example.com/a/b:v1")?,
+            &["mirror.local/a/b:v1", "registry.example.com/a/b:v1"]
+        );
+        assert!(config.sources(&"blocked.example.com/app".parse()?).is_err());
+        assert_eq!(sources(&config, "example.com/app")?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn is_insecure() -> Result<()> {
+        let config = RegistriesConfigBuilder::default()
+            .registries(vec![
+                RegistryBuilder::default()
+                    .location("registry.local:5000/org")
+                    .insecure(true)
+                    .mirrors(vec![
+                        MirrorBuilder::default().location("secure.local").build()?,
+                        MirrorBuilder::default()
+                            .location("insecure.local/path")
+                            .insecure(true)
+                            .build()?,
+                    ])
+                    .build()?,
+                RegistryBuilder::default()
+                    .prefix("*.internal")
+                    .insecure(true)
+                    .build()?,
+            ])
+            .build()?;
+
+        assert!(config.is_insecure("registry.local:5000"));
+        assert!(config.is_insecure("insecure.local"));
+        assert!(config.is_insecure("some.internal"));
+        assert!(!config.is_insecure("secure.local"));
+        assert!(!config.is_insecure("registry.local"));
+        Ok(())
+    }
+
+    #[test]
+    fn split_short_name() {
+        assert_eq!(super::split_short_name("app"), ("app", ""));
+        assert_eq!(super::split_short_name("org/app:v1"), ("org/app", ":v1"));
+        assert_eq!(
+            super::split_short_name("app:v1@sha256:abc"),
+            ("app", ":v1@sha256:abc")
+        );
+    }
+}
@@ -0,0 +1,784 @@
+//! A client for the [OCI distribution specification][0], which is the successor of the Docker
+//! registry HTTP API v2.
+//!
+//! [0]: https://github.com/opencontainers/distribution-spec/blob/main/spec.md
+
+use crate::{
+    digest::{Digest, Digester},
+    manifest::SUPPORTED_MEDIA_TYPES,
+    reference::{Reference, DEFAULT_REGISTRY},
+};
+use anyhow::{bail, Context, Result};
+use auth::{CredentialStore, Credentials};
+use config::RegistriesConfig;
+use derive_builder::Builder;
+use getset::{CopyGetters, Getters};
+use log::debug;
+use reqwest::{
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, RANGE, WWW_AUTHENTICATE},
+    RequestBuilder, Response, StatusCode, Url,
+};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+use thiserror::Error;
+
+pub mod auth;
+pub mod config;
+
+/// The API host of the default registry.
+const DEFAULT_REGISTRY_HOST: &str = "registry-1.docker.io";
+
+/// The header containing the manifest digest calculated by the registry.
+const DOCKER_CONTENT_DIGEST: &str = "Docker-Content-Digest";
+
+/// The client ID sent on OAuth2 token requests.
+const CLIENT_ID: &str = "containrs";
+
+/// The maximum size of a manifest, which prevents registries from filling the memory.
+const MAX_MANIFEST_SIZE: usize = 4 << 20;
+
+/// Cache key for authorizations, which are only valid for a repository and the credentials used
+/// to retrieve them.
+type AuthorizationKey = (String, String, Option<Credentials>);
+
+#[derive(Builder, Clone, Debug, Default, Getters)]
+#[builder(default, pattern = "owned", setter(into))]
+/// A registry client for pulling manifests and blobs.
+pub struct Client {
+    /// The underlying HTTP client.
+    http: reqwest::Client,
+
+    /// The HTTP client for insecure registries, which accepts invalid certificates.
+    insecure_http: InsecureHttpClient,
+
+    #[get = "pub"]
+    /// The registries configuration, which defines mirrors, blocked and insecure registries as
+    /// well as the short name resolution.
+    registries: RegistriesConfig,
+
+    #[get = "pub"]
+    /// Credential lookup if no credentials are provided for a pull.
+    credential_store: CredentialStore,
+
+    /// Cached `Authorization` header values.
+    authorizations: Arc<Mutex<HashMap<AuthorizationKey, String>>>,
+
+    /// Insecure registries which failed the TLS handshake and are accessed via plain HTTP.
+    plain_http: Arc<Mutex<HashSet<String>>>,
+}
+
+#[derive(Clone, Debug)]
+/// An HTTP client which does not verify certificates.
+struct InsecureHttpClient(reqwest::Client);
+
+impl Default for InsecureHttpClient {
+    fn default() -> Self {
+        Self(
+            reqwest::Client::builder()
+                .danger_accept_invalid_certs(true)
+                .build()
+                .expect("build insecure HTTP client"),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Getters)]
+/// A manifest retrieved from the registry.
+pub struct FetchedManifest {
+    #[get = "pub"]
+    /// The raw manifest content.
+    content: Vec<u8>,
+
+    #[get = "pub"]
+    /// The media type as announced by the registry.
+    media_type: Option<String>,
+
+    #[get = "pub"]
+    /// The verified digest of the content.
+    digest: Digest,
+}
+
+#[derive(CopyGetters, Debug, Error, Getters)]
+#[error("request {url} failed with status {status}: {body}")]
+/// A request which the registry answered with an unsuccessful status.
+pub struct StatusError {
+    #[get = "pub"]
+    /// The requested URL.
+    url: Url,
+
+    #[get_copy = "pub"]
+    /// The status of the response.
+    status: StatusCode,
+
+    #[get = "pub"]
+    /// The trimmed response body.
+    body: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+/// Response of a token server, which uses either `token` or the OAuth2 `access_token`.
+struct TokenResponse {
+    #[serde(default)]
+    token: String,
+
+    #[serde(default)]
+    access_token: String,
+}
+
+impl Client {
+    /// Fetch the manifest for the reference, whereas the digest takes precedence over the tag. The
+    /// content is verified against the digest of the reference if available, otherwise against
+    /// the digest the registry announced.
+    pub async fn fetch_manifest(
+        &self,
+        reference: &Reference,
+        credentials: Option<&Credentials>,
+    ) -> Result<FetchedManifest> {
+        let target = match (reference.digest(), reference.tag()) {
+            (Some(digest), _) => digest.to_string(),
+            (None, Some(tag)) => tag.clone(),
+            (None, None) => bail!("reference {} has neither a tag nor a digest", reference),
+        };
+        let path = format!("manifests/{}", target);
+        debug!("Fetching manifest {} of {}", target, reference);
+
+        let accept = SUPPORTED_MEDIA_TYPES.join(", ");
+        let response = self
+            .get(reference, &path, credentials, |http, url| {
+                http.get(url).header(ACCEPT, &accept)
+            })
+            .await
+            .with_context(|| format!("request manifest {} of {}", target, reference))?;
+
+        let media_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string);
+        let announced = response
+            .headers()
+            .get(DOCKER_CONTENT_DIGEST)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<Digest>().ok());
+        let content = Self::read_limited(response, MAX_MANIFEST_SIZE)
+            .await
+            .context("read manifest")?;
+
+        let expected = reference.digest().clone().or(announced);
+        let mut digester =
+            Digester::new(expected.as_ref().map(Digest::algorithm).unwrap_or_default());
+        digester.update(&content);
+        let digest = digester.finalize();
+        if let Some(expected) = expected {
+            if expected != digest {
+                bail!(
+                    "manifest digest mismatch for {}: expected {}, got {}",
+                    reference,
+                    expected,
+                    digest
+                )
+            }
+        }
+
+        Ok(FetchedManifest {
+            content,
+            media_type,
+            digest,
+        })
+    }
+
+    /// Request the blob from the repository of the reference. The response body has to be
+    /// verified by the caller.
+    pub async fn fetch_blob(
+        &self,
+        reference: &Reference,
+        digest: &Digest,
+        credentials: Option<&Credentials>,
+    ) -> Result<Response> {
+        self.fetch_blob_from(reference, digest, 0, credentials)
+            .await
+    }
+
+    /// Request the blob content starting at the byte `offset`, which is used to resume partial
+    /// downloads. Registries without support for range requests answer with the whole blob, so
+    /// the caller has to check for the `206 Partial Content` status if `offset` is not zero.
+    pub async fn fetch_blob_from(
+        &self,
+        reference: &Reference,
+        digest: &Digest,
+        offset: u64,
+        credentials: Option<&Credentials>,
+    ) -> Result<Response> {
+        debug!(
+            "Fetching blob {} of {} from offset {}",
+            digest, reference, offset
+        );
+
+        self.get(
+            reference,
+            &format!("blobs/{}", digest),
+            credentials,
+            |http, url| {
+                let request = http.get(url);
+                if offset > 0 {
+                    request.header(RANGE, format!("bytes={}-", offset))
+                } else {
+                    request
+                }
+            },
+        )
+        .await
+        .with_context(|| format!("request blob {} of {}", digest, reference))
+    }
+
+    /// Send a request for the repository relative `path` built by `request` and answer an
+    /// authentication challenge of the registry if necessary. Authorizations are cached per
+    /// repository and credentials.
+    async fn get<F>(
+        &self,
+        reference: &Reference,
+        path: &str,
+        credentials: Option<&Credentials>,
+        request: F,
+    ) -> Result<Response>
+    where
+        F: Fn(&reqwest::Client, &str) -> RequestBuilder,
+    {
+        let key = (
+            reference.registry().clone(),
+            reference.repository().clone(),
+            credentials.cloned(),
+        );
+        let cached = match credentials {
+            Some(Credentials::RegistryToken(token)) => Some(format!("Bearer {}", token)),
+            _ => self.authorizations().get(&key).cloned(),
+        };
+
+        let response = self
+            .send(reference, path, &request, cached.as_deref())
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Self::check_status(response).await;
+        }
+
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .context("registry returned unauthorized without an authentication challenge")?
+            .to_string();
+        let authorization = self.authorize(&challenge, reference, credentials).await?;
+        self.authorizations().insert(key, authorization.clone());
+
+        let response = self
+            .send(reference, path, &request, Some(&authorization))
+            .await?;
+        Self::check_status(response).await
+    }
+
+    /// Send the request via HTTPS. Insecure registries accept invalid certificates and fall back
+    /// to plain HTTP if the TLS handshake fails.
+    async fn send<F>(
+        &self,
+        reference: &Reference,
+        path: &str,
+        request: &F,
+        authorization: Option<&str>,
+    ) -> Result<Response>
+    where
+        F: Fn(&reqwest::Client, &str) -> RequestBuilder,
+    {
+        let registry = reference.registry();
+        let build = |http, scheme| {
+            let builder = request(http, &self.url(scheme, reference, path));
+            match authorization {
+                Some(authorization) => builder.header(AUTHORIZATION, authorization),
+                None => builder,
+            }
+        };
+        if !self.is_insecure(registry) {
+            return Ok(build(&self.http, "https").send().await?);
+        }
+        if !self.plain_http().contains(registry) {
+            match build(&self.insecure_http.0, "https").send().await {
+                // Failed TLS handshakes are reported as connect errors
+                Err(e) if e.is_connect() => {
+                    debug!(
+                        "Falling back to plain HTTP for insecure registry {}: {}",
+                        registry, e
+                    );
+                    self.plain_http().insert(registry.clone());
+                }
+                res => return Ok(res?),
+            }
+        }
+        Ok(build(&self.http, "http").send().await?)
+    }
+
+    /// Answer the authentication challenge and return the resulting `Authorization` header value.
+    async fn authorize(
+        &self,
+        challenge: &str,
+        reference: &Reference,
+        credentials: Option<&Credentials>,
+    ) -> Result<String> {
+        let (scheme, params) = Self::parse_challenge(challenge)?;
+        match scheme.to_lowercase().as_str() {
+            "basic" => match credentials {
+                Some(Credentials::Basic { username, password }) => Ok(format!(
+                    "Basic {}",
+                    base64::encode(format!("{}:{}", username, password))
+                )),
+                _ => bail!(
+                    "registry {} requires basic authentication, but no credentials are available",
+                    reference.registry()
+                ),
+            },
+            "bearer" => {
+                let token = self.fetch_token(&params, reference, credentials).await?;
+                Ok(format!("Bearer {}", token))
+            }
+            other => bail!("unsupported authentication scheme {}", other),
+        }
+    }
+
+    /// Retrieve a bearer token from the realm of the challenge.
+    async fn fetch_token(
+        &self,
+        params: &HashMap<String, String>,
+        reference: &Reference,
+        credentials: Option<&Credentials>,
+    ) -> Result<String> {
+        let realm = params
+            .get("realm")
+            .context("bearer challenge has no realm")?;
+        let service = params.get("service").cloned().unwrap_or_default();
+        let scope = format!("repository:{}:pull", reference.repository());
+        debug!("Requesting token from {} for scope {}", realm, scope);
+
+        let http = if self.is_insecure(reference.registry()) {
+            &self.insecure_http.0
+        } else {
+            &self.http
+        };
+        let request = match credentials {
+            Some(Credentials::IdentityToken(token)) => http.post(realm).form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", token),
+                ("service", &service),
+                ("scope", &scope),
+                ("client_id", CLIENT_ID),
+            ]),
+            Some(Credentials::RegistryToken(_)) => {
+                bail!(
+                    "registry token has been rejected by {}",
+                    reference.registry()
+                )
+            }
+            Some(Credentials::Basic { username, password }) => http
+                .get(realm)
+                .query(&[("service", &service), ("scope", &scope)])
+                .basic_auth(username, Some(password)),
+            None => http
+                .get(realm)
+                .query(&[("service", &service), ("scope", &scope)]),
+        };
+
+        let response = Self::check_status(request.send().await.context("request token")?).await?;
+        let token: TokenResponse =
+            serde_json::from_slice(&response.bytes().await?).context("parse token response")?;
+        match (token.token, token.access_token) {
+            (token, _) if !token.is_empty() => Ok(token),
+            (_, token) if !token.is_empty() => Ok(token),
+            _ => bail!("token response from {} contains no token", realm),
+        }
+    }
+
+    /// Parse a `WWW-Authenticate` header into its scheme and parameters.
+    fn parse_challenge(challenge: &str) -> Result<(String, HashMap<String, String>)> {
+        let (scheme, rest) = challenge
+            .trim()
+            .split_once(' ')
+            .unwrap_or((challenge.trim(), ""));
+        if scheme.is_empty() {
+            bail!("empty authentication challenge");
+        }
+
+        let mut params = HashMap::new();
+        let mut chars = rest.chars().peekable();
+        loop {
+            let key = chars
+                .by_ref()
+                .skip_while(|c| *c == ',' || c.is_whitespace())
+                .take_while(|c| *c != '=')
+                .collect::<String>();
+            if key.is_empty() {
+                break;
+            }
+
+            let mut value = String::new();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => value.extend(chars.next()),
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                value = chars.by_ref().take_while(|c| *c != ',').collect();
+            }
+            params.insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+        Ok((scheme.into(), params))
+    }
+
+    /// Lock the authorization cache.
+    fn authorizations(&self) -> MutexGuard<'_, HashMap<AuthorizationKey, String>> {
+        self.authorizations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the set of insecure registries accessed via plain HTTP.
+    fn plain_http(&self) -> MutexGuard<'_, HashSet<String>> {
+        self.plain_http
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Build the API URL with the scheme for the provided repository relative path.
+    fn url(&self, scheme: &str, reference: &Reference, path: &str) -> String {
+        let registry = reference.registry();
+        let host = if registry == DEFAULT_REGISTRY {
+            DEFAULT_REGISTRY_HOST
+        } else {
+            registry
+        };
+        format!(
+            "{}://{}/v2/{}/{}",
+            scheme,
+            host,
+            reference.repository(),
+            path
+        )
+    }
+
+    /// Check if the certificate of the registry should not be verified, which allows to fall back
+    /// to plain HTTP. Loopback registries like `localhost:5000` are always insecure.
+    fn is_insecure(&self, registry: &str) -> bool {
+        if self.registries.is_insecure(registry) {
+            return true;
+        }
+        let host = registry.rsplit_once(':').map_or(registry, |(host, _)| host);
+        host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+
+    /// Read the whole response body, which must not exceed `limit` bytes.
+    async fn read_limited(mut response: Response, limit: usize) -> Result<Vec<u8>> {
+        if response
+            .content_length()
+            .is_some_and(|length| length > limit as u64)
+        {
+            bail!("response exceeds {} bytes", limit)
+        }
+        let mut content = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if content.len() + chunk.len() > limit {
+                bail!("response exceeds {} bytes", limit)
+            }
+            content.extend_from_slice(&chunk);
+        }
+        Ok(content)
+    }
+
+    /// Turn unsuccessful responses into an error containing the registry error message.
+    async fn check_status(response: Response) -> Result<Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let url = response.url().clone();
+        let body = response.text().await.unwrap_or_default();
+        Err(StatusError {
+            url,
+            status,
+            body: body.trim().into(),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestAuth, TestRegistry};
+    use config::{RegistriesConfigBuilder, RegistryBuilder};
+
+    #[test]
+    fn url() -> Result<()> {
+        let client = ClientBuilder::default()
+            .registries(
+                RegistriesConfigBuilder::default()
+                    .registries(vec![RegistryBuilder::default()
+                        .location("my-registry:5000")
+                        .insecure(true)
+                        .build()?])
+                    .build()?,
+            )
+            .build()?;
+
+        for (reference, expected) in &[
+            (
+                "nginx",
+                "https://registry-1.docker.io/v2/library/nginx/manifests/latest",
+            ),
+            ("quay.io/app:v1", "https://quay.io/v2/app/manifests/latest"),
+            (
+                "127.0.0.1:5000/a/b",
+                "https://127.0.0.1:5000/v2/a/b/manifests/latest",
+            ),
+        ] {
+            assert_eq!(
+                &client.url("https", &reference.parse()?, "manifests/latest"),
+                expected
+            );
+        }
+        assert_eq!(
+            client.url("http", &"localhost:5000/app".parse()?, "manifests/latest"),
+            "http://localhost:5000/v2/app/manifests/latest"
+        );
+
+        assert!(!client.is_insecure("docker.io"));
+        assert!(!client.is_insecure("quay.io"));
+        assert!(client.is_insecure("localhost:5000"));
+        assert!(client.is_insecure("127.0.0.1:5000"));
+        assert!(client.is_insecure("my-registry:5000"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fallback_plain_http() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let digest = registry.add_blob(b"hello");
+        let client = Client::default();
+
+        // The TLS handshake with the loopback registry fails, which is remembered
+        let reference = format!("{}/app", registry.host()).parse::<Reference>()?;
+        for _ in 0..2 {
+            let response = client.fetch_blob(&reference, &digest, None).await?;
+            assert_eq!(response.url().scheme(), "http");
+            assert_eq!(response.bytes().await?.as_ref(), b"hello");
+        }
+        assert!(client.plain_http().contains(registry.host()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_manifest_by_tag_and_digest() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+        let client = Client::default();
+
+        let reference = format!("{}/app:v1", registry.host()).parse::<Reference>()?;
+        let manifest = client.fetch_manifest(&reference, None).await?;
+        assert_eq!(manifest.digest(), image.manifest_digest());
+
+        let manifest = client
+            .fetch_manifest(
+                &reference.with_digest(image.manifest_digest().clone()),
+                None,
+            )
+            .await?;
+        assert_eq!(manifest.digest(), image.manifest_digest());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_manifest_failure_not_found() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let reference = format!("{}/app:v1", registry.host()).parse::<Reference>()?;
+        assert!(Client::default()
+            .fetch_manifest(&reference, None)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_manifest_failure_digest_mismatch() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[b"layer"])?;
+
+        // Serve the manifest of the image for an unrelated digest
+        let wrong = Digest::from_bytes(Default::default(), b"wrong");
+        registry.tag("app", &wrong.to_string(), image.manifest_digest())?;
+
+        let reference = format!("{}/app", registry.host())
+            .parse::<Reference>()?
+            .with_digest(wrong);
+        assert!(Client::default()
+            .fetch_manifest(&reference, None)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_manifest_failure_too_large() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.add_manifest(
+            "app",
+            Some("v1"),
+            "application/vnd.oci.image.manifest.v1+json",
+            &vec![b' '; MAX_MANIFEST_SIZE + 1],
+        );
+
+        let reference = format!("{}/app:v1", registry.host()).parse::<Reference>()?;
+        let err = Client::default()
+            .fetch_manifest(&reference, None)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("exceeds"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_blob() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let digest = registry.add_blob(b"hello");
+
+        let reference = format!("{}/app", registry.host()).parse::<Reference>()?;
+        let response = Client::default()
+            .fetch_blob(&reference, &digest, None)
+            .await?;
+        assert_eq!(response.bytes().await?.as_ref(), b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_challenge() -> Result<()> {
+        let (scheme, params) = Client::parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:a:pull,push""#,
+        )?;
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:a:pull,push");
+
+        let (scheme, params) = Client::parse_challenge(r#"Basic realm=test, charset="UTF-8""#)?;
+        assert_eq!(scheme, "Basic");
+        assert_eq!(params["realm"], "test");
+        assert_eq!(params["charset"], "UTF-8");
+
+        assert!(Client::parse_challenge("").is_err());
+        Ok(())
+    }
+
+    fn basic(username: &str, password: &str) -> Credentials {
+        Credentials::Basic {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_basic() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.set_auth(TestAuth::Basic {
+            username: "user".into(),
+            password: "pass".into(),
+        });
+        let digest = registry.add_blob(b"hello");
+        let reference = format!("{}/app", registry.host()).parse::<Reference>()?;
+        let client = Client::default();
+
+        assert!(client.fetch_blob(&reference, &digest, None).await.is_err());
+        assert!(client
+            .fetch_blob(&reference, &digest, Some(&basic("user", "wrong")))
+            .await
+            .is_err());
+        client
+            .fetch_blob(&reference, &digest, Some(&basic("user", "pass")))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auth_bearer() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.set_auth(TestAuth::Bearer {
+            username: "user".into(),
+            password: "pass".into(),
+            identity_token: "refresh".into(),
+        });
+        let digest = registry.add_blob(b"hello");
+        let reference = format!("{}/app", registry.host()).parse::<Reference>()?;
+        let client = Client::default();
+
+        assert!(client.fetch_blob(&reference, &digest, None).await.is_err());
+        assert!(client
+            .fetch_blob(&reference, &digest, Some(&basic("user", "wrong")))
+            .await
+            .is_err());
+
+        let credentials = basic("user", "pass");
+        client
+            .fetch_blob(&reference, &digest, Some(&credentials))
+            .await?;
+        client
+            .fetch_blob(&reference, &digest, Some(&credentials))
+            .await?;
+        client
+            .fetch_blob(
+                &reference,
+                &digest,
+                Some(&Credentials::IdentityToken("refresh".into())),
+            )
+            .await?;
+
+        // The token of the first successful login is cached
+        let token_requests = registry
+            .requests()
+            .iter()
+            .filter(|r| r.ends_with("/token"))
+            .count();
+        assert_eq!(token_requests, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auth_registry_token() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.set_auth(TestAuth::Bearer {
+            username: "user".into(),
+            password: "pass".into(),
+            identity_token: "refresh".into(),
+        });
+        let digest = registry.add_blob(b"hello");
+        let reference = format!("{}/app", registry.host()).parse::<Reference>()?;
+        let client = Client::default();
+
+        client
+            .fetch_blob(
+                &reference,
+                &digest,
+                Some(&Credentials::RegistryToken(TestRegistry::TOKEN.into())),
+            )
+            .await?;
+        assert!(client
+            .fetch_blob(
+                &reference,
+                &digest,
+                Some(&Credentials::RegistryToken("wrong".into())),
+            )
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+//! Access to the content of container root file systems.
+//!
+//! Paths inside a root file system are always resolved relative to it, which means that
+//! absolute symbolic links and `..` components can never escape the root.
+
+use anyhow::{bail, Context, Result};
+use getset::{CopyGetters, Getters};
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+/// Maximum number of symbolic links followed while resolving a single path.
+const MAX_SYMLINKS: usize = 255;
+
+/// Location of the user database inside the root file system.
+const PASSWD_PATH: &str = "/etc/passwd";
+
+/// Location of the group database inside the root file system.
+const GROUP_PATH: &str = "/etc/group";
+
+/// Resolve the `path` inside the `root` directory, following symbolic links as if `root` would
+/// be the file system root. Components which do not exist are appended unresolved.
+pub fn resolve_path<R: AsRef<Path>, P: AsRef<Path>>(root: R, path: P) -> Result<PathBuf> {
+    let root = root.as_ref();
+    let mut pending = path
+        .as_ref()
+        .components()
+        .map(|c| c.as_os_str().to_os_string())
+        .collect::<VecDeque<OsString>>();
+    let mut resolved = PathBuf::new();
+    let mut symlinks = 0;
+
+    while let Some(name) = pending.pop_front() {
+        match Path::new(&name).components().next() {
+            Some(Component::Normal(_)) => {}
+            Some(Component::ParentDir) => {
+                resolved.pop();
+                continue;
+            }
+            _ => continue,
+        }
+
+        let candidate = root.join(&resolved).join(&name);
+        let metadata = match fs::symlink_metadata(&candidate) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                resolved.push(name);
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| format!("stat {}", candidate.display())),
+        };
+        if !metadata.file_type().is_symlink() {
+            resolved.push(name);
+            continue;
+        }
+
+        symlinks += 1;
+        if symlinks > MAX_SYMLINKS {
+            bail!("too many symbolic links in {}", path.as_ref().display())
+        }
+        let target = fs::read_link(&candidate)
+            .with_context(|| format!("read link {}", candidate.display()))?;
+        if target.is_absolute() {
+            resolved = PathBuf::new();
+        }
+        for component in target.components().rev() {
+            pending.push_front(component.as_os_str().to_os_string());
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+#[derive(Clone, CopyGetters, Debug, Eq, Getters, PartialEq)]
+/// The user a container process runs as.
+pub struct ResolvedUser {
+    #[get_copy = "pub"]
+    /// The user ID.
+    uid: u32,
+
+    #[get_copy = "pub"]
+    /// The primary group ID.
+    gid: u32,
+
+    #[get = "pub"]
+    /// The supplementary group IDs.
+    additional_gids: Vec<u32>,
+
+    #[get = "pub"]
+    /// The home directory of the user.
+    home: String,
+}
+
+/// Resolve the `user` of an image configuration against the `/etc/passwd` and `/etc/group` files
+/// of the root file system. The user can be given as `user`, `uid`, `user:group`, `uid:gid`,
+/// `uid:group` or `user:gid`, whereas an empty user refers to root. The primary group and
+/// supplementary groups of the user are only applied if no group is specified.
+pub fn resolve_user<P: AsRef<Path>>(rootfs: P, user: &str) -> Result<ResolvedUser> {
+    let rootfs = rootfs.as_ref();
+    let (user, group) = match user.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (user, None),
+    };
+    let user = if user.is_empty() { "0" } else { user };
+
+    let passwd = read_database(rootfs, PASSWD_PATH)?;
+    let entry = passwd.iter().find(|entry| match user.parse::<u32>() {
+        Ok(uid) => entry.get(2).and_then(|id| id.parse().ok()) == Some(uid),
+        Err(_) => entry.first().map(String::as_str) == Some(user),
+    });
+
+    let (uid, name, mut gid, home) = match (entry, user.parse::<u32>()) {
+        (Some(entry), _) => (
+            parse_id(entry, 2, PASSWD_PATH)?,
+            entry[0].clone(),
+            parse_id(entry, 3, PASSWD_PATH)?,
+            entry.get(5).cloned().filter(|h| !h.is_empty()),
+        ),
+        (None, Ok(uid)) => (uid, String::new(), 0, None),
+        (None, Err(_)) => bail!("unable to find user {} in {}", user, PASSWD_PATH),
+    };
+
+    let groups = read_database(rootfs, GROUP_PATH)?;
+    let mut additional_gids = vec![];
+    match group {
+        Some(group) if !group.is_empty() => {
+            gid = match group.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => match groups
+                    .iter()
+                    .find(|entry| entry.first().map(String::as_str) == Some(group))
+                {
+                    Some(entry) => parse_id(entry, 2, GROUP_PATH)?,
+                    None => bail!("unable to find group {} in {}", group, GROUP_PATH),
+                },
+            }
+        }
+        _ if !name.is_empty() => {
+            for entry in &groups {
+                let is_member = entry
+                    .get(3)
+                    .map(|members| members.split(',').any(|m| m == name))
+                    .unwrap_or_default();
+                if is_member {
+                    let id = parse_id(entry, 2, GROUP_PATH)?;
+                    if id != gid && !additional_gids.contains(&id) {
+                        additional_gids.push(id);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(ResolvedUser {
+        uid,
+        gid,
+        additional_gids,
+        home: home.unwrap_or_else(|| "/".into()),
+    })
+}
+
+/// Read the colon separated entries of a database like `/etc/passwd` inside the root file system,
+/// whereas a missing file results in no entries.
+fn read_database(rootfs: &Path, path: &str) -> Result<Vec<Vec<String>>> {
+    let full_path = resolve_path(rootfs, path)?;
+    let content = match fs::read_to_string(&full_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("read {}", full_path.display())),
+    };
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(String::from).collect())
+        .collect())
+}
+
+/// Parse the numeric ID at `index` of a database entry.
+fn parse_id(entry: &[String], index: usize, path: &str) -> Result<u32> {
+    entry
+        .get(index)
+        .and_then(|id| id.parse().ok())
+        .with_context(|| format!("invalid entry {} in {}", entry.join(":"), path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    fn rootfs() -> Result<TempDir> {
+        let dir = TempDir::new()?;
+        fs::create_dir(dir.path().join("etc"))?;
+        fs::write(
+            dir.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\n\
+             # comment\n\
+             app:x:1000:1001:App:/home/app:/bin/sh\n\
+             nohome:x:1002:1002::\n",
+        )?;
+        fs::write(
+            dir.path().join("etc/group"),
+            "root:x:0:\n\
+             app:x:1001:\n\
+             audio:x:29:app,other\n\
+             video:x:44:other,app\n\
+             wheel:x:10:root\n",
+        )?;
+        Ok(dir)
+    }
+
+    fn user(uid: u32, gid: u32, additional_gids: &[u32], home: &str) -> ResolvedUser {
+        ResolvedUser {
+            uid,
+            gid,
+            additional_gids: additional_gids.to_vec(),
+            home: home.into(),
+        }
+    }
+
+    #[test]
+    fn resolve_path_symlinks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("real/etc"))?;
+        symlink("/real/etc", root.join("etc"))?;
+        symlink("../../../../passwd", root.join("real/etc/passwd"))?;
+        symlink("loop", root.join("loop"))?;
+
+        assert_eq!(
+            resolve_path(root, "/etc/group")?,
+            root.join("real/etc/group")
+        );
+        assert_eq!(resolve_path(root, "/etc/passwd")?, root.join("passwd"));
+        assert_eq!(
+            resolve_path(root, "../../etc/./x/")?,
+            root.join("real/etc/x")
+        );
+        assert_eq!(resolve_path(root, "/missing/..")?, root.to_path_buf());
+        assert!(resolve_path(root, "/loop").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_user_by_name() -> Result<()> {
+        let dir = rootfs()?;
+        assert_eq!(
+            resolve_user(dir.path(), "app")?,
+            user(1000, 1001, &[29, 44], "/home/app")
+        );
+        assert_eq!(
+            resolve_user(dir.path(), "root")?,
+            user(0, 0, &[10], "/root")
+        );
+        assert_eq!(
+            resolve_user(dir.path(), "nohome")?,
+            user(1002, 1002, &[], "/")
+        );
+        assert!(resolve_user(dir.path(), "missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_user_by_id() -> Result<()> {
+        let dir = rootfs()?;
+        assert_eq!(resolve_user(dir.path(), "")?, user(0, 0, &[10], "/root"));
+        assert_eq!(
+            resolve_user(dir.path(), "1000")?,
+            user(1000, 1001, &[29, 44], "/home/app")
+        );
+        assert_eq!(resolve_user(dir.path(), "4242")?, user(4242, 0, &[], "/"));
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_user_with_group() -> Result<()> {
+        let dir = rootfs()?;
+        assert_eq!(
+            resolve_user(dir.path(), "app:video")?,
+            user(1000, 44, &[], "/home/app")
+        );
+        assert_eq!(
+            resolve_user(dir.path(), "app:4242")?,
+            user(1000, 4242, &[], "/home/app")
+        );
+        assert_eq!(
+            resolve_user(dir.path(), "4242:29")?,
+            user(4242, 29, &[], "/")
+        );
+        assert!(resolve_user(dir.path(), "app:missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_user_without_database() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert_eq!(resolve_user(dir.path(), "")?, user(0, 0, &[], "/"));
+        assert_eq!(resolve_user(dir.path(), "1:2")?, user(1, 2, &[], "/"));
+        assert!(resolve_user(dir.path(), "app").is_err());
+        assert!(resolve_user(dir.path(), "1:group").is_err());
+        Ok(())
+    }
+}
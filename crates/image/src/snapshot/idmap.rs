@@ -0,0 +1,423 @@
+//! Shifting the ownership of snapshots for containers running in a user namespace.
+//!
+//! Idmapped mounts shift the ownership of the lower directories while mounting them, which
+//! requires kernel support for idmapped overlay layers. Otherwise the layers get copied with
+//! their ownership shifted on disk.
+
+use super::overlay;
+use crate::digest::Digest;
+use anyhow::{bail, Context, Result};
+use getset::{CopyGetters, Getters};
+use libc::c_uint;
+use log::{info, trace};
+use nix::{
+    errno::Errno,
+    mount::{self, MntFlags},
+    sched::{self, CloneFlags},
+    sys::{
+        signal::{self, Signal},
+        wait::{self, WaitPidFlag, WaitStatus},
+    },
+    unistd::{self, ForkResult},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CString,
+    fmt, fs,
+    fs::File,
+    mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, OwnedFd},
+    },
+    path::{Path, PathBuf},
+};
+use strum::{AsRefStr, Display, EnumString};
+use tempfile::TempDir;
+
+/// Directory of a snapshot containing the idmapped mounts of its lower directories.
+const LOWER_DIR: &str = "lower";
+
+/// The ID which unmapped IDs are shifted to, matching the default overflow ID of the kernel.
+const OVERFLOW_ID: u32 = 65534;
+
+/// Clone the mount tree instead of opening it, see `open_tree(2)`.
+const OPEN_TREE_CLONE: c_uint = 1;
+
+/// Move the mount referred to by the file descriptor, see `move_mount(2)`.
+const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x4;
+
+/// Idmap the mount with the user namespace of `userns_fd`, see `mount_setattr(2)`.
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+
+#[repr(C)]
+/// The argument of `mount_setattr(2)`.
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+#[derive(AsRefStr, Clone, Copy, Debug, Display, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+/// The method used to shift the ownership of layers for containers in a user namespace.
+pub enum IdMapMethod {
+    /// Mount the lower directories of overlay snapshots idmapped, which is free but requires
+    /// kernel support.
+    Mount,
+
+    /// Copy the layers with shifted ownership, whereas the copies are shared by all containers
+    /// using the same mappings.
+    Copy,
+}
+
+impl IdMapMethod {
+    /// Select idmapped mounts if overlay can be mounted on top of them within the root
+    /// directory, otherwise copies.
+    pub fn detect<P: AsRef<Path>>(root: P) -> Self {
+        match check(root.as_ref()) {
+            Ok(()) => Self::Mount,
+            Err(e) => {
+                info!(
+                    "Idmapped mounts are not supported, falling back to copies: {:#}",
+                    e
+                );
+                Self::Copy
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+/// A contiguous range of IDs of the container mapped to the host.
+pub struct IdMapping {
+    #[get_copy = "pub"]
+    /// The first ID within the container.
+    container_id: u32,
+
+    #[get_copy = "pub"]
+    /// The first ID on the host.
+    host_id: u32,
+
+    #[get_copy = "pub"]
+    /// The number of mapped IDs.
+    size: u32,
+}
+
+impl IdMapping {
+    /// Create a new mapping of `size` IDs starting at `container_id` to `host_id`.
+    pub fn new(container_id: u32, host_id: u32, size: u32) -> Self {
+        Self {
+            container_id,
+            host_id,
+            size,
+        }
+    }
+
+    /// Map the container ID to the host, which is `None` if it is not within the range.
+    fn map(&self, id: u32) -> Option<u32> {
+        id.checked_sub(self.container_id)
+            .filter(|offset| *offset < self.size)
+            .map(|offset| self.host_id + offset)
+    }
+}
+
+impl fmt::Display for IdMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.container_id, self.host_id, self.size)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, Getters, Hash, PartialEq, Serialize)]
+/// The user and group ID mappings of a user namespace.
+pub struct IdMappings {
+    #[get = "pub"]
+    /// The user ID mappings, ordered by their container ID.
+    uids: Vec<IdMapping>,
+
+    #[get = "pub"]
+    /// The group ID mappings, ordered by their container ID.
+    gids: Vec<IdMapping>,
+}
+
+impl IdMappings {
+    /// Create new mappings, whereas neither the container nor the host ranges may overlap.
+    pub fn new(mut uids: Vec<IdMapping>, mut gids: Vec<IdMapping>) -> Result<Self> {
+        for (kind, mappings) in [("uid", &mut uids), ("gid", &mut gids)] {
+            if mappings.is_empty() {
+                bail!("no {} mappings provided", kind)
+            }
+            if let Some(m) = mappings.iter().find(|m| {
+                m.size == 0
+                    || m.container_id.checked_add(m.size - 1).is_none()
+                    || m.host_id.checked_add(m.size - 1).is_none()
+            }) {
+                bail!("invalid {} mapping {}", kind, m)
+            }
+            let ranges = |key: fn(&IdMapping) -> u32| {
+                let mut ranges = mappings
+                    .iter()
+                    .map(|m| (key(m), m.size))
+                    .collect::<Vec<_>>();
+                ranges.sort_unstable();
+                ranges
+                    .windows(2)
+                    .any(|w| u64::from(w[0].0) + u64::from(w[0].1) > u64::from(w[1].0))
+            };
+            if ranges(|m| m.container_id) || ranges(|m| m.host_id) {
+                bail!("overlapping {} mappings", kind)
+            }
+            mappings.sort_unstable_by_key(|m| m.container_id);
+        }
+        Ok(Self { uids, gids })
+    }
+
+    /// The key identifying the mappings, which is equal for equal mappings regardless of their
+    /// order.
+    pub fn key(&self) -> String {
+        Digest::from_bytes(Default::default(), self.to_string().as_bytes())
+            .encoded()
+            .clone()
+    }
+
+    /// Map the user ID of the container to the host.
+    pub fn map_uid(&self, uid: u32) -> u32 {
+        Self::map(&self.uids, uid)
+    }
+
+    /// Map the group ID of the container to the host.
+    pub fn map_gid(&self, gid: u32) -> u32 {
+        Self::map(&self.gids, gid)
+    }
+
+    /// Map the ID, whereas unmapped IDs become the overflow ID.
+    fn map(mappings: &[IdMapping], id: u32) -> u32 {
+        mappings
+            .iter()
+            .find_map(|m| m.map(id))
+            .unwrap_or(OVERFLOW_ID)
+    }
+}
+
+impl fmt::Display for IdMappings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |mappings: &[IdMapping]| {
+            mappings
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        write!(f, "uids={};gids={}", join(&self.uids), join(&self.gids))
+    }
+}
+
+/// Check if overlay can be mounted on top of an idmapped mount within the root directory.
+fn check(root: &Path) -> Result<()> {
+    if !unistd::geteuid().is_root() {
+        bail!("idmapped mounts require root privileges")
+    }
+    let dir = TempDir::new_in(root).context("create idmap check dir")?;
+    let lower = dir.path().join("layer");
+    fs::create_dir(&lower)?;
+    for sub_dir in &[overlay::UPPER_DIR, overlay::WORK_DIR] {
+        fs::create_dir(dir.path().join(sub_dir))?;
+    }
+    let mappings = IdMappings::new(
+        vec![IdMapping::new(0, 100_000, 65536)],
+        vec![IdMapping::new(0, 100_000, 65536)],
+    )?;
+    let mounted = mount(dir.path(), &[lower], &mappings)
+        .and_then(|lower_dirs| overlay::mount(dir.path(), &lower_dirs))
+        .and_then(|_| overlay::unmount(dir.path()));
+    unmount(dir.path())?;
+    mounted
+}
+
+/// Mount the lower directories of the snapshot in `dir` idmapped and return the mount points in
+/// the same order.
+pub fn mount(dir: &Path, lower_dirs: &[PathBuf], mappings: &IdMappings) -> Result<Vec<PathBuf>> {
+    let userns = user_namespace(mappings).context("create user namespace")?;
+    let mut targets = Vec::with_capacity(lower_dirs.len());
+    for (index, lower_dir) in lower_dirs.iter().enumerate() {
+        let target = dir.join(LOWER_DIR).join(index.to_string());
+        fs::create_dir_all(&target)
+            .with_context(|| format!("create mount point {}", target.display()))?;
+        if let Err(e) = mount_idmapped(lower_dir, &target, &userns) {
+            unmount(dir)?;
+            return Err(e);
+        }
+        targets.push(target);
+    }
+    trace!("Mounted {} idmapped lower dirs", targets.len());
+    Ok(targets)
+}
+
+/// Unmount all idmapped lower directories of the snapshot in `dir` and remove their mount
+/// points, which succeeds if none are mounted.
+pub fn unmount(dir: &Path) -> Result<()> {
+    let lower = dir.join(LOWER_DIR);
+    let entries = match fs::read_dir(&lower) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", lower.display())),
+    };
+    for entry in entries {
+        let target = entry?.path();
+        match mount::umount2(&target, MntFlags::MNT_DETACH) {
+            Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => {}
+            Err(e) => return Err(e).with_context(|| format!("unmount {}", target.display())),
+        }
+        // Never remove anything within a mount point which is still mounted
+        fs::remove_dir(&target).with_context(|| format!("remove {}", target.display()))?;
+    }
+    fs::remove_dir(&lower).with_context(|| format!("remove {}", lower.display()))
+}
+
+/// Create a user namespace with the mappings and return a file descriptor referring to it.
+fn user_namespace(mappings: &IdMappings) -> Result<File> {
+    // SAFETY: the child only uses async-signal-safe functions before exiting.
+    match unsafe { unistd::fork() }.context("fork")? {
+        ForkResult::Child => {
+            let code = match sched::unshare(CloneFlags::CLONE_NEWUSER) {
+                Ok(()) => signal::raise(Signal::SIGSTOP).map_or(1, |()| 0),
+                Err(_) => 1,
+            };
+            unsafe { libc::_exit(code) }
+        }
+        ForkResult::Parent { child } => {
+            let userns = (|| {
+                match wait::waitpid(child, Some(WaitPidFlag::WUNTRACED))? {
+                    WaitStatus::Stopped(..) => {}
+                    status => bail!("unshare user namespace failed: {:?}", status),
+                }
+                let proc = Path::new("/proc").join(child.to_string());
+                for (file, mappings) in [("uid_map", mappings.uids()), ("gid_map", mappings.gids())]
+                {
+                    let content = mappings
+                        .iter()
+                        .map(|m| format!("{}\n", m))
+                        .collect::<String>();
+                    fs::write(proc.join(file), content)
+                        .with_context(|| format!("write {}", file))?;
+                }
+                File::open(proc.join("ns/user")).context("open user namespace")
+            })();
+            signal::kill(child, Signal::SIGKILL).ok();
+            wait::waitpid(child, None).ok();
+            userns
+        }
+    }
+}
+
+/// Bind mount `source` to the existing `target` directory, idmapped by the user namespace.
+fn mount_idmapped(source: &Path, target: &Path, userns: &File) -> Result<()> {
+    let empty = CString::default();
+    let source_path = CString::new(source.as_os_str().as_bytes())?;
+    let tree = Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            source_path.as_ptr(),
+            OPEN_TREE_CLONE | libc::O_CLOEXEC as c_uint,
+        )
+    })
+    .with_context(|| format!("clone mount tree of {}", source.display()))?;
+    // SAFETY: open_tree returned a new file descriptor which is owned from now on.
+    let tree = unsafe { OwnedFd::from_raw_fd(tree as _) };
+
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns.as_raw_fd() as u64,
+    };
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_EMPTY_PATH,
+            &attr as *const MountAttr,
+            mem::size_of::<MountAttr>(),
+        )
+    })
+    .with_context(|| format!("idmap mount of {}", source.display()))?;
+
+    let target_path = CString::new(target.as_os_str().as_bytes())?;
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            target_path.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    })
+    .with_context(|| format!("move idmapped mount to {}", target.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings() -> Result<()> {
+        let sut = IdMappings::new(
+            vec![
+                IdMapping::new(1000, 300_000, 10),
+                IdMapping::new(0, 200_000, 1000),
+            ],
+            vec![IdMapping::new(0, 200_000, 65536)],
+        )?;
+        assert_eq!(sut.uids()[0].container_id(), 0);
+        assert_eq!(sut.map_uid(0), 200_000);
+        assert_eq!(sut.map_uid(999), 200_999);
+        assert_eq!(sut.map_uid(1005), 300_005);
+        assert_eq!(sut.map_uid(1010), OVERFLOW_ID);
+        assert_eq!(sut.map_gid(42), 200_042);
+
+        // The key does not depend on the order of the mappings
+        let reordered = IdMappings::new(
+            vec![
+                IdMapping::new(0, 200_000, 1000),
+                IdMapping::new(1000, 300_000, 10),
+            ],
+            vec![IdMapping::new(0, 200_000, 65536)],
+        )?;
+        assert_eq!(sut.key(), reordered.key());
+        let other = IdMappings::new(
+            vec![IdMapping::new(0, 200_000, 1000)],
+            vec![IdMapping::new(0, 200_000, 65536)],
+        )?;
+        assert_ne!(sut.key(), other.key());
+        Ok(())
+    }
+
+    #[test]
+    fn mappings_failure() {
+        let valid = vec![IdMapping::new(0, 100_000, 65536)];
+        assert!(IdMappings::new(vec![], valid.clone()).is_err());
+        assert!(IdMappings::new(valid.clone(), vec![IdMapping::new(0, 0, 0)]).is_err());
+        assert!(IdMappings::new(valid.clone(), vec![IdMapping::new(1, u32::MAX, 2)]).is_err());
+        assert!(IdMappings::new(
+            vec![
+                IdMapping::new(0, 100_000, 10),
+                IdMapping::new(5, 200_000, 10)
+            ],
+            valid.clone()
+        )
+        .is_err());
+        assert!(IdMappings::new(
+            valid.clone(),
+            vec![
+                IdMapping::new(0, 100_000, 10),
+                IdMapping::new(10, 100_005, 10)
+            ]
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,1080 @@
+//! Snapshots of unpacked image layers and the writable root file systems of containers.
+//!
+//! Every layer of an image gets committed as snapshot named by its [chain ID][0], which points
+//! to the snapshot of the layer below as parent. Containers prepare an active snapshot on top of
+//! the last layer, which is either mounted as overlay or is a full copy of its parent chain.
+//!
+//! Containers in a user namespace need a root file system owned by their mapped IDs. Their
+//! layers are either mounted idmapped or get copied with shifted ownership into committed
+//! snapshots, which are keyed by the mappings and therefore shared between containers.
+//!
+//! The disk usage of active snapshots can be limited by project quotas if the file system
+//! supports them.
+//!
+//! [0]: https://github.com/opencontainers/image-spec/blob/main/config.md#layer-chainid
+
+use crate::{
+    digest::Digest,
+    encryption::Decryptor,
+    layer::{LayerApplier, LayerApplierBuilder, WhiteoutFormat},
+    store::BlobStore,
+    usage::Usage,
+};
+use anyhow::{bail, Context, Result};
+use getset::{CopyGetters, Getters};
+use log::{debug, info, trace, warn};
+use oci_spec::image::ImageManifest;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    os::unix::fs::{lchown, MetadataExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+use storage::{default_key_value_storage::DefaultKeyValueStorage, KeyValueStorage};
+use strum::{AsRefStr, Display, EnumString};
+
+pub use idmap::{IdMapMethod, IdMapping, IdMappings};
+pub use quota::QuotaControl;
+
+mod idmap;
+mod overlay;
+mod quota;
+mod vfs;
+
+/// Directory containing the data of all snapshots.
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+/// Directory containing the snapshot metadata database.
+const METADATA_DIR: &str = "metadata";
+
+/// Storage key prefix of the snapshot metadata.
+const SNAPSHOT_PREFIX: &str = "snapshot/";
+
+/// Storage key of the next snapshot ID.
+const NEXT_ID_KEY: &str = "next-id";
+
+/// Key prefix of the active snapshots used while creating committed ones, for example when
+/// unpacking a layer.
+pub const EXTRACT_PREFIX: &str = "extract-";
+
+/// Key prefix of the committed snapshots with ownership shifted by ID mappings.
+const IDMAP_PREFIX: &str = "idmap-";
+
+#[derive(AsRefStr, Clone, Copy, Debug, Display, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+/// The implementation used to stack snapshots.
+pub enum Driver {
+    /// Stack snapshots via overlay mounts, which requires privileges and kernel support.
+    Overlay,
+
+    /// Copy the parent snapshot on prepare, which works everywhere but is slow and needs much
+    /// more disk space.
+    Vfs,
+}
+
+impl Driver {
+    /// Select overlay if it can be mounted within the root directory, otherwise vfs.
+    pub fn detect<P: AsRef<Path>>(root: P) -> Self {
+        match overlay::check(root.as_ref()) {
+            Ok(()) => Self::Overlay,
+            Err(e) => {
+                info!("Overlay is not supported, falling back to vfs: {:#}", e);
+                Self::Vfs
+            }
+        }
+    }
+
+    /// The whiteout format for applying layers into snapshots of the driver.
+    fn whiteout_format(self) -> WhiteoutFormat {
+        match self {
+            Self::Overlay => WhiteoutFormat::Overlay,
+            Self::Vfs => WhiteoutFormat::Remove,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// The state of a snapshot.
+pub enum SnapshotKind {
+    /// A writable snapshot, which cannot be used as parent.
+    Active,
+
+    /// An immutable snapshot, which can be used as parent.
+    Committed,
+}
+
+#[derive(Clone, CopyGetters, Debug, Deserialize, Getters, Serialize)]
+/// The metadata of a snapshot.
+pub struct SnapshotInfo {
+    #[get = "pub"]
+    /// The unique key of the snapshot.
+    key: String,
+
+    #[get_copy = "pub"]
+    /// The internal ID, which names the directory of the snapshot.
+    id: u64,
+
+    #[get = "pub"]
+    /// The key of the committed parent snapshot.
+    parent: Option<String>,
+
+    #[get_copy = "pub"]
+    /// The state of the snapshot.
+    kind: SnapshotKind,
+
+    #[get_copy = "pub"]
+    /// The creation time of the snapshot.
+    created: SystemTime,
+
+    #[get = "pub"]
+    #[serde(default)]
+    /// The ID mappings of the idmapped mounts of the parent snapshots.
+    id_mappings: Option<IdMappings>,
+
+    #[get_copy = "pub"]
+    #[serde(default)]
+    /// The disk quota of the snapshot in bytes.
+    size_limit: Option<u64>,
+}
+
+#[derive(Clone, CopyGetters, Debug, Getters)]
+/// Manages snapshots on disk and their metadata.
+pub struct Snapshotter {
+    #[get = "pub"]
+    /// The root path of the snapshotter.
+    root: PathBuf,
+
+    #[get_copy = "pub"]
+    /// The driver used to stack snapshots.
+    driver: Driver,
+
+    /// Metadata of all snapshots.
+    storage: Arc<Mutex<DefaultKeyValueStorage>>,
+
+    #[get = "pub"]
+    /// Decrypts encrypted layers while unpacking.
+    decryptor: Decryptor,
+
+    #[get_copy = "pub"]
+    /// The method used to shift the ownership of layers for snapshots with ID mappings.
+    id_map_method: IdMapMethod,
+
+    #[get = "pub"]
+    /// Enforces the size limits of snapshots, which is not available on all file systems.
+    quota: Option<QuotaControl>,
+}
+
+impl Snapshotter {
+    /// Open the snapshotter at the root path, which gets created if it does not exist.
+    pub fn open<P: AsRef<Path>>(root: P, driver: Driver) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join(SNAPSHOTS_DIR))
+            .with_context(|| format!("create snapshots dir in {}", root.display()))?;
+        let storage = DefaultKeyValueStorage::open(root.join(METADATA_DIR))?;
+
+        debug!("Opened {} snapshotter {}", driver, root.display());
+        Ok(Self {
+            root,
+            driver,
+            storage: Arc::new(Mutex::new(storage)),
+            decryptor: Decryptor::default(),
+            id_map_method: IdMapMethod::Copy,
+            quota: None,
+        })
+    }
+
+    /// Use the decryptor for encrypted layers, which cannot be unpacked otherwise.
+    pub fn with_decryptor(mut self, decryptor: Decryptor) -> Self {
+        self.decryptor = decryptor;
+        self
+    }
+
+    /// Use the method to shift the ownership of layers, whereas idmapped mounts are only used
+    /// by the overlay driver.
+    pub fn with_id_map_method(mut self, id_map_method: IdMapMethod) -> Self {
+        self.id_map_method = id_map_method;
+        self
+    }
+
+    /// Use the quota control to enforce size limits of snapshots.
+    pub fn with_quota(mut self, quota: QuotaControl) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Create an active snapshot on top of the committed parent and return the path to its
+    /// writable directory. For overlay this is the empty upper directory, whereas vfs copies the
+    /// parent into it.
+    pub async fn prepare(&self, key: &str, parent: Option<&str>) -> Result<PathBuf> {
+        let info = self.create(key, parent, None)?;
+        if let (Driver::Vfs, Some(parent)) = (self.driver, parent) {
+            let source = self.fs_dir(self.info(parent)?.id);
+            let target = self.fs_dir(info.id);
+            let copied = tokio::task::spawn_blocking(move || vfs::copy_dir(&source, &target, None))
+                .await
+                .context("join snapshot copy");
+            if let Err(e) = copied.and_then(|r| r) {
+                self.remove(key).await?;
+                return Err(e).with_context(|| format!("copy parent snapshot {}", parent));
+            }
+        }
+        Ok(self.fs_dir(info.id))
+    }
+
+    /// Create an active snapshot like [`prepare`], whose file system is owned by the IDs the
+    /// `mappings` assign on the host. Overlay snapshots mount their parents idmapped if the
+    /// [`IdMapMethod`] allows it. Otherwise the parents are copied once with shifted ownership
+    /// and the copies are reused by all snapshots with the same mappings.
+    ///
+    /// [`prepare`]: Snapshotter::prepare
+    pub async fn prepare_mapped(
+        &self,
+        key: &str,
+        parent: Option<&str>,
+        mappings: &IdMappings,
+    ) -> Result<PathBuf> {
+        let idmapped = self.driver == Driver::Overlay && self.id_map_method == IdMapMethod::Mount;
+        let parent = match parent {
+            Some(parent) if !idmapped => Some(self.map_parents(parent, mappings).await?),
+            parent => parent.map(ToString::to_string),
+        };
+        let dir = if idmapped {
+            let info = self.create(key, parent.as_deref(), Some(mappings.clone()))?;
+            self.fs_dir(info.id)
+        } else {
+            self.prepare(key, parent.as_deref()).await?
+        };
+
+        // The root of the file system is the upper directory for overlay, which gets the owner
+        // of the topmost parent like the copies of vfs do
+        let owner = match (&parent, self.driver) {
+            (None, _) => Some((mappings.map_uid(0), mappings.map_gid(0))),
+            (Some(parent), Driver::Overlay) => {
+                let metadata = fs::metadata(self.fs_dir(self.info(parent)?.id))?;
+                Some(if idmapped {
+                    (
+                        mappings.map_uid(metadata.uid()),
+                        mappings.map_gid(metadata.gid()),
+                    )
+                } else {
+                    (metadata.uid(), metadata.gid())
+                })
+            }
+            (Some(_), Driver::Vfs) => None,
+        };
+        if let Some((uid, gid)) = owner {
+            if let Err(e) = lchown(&dir, Some(uid), Some(gid)) {
+                self.remove(key).await?;
+                return Err(e).with_context(|| format!("change owner of snapshot {}", key));
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Create the metadata and directories of a new active snapshot on top of the parent.
+    fn create(
+        &self,
+        key: &str,
+        parent: Option<&str>,
+        id_mappings: Option<IdMappings>,
+    ) -> Result<SnapshotInfo> {
+        let info = {
+            let mut storage = self.storage();
+            if Self::get(&storage, key)?.is_some() {
+                bail!("snapshot {} already exists", key)
+            }
+            if let Some(parent) = parent {
+                match Self::get(&storage, parent)? {
+                    Some(info) if info.kind == SnapshotKind::Committed => {}
+                    Some(_) => bail!("parent snapshot {} is not committed", parent),
+                    None => bail!("parent snapshot {} does not exist", parent),
+                }
+            }
+
+            let id = storage.get::<_, u64>(NEXT_ID_KEY)?.unwrap_or_default();
+            storage.insert(NEXT_ID_KEY, id + 1)?;
+            let info = SnapshotInfo {
+                key: key.into(),
+                id,
+                parent: parent.map(ToString::to_string),
+                kind: SnapshotKind::Active,
+                created: SystemTime::now(),
+                id_mappings,
+                size_limit: None,
+            };
+            let dir = self.dir(id);
+            if dir.exists() {
+                // Leftover of a snapshot whose metadata never got written
+                fs::remove_dir_all(&dir)?;
+            }
+            fs::create_dir_all(self.fs_dir(id))?;
+            if self.driver == Driver::Overlay {
+                fs::create_dir(dir.join(overlay::WORK_DIR))?;
+            }
+            storage.insert(Self::storage_key(key), &info)?;
+            info
+        };
+        trace!("Prepared snapshot {} with ID {}", key, info.id);
+        Ok(info)
+    }
+
+    /// Commit the active snapshot under the new name, which makes it immutable and allows using
+    /// it as parent.
+    pub fn commit(&self, name: &str, key: &str) -> Result<()> {
+        let info = self.info(key)?;
+        if info.kind != SnapshotKind::Active {
+            bail!("snapshot {} is not active", key)
+        }
+        self.unmount_dir(&self.dir(info.id))?;
+
+        let mut storage = self.storage();
+        if Self::get(&storage, name)?.is_some() {
+            bail!("snapshot {} already exists", name)
+        }
+        let committed = SnapshotInfo {
+            key: name.into(),
+            kind: SnapshotKind::Committed,
+            ..info
+        };
+        storage.insert(Self::storage_key(name), &committed)?;
+        storage.remove(Self::storage_key(key))?;
+        trace!("Committed snapshot {} as {}", key, name);
+        Ok(())
+    }
+
+    /// Limit the disk usage of the active snapshot to `bytes`, which fails if the file system
+    /// does not support quotas. Overlay only limits the writable layer, whereas vfs snapshots
+    /// account their whole file system.
+    pub fn limit(&self, key: &str, bytes: u64) -> Result<()> {
+        let quota = self
+            .quota
+            .as_ref()
+            .context("disk quotas are not supported")?;
+        let info = self.info(key)?;
+        if info.kind != SnapshotKind::Active {
+            bail!("snapshot {} is not active", key)
+        }
+        let mut dirs = vec![self.fs_dir(info.id)];
+        if self.driver == Driver::Overlay {
+            dirs.push(self.dir(info.id).join(overlay::WORK_DIR));
+        }
+        quota
+            .apply(info.id, &dirs, bytes)
+            .with_context(|| format!("limit snapshot {}", key))?;
+
+        let mut storage = self.storage();
+        let mut info = Self::get(&storage, key)?
+            .with_context(|| format!("snapshot {} does not exist", key))?;
+        info.size_limit = Some(bytes);
+        storage.insert(Self::storage_key(key), &info)?;
+        trace!("Limited snapshot {} to {} bytes", key, bytes);
+        Ok(())
+    }
+
+    /// Remove the snapshot including its data, which succeeds if it does not exist. Snapshots
+    /// which are parents of other snapshots cannot be removed.
+    pub async fn remove(&self, key: &str) -> Result<()> {
+        let info = match self.stat(key)? {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        let dir = self.dir(info.id);
+        self.unmount_dir(&dir)?;
+
+        {
+            let mut storage = self.storage();
+            let children = Self::list_storage(&storage)?
+                .into_iter()
+                .filter(|s| s.parent.as_deref() == Some(key))
+                .map(|s| s.key)
+                .collect::<Vec<_>>();
+            if !children.is_empty() {
+                bail!("snapshot {} is the parent of {}", key, children.join(", "))
+            }
+            storage.remove(Self::storage_key(key))?;
+        }
+
+        tokio::task::spawn_blocking(move || fs::remove_dir_all(&dir))
+            .await
+            .context("join snapshot removal")?
+            .with_context(|| format!("remove snapshot {}", key))?;
+        if let (Some(quota), Some(_)) = (&self.quota, info.size_limit) {
+            if let Err(e) = quota.clear(info.id) {
+                warn!("Unable to clear quota of snapshot {}: {:#}", key, e)
+            }
+        }
+        trace!("Removed snapshot {}", key);
+        Ok(())
+    }
+
+    /// Mount the active snapshot and return the path to its root file system. Snapshots without
+    /// parent and vfs snapshots are used directly without any mount.
+    pub fn mount(&self, key: &str) -> Result<PathBuf> {
+        let info = self.info(key)?;
+        if info.kind != SnapshotKind::Active {
+            bail!("snapshot {} is not active", key)
+        }
+        if self.driver == Driver::Vfs || info.parent.is_none() {
+            return Ok(self.fs_dir(info.id));
+        }
+
+        let dir = self.dir(info.id);
+        let mut lower_dirs = self
+            .parents(&info)?
+            .iter()
+            .map(|parent| self.fs_dir(parent.id))
+            .collect::<Vec<_>>();
+        // Mounted snapshots already use their idmapped lower directories
+        if let Some(mappings) = info.id_mappings.as_ref() {
+            if !overlay::is_mounted(&dir)? {
+                idmap::unmount(&dir)?;
+                lower_dirs = idmap::mount(&dir, &lower_dirs, mappings)
+                    .with_context(|| format!("mount parents of snapshot {} idmapped", key))?;
+            }
+        }
+        overlay::mount(&dir, &lower_dirs).map_err(|e| {
+            if let Err(e) = idmap::unmount(&dir) {
+                warn!("Unable to unmount idmapped parents of {}: {:#}", key, e)
+            }
+            e.context(format!("mount snapshot {}", key))
+        })
+    }
+
+    /// Unmount the snapshot if it is mounted.
+    pub fn unmount(&self, key: &str) -> Result<()> {
+        let info = self.info(key)?;
+        self.unmount_dir(&self.dir(info.id))
+    }
+
+    /// Get the metadata of a snapshot.
+    pub fn stat(&self, key: &str) -> Result<Option<SnapshotInfo>> {
+        Self::get(&self.storage(), key)
+    }
+
+    /// List the metadata of all snapshots.
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>> {
+        Self::list_storage(&self.storage())
+    }
+
+    /// Calculate the disk usage of the snapshot, which is only its own layer for overlay but the
+    /// whole file system for vfs. Limited snapshots report the usage accounted by their quota.
+    pub async fn usage(&self, key: &str) -> Result<Usage> {
+        let info = self.info(key)?;
+        if let (Some(quota), Some(_)) = (&self.quota, info.size_limit) {
+            return quota.usage(info.id);
+        }
+        let dir = self.fs_dir(info.id);
+        tokio::task::spawn_blocking(move || Usage::of_dir(dir))
+            .await
+            .context("join snapshot usage")?
+    }
+
+    /// Unpack all layers of the image into committed snapshots and return the key of the
+    /// topmost one, which is `None` for images without layers. Layers which are already
+    /// unpacked are skipped.
+    pub async fn unpack(
+        &self,
+        store: &BlobStore,
+        manifest: &ImageManifest,
+    ) -> Result<Option<String>> {
+        let diff_ids = diff_ids(store, manifest).await?;
+        if diff_ids.len() != manifest.layers().len() {
+            bail!(
+                "image config has {} diff IDs, but the manifest has {} layers",
+                diff_ids.len(),
+                manifest.layers().len()
+            )
+        }
+
+        let applier = LayerApplierBuilder::default()
+            .rootless(LayerApplier::default().rootless())
+            .whiteouts(self.driver.whiteout_format())
+            .decryptor(self.decryptor.clone())
+            .build()?;
+        let mut parent: Option<String> = None;
+        let layers = manifest.layers().iter().zip(chain_ids(&diff_ids));
+        for ((layer, chain_id), diff_id) in layers.zip(diff_ids) {
+            let chain_id = chain_id.to_string();
+            if self.is_committed(&chain_id)? {
+                trace!("Layer {} is already unpacked", chain_id);
+                parent = Some(chain_id);
+                continue;
+            }
+
+            let key = extract_key(&chain_id)?;
+            let dir = self.prepare(&key, parent.as_deref()).await?;
+            let digest = layer.digest().parse::<Digest>()?;
+            let applied = applier
+                .apply_blob(store, layer, &dir)
+                .await
+                .and_then(|applied| {
+                    if applied != diff_id {
+                        bail!(
+                            "diff ID mismatch for layer {}: expected {}, got {}",
+                            digest,
+                            diff_id,
+                            applied
+                        )
+                    }
+                    self.commit(&chain_id, &key)
+                });
+            if let Err(e) = applied {
+                self.remove(&key).await?;
+                // Another unpack of the same layer may have won the race
+                if !self.is_committed(&chain_id)? {
+                    return Err(e);
+                }
+            }
+            debug!("Unpacked layer {} as snapshot {}", digest, chain_id);
+            parent = Some(chain_id);
+        }
+        Ok(parent)
+    }
+
+    /// Get the committed snapshot containing the parent with ownership shifted by the mappings.
+    /// Missing shifted copies are created from the parent chain, which is layer by layer for
+    /// overlay but only the parent itself for vfs, since it already contains the whole chain.
+    async fn map_parents(&self, parent: &str, mappings: &IdMappings) -> Result<String> {
+        let info = self.info(parent)?;
+        if info.kind != SnapshotKind::Committed {
+            bail!("parent snapshot {} is not committed", parent)
+        }
+        let chain = match self.driver {
+            Driver::Overlay => {
+                let mut chain = self.parents(&info)?;
+                chain.reverse();
+                chain.push(info);
+                chain
+            }
+            Driver::Vfs => vec![info],
+        };
+
+        let mut mapped_parent = None;
+        for layer in chain {
+            let key = format!("{}{}-{}", IDMAP_PREFIX, mappings.key(), layer.key);
+            if self.is_committed(&key)? {
+                trace!("Snapshot {} is already shifted", layer.key);
+            } else {
+                self.map_layer(&layer, &key, mapped_parent.as_deref(), mappings)
+                    .await?;
+            }
+            mapped_parent = Some(key);
+        }
+        mapped_parent.with_context(|| format!("no shifted copy of {}", parent))
+    }
+
+    /// Copy the committed snapshot with shifted ownership and commit the copy as `name` on top
+    /// of the parent.
+    async fn map_layer(
+        &self,
+        layer: &SnapshotInfo,
+        name: &str,
+        parent: Option<&str>,
+        mappings: &IdMappings,
+    ) -> Result<()> {
+        let key = extract_key(name)?;
+        let source = self.fs_dir(layer.id);
+        let target = self.fs_dir(self.create(&key, parent, None)?.id);
+        let mappings = mappings.clone();
+        let copied =
+            tokio::task::spawn_blocking(move || vfs::copy_dir(&source, &target, Some(&mappings)))
+                .await
+                .context("join snapshot copy")
+                .and_then(|r| r)
+                .and_then(|()| self.commit(name, &key));
+        if let Err(e) = copied {
+            self.remove(&key).await?;
+            // Another container with the same mappings may have won the race
+            if !self.is_committed(name)? {
+                return Err(e).with_context(|| format!("shift ownership of {}", layer.key));
+            }
+        }
+        debug!("Shifted ownership of snapshot {} into {}", layer.key, name);
+        Ok(())
+    }
+
+    /// Unmount the overlay and the idmapped parents of the snapshot in `dir`.
+    fn unmount_dir(&self, dir: &Path) -> Result<()> {
+        if self.driver == Driver::Overlay {
+            overlay::unmount(dir)?;
+            idmap::unmount(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Check if a committed snapshot exists for the key.
+    fn is_committed(&self, key: &str) -> Result<bool> {
+        Ok(self
+            .stat(key)?
+            .is_some_and(|info| info.kind == SnapshotKind::Committed))
+    }
+
+    /// Get the metadata of an existing snapshot.
+    fn info(&self, key: &str) -> Result<SnapshotInfo> {
+        self.stat(key)?
+            .with_context(|| format!("snapshot {} does not exist", key))
+    }
+
+    /// Get all parents of the snapshot, starting with the nearest one.
+    fn parents(&self, info: &SnapshotInfo) -> Result<Vec<SnapshotInfo>> {
+        let storage = self.storage();
+        let mut parents = vec![];
+        let mut next = info.parent.clone();
+        while let Some(key) = next {
+            let parent = Self::get(&storage, &key)?
+                .with_context(|| format!("parent snapshot {} does not exist", key))?;
+            next = parent.parent.clone();
+            parents.push(parent);
+        }
+        Ok(parents)
+    }
+
+    /// The directory of the snapshot.
+    fn dir(&self, id: u64) -> PathBuf {
+        self.root.join(SNAPSHOTS_DIR).join(id.to_string())
+    }
+
+    /// The directory containing the file system data of the snapshot.
+    fn fs_dir(&self, id: u64) -> PathBuf {
+        self.dir(id).join("fs")
+    }
+
+    /// Lock the metadata storage.
+    fn storage(&self) -> MutexGuard<'_, DefaultKeyValueStorage> {
+        self.storage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The storage key of the snapshot metadata.
+    fn storage_key(key: &str) -> String {
+        format!("{}{}", SNAPSHOT_PREFIX, key)
+    }
+
+    /// Get the snapshot metadata from the locked storage.
+    fn get(storage: &DefaultKeyValueStorage, key: &str) -> Result<Option<SnapshotInfo>> {
+        storage.get(Self::storage_key(key))
+    }
+
+    /// List all snapshot metadata from the locked storage.
+    fn list_storage(storage: &DefaultKeyValueStorage) -> Result<Vec<SnapshotInfo>> {
+        Ok(storage
+            .scan_prefix(SNAPSHOT_PREFIX)?
+            .into_iter()
+            .map(|(_, info)| info)
+            .collect())
+    }
+}
+
+/// The key of the active snapshot used while creating the committed snapshot `name`.
+fn extract_key(name: &str) -> Result<String> {
+    Ok(format!(
+        "{}{}-{}",
+        EXTRACT_PREFIX,
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos(),
+        name
+    ))
+}
+
+/// Read the digests of the uncompressed layers from the configuration of the image.
+pub async fn diff_ids(store: &BlobStore, manifest: &ImageManifest) -> Result<Vec<Digest>> {
+    let config_digest = manifest.config().digest().parse::<Digest>()?;
+    let config: serde_json::Value =
+        serde_json::from_slice(&store.read(&config_digest).await?).context("parse image config")?;
+    Ok(config["rootfs"]["diff_ids"]
+        .as_array()
+        .map(|ids| {
+            ids.iter()
+                .map(|id| Ok(id.as_str().context("diff ID is no string")?.parse()?))
+                .collect::<Result<Vec<Digest>>>()
+        })
+        .transpose()?
+        .unwrap_or_default())
+}
+
+/// Calculate the chain IDs of the layers, which are the keys of their committed snapshots.
+pub fn chain_ids(diff_ids: &[Digest]) -> Vec<Digest> {
+    let mut chain_ids: Vec<Digest> = Vec::with_capacity(diff_ids.len());
+    for diff_id in diff_ids {
+        let chain_id = match chain_ids.last() {
+            Some(parent) => Digest::from_bytes(
+                Default::default(),
+                format!("{} {}", parent, diff_id).as_bytes(),
+            ),
+            None => diff_id.clone(),
+        };
+        chain_ids.push(chain_id);
+    }
+    chain_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encryption, testing};
+    use serde_json::json;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::TempDir;
+
+    fn snapshotters(dir: &TempDir) -> Result<Vec<Snapshotter>> {
+        let mut snapshotters = vec![Snapshotter::open(dir.path().join("vfs"), Driver::Vfs)?];
+        let overlay_root = dir.path().join("overlay");
+        fs::create_dir(&overlay_root)?;
+        if Driver::detect(&overlay_root) == Driver::Overlay {
+            snapshotters.push(Snapshotter::open(overlay_root, Driver::Overlay)?);
+        }
+        Ok(snapshotters)
+    }
+
+    #[tokio::test]
+    async fn prepare_commit_mount() -> Result<()> {
+        let dir = TempDir::new()?;
+        for sut in snapshotters(&dir)? {
+            let base = sut.prepare("base-active", None).await?;
+            fs::write(base.join("base"), "base")?;
+            fs::create_dir(base.join("dir"))?;
+            fs::write(base.join("dir/file"), "file")?;
+            std::os::unix::fs::symlink("base", base.join("link"))?;
+            fs::hard_link(base.join("base"), base.join("hardlink"))?;
+            sut.commit("base", "base-active")?;
+            assert!(sut.stat("base-active")?.is_none());
+
+            sut.prepare("container", Some("base")).await?;
+            let rootfs = sut.mount("container")?;
+            assert_eq!(fs::read_to_string(rootfs.join("dir/file"))?, "file");
+            assert_eq!(fs::read_link(rootfs.join("link"))?, Path::new("base"));
+            assert_eq!(
+                fs::metadata(rootfs.join("base"))?.ino(),
+                fs::metadata(rootfs.join("hardlink"))?.ino()
+            );
+
+            // Writes do not modify the parent
+            fs::write(rootfs.join("base"), "changed")?;
+            fs::write(rootfs.join("new"), "new")?;
+            assert_eq!(fs::read_to_string(rootfs.join("base"))?, "changed");
+            assert_eq!(fs::read_to_string(base.join("base"))?, "base");
+            assert!(!base.join("new").exists());
+
+            sut.unmount("container")?;
+            sut.remove("container").await?;
+            sut.remove("base").await?;
+            assert!(sut.list()?.is_empty());
+            assert_eq!(fs::read_dir(sut.root().join(SNAPSHOTS_DIR))?.count(), 0);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn usage() -> Result<()> {
+        let dir = TempDir::new()?;
+        for sut in snapshotters(&dir)? {
+            let base = sut.prepare("base-active", None).await?;
+            fs::write(base.join("file"), vec![0; 8192])?;
+            sut.commit("base", "base-active")?;
+            sut.prepare("container", Some("base")).await?;
+
+            let base_usage = sut.usage("base").await?;
+            assert_eq!(base_usage.inodes(), 2);
+            assert!(base_usage.bytes() >= 8192);
+            let container_usage = sut.usage("container").await?;
+            match sut.driver() {
+                Driver::Overlay => assert_eq!(container_usage.inodes(), 1),
+                Driver::Vfs => assert_eq!(container_usage.inodes(), 2),
+            }
+            assert!(sut.usage("missing").await.is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prepare_failure() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = Snapshotter::open(dir.path(), Driver::Vfs)?;
+        sut.prepare("active", None).await?;
+
+        assert!(sut.prepare("active", None).await.is_err());
+        assert!(sut.prepare("other", Some("missing")).await.is_err());
+        assert!(sut.prepare("other", Some("active")).await.is_err());
+        assert!(sut.mount("missing").is_err());
+
+        sut.commit("committed", "active")?;
+        assert!(sut.commit("other", "committed").is_err());
+        assert!(sut.mount("committed").is_err());
+
+        // Size limits require quota support
+        sut.prepare("limited", None).await?;
+        assert!(sut.limit("limited", 1024).is_err());
+        assert!(sut
+            .stat("limited")?
+            .context("no snapshot")?
+            .size_limit()
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = Snapshotter::open(dir.path(), Driver::Vfs)?;
+        sut.prepare("parent-active", None).await?;
+        sut.commit("parent", "parent-active")?;
+        sut.prepare("child", Some("parent")).await?;
+
+        assert!(sut.remove("parent").await.is_err());
+        sut.remove("child").await?;
+        sut.remove("parent").await?;
+        sut.remove("missing").await?;
+        assert!(sut.list()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopen() -> Result<()> {
+        let dir = TempDir::new()?;
+        {
+            let sut = Snapshotter::open(dir.path(), Driver::Vfs)?;
+            sut.prepare("active", None).await?;
+            sut.commit("committed", "active")?;
+        }
+
+        let sut = Snapshotter::open(dir.path(), Driver::Vfs)?;
+        let info = sut.stat("committed")?.context("no snapshot")?;
+        assert_eq!(info.kind(), SnapshotKind::Committed);
+        assert_ne!(sut.prepare("new", None).await?, sut.fs_dir(info.id()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unpack() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path().join("store"))?;
+        let (_, manifest) = testing::store_image(
+            &store,
+            &[
+                testing::layer(&[("a", b"lower"), ("b", b"lower")])?,
+                testing::layer(&[("a", b"upper"), (".wh.b", b"")])?,
+            ],
+        )
+        .await?;
+
+        for sut in snapshotters(&dir)? {
+            let top = sut.unpack(&store, &manifest).await?.context("no layers")?;
+            let snapshots = sut.list()?;
+            assert_eq!(snapshots.len(), 2);
+            assert!(snapshots
+                .iter()
+                .all(|s| s.kind() == SnapshotKind::Committed));
+
+            // Unpacking again reuses the snapshots
+            assert_eq!(sut.unpack(&store, &manifest).await?, Some(top.clone()));
+            assert_eq!(sut.list()?.len(), 2);
+
+            sut.prepare("container", Some(&top)).await?;
+            let rootfs = sut.mount("container")?;
+            assert_eq!(fs::read_to_string(rootfs.join("a"))?, "upper");
+            assert!(!rootfs.join("b").exists());
+            sut.unmount("container")?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unpack_failure_diff_id_mismatch() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path().join("store"))?;
+        let (_, manifest) = testing::store_image(&store, &[testing::layer(&[("a", b"")])?]).await?;
+
+        // Replace the config with one referencing a wrong diff ID
+        let config = serde_json::to_vec(&json!({
+            "rootfs": {"type": "layers", "diff_ids": [Digest::from_bytes(Default::default(), b"")]}
+        }))?;
+        let config_digest = store.write(&config, None).await?;
+        let mut manifest = manifest;
+        let mut config_descriptor = manifest.config().clone();
+        config_descriptor.set_digest(config_digest.to_string());
+        manifest.set_config(config_descriptor);
+
+        let sut = Snapshotter::open(dir.path().join("snapshots"), Driver::Vfs)?;
+        assert!(sut.unpack(&store, &manifest).await.is_err());
+        assert!(sut.list()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unpack_encrypted() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path().join("store"))?;
+        let layer = testing::layer(&[("a", b"secret")])?;
+        let (_, mut manifest) = testing::store_image(&store, std::slice::from_ref(&layer)).await?;
+
+        let key = encryption::tests::rsa_key()?;
+        let (encrypted, descriptor) =
+            encryption::tests::encrypt(&layer, std::slice::from_ref(&key), &[])?;
+        store.write(&encrypted, None).await?;
+        manifest.set_layers(vec![descriptor]);
+
+        // Unpacking fails closed without a matching key
+        let sut = Snapshotter::open(dir.path().join("snapshots"), Driver::Vfs)?;
+        assert!(sut.unpack(&store, &manifest).await.is_err());
+        let sut = sut.with_decryptor(Decryptor::new(vec![encryption::tests::rsa_key()?]));
+        assert!(sut.unpack(&store, &manifest).await.is_err());
+        assert!(sut.list()?.is_empty());
+
+        let sut = sut.with_decryptor(Decryptor::new(vec![key]));
+        let top = sut.unpack(&store, &manifest).await?.context("no layers")?;
+        sut.prepare("container", Some(&top)).await?;
+        let rootfs = sut.mount("container")?;
+        assert_eq!(fs::read_to_string(rootfs.join("a"))?, "secret");
+        Ok(())
+    }
+
+    /// Commit a base snapshot containing files owned by root and by 1000:1000 and return the
+    /// key of a child snapshot on top of it.
+    async fn mapped_parents(sut: &Snapshotter) -> Result<String> {
+        let base = sut.prepare("base-active", None).await?;
+        fs::write(base.join("root"), "root")?;
+        fs::create_dir(base.join("home"))?;
+        lchown(base.join("home"), Some(1000), Some(1000))?;
+        sut.commit("base", "base-active")?;
+        let child = sut.prepare("child-active", Some("base")).await?;
+        fs::write(child.join("user"), "user")?;
+        lchown(child.join("user"), Some(1000), Some(1000))?;
+        sut.commit("child", "child-active")?;
+        Ok("child".into())
+    }
+
+    fn mappings(host_id: u32) -> Result<IdMappings> {
+        IdMappings::new(
+            vec![IdMapping::new(0, host_id, 65536)],
+            vec![IdMapping::new(0, host_id, 65536)],
+        )
+    }
+
+    fn owner(path: &Path) -> Result<(u32, u32)> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok((metadata.uid(), metadata.gid()))
+    }
+
+    fn shifted(sut: &Snapshotter) -> Result<usize> {
+        Ok(sut
+            .list()?
+            .iter()
+            .filter(|s| s.key().starts_with(IDMAP_PREFIX))
+            .count())
+    }
+
+    #[tokio::test]
+    async fn prepare_mapped_copy() -> Result<()> {
+        if !nix::unistd::geteuid().is_root() {
+            return Ok(());
+        }
+        let dir = TempDir::new()?;
+        for sut in snapshotters(&dir)? {
+            let parent = mapped_parents(&sut).await?;
+            let layers = match sut.driver() {
+                Driver::Overlay => 2,
+                Driver::Vfs => 1,
+            };
+
+            sut.prepare_mapped("a", Some(&parent), &mappings(100_000)?)
+                .await?;
+            let rootfs = sut.mount("a")?;
+            assert_eq!(owner(&rootfs)?, (100_000, 100_000));
+            assert_eq!(owner(&rootfs.join("root"))?, (100_000, 100_000));
+            assert_eq!(owner(&rootfs.join("home"))?, (101_000, 101_000));
+            assert_eq!(owner(&rootfs.join("user"))?, (101_000, 101_000));
+            assert_eq!(shifted(&sut)?, layers);
+
+            // The parents keep their ownership
+            sut.prepare("unmapped", Some(&parent)).await?;
+            let unmapped = sut.mount("unmapped")?;
+            assert_eq!(owner(&unmapped.join("user"))?, (1000, 1000));
+
+            // The shifted copies are shared by equal mappings only
+            sut.prepare_mapped("b", Some(&parent), &mappings(100_000)?)
+                .await?;
+            assert_eq!(shifted(&sut)?, layers);
+            sut.prepare_mapped("c", Some(&parent), &mappings(200_000)?)
+                .await?;
+            assert_eq!(owner(&sut.mount("c")?.join("user"))?, (201_000, 201_000));
+            assert_eq!(shifted(&sut)?, 2 * layers);
+
+            let empty = sut.prepare_mapped("d", None, &mappings(100_000)?).await?;
+            assert_eq!(owner(&empty)?, (100_000, 100_000));
+
+            for key in ["a", "b", "c", "d", "unmapped"] {
+                sut.unmount(key)?;
+                sut.remove(key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prepare_mapped_mount() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sut = match snapshotters(&dir)?.pop() {
+            Some(sut) if sut.driver() == Driver::Overlay => match IdMapMethod::detect(sut.root()) {
+                IdMapMethod::Mount => sut.with_id_map_method(IdMapMethod::Mount),
+                IdMapMethod::Copy => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        let parent = mapped_parents(&sut).await?;
+
+        sut.prepare_mapped("container", Some(&parent), &mappings(100_000)?)
+            .await?;
+        let rootfs = sut.mount("container")?;
+        assert_eq!(sut.mount("container")?, rootfs);
+        assert_eq!(owner(&rootfs)?, (100_000, 100_000));
+        assert_eq!(owner(&rootfs.join("home"))?, (101_000, 101_000));
+        assert_eq!(owner(&rootfs.join("user"))?, (101_000, 101_000));
+        assert_eq!(shifted(&sut)?, 0);
+
+        // Writes end up in the upper directory only
+        fs::write(rootfs.join("new"), "new")?;
+        lchown(rootfs.join("new"), Some(100_005), Some(100_005))?;
+        sut.unmount("container")?;
+        sut.remove("container").await?;
+
+        sut.prepare("unmapped", Some(&parent)).await?;
+        let rootfs = sut.mount("unmapped")?;
+        assert!(!rootfs.join("new").exists());
+        assert_eq!(owner(&rootfs.join("user"))?, (1000, 1000));
+        assert_eq!(fs::read_to_string(rootfs.join("root"))?, "root");
+        sut.unmount("unmapped")?;
+        Ok(())
+    }
+
+    #[test]
+    fn chain_ids_from_diff_ids() -> Result<()> {
+        let diff_ids = vec![
+            Digest::from_bytes(Default::default(), b"a"),
+            Digest::from_bytes(Default::default(), b"b"),
+        ];
+        let chain_ids = chain_ids(&diff_ids);
+        assert_eq!(chain_ids.len(), 2);
+        assert_eq!(chain_ids[0], diff_ids[0]);
+        assert_eq!(
+            chain_ids[1],
+            Digest::from_bytes(
+                Default::default(),
+                format!("{} {}", diff_ids[0], diff_ids[1]).as_bytes()
+            )
+        );
+        assert!(super::chain_ids(&[]).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unpack_no_layers() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path().join("store"))?;
+        let (_, manifest) = testing::store_image(&store, &[]).await?;
+        let sut = Snapshotter::open(dir.path().join("snapshots"), Driver::Vfs)?;
+        assert!(sut.unpack(&store, &manifest).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Overlay mounts of stacked snapshots.
+
+use anyhow::{bail, Context, Result};
+use log::trace;
+use nix::{
+    errno::Errno,
+    mount::{self, MntFlags, MsFlags},
+    unistd::geteuid,
+};
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+
+/// Directory of a snapshot used by overlay to prepare files before moving them into the upper
+/// directory.
+pub const WORK_DIR: &str = "work";
+
+/// Directory of a snapshot where the overlay gets mounted.
+const MERGED_DIR: &str = "merged";
+
+/// Directory of a snapshot containing the writable upper layer.
+pub const UPPER_DIR: &str = "fs";
+
+/// Check if overlay can be mounted within the root directory.
+pub fn check(root: &Path) -> Result<()> {
+    if !geteuid().is_root() {
+        bail!("overlay mounts require root privileges")
+    }
+    let dir = TempDir::new_in(root).context("create overlay check dir")?;
+    let lower = dir.path().join("lower");
+    fs::create_dir(&lower)?;
+    for sub_dir in &[UPPER_DIR, WORK_DIR] {
+        fs::create_dir(dir.path().join(sub_dir))?;
+    }
+    mount(dir.path(), &[lower])?;
+    unmount(dir.path())
+}
+
+/// Mount the snapshot in `dir` with the provided lower directories, starting with the topmost
+/// one. Returns the mount point, whereas already mounted snapshots are left untouched.
+pub fn mount(dir: &Path, lower_dirs: &[PathBuf]) -> Result<PathBuf> {
+    let target = dir.join(MERGED_DIR);
+    if is_mounted(dir)? {
+        return Ok(target);
+    }
+    fs::create_dir_all(&target)
+        .with_context(|| format!("create mount point {}", target.display()))?;
+
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower_dirs
+            .iter()
+            .map(|d| d.display().to_string())
+            .collect::<Vec<_>>()
+            .join(":"),
+        dir.join(UPPER_DIR).display(),
+        dir.join(WORK_DIR).display(),
+    );
+    mount::mount(
+        Some("overlay"),
+        &target,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(options.as_str()),
+    )
+    .with_context(|| format!("mount overlay with options {}", options))?;
+
+    trace!("Mounted overlay {}", target.display());
+    Ok(target)
+}
+
+/// Unmount the snapshot in `dir`, which succeeds if it is not mounted.
+pub fn unmount(dir: &Path) -> Result<()> {
+    let target = dir.join(MERGED_DIR);
+    match mount::umount2(&target, MntFlags::MNT_DETACH) {
+        Ok(()) => {
+            trace!("Unmounted overlay {}", target.display());
+            Ok(())
+        }
+        Err(Errno::EINVAL) | Err(Errno::ENOENT) => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("unmount overlay {}", target.display())),
+    }
+}
+
+/// Check if the snapshot in `dir` is mounted, which is the case if the mount point resides on a
+/// different device than the snapshot directory.
+pub fn is_mounted(dir: &Path) -> Result<bool> {
+    match fs::metadata(dir.join(MERGED_DIR)) {
+        Ok(metadata) => Ok(metadata.dev() != fs::metadata(dir)?.dev()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
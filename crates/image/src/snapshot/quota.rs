@@ -0,0 +1,288 @@
+//! Disk quotas of writable snapshots via XFS and ext4 project quotas.
+//!
+//! Every limited snapshot gets its own project ID, which is derived from the snapshot ID and
+//! inherited by all files created within it. The kernel accounts the usage per project, which
+//! allows enforcing the limit as well as reading the usage without walking the directory tree.
+
+use crate::usage::Usage;
+use anyhow::{bail, Context, Result};
+use getset::{CopyGetters, Getters};
+use log::{debug, trace};
+use nix::{
+    errno::Errno,
+    sys::{
+        stat::{self, Mode, SFlag},
+        statfs::{self, FsType, EXT4_SUPER_MAGIC},
+    },
+    unistd,
+};
+use std::{
+    convert::TryFrom,
+    ffi::CString,
+    fs::{self, File},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+/// The block device node within the root, which refers to the device backing the file system
+/// as required by `quotactl(2)`.
+const BACKING_DEVICE: &str = "backingFsBlockDev";
+
+/// The file system type of XFS, which is not provided by nix.
+const XFS_SUPER_MAGIC: FsType = FsType(0x5846_5342);
+
+/// The quota type of projects.
+const PRJQUOTA: i32 = 2;
+
+/// Get the disk quota of a project, see `quotactl(2)`.
+const Q_XGETQUOTA: i32 = 0x5803;
+
+/// Set the disk quota limits of a project, see `quotactl(2)`.
+const Q_XSETQLIM: i32 = 0x5804;
+
+/// The version of [`DiskQuota`].
+const FS_DQUOT_VERSION: i8 = 1;
+
+/// The quota of [`DiskQuota`] belongs to a project.
+const FS_PROJ_QUOTA: i8 = 2;
+
+/// Set the soft block limit of [`DiskQuota`].
+const FS_DQ_BSOFT: u16 = 1 << 0;
+
+/// Set the hard block limit of [`DiskQuota`].
+const FS_DQ_BHARD: u16 = 1 << 1;
+
+/// The size of the basic blocks used for quota limits and usage.
+const BASIC_BLOCK_SIZE: u64 = 512;
+
+/// New files and directories inherit the project ID of the directory.
+const FS_XFLAG_PROJINHERIT: u32 = 0x200;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+/// The extended attributes of an inode, see `ioctl_xfs_fsgetxattr(2)`.
+struct FsXattr {
+    fsx_xflags: u32,
+    fsx_extsize: u32,
+    fsx_nextents: u32,
+    fsx_projid: u32,
+    fsx_cowextsize: u32,
+    fsx_pad: [u8; 8],
+}
+
+nix::ioctl_read!(fs_get_xattr, b'X', 31, FsXattr);
+nix::ioctl_write_ptr!(fs_set_xattr, b'X', 32, FsXattr);
+
+#[repr(C)]
+#[derive(Debug, Default)]
+/// The disk quota of `Q_XGETQUOTA` and `Q_XSETQLIM`.
+struct DiskQuota {
+    d_version: i8,
+    d_flags: i8,
+    d_fieldmask: u16,
+    d_id: u32,
+    d_blk_hardlimit: u64,
+    d_blk_softlimit: u64,
+    d_ino_hardlimit: u64,
+    d_ino_softlimit: u64,
+    d_bcount: u64,
+    d_icount: u64,
+    d_itimer: i32,
+    d_btimer: i32,
+    d_iwarns: u16,
+    d_bwarns: u16,
+    d_itimer_hi: i8,
+    d_btimer_hi: i8,
+    d_rtbtimer_hi: i8,
+    d_padding2: i8,
+    d_rtb_hardlimit: u64,
+    d_rtb_softlimit: u64,
+    d_rtbcount: u64,
+    d_rtbtimer: i32,
+    d_rtbwarns: u16,
+    d_padding3: i16,
+    d_padding4: [u8; 8],
+}
+
+#[derive(Clone, CopyGetters, Debug, Getters)]
+/// Limits and accounts the disk usage of snapshots by project quotas.
+pub struct QuotaControl {
+    #[get = "pub"]
+    /// The block device node of the file system.
+    device: PathBuf,
+
+    #[get_copy = "pub"]
+    /// The project ID of the snapshot with ID zero, whereas all others follow consecutively.
+    base_project_id: u32,
+}
+
+impl QuotaControl {
+    /// Check if project quotas are enforced on the XFS or ext4 file system at the root, which
+    /// requires mounting it with the `prjquota` option.
+    pub fn detect<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref();
+        if !unistd::geteuid().is_root() {
+            bail!("project quotas require root privileges")
+        }
+        let fs_type = statfs::statfs(root)
+            .with_context(|| format!("get file system of {}", root.display()))?
+            .filesystem_type();
+        if fs_type != XFS_SUPER_MAGIC && fs_type != EXT4_SUPER_MAGIC {
+            bail!("file system {:?} has no project quotas", fs_type)
+        }
+
+        // Projects below the one of the root belong to the administrator
+        let base_project_id = project_id(root)? + 1;
+        let device = root.join(BACKING_DEVICE);
+        match fs::remove_file(&device) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("remove {}", device.display())),
+        }
+        stat::mknod(
+            &device,
+            SFlag::S_IFBLK,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+            fs::metadata(root)?.dev(),
+        )
+        .with_context(|| format!("create backing device {}", device.display()))?;
+
+        let control = Self {
+            device,
+            base_project_id,
+        };
+        control
+            .set_limit(base_project_id, 0)
+            .context("set test project quota")?;
+        debug!(
+            "Using project quotas starting at project {}",
+            base_project_id
+        );
+        Ok(control)
+    }
+
+    /// Assign the project of the snapshot to all files and directories within `dirs` and limit
+    /// the project to `bytes`.
+    pub fn apply(&self, id: u64, dirs: &[PathBuf], bytes: u64) -> Result<()> {
+        let project_id = self.project_id(id)?;
+        for dir in dirs {
+            set_project_id(dir, project_id)
+                .with_context(|| format!("set project of {}", dir.display()))?;
+        }
+        self.set_limit(project_id, bytes)?;
+        trace!("Limited project {} to {} bytes", project_id, bytes);
+        Ok(())
+    }
+
+    /// Remove the limit of the snapshot, which allows reusing its project.
+    pub fn clear(&self, id: u64) -> Result<()> {
+        self.set_limit(self.project_id(id)?, 0)
+    }
+
+    /// Get the usage accounted for the project of the snapshot.
+    pub fn usage(&self, id: u64) -> Result<Usage> {
+        let mut quota = DiskQuota::default();
+        self.quotactl(Q_XGETQUOTA, self.project_id(id)?, &mut quota)
+            .context("get project quota")?;
+        Ok(Usage::new(
+            quota.d_bcount * BASIC_BLOCK_SIZE,
+            quota.d_icount,
+        ))
+    }
+
+    /// The project ID of the snapshot.
+    fn project_id(&self, id: u64) -> Result<u32> {
+        u32::try_from(id)
+            .ok()
+            .and_then(|id| self.base_project_id.checked_add(id))
+            .with_context(|| format!("no project ID available for snapshot {}", id))
+    }
+
+    /// Set the hard and soft block limit of the project, whereas zero means no limit.
+    fn set_limit(&self, project_id: u32, bytes: u64) -> Result<()> {
+        let blocks = bytes.div_ceil(BASIC_BLOCK_SIZE);
+        let mut quota = DiskQuota {
+            d_version: FS_DQUOT_VERSION,
+            d_flags: FS_PROJ_QUOTA,
+            d_fieldmask: FS_DQ_BSOFT | FS_DQ_BHARD,
+            d_id: project_id,
+            d_blk_hardlimit: blocks,
+            d_blk_softlimit: blocks,
+            ..Default::default()
+        };
+        self.quotactl(Q_XSETQLIM, project_id, &mut quota)
+            .with_context(|| format!("set quota of project {}", project_id))
+    }
+
+    /// Run the project quota command on the backing device.
+    fn quotactl(&self, cmd: i32, project_id: u32, quota: &mut DiskQuota) -> Result<()> {
+        let device = CString::new(self.device.as_os_str().as_bytes())?;
+        Errno::result(unsafe {
+            libc::quotactl(
+                (cmd << 8) | PRJQUOTA,
+                device.as_ptr(),
+                project_id as i32,
+                quota as *mut DiskQuota as *mut libc::c_char,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+/// Get the project ID of the file or directory.
+fn project_id(path: &Path) -> Result<u32> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut attr = FsXattr::default();
+    unsafe { fs_get_xattr(file.as_raw_fd(), &mut attr) }
+        .with_context(|| format!("get project of {}", path.display()))?;
+    Ok(attr.fsx_projid)
+}
+
+/// Recursively set the project ID of all files and directories, whereas directories pass it on
+/// to new entries. Other file types cannot be opened safely and keep their project.
+fn set_project_id(path: &Path, project_id: u32) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() && !metadata.is_file() {
+        return Ok(());
+    }
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut attr = FsXattr::default();
+    unsafe { fs_get_xattr(file.as_raw_fd(), &mut attr) }?;
+    attr.fsx_projid = project_id;
+    if metadata.is_dir() {
+        attr.fsx_xflags |= FS_XFLAG_PROJINHERIT;
+    }
+    unsafe { fs_set_xattr(file.as_raw_fd(), &attr) }?;
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            set_project_id(&entry?.path(), project_id)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn abi() {
+        assert_eq!(mem::size_of::<FsXattr>(), 28);
+        assert_eq!(mem::size_of::<DiskQuota>(), 112);
+    }
+
+    #[test]
+    fn project_ids() -> Result<()> {
+        let sut = QuotaControl {
+            device: PathBuf::from(BACKING_DEVICE),
+            base_project_id: 10,
+        };
+        assert_eq!(sut.project_id(0)?, 10);
+        assert_eq!(sut.project_id(5)?, 15);
+        assert!(sut.project_id(u64::from(u32::MAX)).is_err());
+        assert!(sut.project_id(u64::MAX).is_err());
+        Ok(())
+    }
+}
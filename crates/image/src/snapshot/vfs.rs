@@ -0,0 +1,135 @@
+//! Full copies of snapshots for systems without overlay support.
+
+use super::idmap::IdMappings;
+use anyhow::{bail, Context, Result};
+use log::trace;
+use nix::{
+    sys::{
+        stat::{self, Mode, SFlag, UtimensatFlags},
+        time::TimeSpec,
+    },
+    unistd,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, Metadata},
+    os::unix::fs::{lchown, FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+/// Recursively copy the content of the `source` directory into the existing `target`
+/// directory, preserving ownership, permissions, timestamps, hard links and extended
+/// attributes. The ownership gets shifted if `mappings` are provided, which requires root
+/// privileges.
+pub fn copy_dir(source: &Path, target: &Path, mappings: Option<&IdMappings>) -> Result<()> {
+    let privileged = unistd::geteuid().is_root();
+    if mappings.is_some() && !privileged {
+        bail!("shifting the ownership requires root privileges")
+    }
+    let mut links = HashMap::new();
+    copy_entries(source, target, privileged, mappings, &mut links)?;
+    copy_metadata(
+        source,
+        target,
+        &fs::symlink_metadata(source)?,
+        privileged,
+        mappings,
+    )?;
+    trace!("Copied {} to {}", source.display(), target.display());
+    Ok(())
+}
+
+/// Copy all entries of the `source` directory, whereas `links` tracks the already copied
+/// inodes for recreating hard links.
+fn copy_entries(
+    source: &Path,
+    target: &Path,
+    privileged: bool,
+    mappings: Option<&IdMappings>,
+    links: &mut HashMap<(u64, u64), PathBuf>,
+) -> Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let from = entry.path();
+        let to = target.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&from)?;
+        let file_type = metadata.file_type();
+
+        if !file_type.is_dir() && metadata.nlink() > 1 {
+            if let Some(existing) = links.get(&(metadata.dev(), metadata.ino())) {
+                fs::hard_link(existing, &to).with_context(|| format!("link {}", to.display()))?;
+                continue;
+            }
+            links.insert((metadata.dev(), metadata.ino()), to.clone());
+        }
+
+        if file_type.is_dir() {
+            fs::create_dir(&to)?;
+            copy_entries(&from, &to, privileged, mappings, links)?;
+        } else if file_type.is_file() {
+            fs::copy(&from, &to).with_context(|| format!("copy {}", from.display()))?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+        } else if file_type.is_char_device() || file_type.is_block_device() || file_type.is_fifo() {
+            stat::mknod(
+                &to,
+                SFlag::from_bits_truncate(metadata.mode()),
+                Mode::from_bits_truncate(metadata.mode()),
+                metadata.rdev(),
+            )
+            .with_context(|| format!("create node {}", to.display()))?;
+        } else {
+            trace!("Skipping socket {}", from.display());
+            continue;
+        }
+        copy_metadata(&from, &to, &metadata, privileged, mappings)?;
+    }
+    Ok(())
+}
+
+/// Copy ownership, permissions, extended attributes and timestamps, whereas the ownership gets
+/// shifted by the `mappings`.
+fn copy_metadata(
+    source: &Path,
+    target: &Path,
+    metadata: &Metadata,
+    privileged: bool,
+    mappings: Option<&IdMappings>,
+) -> Result<()> {
+    let is_symlink = metadata.file_type().is_symlink();
+    if privileged {
+        let (uid, gid) = match mappings {
+            Some(mappings) => (
+                mappings.map_uid(metadata.uid()),
+                mappings.map_gid(metadata.gid()),
+            ),
+            None => (metadata.uid(), metadata.gid()),
+        };
+        lchown(target, Some(uid), Some(gid))
+            .with_context(|| format!("change owner of {}", target.display()))?;
+    }
+
+    if !is_symlink {
+        fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode()))?;
+        for name in xattr::list(source)? {
+            let key = name.to_string_lossy();
+            if !privileged && !key.starts_with("user.") {
+                continue;
+            }
+            if let Some(value) = xattr::get(source, &name)? {
+                xattr::set(target, &name, &value)
+                    .with_context(|| format!("set xattr {} on {}", key, target.display()))?;
+            }
+        }
+    }
+
+    stat::utimensat(
+        None,
+        target,
+        &TimeSpec::new(metadata.atime(), metadata.atime_nsec()),
+        &TimeSpec::new(metadata.mtime(), metadata.mtime_nsec()),
+        UtimensatFlags::NoFollowSymlink,
+    )
+    .with_context(|| format!("set times of {}", target.display()))?;
+    Ok(())
+}
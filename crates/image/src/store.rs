@@ -0,0 +1,584 @@
+//! A content addressable blob store for image manifests, configurations and layers.
+//!
+//! Blobs are stored by their digest below `<root>/blobs/<algorithm>/<encoded>`. Every write goes
+//! into a temporary file below `<root>/ingest` first, which gets renamed into place after the
+//! digest has been verified. This means that a blob is either fully available or not at all.
+//! Resumable writes keep their partial content in the ingest directory until they get committed.
+
+use crate::digest::{Algorithm, Digest, Digester};
+use getset::Getters;
+use log::trace;
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+use storage::{default_key_value_storage::DefaultKeyValueStorage, KeyValueStorage};
+use strum::IntoEnumIterator;
+use tempfile::TempPath;
+use thiserror::Error;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+pub type Result<T> = std::result::Result<T, StoreError>;
+
+#[derive(Debug, Error)]
+/// Errors which can occur on blob store operations.
+pub enum StoreError {
+    #[error("digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: Digest, actual: Digest },
+
+    #[error("blob {0} not found")]
+    NotFound(Digest),
+
+    #[error("blob {digest} is still referenced {count} times")]
+    InUse { digest: Digest, count: u64 },
+
+    #[error("blob {0} is not referenced")]
+    NotReferenced(Digest),
+
+    #[error("{0}")]
+    IO(#[from] io::Error),
+
+    #[error("{0:#}")]
+    Storage(#[from] anyhow::Error),
+}
+
+/// Directory containing the verified blobs.
+const BLOBS_DIR: &str = "blobs";
+
+/// Directory containing the temporary files of in-flight writes.
+const INGEST_DIR: &str = "ingest";
+
+/// Directory of the reference count database.
+const REFS_DIR: &str = "refs";
+
+/// File name prefix of partial content of resumable writes within the ingest directory.
+const PARTIAL_PREFIX: &str = "partial-";
+
+/// The buffer size used to digest existing partial content.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Getters)]
+/// A local content addressable blob store.
+pub struct BlobStore {
+    #[get = "pub"]
+    /// The root path of the store.
+    root: PathBuf,
+
+    /// Reference counts per blob digest.
+    refs: Arc<Mutex<DefaultKeyValueStorage>>,
+}
+
+impl BlobStore {
+    /// Open the blob store at the provided root path, which gets created if it does not exist.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        for algorithm in Algorithm::iter() {
+            std::fs::create_dir_all(root.join(BLOBS_DIR).join(algorithm.as_ref()))?;
+        }
+        std::fs::create_dir_all(root.join(INGEST_DIR))?;
+        let refs = DefaultKeyValueStorage::open(root.join(REFS_DIR))?;
+
+        trace!("Opened blob store {}", root.display());
+        Ok(Self {
+            root,
+            refs: Arc::new(Mutex::new(refs)),
+        })
+    }
+
+    /// The path of the blob on disk, regardless if it exists or not.
+    pub fn path(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join(BLOBS_DIR)
+            .join(digest.algorithm().as_ref())
+            .join(digest.encoded())
+    }
+
+    /// Check if the blob is available in the store.
+    pub async fn contains(&self, digest: &Digest) -> bool {
+        fs::metadata(self.path(digest)).await.is_ok()
+    }
+
+    /// The size of the blob in bytes.
+    pub async fn size(&self, digest: &Digest) -> Result<u64> {
+        let metadata = fs::metadata(self.path(digest))
+            .await
+            .map_err(|e| Self::map_not_found(e, digest))?;
+        Ok(metadata.len())
+    }
+
+    /// Read the whole blob into memory.
+    pub async fn read(&self, digest: &Digest) -> Result<Vec<u8>> {
+        fs::read(self.path(digest))
+            .await
+            .map_err(|e| Self::map_not_found(e, digest))
+    }
+
+    /// Open the blob for streamed reading.
+    pub async fn reader(&self, digest: &Digest) -> Result<File> {
+        File::open(self.path(digest))
+            .await
+            .map_err(|e| Self::map_not_found(e, digest))
+    }
+
+    /// Write the provided data into the store and return its digest. If an `expected` digest is
+    /// provided, then the write fails if the content does not match it.
+    pub async fn write(&self, data: &[u8], expected: Option<&Digest>) -> Result<Digest> {
+        let mut writer = self.writer(expected.cloned())?;
+        writer.write(data).await?;
+        writer.commit().await
+    }
+
+    /// Create a new writer for streaming content into the store. The digest algorithm is taken
+    /// from the `expected` digest and defaults to SHA-256 if none is provided.
+    pub fn writer(&self, expected: Option<Digest>) -> Result<BlobWriter> {
+        let algorithm = expected.as_ref().map(Digest::algorithm).unwrap_or_default();
+        let (file, temp_path) = tempfile::Builder::new()
+            .prefix("blob-")
+            .tempfile_in(self.root.join(INGEST_DIR))?
+            .into_parts();
+
+        Ok(BlobWriter {
+            store: self.clone(),
+            file: File::from_std(file),
+            ingest: Ingest::Temporary(temp_path),
+            digester: Digester::new(algorithm),
+            expected,
+            size: 0,
+        })
+    }
+
+    /// Create a writer which continues the partial content of a previous writer for the same
+    /// `expected` digest. Dropping the writer keeps the content written so far, whereas `commit`
+    /// removes it if it does not match the digest. Resumable writers for the same digest must
+    /// not be used concurrently.
+    pub async fn resumable_writer(&self, expected: Digest) -> Result<BlobWriter> {
+        let path = self.root.join(INGEST_DIR).join(format!(
+            "{}{}-{}",
+            PARTIAL_PREFIX,
+            expected.algorithm(),
+            expected.encoded()
+        ));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .await?;
+
+        let mut digester = Digester::new(expected.algorithm());
+        let mut size = 0;
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            digester.update(&buf[..n]);
+            size += n as u64;
+        }
+        if size > 0 {
+            trace!("Resuming blob {} at {} bytes", expected, size);
+        }
+
+        Ok(BlobWriter {
+            store: self.clone(),
+            file,
+            ingest: Ingest::Partial(path),
+            digester,
+            expected: Some(expected),
+            size,
+        })
+    }
+
+    /// List the digests of all blobs in the store.
+    pub async fn list(&self) -> Result<Vec<Digest>> {
+        let mut digests = vec![];
+        for algorithm in Algorithm::iter() {
+            let mut entries =
+                fs::read_dir(self.root.join(BLOBS_DIR).join(algorithm.as_ref())).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                if let Ok(digest) = Digest::new(algorithm, entry.file_name().to_string_lossy()) {
+                    digests.push(digest);
+                }
+            }
+        }
+        Ok(digests)
+    }
+
+    /// Remove the blob from the store. Removing a blob which does not exist is not an error, but
+    /// removing a blob which is still referenced is.
+    pub fn remove(&self, digest: &Digest) -> Result<()> {
+        let refs = self.refs();
+        let count = Self::count(&refs, digest)?;
+        if count > 0 {
+            return Err(StoreError::InUse {
+                digest: digest.clone(),
+                count,
+            });
+        }
+        match std::fs::remove_file(self.path(digest)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => {
+                trace!("Removed blob {}", digest);
+                Ok(())
+            }
+        }
+    }
+
+    /// The current reference count of the blob.
+    pub fn ref_count(&self, digest: &Digest) -> Result<u64> {
+        Self::count(&self.refs(), digest)
+    }
+
+    /// Increment the reference count of an existing blob and return the new count.
+    pub fn acquire(&self, digest: &Digest) -> Result<u64> {
+        let mut refs = self.refs();
+        if !self.path(digest).exists() {
+            return Err(StoreError::NotFound(digest.clone()));
+        }
+        let count = Self::count(&refs, digest)? + 1;
+        refs.insert(digest.to_string(), count)?;
+        Ok(count)
+    }
+
+    /// Decrement the reference count of the blob and return the new count.
+    pub fn release(&self, digest: &Digest) -> Result<u64> {
+        let mut refs = self.refs();
+        let count = Self::count(&refs, digest)?
+            .checked_sub(1)
+            .ok_or_else(|| StoreError::NotReferenced(digest.clone()))?;
+        if count == 0 {
+            refs.remove(digest.to_string())?;
+        } else {
+            refs.insert(digest.to_string(), count)?;
+        }
+        Ok(count)
+    }
+
+    /// Lock the reference count storage.
+    fn refs(&self) -> MutexGuard<'_, DefaultKeyValueStorage> {
+        self.refs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Retrieve the reference count from the locked storage.
+    fn count(refs: &DefaultKeyValueStorage, digest: &Digest) -> Result<u64> {
+        Ok(refs.get(digest.to_string())?.unwrap_or_default())
+    }
+
+    /// Map a not found IO error to its typed store error.
+    fn map_not_found(error: io::Error, digest: &Digest) -> StoreError {
+        if error.kind() == io::ErrorKind::NotFound {
+            StoreError::NotFound(digest.clone())
+        } else {
+            error.into()
+        }
+    }
+}
+
+#[derive(Debug)]
+/// The location of the content of a writer before it gets committed.
+enum Ingest {
+    /// A temporary file, which gets removed on drop.
+    Temporary(TempPath),
+
+    /// The partial content of a resumable write, which is kept on drop.
+    Partial(PathBuf),
+}
+
+#[derive(Debug)]
+/// A streaming writer into the blob store. The content becomes visible in the store only after a
+/// successful `commit`, dropping the writer discards everything written so far unless it is
+/// resumable.
+pub struct BlobWriter {
+    /// The store to commit to.
+    store: BlobStore,
+
+    /// The file the content gets written to.
+    file: File,
+
+    /// Where the written content is located.
+    ingest: Ingest,
+
+    /// The digest of the content written so far.
+    digester: Digester,
+
+    /// The expected digest, if known.
+    expected: Option<Digest>,
+
+    /// The amount of bytes written so far.
+    size: u64,
+}
+
+impl BlobWriter {
+    /// Append data to the blob.
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await?;
+        self.digester.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// The amount of bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Discard everything written so far, including the partial content of previous writers.
+    pub async fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0).await?;
+        self.file.seek(SeekFrom::Start(0)).await?;
+        let algorithm = self
+            .expected
+            .as_ref()
+            .map(Digest::algorithm)
+            .unwrap_or_default();
+        self.digester = Digester::new(algorithm);
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Discard the writer including its partial content.
+    pub async fn discard(self) -> Result<()> {
+        if let Ingest::Partial(path) = &self.ingest {
+            Self::remove_partial(path).await?;
+        }
+        Ok(())
+    }
+
+    /// Verify the written content and move it into the store.
+    pub async fn commit(mut self) -> Result<Digest> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+
+        let digest = self.digester.finalize();
+        if let Some(expected) = self.expected {
+            if expected != digest {
+                if let Ingest::Partial(path) = &self.ingest {
+                    Self::remove_partial(path).await?;
+                }
+                return Err(StoreError::DigestMismatch {
+                    expected,
+                    actual: digest,
+                });
+            }
+        }
+
+        let path = self.store.path(&digest);
+        match self.ingest {
+            Ingest::Temporary(temp_path) => temp_path.persist(path).map_err(|e| e.error)?,
+            Ingest::Partial(partial) => fs::rename(partial, path).await?,
+        }
+        trace!("Committed blob {} ({} bytes)", digest, self.size);
+        Ok(digest)
+    }
+
+    /// Remove the partial content of a resumable writer.
+    async fn remove_partial(path: &Path) -> Result<()> {
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::TempDir;
+    use tokio::io::AsyncReadExt;
+
+    fn ingest_is_empty(store: &BlobStore) -> Result<bool> {
+        Ok(std::fs::read_dir(store.root().join(INGEST_DIR))?
+            .next()
+            .is_none())
+    }
+
+    #[tokio::test]
+    async fn write_read() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path())?;
+        let expected = Digest::from_bytes(Algorithm::Sha256, b"hello");
+
+        let digest = store.write(b"hello", Some(&expected)).await?;
+        assert_eq!(digest, expected);
+        assert!(store.contains(&digest).await);
+        assert_eq!(store.size(&digest).await?, 5);
+        assert_eq!(store.read(&digest).await?, b"hello");
+
+        let mut content = String::new();
+        store
+            .reader(&digest)
+            .await?
+            .read_to_string(&mut content)
+            .await?;
+        assert_eq!(content, "hello");
+
+        assert_eq!(store.list().await?, vec![digest]);
+        assert!(ingest_is_empty(&store)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_stream_sha512() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path())?;
+        let expected = Digest::from_bytes(Algorithm::Sha512, b"hello world");
+
+        let mut writer = store.writer(Some(expected.clone()))?;
+        writer.write(b"hello").await?;
+        writer.write(b" world").await?;
+        assert_eq!(writer.size(), 11);
+
+        let digest = writer.commit().await?;
+        assert_eq!(digest, expected);
+        assert!(store
+            .path(&digest)
+            .starts_with(dir.path().join("blobs/sha512")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_failure_digest_mismatch() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path())?;
+        let expected = Digest::from_bytes(Algorithm::Sha256, b"hello");
+
+        let res = store.write(b"wrong", Some(&expected)).await;
+        assert!(matches!(res, Err(StoreError::DigestMismatch { .. })));
+        assert!(!store.contains(&expected).await);
+        assert!(store.list().await?.is_empty());
+        assert!(ingest_is_empty(&store)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_dropped() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path())?;
+
+        let mut writer = store.writer(None)?;
+        writer.write(b"hello").await?;
+        drop(writer);
+
+        assert!(store.list().await?.is_empty());
+        assert!(ingest_is_empty(&store)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_resumable() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path())?;
+        let expected = Digest::from_bytes(Algorithm::Sha256, b"hello world");
+
+        let mut writer = store.resumable_writer(expected.clone()).await?;
+        writer.write(b"hello").await?;
+        drop(writer);
+        assert!(!ingest_is_empty(&store)?);
+
+        let mut writer = store.resumable_writer(expected.clone()).await?;
+        assert_eq!(writer.size(), 5);
+        writer.write(b" world").await?;
+        assert_eq!(writer.commit().await?, expected);
+        assert_eq!(store.read(&expected).await?, b"hello world");
+        assert!(ingest_is_empty(&store)?);
+
+        let mut writer = store.resumable_writer(expected.clone()).await?;
+        writer.write(b"wrong").await?;
+        writer.truncate().await?;
+        assert_eq!(writer.size(), 0);
+        writer.write(b"hello world").await?;
+        assert_eq!(writer.commit().await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_resumable_failure_digest_mismatch() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path())?;
+        let expected = Digest::from_bytes(Algorithm::Sha256, b"hello");
+
+        let mut writer = store.resumable_writer(expected.clone()).await?;
+        writer.write(b"wrong").await?;
+        assert!(matches!(
+            writer.commit().await,
+            Err(StoreError::DigestMismatch { .. })
+        ));
+        assert!(ingest_is_empty(&store)?);
+
+        let mut writer = store.resumable_writer(expected).await?;
+        writer.write(b"hel").await?;
+        writer.discard().await?;
+        assert!(ingest_is_empty(&store)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_failure_not_found() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path())?;
+        let digest = Digest::from_bytes(Algorithm::Sha256, b"hello");
+
+        assert!(matches!(
+            store.read(&digest).await,
+            Err(StoreError::NotFound(_))
+        ));
+        assert!(matches!(
+            store.size(&digest).await,
+            Err(StoreError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reference_count() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = BlobStore::open(dir.path())?;
+        let digest = store.write(b"hello", None).await?;
+
+        assert_eq!(store.ref_count(&digest)?, 0);
+        assert_eq!(store.acquire(&digest)?, 1);
+        assert_eq!(store.acquire(&digest)?, 2);
+        assert!(matches!(
+            store.remove(&digest),
+            Err(StoreError::InUse { count: 2, .. })
+        ));
+
+        assert_eq!(store.release(&digest)?, 1);
+        assert_eq!(store.release(&digest)?, 0);
+        assert!(matches!(
+            store.release(&digest),
+            Err(StoreError::NotReferenced(_))
+        ));
+
+        store.remove(&digest)?;
+        assert!(!store.contains(&digest).await);
+        store.remove(&digest)?;
+
+        assert!(matches!(
+            store.acquire(&digest),
+            Err(StoreError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reference_count_persisted() -> Result<()> {
+        let dir = TempDir::new()?;
+        let digest = {
+            let store = BlobStore::open(dir.path())?;
+            let digest = store.write(b"hello", None).await?;
+            store.acquire(&digest)?;
+            digest
+        };
+
+        let store = BlobStore::open(dir.path())?;
+        assert_eq!(store.ref_count(&digest)?, 1);
+        Ok(())
+    }
+}
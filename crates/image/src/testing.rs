@@ -0,0 +1,582 @@
+//! An in-process registry stand-in which serves the pull endpoints of the distribution API via
+//! plain HTTP on a random loopback port.
+
+use crate::{
+    digest::{Algorithm, Digest},
+    store::BlobStore,
+};
+use anyhow::{Context, Result};
+use getset::Getters;
+use hyper::{
+    header::{
+        AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, HOST, RANGE, WWW_AUTHENTICATE,
+    },
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use oci_spec::image::{Arch, ImageManifest, MediaType, Os, Platform};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+#[derive(Debug, Default)]
+/// The content served by the registry.
+struct Content {
+    /// Blobs by their digest, shared between all repositories.
+    blobs: HashMap<Digest, Vec<u8>>,
+
+    /// Manifest media type and content by repository and tag or digest.
+    manifests: HashMap<(String, String), (String, Vec<u8>)>,
+
+    /// All requests in the form `METHOD /path`.
+    requests: Vec<String>,
+
+    /// The required authentication, if any.
+    auth: Option<TestAuth>,
+
+    /// Blobs whose next download gets aborted after the amount of bytes.
+    interrupted: HashMap<Digest, usize>,
+
+    /// The delay before answering blob requests.
+    blob_delay: Duration,
+
+    /// The number of blob requests currently being delayed.
+    active_blob_requests: usize,
+
+    /// The highest number of blob requests delayed at the same time.
+    max_active_blob_requests: usize,
+}
+
+#[derive(Clone, Debug)]
+/// Authentication required by the registry.
+pub enum TestAuth {
+    /// Plain basic authentication on every request.
+    Basic { username: String, password: String },
+
+    /// Bearer token authentication, whereas tokens are issued for the user name and password or
+    /// the identity token.
+    Bearer {
+        username: String,
+        password: String,
+        identity_token: String,
+    },
+}
+
+#[derive(Debug, Getters)]
+/// A running registry stand-in, which shuts down on drop.
+pub struct TestRegistry {
+    #[get = "pub"]
+    /// The `address:port` of the registry, usable as registry part of a reference.
+    host: String,
+
+    /// The served content.
+    content: Arc<Mutex<Content>>,
+
+    /// Shutdown trigger for the server.
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Clone, Debug, Getters)]
+/// An image pushed to the registry.
+pub struct TestImage {
+    #[get = "pub"]
+    /// The digest of the image manifest.
+    manifest_digest: Digest,
+
+    #[get = "pub"]
+    /// The size of the image manifest.
+    manifest_size: usize,
+
+    #[get = "pub"]
+    /// The digest of the image configuration, which is the image ID.
+    config_digest: Digest,
+
+    #[get = "pub"]
+    /// The layer digests in manifest order.
+    layers: Vec<Digest>,
+}
+
+impl TestRegistry {
+    /// The bearer token issued by the registry.
+    pub const TOKEN: &'static str = "test-token";
+
+    /// Start a new empty registry.
+    pub async fn start() -> Result<Self> {
+        let content = Arc::new(Mutex::new(Content::default()));
+        let service_content = content.clone();
+        let make_service = make_service_fn(move |_| {
+            let content = service_content.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let content = content.clone();
+                    async move { Ok::<_, Infallible>(Self::handle(content, request).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .context("bind test registry")?
+            .serve(make_service);
+        let host = server.local_addr().to_string();
+        let (shutdown, rx) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            rx.await.ok();
+        }));
+
+        Ok(Self {
+            host,
+            content,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// Require authentication for all further requests.
+    pub fn set_auth(&self, auth: TestAuth) {
+        self.content().auth = Some(auth);
+    }
+
+    /// Add a blob and return its digest.
+    pub fn add_blob(&self, data: &[u8]) -> Digest {
+        let digest = Digest::from_bytes(Algorithm::Sha256, data);
+        self.content().blobs.insert(digest.clone(), data.into());
+        digest
+    }
+
+    /// Serve different content for an existing blob, for example to simulate corruption.
+    pub fn replace_blob(&self, digest: &Digest, data: &[u8]) {
+        self.content().blobs.insert(digest.clone(), data.into());
+    }
+
+    /// Remove a blob from the registry.
+    pub fn remove_blob(&self, digest: &Digest) {
+        self.content().blobs.remove(digest);
+    }
+
+    /// Abort the next download of the blob after `after` bytes, for example to simulate a
+    /// connection loss.
+    pub fn interrupt_blob(&self, digest: &Digest, after: usize) {
+        self.content().interrupted.insert(digest.clone(), after);
+    }
+
+    /// Delay all further blob responses, which allows to observe concurrent downloads.
+    pub fn set_blob_delay(&self, delay: Duration) {
+        self.content().blob_delay = delay;
+    }
+
+    /// The highest number of blob requests which have been delayed at the same time.
+    pub fn max_concurrent_blob_requests(&self) -> usize {
+        self.content().max_active_blob_requests
+    }
+
+    /// Add a manifest to the repository, which is available by its digest and the optional tag.
+    pub fn add_manifest(
+        &self,
+        repository: &str,
+        tag: Option<&str>,
+        media_type: &str,
+        data: &[u8],
+    ) -> Digest {
+        let digest = Digest::from_bytes(Algorithm::Sha256, data);
+        let mut content = self.content();
+        for target in std::iter::once(digest.to_string()).chain(tag.map(ToString::to_string)) {
+            content.manifests.insert(
+                (repository.into(), target),
+                (media_type.into(), data.into()),
+            );
+        }
+        digest
+    }
+
+    /// Make the manifest with the provided digest available under another tag.
+    pub fn tag(&self, repository: &str, tag: &str, digest: &Digest) -> Result<()> {
+        let mut content = self.content();
+        let manifest = content
+            .manifests
+            .get(&(repository.into(), digest.to_string()))
+            .cloned()
+            .with_context(|| format!("manifest {} not found", digest))?;
+        content
+            .manifests
+            .insert((repository.into(), tag.into()), manifest);
+        Ok(())
+    }
+
+    /// Add an image for the current platform consisting of uncompressed layers.
+    pub fn add_image(
+        &self,
+        repository: &str,
+        tag: Option<&str>,
+        layers: &[&[u8]],
+    ) -> Result<TestImage> {
+        self.add_image_with_config(repository, tag, json!({}), layers)
+    }
+
+    /// Add an image for the current platform with the provided container `config` section.
+    pub fn add_image_with_config(
+        &self,
+        repository: &str,
+        tag: Option<&str>,
+        config: Value,
+        layers: &[&[u8]],
+    ) -> Result<TestImage> {
+        let layer_descriptors = layers
+            .iter()
+            .map(|layer| {
+                json!({
+                    "mediaType": MediaType::ImageLayer,
+                    "digest": self.add_blob(layer),
+                    "size": layer.len(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let layer_digests = layer_descriptors
+            .iter()
+            .map(|d| d["digest"].clone())
+            .collect::<Vec<_>>();
+
+        let config = serde_json::to_vec(&json!({
+            "architecture": Arch::default(),
+            "os": Os::default(),
+            "config": config,
+            "rootfs": {"type": "layers", "diff_ids": layer_digests},
+            "history": [],
+        }))?;
+        let config_digest = self.add_blob(&config);
+
+        let manifest = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": MediaType::ImageManifest,
+            "config": {
+                "mediaType": MediaType::ImageConfig,
+                "digest": config_digest,
+                "size": config.len(),
+            },
+            "layers": layer_descriptors,
+        }))?;
+        let manifest_digest = self.add_manifest(
+            repository,
+            tag,
+            &MediaType::ImageManifest.to_string(),
+            &manifest,
+        );
+
+        Ok(TestImage {
+            manifest_digest,
+            manifest_size: manifest.len(),
+            config_digest,
+            layers: layer_digests
+                .into_iter()
+                .map(serde_json::from_value)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Add an image index referencing the provided images for their platforms.
+    pub fn add_index(
+        &self,
+        repository: &str,
+        tag: Option<&str>,
+        images: &[(&TestImage, Platform)],
+    ) -> Result<Digest> {
+        let manifests = images
+            .iter()
+            .map(|(image, platform)| {
+                json!({
+                    "mediaType": MediaType::ImageManifest,
+                    "digest": image.manifest_digest(),
+                    "size": image.manifest_size(),
+                    "platform": platform,
+                })
+            })
+            .collect::<Vec<_>>();
+        let index = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": MediaType::ImageIndex,
+            "manifests": manifests,
+        }))?;
+        Ok(self.add_manifest(repository, tag, &MediaType::ImageIndex.to_string(), &index))
+    }
+
+    /// All requests received so far in the form `METHOD /path`.
+    pub fn requests(&self) -> Vec<String> {
+        self.content().requests.clone()
+    }
+
+    /// Lock the served content.
+    fn content(&self) -> MutexGuard<'_, Content> {
+        self.content.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Serve a single request.
+    async fn handle(content: Arc<Mutex<Content>>, request: Request<Body>) -> Response<Body> {
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        let authorization = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string);
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let offset = request
+            .headers()
+            .get(RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| v.strip_suffix('-'))
+            .and_then(|v| v.parse::<usize>().ok());
+        let body = hyper::body::to_bytes(request.into_body())
+            .await
+            .unwrap_or_default();
+
+        if path.contains("/blobs/") {
+            Self::delay_blob(&content).await;
+        }
+
+        let mut content = content.lock().unwrap_or_else(PoisonError::into_inner);
+        match offset {
+            Some(offset) => content
+                .requests
+                .push(format!("{} {} bytes={}-", method, path, offset)),
+            None => content.requests.push(format!("{} {}", method, path)),
+        }
+
+        if path == "/token" {
+            return Self::token(&content, authorization.as_deref(), &body);
+        }
+        if method != Method::GET && method != Method::HEAD {
+            return Self::error(StatusCode::METHOD_NOT_ALLOWED, "UNSUPPORTED");
+        }
+        if let Some(response) = Self::check_auth(&content, authorization.as_deref(), &host) {
+            return response;
+        }
+
+        let found = match path.strip_prefix("/v2/") {
+            Some("") => Some(("application/json".to_string(), b"{}".to_vec())),
+            Some(rest) => match (rest.rsplit_once("/manifests/"), rest.rsplit_once("/blobs/")) {
+                (Some((repository, target)), _) => content
+                    .manifests
+                    .get(&(repository.into(), target.into()))
+                    .cloned(),
+                (_, Some((_, digest))) => digest
+                    .parse::<Digest>()
+                    .ok()
+                    .and_then(|d| content.blobs.get(&d))
+                    .map(|b| ("application/octet-stream".to_string(), b.clone())),
+                _ => None,
+            },
+            None => None,
+        };
+
+        match found {
+            Some((media_type, data)) => {
+                let digest = Digest::from_bytes(Algorithm::Sha256, &data);
+                let mut response = Response::builder()
+                    .header(CONTENT_TYPE, media_type)
+                    .header("Docker-Content-Digest", digest.to_string());
+                let data = match offset {
+                    Some(offset) if offset < data.len() => {
+                        response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                            CONTENT_RANGE,
+                            format!("bytes {}-{}/{}", offset, data.len() - 1, data.len()),
+                        );
+                        data[offset..].to_vec()
+                    }
+                    _ => data,
+                };
+                response = response.header(CONTENT_LENGTH, data.len());
+
+                let body = if method == Method::HEAD {
+                    Body::empty()
+                } else if let Some(after) = content.interrupted.remove(&digest) {
+                    let (mut sender, body) = Body::channel();
+                    let partial = data[..after.min(data.len())].to_vec();
+                    tokio::spawn(async move {
+                        sender.send_data(partial.into()).await.ok();
+                        // Give the partial content a chance to be flushed before aborting
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        sender.abort();
+                    });
+                    body
+                } else {
+                    data.into()
+                };
+                response.body(body).unwrap_or_default()
+            }
+            None => Self::error(StatusCode::NOT_FOUND, "NOT_FOUND"),
+        }
+    }
+
+    /// Delay a blob request by the configured delay and track the number of concurrent ones.
+    async fn delay_blob(content: &Mutex<Content>) {
+        let delay = {
+            let mut content = content.lock().unwrap_or_else(PoisonError::into_inner);
+            if content.blob_delay.is_zero() {
+                return;
+            }
+            content.active_blob_requests += 1;
+            content.max_active_blob_requests = content
+                .max_active_blob_requests
+                .max(content.active_blob_requests);
+            content.blob_delay
+        };
+        tokio::time::sleep(delay).await;
+        content
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .active_blob_requests -= 1;
+    }
+
+    /// Verify the authorization of a registry request and return the challenge if it is missing
+    /// or invalid.
+    fn check_auth(
+        content: &Content,
+        authorization: Option<&str>,
+        host: &str,
+    ) -> Option<Response<Body>> {
+        let (expected, challenge) = match &content.auth {
+            None => return None,
+            Some(TestAuth::Basic { username, password }) => (
+                Self::basic(username, password),
+                r#"Basic realm="test-registry""#.to_string(),
+            ),
+            Some(TestAuth::Bearer { .. }) => (
+                format!("Bearer {}", Self::TOKEN),
+                format!(
+                    r#"Bearer realm="http://{}/token",service="test-registry""#,
+                    host
+                ),
+            ),
+        };
+        if authorization == Some(expected.as_str()) {
+            return None;
+        }
+        let mut response = Self::error(StatusCode::UNAUTHORIZED, "UNAUTHORIZED");
+        if let Ok(challenge) = challenge.parse() {
+            response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+        }
+        Some(response)
+    }
+
+    /// Serve the token endpoint, which accepts basic authentication and refresh tokens.
+    fn token(content: &Content, authorization: Option<&str>, body: &[u8]) -> Response<Body> {
+        let authorized = match &content.auth {
+            Some(TestAuth::Bearer {
+                username,
+                password,
+                identity_token,
+            }) => {
+                let form = String::from_utf8_lossy(body);
+                authorization == Some(Self::basic(username, password).as_str())
+                    || form
+                        .split('&')
+                        .any(|p| p == format!("refresh_token={}", identity_token))
+            }
+            _ => false,
+        };
+        if !authorized {
+            return Self::error(StatusCode::UNAUTHORIZED, "UNAUTHORIZED");
+        }
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({ "token": Self::TOKEN }).to_string().into())
+            .unwrap_or_default()
+    }
+
+    /// Build a basic authorization header value.
+    fn basic(username: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", username, password))
+        )
+    }
+
+    /// Build a distribution API error response.
+    fn error(status: StatusCode, code: &str) -> Response<Body> {
+        let body = json!({"errors": [{"code": code, "message": code.to_lowercase()}]});
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string().into())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for TestRegistry {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+/// Create an uncompressed layer archive containing regular files with the provided paths and
+/// contents. Whiteouts can be created by using their `.wh.` prefixed names.
+pub fn layer(files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        builder.append_data(&mut header, path, *data)?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Write an image consisting of the uncompressed layers into the store and return the digest of
+/// its manifest together with the manifest itself.
+pub async fn store_image(store: &BlobStore, layers: &[Vec<u8>]) -> Result<(Digest, ImageManifest)> {
+    store_image_with_config(store, json!({}), layers).await
+}
+
+/// Write an image like [`store_image`] with the provided container `config` section.
+pub async fn store_image_with_config(
+    store: &BlobStore,
+    config: Value,
+    layers: &[Vec<u8>],
+) -> Result<(Digest, ImageManifest)> {
+    let mut descriptors = vec![];
+    let mut diff_ids = vec![];
+    for layer in layers {
+        let digest = store.write(layer, None).await?;
+        diff_ids.push(digest.to_string());
+        descriptors.push(json!({
+            "mediaType": MediaType::ImageLayer,
+            "digest": digest,
+            "size": layer.len(),
+        }));
+    }
+    let config = serde_json::to_vec(&json!({
+        "architecture": Arch::default(),
+        "os": Os::default(),
+        "config": config,
+        "rootfs": {"type": "layers", "diff_ids": diff_ids},
+    }))?;
+    let config_digest = store.write(&config, None).await?;
+
+    let manifest = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": MediaType::ImageManifest,
+        "config": {
+            "mediaType": MediaType::ImageConfig,
+            "digest": config_digest,
+            "size": config.len(),
+        },
+        "layers": descriptors,
+    }))?;
+    let manifest_digest = store.write(&manifest, None).await?;
+    Ok((manifest_digest, serde_json::from_slice(&manifest)?))
+}
@@ -0,0 +1,199 @@
+//! File system usage accounting for the image and container storage.
+
+use anyhow::{Context, Result};
+use getset::CopyGetters;
+use nix::sys::statvfs::statvfs;
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    ops::{Add, AddAssign},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Eq, PartialEq)]
+/// The disk space and inodes used by a directory tree.
+pub struct Usage {
+    #[get_copy = "pub"]
+    /// The allocated bytes.
+    bytes: u64,
+
+    #[get_copy = "pub"]
+    /// The number of used inodes.
+    inodes: u64,
+}
+
+impl Usage {
+    /// Create a new usage.
+    pub fn new(bytes: u64, inodes: u64) -> Self {
+        Self { bytes, inodes }
+    }
+
+    /// Calculate the usage of the directory tree. Every inode is accounted only once, even if it
+    /// is linked multiple times. Directories which do not exist have no usage.
+    pub fn of_dir<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut seen = HashSet::new();
+        let mut usage = Self::default();
+        let mut pending = vec![path.as_ref().to_path_buf()];
+        while let Some(path) = pending.pop() {
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                // Removed concurrently
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("stat {}", path.display())),
+            };
+            if seen.insert((metadata.dev(), metadata.ino())) {
+                // Blocks are always reported in 512 byte units
+                usage += Self::new(metadata.blocks() * 512, 1);
+            }
+            if metadata.is_dir() {
+                match fs::read_dir(&path) {
+                    Ok(entries) => {
+                        for entry in entries {
+                            pending.push(entry?.path());
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e).with_context(|| format!("read dir {}", path.display()))
+                    }
+                }
+            }
+        }
+        Ok(usage)
+    }
+}
+
+impl Add for Usage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.bytes + other.bytes, self.inodes + other.inodes)
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other
+    }
+}
+
+#[derive(Clone, Copy, CopyGetters, Debug, Eq, PartialEq)]
+/// The disk usage of a file system.
+pub struct DiskUsage {
+    #[get_copy = "pub"]
+    /// The used bytes.
+    used: u64,
+
+    #[get_copy = "pub"]
+    /// The total capacity in bytes.
+    capacity: u64,
+}
+
+impl DiskUsage {
+    /// Create a new disk usage.
+    pub fn new(used: u64, capacity: u64) -> Self {
+        Self { used, capacity }
+    }
+
+    /// Retrieve the disk usage of the file system containing the path.
+    pub fn of<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let stat = statvfs(path).with_context(|| format!("statvfs {}", path.display()))?;
+        let fragment_size = stat.fragment_size() as u64;
+        let capacity = stat.blocks() as u64 * fragment_size;
+        let free = stat.blocks_free() as u64 * fragment_size;
+        Ok(Self::new(capacity.saturating_sub(free), capacity))
+    }
+
+    /// The used bytes as percentage of the capacity.
+    pub fn percent(&self) -> u64 {
+        if self.capacity == 0 {
+            return 0;
+        }
+        (self.used as u128 * 100 / self.capacity as u128) as u64
+    }
+}
+
+/// Find the mount point of the file system containing the path, which is the topmost parent
+/// residing on the same device.
+pub fn mountpoint<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let path = path
+        .as_ref()
+        .canonicalize()
+        .with_context(|| format!("canonicalize {}", path.as_ref().display()))?;
+    let dev = fs::metadata(&path)?.dev();
+    let mut mountpoint = path.as_path();
+    while let Some(parent) = mountpoint.parent() {
+        if fs::metadata(parent)?.dev() != dev {
+            break;
+        }
+        mountpoint = parent;
+    }
+    Ok(mountpoint.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn usage_of_dir() -> Result<()> {
+        let dir = TempDir::new()?;
+        let empty = Usage::of_dir(dir.path())?;
+        assert_eq!(empty.inodes(), 1);
+
+        fs::create_dir(dir.path().join("dir"))?;
+        fs::write(dir.path().join("dir/file"), vec![1; 8192])?;
+        fs::hard_link(dir.path().join("dir/file"), dir.path().join("link"))?;
+        std::os::unix::fs::symlink("dir/file", dir.path().join("symlink"))?;
+
+        let usage = Usage::of_dir(dir.path())?;
+        assert_eq!(usage.inodes(), 4);
+        assert!(usage.bytes() >= empty.bytes() + 8192);
+        assert!(usage.bytes() < empty.bytes() + 2 * 8192 + 3 * 4096);
+        Ok(())
+    }
+
+    #[test]
+    fn usage_of_missing_dir() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert_eq!(Usage::of_dir(dir.path().join("missing"))?, Usage::default());
+        Ok(())
+    }
+
+    #[test]
+    fn usage_add() {
+        let mut usage = Usage::new(1, 2) + Usage::new(3, 4);
+        assert_eq!(usage, Usage::new(4, 6));
+        usage += Usage::new(1, 1);
+        assert_eq!(usage, Usage::new(5, 7));
+    }
+
+    #[test]
+    fn disk_usage() -> Result<()> {
+        let dir = TempDir::new()?;
+        let usage = DiskUsage::of(dir.path())?;
+        assert!(usage.capacity() > 0);
+        assert!(usage.used() <= usage.capacity());
+        assert!(usage.percent() <= 100);
+        assert_eq!(DiskUsage::new(1, 0).percent(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn mountpoint_of_dir() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mountpoint = mountpoint(dir.path())?;
+        assert!(dir.path().canonicalize()?.starts_with(&mountpoint));
+        assert_eq!(
+            fs::metadata(&mountpoint)?.dev(),
+            fs::metadata(dir.path())?.dev()
+        );
+        assert_eq!(super::mountpoint("/proc/self")?, Path::new("/proc"));
+        assert!(super::mountpoint(dir.path().join("missing")).is_err());
+        Ok(())
+    }
+}
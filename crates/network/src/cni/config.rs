@@ -384,7 +384,7 @@ mod tests {
 
     #[test]
     fn config_file_from_path_failure_not_exists() {
-        assert!(ConfigFile::from(&Path::new("")).is_err())
+        assert!(ConfigFile::from(Path::new("")).is_err())
     }
 
     #[test]
@@ -422,6 +422,6 @@ mod tests {
 
     #[test]
     fn config_list_file_from_path_failure_not_exists() {
-        assert!(ConfigListFile::from(&Path::new("")).is_err())
+        assert!(ConfigListFile::from(Path::new("")).is_err())
     }
 }
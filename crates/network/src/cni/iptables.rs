@@ -588,6 +588,7 @@ mod tests {
     }
 
     impl ExecCommandMock {
+        #[allow(clippy::wrong_self_convention)]
         fn to_iptables(self) -> Result<DefaultIptables> {
             let mut iptables = DefaultIptablesBuilder::default()
                 .iptables_binary("")
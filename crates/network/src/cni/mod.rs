@@ -211,7 +211,7 @@ impl CNI {
     /// Handle a file watcher event.
     async fn handle_event(state: &State, event: Event) -> Result<()> {
         trace!("Got file watcher event: {:?}", &event);
-        match (event.kind, event.paths.get(0), event.paths.get(1)) {
+        match (event.kind, event.paths.first(), event.paths.get(1)) {
             // File creation handline
             (EventKind::Create(CreateKind::File), Some(file), None)
                 if Self::is_config_file(file) =>
@@ -345,7 +345,7 @@ impl CNI {
             config
                 .list()
                 .plugins()
-                .get(0)
+                .first()
                 .context("no plugin in config list")?
                 .typ(),
             file.display()
@@ -461,8 +461,8 @@ impl CNI {
     }
 
     /// Retrieve necessary data for network start/stop.
-    async fn get_start_stop_data<'a, 'b>(
-        &'b self,
+    async fn get_start_stop_data<'a>(
+        &self,
         sandbox_data: &'a SandboxConfig,
     ) -> Result<(&'a Path, Namespace)> {
         let network_namespace_path = sandbox_data
@@ -686,8 +686,10 @@ pub mod tests {
         File::create(d2.join("cfg-1.other"))?;
         File::create(d2.join("cfg-2.conflist"))?;
 
-        let mut cni = CNI::default();
-        cni.config_paths = vec![d1.clone(), d2.clone()];
+        let cni = CNI {
+            config_paths: vec![d1.clone(), d2.clone()],
+            ..Default::default()
+        };
 
         let files = cni.config_files()?;
         assert_eq!(
@@ -1,6 +1,6 @@
 //! Netlink related helpers and structures.
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use derive_builder::Builder;
 use dyn_clone::{clone_trait_object, DynClone};
@@ -19,17 +19,17 @@ use std::fmt;
 pub trait Netlink: DynClone + Send + Sync {
     /// Get the loopback link.
     async fn loopback(&self) -> Result<Link> {
-        bail!("no loopback")
+        Err(anyhow!("no loopback"))
     }
 
     /// Get a link referenced by its name.
     async fn link_by_name(&self, _name: &str) -> Result<Link> {
-        bail!("no link for name")
+        Err(anyhow!("no link for name"))
     }
 
     /// Get a link referenced by its index.
     async fn link_by_index(&self, _index: u32) -> Result<Link> {
-        bail!("no link for index")
+        Err(anyhow!("no link for index"))
     }
 
     /// Set a link down.
@@ -427,7 +427,7 @@ pub mod tests {
         assert_eq!(
             result
                 .ips()
-                .get(0)
+                .first()
                 .context("no first addr")?
                 .address()
                 .prefix(),
@@ -11,4 +11,6 @@ pub enum SandboxError {
     Pinning(String),
     #[error("IO")]
     IO(#[from] io::Error),
+    #[error("cgroup: {0:#}")]
+    Cgroup(anyhow::Error),
 }
@@ -7,9 +7,11 @@ pub mod pinns;
 use crate::error::{Result, SandboxError};
 use async_trait::async_trait;
 use bitflags::bitflags;
-use common::Namespace;
+use common::{cgroup::manager::CgroupManager, Namespace};
 use derive_builder::Builder;
 use getset::{CopyGetters, Getters, MutGetters, Setters};
+use log::warn;
+use oci_spec::runtime::LinuxResources;
 use pinns::Pinns;
 use std::{collections::HashMap, fmt, path::PathBuf};
 
@@ -100,6 +102,17 @@ pub struct SandboxConfig {
     #[get = "pub"]
     cgroup_parent: PathBuf,
 
+    #[get = "pub"]
+    #[builder(default)]
+    /// The manager creating the pod cgroup below the cgroup parent. No cgroup gets created if
+    /// unset.
+    cgroup_manager: Option<Box<dyn CgroupManager>>,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// Resources of the pod cgroup, which should include the pod overhead.
+    resources: Option<LinuxResources>,
+
     #[get = "pub"]
     #[builder(default)]
     security: SecurityConfig,
@@ -204,9 +217,25 @@ where
         &self.context.config.id
     }
 
-    /// Wrapper for the implementations `run` method
+    /// Wrapper for the implementations `run` method, which creates the pod cgroup beforehand.
     pub async fn run(&mut self) -> Result<()> {
-        self.implementation.run(&self.context).await
+        let config = &self.context.config;
+        if let Some(manager) = config.cgroup_manager() {
+            manager
+                .create_pod(
+                    config.cgroup_parent(),
+                    config.id(),
+                    config.resources().as_ref(),
+                )
+                .map_err(SandboxError::Cgroup)?;
+        }
+        let result = self.implementation.run(&self.context).await;
+        if result.is_err() {
+            if let Err(e) = self.remove_cgroup() {
+                warn!("Unable to remove cgroup of sandbox {}: {}", self, e)
+            }
+        }
+        result
     }
 
     #[allow(dead_code)]
@@ -216,9 +245,10 @@ where
     }
 
     #[allow(dead_code)]
-    /// Wrapper for the implementations `remove` method
+    /// Wrapper for the implementations `remove` method, which removes the pod cgroup afterwards.
     pub fn remove(&mut self) -> Result<()> {
-        self.implementation.remove(&self.context)
+        self.implementation.remove(&self.context)?;
+        self.remove_cgroup()
     }
 
     #[allow(dead_code)]
@@ -226,6 +256,17 @@ where
     pub fn ready(&mut self) -> Result<bool> {
         self.implementation.ready(&self.context)
     }
+
+    /// Remove the pod cgroup if the sandbox has a cgroup manager.
+    fn remove_cgroup(&self) -> Result<()> {
+        let config = &self.context.config;
+        match config.cgroup_manager() {
+            Some(manager) => manager
+                .remove_pod(config.cgroup_parent(), config.id())
+                .map_err(SandboxError::Cgroup),
+            None => Ok(()),
+        }
+    }
 }
 
 impl<T> fmt::Debug for Sandbox<T>
@@ -248,6 +289,8 @@ where
             .field("pinns", config.pinns())
             .field("sysctls", config.sysctls())
             .field("cgroup_parent", config.cgroup_parent())
+            .field("cgroup_manager", config.cgroup_manager())
+            .field("resources", config.resources())
             .field("run_as_user", config.security.run_as_user())
             .field("run_as_group", config.security.run_as_group())
             .field("supplemental_groups", config.security.supplemental_groups())
@@ -271,12 +314,17 @@ where
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use common::cgroup::{hierarchy::Hierarchy, manager::CgroupDriver};
+    use oci_spec::runtime::{LinuxPidsBuilder, LinuxResourcesBuilder};
+    use pinns::PinnsBuilder;
+    use std::fs;
+    use tempfile::TempDir;
 
     pub fn new_sandbox_data() -> Result<SandboxConfig> {
         let mut annotations: HashMap<String, String> = HashMap::new();
         annotations.insert("annotationkey1".into(), "annotationvalue1".into());
 
-        Ok(SandboxConfigBuilder::default()
+        SandboxConfigBuilder::default()
             .id("uid")
             .name("name")
             .namespace("namespace")
@@ -286,7 +334,7 @@ pub mod tests {
             .hostname("hostname")
             .log_directory("log_directory")
             .annotations(annotations)
-            .build()?)
+            .build()
     }
 
     #[derive(Default)]
@@ -411,4 +459,40 @@ pub mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn pod_cgroup() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("cgroup.controllers"), "pids")?;
+        let config = SandboxConfigBuilder::default()
+            .id("uid")
+            .name("name")
+            .namespace("namespace")
+            .attempt(1u32)
+            .linux_namespaces(LinuxNamespaces::empty())
+            .cgroup_parent(PathBuf::from("/kubepods/poduid"))
+            .pinns(PinnsBuilder::default().binary("pinns").build()?)
+            .cgroup_manager(CgroupDriver::Cgroupfs.manager(Hierarchy::new(dir.path())))
+            .resources(
+                LinuxResourcesBuilder::default()
+                    .pids(LinuxPidsBuilder::default().limit(42).build()?)
+                    .build()?,
+            )
+            .hostname("hostname")
+            .log_directory("log_directory")
+            .build()?;
+        let context = SandboxContextBuilder::default().config(config).build()?;
+        let mut sandbox = SandboxBuilder::<Mock>::default().context(context).build()?;
+
+        let cgroup = dir.path().join("kubepods/poduid");
+        sandbox.run().await?;
+        assert_eq!(fs::read_to_string(cgroup.join("pids.max"))?, "42");
+
+        sandbox.stop()?;
+        assert!(cgroup.exists());
+
+        sandbox.remove()?;
+        assert!(!cgroup.exists());
+        Ok(())
+    }
 }
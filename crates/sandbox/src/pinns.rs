@@ -113,23 +113,18 @@ impl Display for Arg {
     }
 }
 
-#[derive(AsRefStr, Display, Clone, Copy, Debug)]
+#[derive(AsRefStr, Display, Clone, Copy, Debug, Default)]
 #[strum(serialize_all = "lowercase")]
 pub enum LogLevel {
     Trace,
     Debug,
+    #[default]
     Info,
     Warn,
     Error,
     Off,
 }
 
-impl Default for LogLevel {
-    fn default() -> Self {
-        LogLevel::Info
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -157,7 +152,10 @@ mod tests {
         ];
 
         for t in test_data {
-            let output = pinns.run(&[t.0.clone()]).await.context("run pinns")?;
+            let output = pinns
+                .run(std::slice::from_ref(&t.0))
+                .await
+                .context("run pinns")?;
             assert!(output.status.success());
             assert!(String::from_utf8(output.stderr)?.is_empty());
             assert_eq!(String::from_utf8(output.stdout)?, t.1);
@@ -181,7 +179,10 @@ mod tests {
         ];
 
         for t in test_data {
-            let output = pinns.run(&[t.0.clone()]).await.context("run pinns")?;
+            let output = pinns
+                .run(std::slice::from_ref(&t.0))
+                .await
+                .context("run pinns")?;
             assert!(output.status.success());
             assert!(String::from_utf8(output.stderr)?.is_empty());
             assert_eq!(String::from_utf8(output.stdout)?, t.1);
@@ -203,7 +204,10 @@ mod tests {
         ];
 
         for t in test_data {
-            let output = pinns.run(&[t.0.clone()]).await.context("run pinns")?;
+            let output = pinns
+                .run(std::slice::from_ref(&t.0))
+                .await
+                .context("run pinns")?;
             assert!(output.status.success());
             assert!(String::from_utf8(output.stderr)?.is_empty());
             assert_eq!(String::from_utf8(output.stdout)?, t.1);
@@ -5,7 +5,11 @@
 include!("runtime.v1alpha2.rs");
 
 use crate::error::ServiceError;
-use oci_spec::runtime::MountBuilder;
+use image::{index::ImageRecord, registry::auth::Credentials};
+use oci_spec::runtime::{
+    LinuxCpuBuilder, LinuxHugepageLimitBuilder, LinuxMemoryBuilder, LinuxResources,
+    LinuxResourcesBuilder, MountBuilder,
+};
 use std::{convert::TryFrom, fmt::Display, fs, path::PathBuf};
 
 use crate::cri::api::Mount as CRIMount;
@@ -23,6 +27,28 @@ impl Display for MountPropagation {
     }
 }
 
+impl From<&ImageRecord> for Image {
+    fn from(record: &ImageRecord) -> Self {
+        Self {
+            id: record.id().to_string(),
+            repo_tags: record.repo_tags().iter().map(ToString::to_string).collect(),
+            repo_digests: record
+                .repo_digests()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            size: record.size(),
+            uid: record.uid().map(|value| Int64Value { value }),
+            username: record.username().clone(),
+            spec: Some(ImageSpec {
+                image: record.id().to_string(),
+                ..Default::default()
+            }),
+            pinned: record.pinned(),
+        }
+    }
+}
+
 impl TryFrom<&CRIMount> for OCIMount {
     type Error = ServiceError;
 
@@ -61,3 +87,118 @@ impl TryFrom<&CRIMount> for OCIMount {
         Ok(oci_mount)
     }
 }
+
+impl TryFrom<&LinuxContainerResources> for LinuxResources {
+    type Error = ServiceError;
+
+    fn try_from(resources: &LinuxContainerResources) -> Result<Self, Self::Error> {
+        let mut cpu = LinuxCpuBuilder::default();
+        if resources.cpu_shares < 0 || resources.cpu_period < 0 {
+            return Err(ServiceError::Other(format!(
+                "invalid cpu shares {} or period {}",
+                resources.cpu_shares, resources.cpu_period
+            )));
+        }
+        if resources.cpu_shares > 0 {
+            cpu = cpu.shares(resources.cpu_shares as u64);
+        }
+        if resources.cpu_period > 0 {
+            cpu = cpu.period(resources.cpu_period as u64);
+        }
+        if resources.cpu_quota != 0 {
+            cpu = cpu.quota(resources.cpu_quota);
+        }
+        if !resources.cpuset_cpus.is_empty() {
+            cpu = cpu.cpus(resources.cpuset_cpus.as_str());
+        }
+        if !resources.cpuset_mems.is_empty() {
+            cpu = cpu.mems(resources.cpuset_mems.as_str());
+        }
+
+        let mut memory = LinuxMemoryBuilder::default();
+        if resources.memory_limit_in_bytes < 0 {
+            return Err(ServiceError::Other(format!(
+                "invalid memory limit {}",
+                resources.memory_limit_in_bytes
+            )));
+        }
+        if resources.memory_limit_in_bytes > 0 {
+            memory = memory.limit(resources.memory_limit_in_bytes);
+        }
+        // The swap limit includes the memory, whereas -1 means unlimited swap
+        match resources.memory_swap_limit_in_bytes {
+            0 => {}
+            swap if swap < -1 => {
+                return Err(ServiceError::Other(format!(
+                    "invalid memory swap limit {}",
+                    swap
+                )))
+            }
+            swap => memory = memory.swap(swap),
+        }
+
+        let mut hugepage_limits = vec![];
+        for hugepage in &resources.hugepage_limits {
+            let size = hugepage
+                .page_size
+                .strip_suffix('B')
+                .and_then(|s| s.strip_suffix(|c| matches!(c, 'K' | 'M' | 'G' | 'T' | 'P')))
+                .unwrap_or_default();
+            if size.is_empty() || !size.chars().all(|c| c.is_ascii_digit()) {
+                return Err(ServiceError::Other(format!(
+                    "invalid hugepage size {:?}",
+                    hugepage.page_size
+                )));
+            }
+            hugepage_limits.push(
+                LinuxHugepageLimitBuilder::default()
+                    .page_size(hugepage.page_size.as_str())
+                    .limit(i64::try_from(hugepage.limit).map_err(|e| {
+                        ServiceError::Other(format!("invalid hugepage limit: {}", e))
+                    })?)
+                    .build()?,
+            );
+        }
+
+        let mut builder = LinuxResourcesBuilder::default()
+            .cpu(cpu.build()?)
+            .memory(memory.build()?);
+        if !hugepage_limits.is_empty() {
+            builder = builder.hugepage_limits(hugepage_limits);
+        }
+        if !resources.unified.is_empty() {
+            builder = builder.unified(resources.unified.clone());
+        }
+        Ok(builder.build()?)
+    }
+}
+
+impl TryFrom<&AuthConfig> for Credentials {
+    type Error = ServiceError;
+
+    fn try_from(auth: &AuthConfig) -> Result<Self, Self::Error> {
+        if !auth.registry_token.is_empty() {
+            return Ok(Credentials::RegistryToken(auth.registry_token.clone()));
+        }
+
+        if !auth.identity_token.is_empty() {
+            return Ok(Credentials::IdentityToken(auth.identity_token.clone()));
+        }
+
+        if !auth.username.is_empty() {
+            return Ok(Credentials::Basic {
+                username: auth.username.clone(),
+                password: auth.password.clone(),
+            });
+        }
+
+        if !auth.auth.is_empty() {
+            return Credentials::from_auth(&auth.auth)
+                .map_err(|e| ServiceError::Other(format!("{:#}", e)));
+        }
+
+        Err(ServiceError::Other(
+            "auth config contains no credentials".to_owned(),
+        ))
+    }
+}
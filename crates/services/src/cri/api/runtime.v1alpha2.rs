@@ -508,6 +508,17 @@ pub struct LinuxContainerResources {
     /// List of HugepageLimits to limit the HugeTLB usage of container per page size. Default: nil (not specified).
     #[prost(message, repeated, tag = "8")]
     pub hugepage_limits: ::prost::alloc::vec::Vec<HugepageLimit>,
+    /// Unified resources for cgroup v2. Default: nil (not specified).
+    /// Each key/value in the map refers to the cgroup v2.
+    /// e.g. "memory.max": "6937202688" or "io.weight": "default 100".
+    #[prost(map = "string, string", tag = "9")]
+    pub unified: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
+    /// Memory swap limit in bytes. Default 0 (not specified).
+    #[prost(int64, tag = "10")]
+    pub memory_swap_limit_in_bytes: i64,
 }
 /// HugepageLimit corresponds to the file`hugetlb.<hugepagesize>.limit_in_byte` in container level cgroup.
 /// For example, `PageSize=1GB`, `Limit=1073741824` means setting `1073741824` bytes to hugetlb.1GB.limit_in_bytes.
@@ -1162,6 +1173,11 @@ pub struct Image {
     /// ImageSpec for image which includes annotations
     #[prost(message, optional, tag = "7")]
     pub spec: ::core::option::Option<ImageSpec>,
+    /// Recommendation on whether this image should be exempt from garbage collection.
+    /// It must only be treated as a recommendation -- the client can still request that the image be deleted,
+    /// and the runtime must oblige.
+    #[prost(bool, tag = "8")]
+    pub pinned: bool,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ListImagesResponse {
@@ -1355,6 +1371,12 @@ pub struct ImageFsInfoResponse {
     /// Information of image filesystem(s).
     #[prost(message, repeated, tag = "1")]
     pub image_filesystems: ::prost::alloc::vec::Vec<FilesystemUsage>,
+    /// Information of container filesystem(s).
+    /// This is an optional field, may be used for example if container and image
+    /// storage are separated.
+    /// Default will be to return this as empty.
+    #[prost(message, repeated, tag = "2")]
+    pub container_filesystems: ::prost::alloc::vec::Vec<FilesystemUsage>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ContainerStatsRequest {
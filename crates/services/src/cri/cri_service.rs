@@ -1,22 +1,132 @@
 //! A CRI API service implementation.
 
 use anyhow::Result;
+use common::cgroup::{
+    hierarchy::Hierarchy,
+    manager::{CgroupDriver, CgroupManager},
+};
+use container::{
+    bundle::{Bundle, Bundles},
+    container::local::OCIContainerBuilder,
+    monitor::MonitorConfig,
+};
 use derive_builder::Builder;
+use getset::{CopyGetters, Getters};
+use image::{
+    gc::GarbageCollector, index::ImageIndex, pull::Puller, reference::Reference,
+    snapshot::Snapshotter,
+};
 use log::debug;
-use std::fmt::{Debug, Display};
-use storage::default_key_value_storage::DefaultKeyValueStorage;
+use std::{
+    fmt::{Debug, Display},
+    path::{Path, PathBuf},
+};
+use storage::{default_key_value_storage::DefaultKeyValueStorage, KeyValueStorage};
 use tonic::{Request, Response, Status};
 
-#[derive(Clone, Builder)]
+/// Storage key prefix of the cgroup parents of the pod sandboxes.
+const CGROUP_PARENT_PREFIX: &str = "cgroup-parent/";
+
+/// Annotation of the runtime spec containing the ID of the container image. It marks the image
+/// as in use for the garbage collection.
+pub(crate) const IMAGE_ANNOTATION: &str = "io.containers.image-id";
+
+#[derive(Clone, Builder, CopyGetters, Getters)]
 #[builder(pattern = "owned", setter(into))]
 /// The service implementation for the CRI API
 pub struct CRIService {
     /// Storage used by the service.
-    #[allow(dead_code)]
     storage: DefaultKeyValueStorage,
+
+    #[get = "pub"]
+    /// Image puller including the blob store for image content.
+    puller: Puller,
+
+    #[get = "pub"]
+    /// Snapshotter for the unpacked image layers and container root file systems.
+    snapshotter: Snapshotter,
+
+    #[get = "pub"]
+    /// Index of all locally available images.
+    image_index: ImageIndex,
+
+    #[get = "pub"]
+    /// Garbage collector for unreferenced image content.
+    garbage_collector: GarbageCollector,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// Images which get pinned on pull.
+    pinned_images: Vec<Reference>,
+
+    #[get_copy = "pub"]
+    #[builder(default)]
+    /// The default disk quota of container writable layers in bytes.
+    writable_layer_size: Option<u64>,
+
+    #[get = "pub"]
+    /// Path to the OCI runtime binary used to run containers.
+    runtime: PathBuf,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// Root directory of the OCI runtime for the container state.
+    runtime_root: Option<PathBuf>,
+
+    #[get = "pub"]
+    /// The OCI bundles of all containers.
+    bundles: Bundles,
+
+    #[get = "pub"]
+    #[builder(default)]
+    /// The configuration of the monitor supervising the containers, which are not monitored if
+    /// unset.
+    monitor: Option<MonitorConfig>,
+
+    #[get = "pub"]
+    #[builder(default = "CgroupDriver::default().manager(Hierarchy::default())")]
+    /// The manager of the pod cgroups, which has to match the cgroup driver of the kubelet.
+    cgroup_manager: Box<dyn CgroupManager>,
 }
 
 impl CRIService {
+    /// Prepare the container of the bundle for the configured OCI runtime.
+    pub fn container_builder(&self, bundle: Bundle) -> OCIContainerBuilder {
+        let mut builder = OCIContainerBuilder::default()
+            .id(bundle.id().as_str())
+            .runtime(self.runtime())
+            .bundle(bundle);
+        if let Some(root) = self.runtime_root() {
+            builder = builder.runtime_root(root.clone());
+        }
+        if let Some(monitor) = self.monitor() {
+            builder = builder.monitor(monitor.clone());
+        }
+        builder.systemd_cgroup(self.cgroup_manager().driver() == CgroupDriver::Systemd)
+    }
+
+    /// Record the cgroup parent of the pod sandbox, which is required to remove its cgroup.
+    pub fn record_cgroup_parent(&self, pod_id: &str, parent: &Path) -> Result<()> {
+        self.storage
+            .clone()
+            .insert(Self::cgroup_parent_key(pod_id), parent)
+    }
+
+    /// The recorded cgroup parent of the pod sandbox, if any.
+    pub fn cgroup_parent(&self, pod_id: &str) -> Result<Option<PathBuf>> {
+        self.storage.get(Self::cgroup_parent_key(pod_id))
+    }
+
+    /// Remove the recorded cgroup parent of the pod sandbox.
+    pub fn forget_cgroup_parent(&self, pod_id: &str) -> Result<()> {
+        self.storage.clone().remove(Self::cgroup_parent_key(pod_id))
+    }
+
+    /// The storage key of the cgroup parent of the pod sandbox.
+    fn cgroup_parent_key(pod_id: &str) -> String {
+        format!("{}{}", CGROUP_PARENT_PREFIX, pod_id)
+    }
+
     /// Debug log a request.
     pub fn debug_request<T>(&self, request: &Request<T>)
     where
@@ -35,6 +145,8 @@ impl CRIService {
 }
 
 /// Option to Status transformer for less verbose request unpacking.
+// `Status` is large, but the error type of every handler anyway
+#[allow(clippy::result_large_err)]
 pub trait OptionStatus<T> {
     /// Maps the self type to an invalid argument status containing the provided `msg`.
     fn ok_or_invalid(self, msg: impl Into<String>) -> Result<T, Status>
@@ -66,6 +178,7 @@ impl<T> OptionStatus<T> for Option<T> {
 }
 
 /// Result to Status transformer for less verbose request unpacking.
+#[allow(clippy::result_large_err)]
 pub trait ResultStatus<T, E>
 where
     E: Display,
@@ -78,6 +191,14 @@ where
         self.map_err(|e| Status::internal(format!("{}: {}", msg, e)))
     }
 
+    /// Maps the self type to an invalid argument error status containing the provided `msg`.
+    fn map_invalid(self, msg: impl Into<String> + Display) -> Result<T, Status>
+    where
+        Self: Sized,
+    {
+        self.map_err(|e| Status::invalid_argument(format!("{}: {}", msg, e)))
+    }
+
     /// Maps a `ResultStatus<T, E>` to `Result<T, F>` by applying a function to a
     /// contained [`Err`] value, leaving an [`Ok`] value untouched.
     ///
@@ -104,13 +225,153 @@ where
 pub mod tests {
     use super::*;
     use anyhow::Result;
+    use image::{
+        gc::{GarbageCollectorBuilder, GcPolicy},
+        index::{ImageRecord, ImageRecordBuilder},
+        policy::Policy,
+        pull::PullerBuilder,
+        registry::Client,
+        snapshot::{Driver, Snapshotter},
+        store::BlobStore,
+        testing,
+    };
+    use serde_json::{json, Value};
+    use std::ops::Deref;
     use storage::KeyValueStorage;
     use tempfile::TempDir;
 
-    pub fn new_cri_service() -> Result<CRIService> {
+    /// A service for tests, whose state gets removed once it is dropped.
+    pub struct TestService {
+        /// The service under test.
+        service: CRIService,
+
+        /// The directory containing the state of the service, which has to outlive it.
+        _dir: TempDir,
+    }
+
+    impl Deref for TestService {
+        type Target = CRIService;
+
+        fn deref(&self) -> &Self::Target {
+            &self.service
+        }
+    }
+
+    #[derive(Default)]
+    /// The settings of a service for tests, which differ from the defaults.
+    pub struct TestOptions {
+        /// The OCI runtime, which is `true` if not set.
+        pub runtime: Option<PathBuf>,
+
+        /// The registry client for pulls.
+        pub client: Client,
+
+        /// The signature policy for pulls.
+        pub signature_policy: Policy,
+
+        /// The policy for evicting images, whereas nothing gets evicted without one.
+        pub gc_policy: Option<GcPolicy>,
+
+        /// The images which are never evicted.
+        pub pinned_images: Vec<Reference>,
+    }
+
+    pub fn new_cri_service() -> Result<TestService> {
+        new_cri_service_with(TestOptions::default())
+    }
+
+    /// Create a service like [`new_cri_service`] with the provided `options`.
+    pub fn new_cri_service_with(options: TestOptions) -> Result<TestService> {
+        let runtime = match options.runtime {
+            Some(runtime) => runtime,
+            None => which::which("true")?,
+        };
         let dir = TempDir::new()?;
-        Ok(CRIService {
-            storage: DefaultKeyValueStorage::open(dir.path())?,
-        })
+        let storage = DefaultKeyValueStorage::open(dir.path().join("storage"))?;
+        let store = BlobStore::open(dir.path().join("blobs"))?;
+        let snapshotter = Snapshotter::open(dir.path().join("snapshots"), Driver::Vfs)?;
+        let cgroup_root = dir.path().join("cgroup");
+        std::fs::create_dir(&cgroup_root)?;
+        std::fs::write(cgroup_root.join("cgroup.controllers"), "cpu memory pids")?;
+        let mut garbage_collector = GarbageCollectorBuilder::default()
+            .store(store.clone())
+            .snapshotter(snapshotter.clone());
+        if let Some(policy) = options.gc_policy {
+            garbage_collector = garbage_collector.policy(policy);
+        }
+        let garbage_collector = garbage_collector.build()?;
+        let service = CRIService {
+            storage: storage.clone(),
+            puller: PullerBuilder::default()
+                .client(options.client)
+                .store(store)
+                .policy(options.signature_policy)
+                .garbage_collector(garbage_collector.clone())
+                .build()?,
+            snapshotter,
+            image_index: ImageIndex::new(storage.clone()),
+            garbage_collector,
+            pinned_images: options.pinned_images,
+            writable_layer_size: None,
+            runtime,
+            runtime_root: None,
+            bundles: Bundles::new(dir.path().join("bundles"), storage.clone()),
+            monitor: None,
+            cgroup_manager: CgroupDriver::Cgroupfs.manager(Hierarchy::new(cgroup_root)),
+        };
+        Ok(TestService { service, _dir: dir })
+    }
+
+    /// Store and unpack an image with a single layer containing the `file`, then add it to the
+    /// index of the service under the provided tags.
+    pub async fn add_image(sut: &CRIService, file: &str, tags: &[&str]) -> Result<ImageRecord> {
+        add_image_with_config(sut, json!({}), &[(file, b"content")], tags).await
+    }
+
+    /// Add an image like [`add_image`] with the provided container `config` section and a single
+    /// layer containing the `files`.
+    pub async fn add_image_with_config(
+        sut: &CRIService,
+        config: Value,
+        files: &[(&str, &[u8])],
+        tags: &[&str],
+    ) -> Result<ImageRecord> {
+        let (manifest_digest, manifest) = testing::store_image_with_config(
+            sut.puller().store(),
+            config,
+            &[testing::layer(files)?],
+        )
+        .await?;
+        let top_layer = sut
+            .snapshotter()
+            .unpack(sut.puller().store(), &manifest)
+            .await?;
+        let mut repo_tags = vec![];
+        for tag in tags {
+            repo_tags.push(tag.parse::<Reference>()?);
+        }
+        let record = ImageRecordBuilder::default()
+            .id(manifest
+                .config()
+                .digest()
+                .parse::<image::digest::Digest>()?)
+            .repo_digests(
+                repo_tags
+                    .iter()
+                    .map(|tag| tag.with_digest(manifest_digest.clone()))
+                    .collect::<Vec<_>>(),
+            )
+            .repo_tags(repo_tags)
+            .manifests(vec![manifest_digest])
+            .size(
+                manifest
+                    .layers()
+                    .iter()
+                    .map(|l| l.size() as u64)
+                    .sum::<u64>(),
+            )
+            .top_layer(top_layer)
+            .build()?;
+        sut.image_index().add(record)
     }
 }
@@ -1,7 +1,15 @@
 use crate::cri::{
-    api::{ImageFsInfoRequest, ImageFsInfoResponse},
-    cri_service::CRIService,
+    api::{
+        FilesystemIdentifier, FilesystemUsage, ImageFsInfoRequest, ImageFsInfoResponse, UInt64Value,
+    },
+    cri_service::{CRIService, ResultStatus},
 };
+use anyhow::{Context, Result};
+use image::{
+    snapshot::SnapshotKind,
+    usage::{self, Usage},
+};
+use std::{path::Path, time::SystemTime};
 use tonic::{Request, Response, Status};
 
 impl CRIService {
@@ -11,9 +19,164 @@ impl CRIService {
         &self,
         _request: Request<ImageFsInfoRequest>,
     ) -> Result<Response<ImageFsInfoResponse>, Status> {
+        let (image_usage, container_usage) = self
+            .storage_usage()
+            .await
+            .map_internal("failed to calculate storage usage")?;
+
         let resp = ImageFsInfoResponse {
-            image_filesystems: Vec::new(),
+            image_filesystems: vec![filesystem_usage(self.puller().store().root(), image_usage)
+                .map_internal("failed to get image filesystem")?],
+            container_filesystems: vec![filesystem_usage(
+                self.snapshotter().root(),
+                container_usage,
+            )
+            .map_internal("failed to get container filesystem")?],
         };
         Ok(Response::new(resp))
     }
+
+    /// Calculate the usage of the images, which are the blobs and committed snapshots, and the
+    /// usage of the container writable layers, which are the active snapshots.
+    async fn storage_usage(&self) -> Result<(Usage, Usage)> {
+        let store_root = self.puller().store().root().clone();
+        let mut image_usage = tokio::task::spawn_blocking(move || Usage::of_dir(store_root))
+            .await
+            .context("join blob store usage")??;
+        let mut container_usage = Usage::default();
+
+        for info in self.snapshotter().list()? {
+            let usage = match self.snapshotter().usage(info.key()).await {
+                Ok(usage) => usage,
+                // Removed concurrently
+                Err(_) if self.snapshotter().stat(info.key())?.is_none() => continue,
+                Err(e) => return Err(e),
+            };
+            match info.kind() {
+                SnapshotKind::Committed => image_usage += usage,
+                SnapshotKind::Active => container_usage += usage,
+            }
+        }
+        Ok((image_usage, container_usage))
+    }
+
+    /// Get the usage of the writable layer of the container, which is `None` if the container
+    /// has no active snapshot.
+    pub(crate) async fn writable_layer_usage(
+        &self,
+        id: &str,
+    ) -> Result<Option<FilesystemUsage>, Status> {
+        match self
+            .snapshotter()
+            .stat(id)
+            .map_internal("failed to get writable layer")?
+        {
+            Some(info) if info.kind() == SnapshotKind::Active => {}
+            _ => return Ok(None),
+        }
+        let usage = self
+            .snapshotter()
+            .usage(id)
+            .await
+            .map_internal("failed to calculate writable layer usage")?;
+        filesystem_usage(self.snapshotter().root(), usage)
+            .map(Some)
+            .map_internal("failed to get writable layer filesystem")
+    }
+}
+
+/// Build the CRI file system usage for the storage at `path`.
+fn filesystem_usage(path: &Path, usage: Usage) -> Result<FilesystemUsage> {
+    Ok(FilesystemUsage {
+        timestamp: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos() as i64,
+        fs_id: Some(FilesystemIdentifier {
+            mountpoint: usage::mountpoint(path)?.display().to_string(),
+        }),
+        used_bytes: Some(UInt64Value {
+            value: usage.bytes(),
+        }),
+        inodes_used: Some(UInt64Value {
+            value: usage.inodes(),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::cri_service::tests::new_cri_service;
+    use std::fs;
+
+    fn used_bytes(usage: &FilesystemUsage) -> u64 {
+        usage
+            .used_bytes
+            .as_ref()
+            .map(|u| u.value)
+            .unwrap_or_default()
+    }
+
+    fn inodes_used(usage: &FilesystemUsage) -> u64 {
+        usage
+            .inodes_used
+            .as_ref()
+            .map(|u| u.value)
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn image_fs_info_success() -> Result<()> {
+        let sut = new_cri_service()?;
+        let response = sut
+            .handle_image_fs_info(Request::new(ImageFsInfoRequest {}))
+            .await?;
+        let response = response.get_ref();
+        assert_eq!(response.image_filesystems.len(), 1);
+        assert_eq!(response.container_filesystems.len(), 1);
+
+        let image_fs = &response.image_filesystems[0];
+        assert!(image_fs.timestamp > 0);
+        assert!(sut
+            .puller()
+            .store()
+            .root()
+            .canonicalize()?
+            .starts_with(&image_fs.fs_id.as_ref().context("no fs ID")?.mountpoint));
+        assert!(used_bytes(image_fs) > 0);
+        assert!(inodes_used(image_fs) > 0);
+
+        let container_fs = &response.container_filesystems[0];
+        assert!(container_fs.timestamp > 0);
+        assert_eq!(used_bytes(container_fs), 0);
+        assert_eq!(inodes_used(container_fs), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn image_fs_info_snapshots() -> Result<()> {
+        let sut = new_cri_service()?;
+        let layer = sut.snapshotter().prepare("layer-active", None).await?;
+        fs::write(layer.join("file"), vec![1; 8192])?;
+        sut.snapshotter().commit("layer", "layer-active")?;
+
+        let request = || Request::new(ImageFsInfoRequest {});
+        let before = sut.handle_image_fs_info(request()).await?.into_inner();
+
+        let rootfs = sut
+            .snapshotter()
+            .prepare("container", Some("layer"))
+            .await?;
+        fs::write(rootfs.join("new"), vec![1; 8192])?;
+        let after = sut.handle_image_fs_info(request()).await?.into_inner();
+
+        assert!(used_bytes(&before.image_filesystems[0]) >= 8192);
+        assert_eq!(
+            inodes_used(&after.image_filesystems[0]),
+            inodes_used(&before.image_filesystems[0])
+        );
+        assert!(used_bytes(&after.container_filesystems[0]) >= 8192);
+        assert_eq!(inodes_used(&after.container_filesystems[0]), 3);
+        Ok(())
+    }
 }
@@ -1,7 +1,8 @@
 use crate::cri::{
-    api::{ImageStatusRequest, ImageStatusResponse},
-    cri_service::CRIService,
+    api::{Image, ImageStatusRequest, ImageStatusResponse},
+    cri_service::{CRIService, OptionStatus, ResultStatus},
 };
+use serde_json::json;
 use std::collections::HashMap;
 use tonic::{Request, Response, Status};
 
@@ -11,12 +12,138 @@ impl CRIService {
     /// None.
     pub async fn handle_image_status(
         &self,
-        _request: Request<ImageStatusRequest>,
+        request: Request<ImageStatusRequest>,
     ) -> Result<Response<ImageStatusResponse>, Status> {
+        let request = request.into_inner();
+        let image = request.image.ok_or_invalid("no image spec provided")?;
+        let record = match self.find_image(&image.image)? {
+            Some(record) => record,
+            None => {
+                return Ok(Response::new(ImageStatusResponse {
+                    image: None,
+                    info: HashMap::new(),
+                }))
+            }
+        };
+
+        let mut info = HashMap::new();
+        if request.verbose {
+            let config: serde_json::Value = serde_json::from_slice(
+                &self
+                    .puller()
+                    .store()
+                    .read(record.id())
+                    .await
+                    .map_internal("failed to read image config")?,
+            )
+            .map_internal("failed to parse image config")?;
+            info.insert(
+                "info".into(),
+                json!({
+                    "imageSpec": config,
+                    "chainID": record.top_layer(),
+                    "manifests": record.manifests(),
+                })
+                .to_string(),
+            );
+        }
+
         let resp = ImageStatusResponse {
-            image: None,
-            info: HashMap::new(),
+            image: Some(Image::from(&record)),
+            info,
         };
         Ok(Response::new(resp))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::{
+        api::ImageSpec,
+        cri_service::tests::{add_image, new_cri_service},
+    };
+    use anyhow::{Context, Result};
+    use tonic::Code;
+
+    fn status_request(image: &str, verbose: bool) -> Request<ImageStatusRequest> {
+        Request::new(ImageStatusRequest {
+            image: Some(ImageSpec {
+                image: image.into(),
+                ..Default::default()
+            }),
+            verbose,
+        })
+    }
+
+    #[tokio::test]
+    async fn image_status_success() -> Result<()> {
+        let sut = new_cri_service()?;
+        let record = add_image(&sut, "file", &["app:v1"]).await?;
+
+        for identifier in &[
+            "app:v1".to_string(),
+            record.id().to_string(),
+            record.repo_digests()[0].to_string(),
+        ] {
+            let response = sut
+                .handle_image_status(status_request(identifier, false))
+                .await?;
+            let image = response.get_ref().image.as_ref().context("no image")?;
+            assert_eq!(image.id, record.id().to_string());
+            assert_eq!(image.repo_tags, vec!["docker.io/library/app:v1"]);
+            assert!(response.get_ref().info.is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn image_status_verbose() -> Result<()> {
+        let sut = new_cri_service()?;
+        let record = add_image(&sut, "file", &["app:v1"]).await?;
+
+        let response = sut
+            .handle_image_status(status_request("app:v1", true))
+            .await?;
+        let info: serde_json::Value = serde_json::from_str(
+            response
+                .get_ref()
+                .info
+                .get("info")
+                .context("no verbose info")?,
+        )?;
+        assert_eq!(info["chainID"].as_str(), record.top_layer().as_deref());
+        assert_eq!(
+            info["imageSpec"]["rootfs"]["diff_ids"]
+                .as_array()
+                .map(Vec::len),
+            Some(1)
+        );
+        assert_eq!(
+            info["manifests"][0].as_str(),
+            Some(record.manifests()[0].to_string().as_str())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn image_status_not_present() -> Result<()> {
+        let sut = new_cri_service()?;
+        let response = sut
+            .handle_image_status(status_request("nginx", false))
+            .await?;
+        assert!(response.get_ref().image.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn image_status_fail_invalid_reference() -> Result<()> {
+        let sut = new_cri_service()?;
+        let status = sut
+            .handle_image_status(status_request("nginx:-tag", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+}
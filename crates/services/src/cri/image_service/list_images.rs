@@ -1,6 +1,6 @@
 use crate::cri::{
-    api::{ListImagesRequest, ListImagesResponse},
-    cri_service::CRIService,
+    api::{Image, ListImagesRequest, ListImagesResponse},
+    cri_service::{CRIService, ResultStatus},
 };
 use tonic::{Request, Response, Status};
 
@@ -8,9 +8,123 @@ impl CRIService {
     /// handle_list_images lists existing images.
     pub async fn handle_list_images(
         &self,
-        _request: Request<ListImagesRequest>,
+        request: Request<ListImagesRequest>,
     ) -> Result<Response<ListImagesResponse>, Status> {
-        let resp = ListImagesResponse { images: Vec::new() };
+        let filter = request
+            .into_inner()
+            .filter
+            .and_then(|filter| filter.image)
+            .map(|spec| spec.image)
+            .filter(|image| !image.is_empty());
+
+        let records = match filter {
+            Some(image) => self.find_image(&image)?.into_iter().collect(),
+            None => self
+                .image_index()
+                .list()
+                .map_internal("failed to list images")?,
+        };
+
+        let resp = ListImagesResponse {
+            images: records.iter().map(Image::from).collect(),
+        };
         Ok(Response::new(resp))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::{
+        api::{ImageFilter, ImageSpec},
+        cri_service::tests::{add_image, new_cri_service},
+    };
+    use anyhow::Result;
+    use tonic::Code;
+
+    fn list_request(filter: Option<&str>) -> Request<ListImagesRequest> {
+        Request::new(ListImagesRequest {
+            filter: filter.map(|image| ImageFilter {
+                image: Some(ImageSpec {
+                    image: image.into(),
+                    ..Default::default()
+                }),
+            }),
+        })
+    }
+
+    #[tokio::test]
+    async fn list_images_empty() -> Result<()> {
+        let sut = new_cri_service()?;
+        let response = sut.handle_list_images(list_request(None)).await?;
+        assert!(response.get_ref().images.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_images_success() -> Result<()> {
+        let sut = new_cri_service()?;
+        let first = add_image(&sut, "a", &["app:v1", "app:latest"]).await?;
+        add_image(&sut, "b", &["other:v1"]).await?;
+
+        let response = sut.handle_list_images(list_request(None)).await?;
+        let images = &response.get_ref().images;
+        assert_eq!(images.len(), 2);
+
+        let image = images
+            .iter()
+            .find(|i| i.id == first.id().to_string())
+            .expect("image not listed");
+        assert_eq!(
+            image.repo_tags,
+            vec!["docker.io/library/app:latest", "docker.io/library/app:v1"]
+        );
+        assert_eq!(image.repo_digests.len(), 2);
+        assert!(image.size > 0);
+        assert!(image.uid.is_none());
+        assert!(!image.pinned);
+        assert_eq!(
+            image.spec.as_ref().map(|s| s.image.as_str()),
+            Some(image.id.as_str())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_images_filter() -> Result<()> {
+        let sut = new_cri_service()?;
+        let first = add_image(&sut, "a", &["app:v1"]).await?;
+        add_image(&sut, "b", &["other:v1"]).await?;
+
+        for filter in &[
+            "app:v1".to_string(),
+            "docker.io/library/app:v1".to_string(),
+            first.id().to_string(),
+            first.id().encoded().to_string(),
+        ] {
+            let response = sut.handle_list_images(list_request(Some(filter))).await?;
+            let images = &response.get_ref().images;
+            assert_eq!(images.len(), 1, "{}", filter);
+            assert_eq!(images[0].id, first.id().to_string());
+        }
+
+        let response = sut.handle_list_images(list_request(Some("app:v2"))).await?;
+        assert!(response.get_ref().images.is_empty());
+
+        // An empty filter lists everything
+        let response = sut.handle_list_images(list_request(Some(""))).await?;
+        assert_eq!(response.get_ref().images.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_images_fail_invalid_filter() -> Result<()> {
+        let sut = new_cri_service()?;
+        let status = sut
+            .handle_list_images(list_request(Some("a//b")))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+}
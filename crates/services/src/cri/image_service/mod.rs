@@ -1,7 +1,16 @@
 use crate::cri::{
     api::{self, image_service_server::ImageService},
-    cri_service::CRIService,
+    cri_service::{CRIService, ResultStatus, IMAGE_ANNOTATION},
 };
+use anyhow::{Context, Result};
+use image::{
+    archive,
+    gc::GcImage,
+    index::ImageRecord,
+    reference::{ImageIdentifier, Reference},
+};
+use log::{info, warn};
+use std::{collections::HashSet, fs, path::Path};
 use tonic::{Request, Response, Status};
 
 mod image_fs_info;
@@ -62,3 +71,201 @@ impl ImageService for CRIService {
         response
     }
 }
+
+impl CRIService {
+    /// Find the image by its ID or reference. Short names are resolved via the configured search
+    /// registries and aliases.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn find_image(&self, image: &str) -> Result<Option<ImageRecord>, Status> {
+        let identifier = image
+            .parse::<ImageIdentifier>()
+            .map_invalid("invalid image identifier")?;
+        let candidates = match identifier {
+            ImageIdentifier::Reference(_) if Reference::is_short_name(image) => self
+                .puller()
+                .client()
+                .registries()
+                .candidates(image)
+                .map_invalid("invalid image reference")?
+                .into_iter()
+                .map(ImageIdentifier::Reference)
+                .collect(),
+            identifier => vec![identifier],
+        };
+
+        for candidate in &candidates {
+            if let Some(record) = self
+                .image_index()
+                .get(candidate)
+                .map_internal("failed to lookup image")?
+            {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Pin the record if any of its names is configured as pinned image.
+    fn pin_configured(&self, record: ImageRecord) -> ImageRecord {
+        if record
+            .repo_tags()
+            .iter()
+            .chain(record.repo_digests())
+            .any(|name| self.pinned_images().contains(name))
+        {
+            return record.pin();
+        }
+        record
+    }
+
+    /// Import the images of all docker archives and OCI image layouts in the directory, unpack
+    /// them and add them to the index. Archives which cannot be imported are skipped.
+    pub async fn import_images<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<ImageRecord>> {
+        let dir = dir.as_ref();
+        let mut paths = fs::read_dir(dir)
+            .with_context(|| format!("read image import dir {}", dir.display()))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        paths.sort();
+
+        let mut records = vec![];
+        for path in paths {
+            match self.import_archive(&path).await {
+                Ok(imported) => records.extend(imported),
+                Err(e) => warn!("Unable to import images from {}: {:#}", path.display(), e),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Import the images of a single docker archive or OCI image layout.
+    async fn import_archive(&self, path: &Path) -> Result<Vec<ImageRecord>> {
+        let _pause = self.garbage_collector().pause().await;
+        let mut records = vec![];
+        for imported in archive::import(self.puller().store(), path).await? {
+            let top_layer = self
+                .snapshotter()
+                .unpack(self.puller().store(), imported.manifest())
+                .await
+                .context("unpack image")?;
+            let record = ImageRecord::from_imported(self.puller().store(), &imported, top_layer)
+                .await
+                .context("create image record")?;
+            let record = self
+                .image_index()
+                .add(self.pin_configured(record))
+                .context("add image to index")?;
+            info!("Imported image {} from {}", record.id(), path.display());
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Remove the images selected by the garbage collection policy, then collect the content
+    /// which is not referenced by the remaining images. Images used by containers are never
+    /// selected.
+    async fn evict_images(&self) -> Result<()> {
+        {
+            // Containers in creation pause the collection until their bundle marks the image used
+            let _exclusive = self.garbage_collector().exclusive().await;
+            let images = self.gc_images()?;
+            for image in self.garbage_collector().select(&images)? {
+                info!("Removing image {} to free disk space", image.id());
+                self.image_index()
+                    .remove(&ImageIdentifier::Id(image.id().clone()))?;
+            }
+        }
+        self.collect_garbage().await
+    }
+
+    /// Remove all content which is not referenced by the images of the index.
+    async fn collect_garbage(&self) -> Result<()> {
+        self.garbage_collector().collect(&self.gc_images()?).await?;
+        Ok(())
+    }
+
+    /// The garbage collection roots of all images of the index.
+    fn gc_images(&self) -> Result<Vec<GcImage>> {
+        let in_use = self.images_in_use()?;
+        self.image_index()
+            .list()?
+            .iter()
+            .map(|record| record.gc_image(in_use.contains(&record.id().to_string())))
+            .collect()
+    }
+
+    /// The IDs of the images used by the containers, as annotated in their bundles.
+    fn images_in_use(&self) -> Result<HashSet<String>> {
+        let mut in_use = HashSet::new();
+        for bundle in self.bundles().list()? {
+            let spec = bundle
+                .spec()
+                .with_context(|| format!("read spec of bundle {}", bundle.id()))?;
+            if let Some(id) = spec
+                .annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(IMAGE_ANNOTATION))
+            {
+                in_use.insert(id.clone());
+            }
+        }
+        Ok(in_use)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::cri_service::tests::new_cri_service;
+    use image::{archive::ArchiveFormat, index::ImageRecordBuilder, store::BlobStore, testing};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn import_images() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = BlobStore::open(dir.path().join("source"))?;
+        let import_dir = dir.path().join("import");
+        fs::create_dir(&import_dir)?;
+        let mut expected = vec![];
+        for (format, file) in [
+            (ArchiveFormat::DockerArchive, "docker"),
+            (ArchiveFormat::OciDir, "oci"),
+        ] {
+            let (digest, manifest) =
+                testing::store_image(&source, &[testing::layer(&[(file, b"content")])?]).await?;
+            let record = ImageRecordBuilder::default()
+                .id(manifest
+                    .config()
+                    .digest()
+                    .parse::<image::digest::Digest>()?)
+                .repo_tags(vec![format!("{}:v1", file).parse::<Reference>()?])
+                .manifests(vec![digest])
+                .build()?;
+            archive::export(
+                &source,
+                std::slice::from_ref(&record),
+                format,
+                import_dir.join(file),
+            )
+            .await?;
+            expected.push(record);
+        }
+        fs::write(import_dir.join("invalid"), "no archive")?;
+
+        let sut = new_cri_service()?;
+        let records = sut.import_images(&import_dir).await?;
+        assert_eq!(records.len(), 2);
+        for (record, expected) in records.iter().zip(&expected) {
+            assert_eq!(record.id(), expected.id());
+            assert_eq!(record.repo_tags(), expected.repo_tags());
+            let found = sut
+                .find_image(&expected.repo_tags()[0].to_string())?
+                .context("image not found")?;
+            assert_eq!(found.id(), expected.id());
+            let top_layer = found.top_layer().as_deref().context("no top layer")?;
+            assert!(sut.snapshotter().stat(top_layer)?.is_some());
+        }
+        assert!(sut.import_images(dir.path().join("missing")).await.is_err());
+        Ok(())
+    }
+}
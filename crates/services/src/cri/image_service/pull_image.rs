@@ -1,18 +1,362 @@
 use crate::cri::{
-    api::{PullImageRequest, PullImageResponse},
-    cri_service::CRIService,
+    api::{AuthConfig, PullImageRequest, PullImageResponse},
+    cri_service::{CRIService, OptionStatus, ResultStatus},
 };
+use image::{index::ImageRecord, policy::PolicyViolation, registry::auth::Credentials};
+use log::warn;
+use std::convert::TryFrom;
 use tonic::{Request, Response, Status};
 
 impl CRIService {
     /// handle_pull_image pulls an image with authentication config.
     pub async fn handle_pull_image(
         &self,
-        _request: Request<PullImageRequest>,
+        request: Request<PullImageRequest>,
     ) -> Result<Response<PullImageResponse>, Status> {
-        let resp = PullImageResponse {
-            image_ref: "some_image".into(),
-        };
+        let request = request.into_inner();
+        let image = request.image.ok_or_invalid("no image spec provided")?;
+        let candidates = self
+            .puller()
+            .client()
+            .registries()
+            .candidates(&image.image)
+            .map_invalid("invalid image reference")?;
+
+        // Without any provided auth the credentials get looked up from the local auth files
+        let credentials = request
+            .auth
+            .as_ref()
+            .filter(|auth| **auth != AuthConfig::default())
+            .map(Credentials::try_from)
+            .transpose()
+            .map_invalid("invalid auth config")?;
+
+        // The pulled image pauses the garbage collection until it got added to the index
+        let pulled = self
+            .puller()
+            .pull_any(&candidates, credentials.as_ref())
+            .await
+            .map_err(|e| match e.downcast_ref::<PolicyViolation>() {
+                Some(violation) => Status::permission_denied(violation.to_string()),
+                None => Status::internal(format!("failed to pull image: {}", e)),
+            })?;
+        let top_layer = self
+            .snapshotter()
+            .unpack(self.puller().store(), pulled.manifest())
+            .await
+            .map_internal("failed to unpack image")?;
+
+        let record = ImageRecord::from_pulled(self.puller().store(), &pulled, top_layer)
+            .await
+            .map_internal("failed to create image record")?;
+        self.image_index()
+            .add(self.pin_configured(record))
+            .map_internal("failed to add image to index")?;
+        let image_ref = pulled.id().to_string();
+        drop(pulled);
+
+        if self.garbage_collector().policy().is_some() {
+            if let Err(e) = self.evict_images().await {
+                warn!("Unable to collect garbage after pull: {:#}", e);
+            }
+        }
+
+        let resp = PullImageResponse { image_ref };
         Ok(Response::new(resp))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::{
+        api::ImageSpec,
+        cri_service::{
+            tests::{new_cri_service, new_cri_service_with, TestOptions},
+            IMAGE_ANNOTATION,
+        },
+    };
+    use anyhow::{Context, Result};
+    use image::{
+        gc::GcPolicyBuilder,
+        policy::Policy,
+        registry::{config::RegistriesConfigBuilder, ClientBuilder},
+        testing::{layer, TestAuth, TestRegistry},
+    };
+    use oci_spec::runtime::SpecBuilder;
+    use std::{collections::HashMap, path::Path};
+    use tonic::Code;
+
+    fn pull_request(image: &str) -> Request<PullImageRequest> {
+        Request::new(PullImageRequest {
+            image: Some(ImageSpec {
+                image: image.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn pull_image_success() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("latest"), &[&layer(&[("file", b"layer")])?])?;
+        let sut = new_cri_service()?;
+
+        let response = sut
+            .handle_pull_image(pull_request(&format!("{}/app", registry.host())))
+            .await?;
+        assert_eq!(
+            response.get_ref().image_ref,
+            image.config_digest().to_string()
+        );
+        assert_eq!(sut.snapshotter().list()?.len(), 1);
+
+        let images = sut.image_index().list()?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].id(), image.config_digest());
+        assert_eq!(images[0].manifests(), &[image.manifest_digest().clone()]);
+        assert!(images[0].top_layer().is_some());
+        assert!(!images[0].pinned());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_success_short_name() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("v1"), &[&layer(&[("file", b"layer")])?])?;
+        let sut = new_cri_service_with(TestOptions {
+            client: ClientBuilder::default()
+                .registries(
+                    RegistriesConfigBuilder::default()
+                        .unqualified_search_registries(vec![registry.host().clone()])
+                        .build()?,
+                )
+                .build()?,
+            ..Default::default()
+        })?;
+
+        let response = sut.handle_pull_image(pull_request("app:v1")).await?;
+        assert_eq!(
+            response.get_ref().image_ref,
+            image.config_digest().to_string()
+        );
+        let record = sut.find_image("app:v1")?.context("image not in index")?;
+        assert_eq!(
+            record.repo_tags()[0].to_string(),
+            format!("{}/app:v1", registry.host())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_success_pinned_gc_policy() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.add_image("app", Some("v1"), &[&layer(&[("v1", b"layer")])?])?;
+        let pinned = registry.add_image("pause", Some("v1"), &[&layer(&[("pause", b"layer")])?])?;
+
+        // Every pull exceeds the high threshold and removes all unpinned images
+        let sut = new_cri_service_with(TestOptions {
+            gc_policy: Some(
+                GcPolicyBuilder::default()
+                    .high_threshold_percent(0)
+                    .low_threshold_percent(0)
+                    .build()?,
+            ),
+            pinned_images: vec![format!("{}/pause:v1", registry.host()).parse()?],
+            ..Default::default()
+        })?;
+
+        sut.handle_pull_image(pull_request(&format!("{}/app:v1", registry.host())))
+            .await?;
+        assert!(sut.image_index().list()?.is_empty());
+
+        sut.handle_pull_image(pull_request(&format!("{}/pause:v1", registry.host())))
+            .await?;
+        let images = sut.image_index().list()?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].id(), pinned.config_digest());
+        assert!(images[0].pinned());
+        assert_eq!(sut.snapshotter().list()?.len(), 1);
+        assert_eq!(sut.puller().store().list().await?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_success_in_use_gc_policy() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let used = registry.add_image("app", Some("v1"), &[&layer(&[("v1", b"layer")])?])?;
+        registry.add_image("other", Some("v1"), &[&layer(&[("other", b"layer")])?])?;
+        let sut = new_cri_service_with(TestOptions {
+            gc_policy: Some(
+                GcPolicyBuilder::default()
+                    .high_threshold_percent(0)
+                    .low_threshold_percent(0)
+                    .build()?,
+            ),
+            ..Default::default()
+        })?;
+
+        let rootfs = sut.snapshotter().prepare("container", None).await?;
+        let spec = SpecBuilder::default()
+            .annotations(HashMap::from([(
+                IMAGE_ANNOTATION.to_string(),
+                used.config_digest().to_string(),
+            )]))
+            .build()?;
+        sut.bundles().create("container", &spec, &rootfs).await?;
+
+        sut.handle_pull_image(pull_request(&format!("{}/app:v1", registry.host())))
+            .await?;
+        sut.handle_pull_image(pull_request(&format!("{}/other:v1", registry.host())))
+            .await?;
+        let images = sut.image_index().list()?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].id(), used.config_digest());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_success_auth() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let image = registry.add_image("app", Some("latest"), &[&layer(&[("file", b"layer")])?])?;
+        registry.set_auth(TestAuth::Basic {
+            username: "user".into(),
+            password: "pass".into(),
+        });
+        let sut = new_cri_service()?;
+
+        let mut request = pull_request(&format!("{}/app", registry.host()));
+        request.get_mut().auth = Some(AuthConfig {
+            username: "user".into(),
+            password: "pass".into(),
+            ..Default::default()
+        });
+        let response = sut.handle_pull_image(request).await?;
+        assert_eq!(
+            response.get_ref().image_ref,
+            image.config_digest().to_string()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_fail_wrong_auth() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.add_image("app", Some("latest"), &[&layer(&[("file", b"layer")])?])?;
+        registry.set_auth(TestAuth::Basic {
+            username: "user".into(),
+            password: "pass".into(),
+        });
+        let sut = new_cri_service()?;
+
+        let mut request = pull_request(&format!("{}/app", registry.host()));
+        request.get_mut().auth = Some(AuthConfig {
+            username: "user".into(),
+            password: "wrong".into(),
+            ..Default::default()
+        });
+        let status = sut.handle_pull_image(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_fail_invalid_auth() -> Result<()> {
+        let sut = new_cri_service()?;
+        let mut request = pull_request("app");
+        request.get_mut().auth = Some(AuthConfig {
+            auth: "!!!".into(),
+            ..Default::default()
+        });
+        let status = sut.handle_pull_image(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_fail_invalid_layer() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.add_image("app", Some("latest"), &[b"no tar archive"])?;
+        let sut = new_cri_service()?;
+
+        let status = sut
+            .handle_pull_image(pull_request(&format!("{}/app", registry.host())))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        assert!(sut.snapshotter().list()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_fail_policy_violation() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        registry.add_image("app", Some("latest"), &[&layer(&[("file", b"layer")])?])?;
+        registry.add_image("other", Some("latest"), &[&layer(&[("file", b"other")])?])?;
+        let policy = Policy::parse(
+            serde_json::json!({
+                "default": [{"type": "reject"}],
+                "transports": {
+                    "docker": {
+                        format!("{}/app", registry.host()): [{"type": "insecureAcceptAnything"}],
+                    },
+                },
+            })
+            .to_string()
+            .as_bytes(),
+            Path::new("/"),
+        )?;
+        let sut = new_cri_service_with(TestOptions {
+            signature_policy: policy,
+            ..Default::default()
+        })?;
+
+        sut.handle_pull_image(pull_request(&format!("{}/app", registry.host())))
+            .await?;
+
+        let status = sut
+            .handle_pull_image(pull_request(&format!("{}/other", registry.host())))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert!(status.message().contains("rejected by signature policy"));
+        assert_eq!(sut.image_index().list()?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_fail_not_found() -> Result<()> {
+        let registry = TestRegistry::start().await?;
+        let sut = new_cri_service()?;
+
+        let status = sut
+            .handle_pull_image(pull_request(&format!("{}/app", registry.host())))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_fail_invalid_reference() -> Result<()> {
+        let sut = new_cri_service()?;
+        let status = sut
+            .handle_pull_image(pull_request("Invalid::Reference"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_image_fail_no_image() -> Result<()> {
+        let sut = new_cri_service()?;
+        let status = sut
+            .handle_pull_image(Request::new(PullImageRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 use crate::cri::{
     api::{RemoveImageRequest, RemoveImageResponse},
-    cri_service::CRIService,
+    cri_service::{CRIService, OptionStatus, ResultStatus},
 };
+use image::reference::ImageIdentifier;
+use log::info;
 use tonic::{Request, Response, Status};
 
 impl CRIService {
@@ -9,9 +11,125 @@ impl CRIService {
     /// error if the image has already been removed.
     pub async fn handle_remove_image(
         &self,
-        _request: Request<RemoveImageRequest>,
+        request: Request<RemoveImageRequest>,
     ) -> Result<Response<RemoveImageResponse>, Status> {
+        let image = request
+            .into_inner()
+            .image
+            .ok_or_invalid("no image spec provided")?;
+
+        if let Some(record) = self.find_image(&image.image)? {
+            // Removing by any name removes the image with all its names
+            self.image_index()
+                .remove(&ImageIdentifier::Id(record.id().clone()))
+                .map_internal("failed to remove image from index")?;
+            info!("Removed image {}", record.id());
+            self.collect_garbage()
+                .await
+                .map_internal("failed to collect garbage")?;
+        }
+
         let resp = RemoveImageResponse {};
         Ok(Response::new(resp))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::{
+        api::ImageSpec,
+        cri_service::tests::{add_image, new_cri_service, new_cri_service_with, TestOptions},
+    };
+    use anyhow::Result;
+    use image::gc::GcPolicyBuilder;
+    use tonic::Code;
+
+    fn remove_request(image: &str) -> Request<RemoveImageRequest> {
+        Request::new(RemoveImageRequest {
+            image: Some(ImageSpec {
+                image: image.into(),
+                ..Default::default()
+            }),
+        })
+    }
+
+    #[tokio::test]
+    async fn remove_image_success() -> Result<()> {
+        let sut = new_cri_service()?;
+        let kept = add_image(&sut, "kept", &["kept:v1"]).await?;
+        let blobs = sut.puller().store().list().await?.len();
+
+        let by_tag = add_image(&sut, "tag", &["app:v1", "app:v2"]).await?;
+        sut.handle_remove_image(remove_request("app:v1")).await?;
+        assert!(sut.find_image(&by_tag.id().to_string())?.is_none());
+        assert!(sut.find_image("app:v2")?.is_none());
+
+        let by_digest = add_image(&sut, "digest", &["app:v1"]).await?;
+        sut.handle_remove_image(remove_request(&by_digest.repo_digests()[0].to_string()))
+            .await?;
+        assert!(sut.find_image(&by_digest.id().to_string())?.is_none());
+        assert!(sut.find_image("app:v1")?.is_none());
+
+        let by_id = add_image(&sut, "id", &["app:v1"]).await?;
+        sut.handle_remove_image(remove_request(by_id.id().encoded()))
+            .await?;
+        assert!(sut.find_image(&by_id.id().to_string())?.is_none());
+        assert!(sut.find_image("app:v1")?.is_none());
+
+        // Only the content of the kept image is left
+        let images = sut.image_index().list()?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].id(), kept.id());
+        assert_eq!(sut.puller().store().list().await?.len(), blobs);
+        assert_eq!(sut.snapshotter().list()?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_image_success_no_eviction() -> Result<()> {
+        // Every eviction would remove all unpinned images
+        let sut = new_cri_service_with(TestOptions {
+            gc_policy: Some(
+                GcPolicyBuilder::default()
+                    .high_threshold_percent(0)
+                    .low_threshold_percent(0)
+                    .build()?,
+            ),
+            ..Default::default()
+        })?;
+
+        let kept = add_image(&sut, "kept", &["kept:v1"]).await?;
+        add_image(&sut, "removed", &["removed:v1"]).await?;
+        sut.handle_remove_image(remove_request("removed:v1"))
+            .await?;
+
+        let images = sut.image_index().list()?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].id(), kept.id());
+        assert_eq!(sut.snapshotter().list()?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_image_not_present() -> Result<()> {
+        let sut = new_cri_service()?;
+        sut.handle_remove_image(remove_request(
+            "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b",
+        ))
+        .await?;
+        sut.handle_remove_image(remove_request("app:v1")).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_image_fail_invalid_reference() -> Result<()> {
+        let sut = new_cri_service()?;
+        let status = sut
+            .handle_remove_image(remove_request("a//b"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+}
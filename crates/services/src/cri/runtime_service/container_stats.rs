@@ -1,7 +1,13 @@
 use crate::cri::{
-    api::{ContainerStatsRequest, ContainerStatsResponse},
-    cri_service::CRIService,
+    api::{
+        ContainerAttributes, ContainerStats, ContainerStatsRequest, ContainerStatsResponse,
+        CpuUsage, MemoryUsage, UInt64Value,
+    },
+    cri_service::{CRIService, ResultStatus},
 };
+use container::container::{Container, ContainerState};
+use log::{debug, warn};
+use std::time::SystemTime;
 use tonic::{Request, Response, Status};
 
 impl CRIService {
@@ -9,9 +15,169 @@ impl CRIService {
     /// call returns an error.
     pub async fn handle_container_stats(
         &self,
-        _request: Request<ContainerStatsRequest>,
+        request: Request<ContainerStatsRequest>,
     ) -> Result<Response<ContainerStatsResponse>, Status> {
-        let resp = ContainerStatsResponse { stats: None };
+        let id = request.into_inner().container_id;
+        let stats = self
+            .container_stats(&id)
+            .await?
+            .ok_or_else(|| Status::not_found(format!("container {} not found", id)))?;
+        let resp = ContainerStatsResponse { stats: Some(stats) };
         Ok(Response::new(resp))
     }
+
+    /// Collect the stats of the container, which is `None` if it does not exist.
+    pub(crate) async fn container_stats(&self, id: &str) -> Result<Option<ContainerStats>, Status> {
+        let writable_layer = match self.writable_layer_usage(id).await? {
+            Some(usage) => usage,
+            None => return Ok(None),
+        };
+        let (cpu, memory) = match self.resource_usage(id).await? {
+            Some((cpu, memory)) => (Some(cpu), Some(memory)),
+            None => (None, None),
+        };
+        Ok(Some(ContainerStats {
+            attributes: Some(ContainerAttributes {
+                id: id.into(),
+                ..Default::default()
+            }),
+            cpu,
+            memory,
+            writable_layer: Some(writable_layer),
+        }))
+    }
+
+    /// Read the CPU and memory usage from the cgroup of the container, which is `None` if the
+    /// container has no bundle or is not running. Failing to read the usage of a running
+    /// container is an error.
+    async fn resource_usage(&self, id: &str) -> Result<Option<(CpuUsage, MemoryUsage)>, Status> {
+        let bundle = match self
+            .bundles()
+            .get(id)
+            .map_internal("failed to get container bundle")?
+        {
+            Some(bundle) => bundle,
+            None => return Ok(None),
+        };
+        let container = self
+            .container_builder(bundle)
+            .build()
+            .map_internal("failed to build container")?;
+        let stats = match container.stats().await {
+            Ok(stats) => stats,
+            Err(e) => {
+                match container.state().await {
+                    Ok(ContainerState::Started) => {
+                        return Err(Status::internal(format!(
+                            "failed to retrieve stats of running container {}: {:#}",
+                            id, e
+                        )))
+                    }
+                    Ok(_) => debug!("No stats of container {}: {:#}", id, e),
+                    Err(_) => warn!("Unable to retrieve stats of container {}: {:#}", id, e),
+                }
+                return Ok(None);
+            }
+        };
+
+        let timestamp = stats
+            .timestamp()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_internal("invalid stats timestamp")?
+            .as_nanos() as i64;
+        Ok(Some((
+            CpuUsage {
+                timestamp,
+                usage_core_nano_seconds: Some(UInt64Value {
+                    value: stats.cgroup().cpu().usage_usec() * 1000,
+                }),
+            },
+            MemoryUsage {
+                timestamp,
+                working_set_bytes: Some(UInt64Value {
+                    value: stats.cgroup().memory().working_set_bytes(),
+                }),
+            },
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::cri_service::tests::{new_cri_service, new_cri_service_with, TestOptions};
+    use anyhow::{Context, Result};
+    use oci_spec::runtime::Spec;
+    use std::{fs, os::unix::fs::PermissionsExt};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn container_stats_writable_layer() -> Result<()> {
+        let sut = new_cri_service()?;
+        let rootfs = sut.snapshotter().prepare("container", None).await?;
+        fs::write(rootfs.join("file"), vec![1; 8192])?;
+
+        let request = Request::new(ContainerStatsRequest {
+            container_id: "container".into(),
+        });
+        let stats = sut
+            .handle_container_stats(request)
+            .await?
+            .into_inner()
+            .stats
+            .context("no stats")?;
+        assert_eq!(stats.attributes.context("no attributes")?.id, "container");
+        assert!(stats.cpu.is_none());
+        assert!(stats.memory.is_none());
+        let writable_layer = stats.writable_layer.context("no writable layer")?;
+        assert!(writable_layer.used_bytes.context("no used bytes")?.value >= 8192);
+        assert_eq!(writable_layer.inodes_used.context("no inodes")?.value, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_stats_fail_running() -> Result<()> {
+        // The runtime reports a running container whose process does not exist
+        let dir = TempDir::new()?;
+        let runtime = dir.path().join("runtime");
+        fs::write(
+            &runtime,
+            r#"#!/bin/sh
+echo '{"id":"container","pid":2147483647,"status":"running","bundle":"/b"}'
+"#,
+        )?;
+        fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755))?;
+        let sut = new_cri_service_with(TestOptions {
+            runtime: Some(runtime),
+            ..Default::default()
+        })?;
+        let rootfs = sut.snapshotter().prepare("container", None).await?;
+        sut.bundles()
+            .create("container", &Spec::default(), &rootfs)
+            .await?;
+
+        let request = Request::new(ContainerStatsRequest {
+            container_id: "container".into(),
+        });
+        let status = sut.handle_container_stats(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn container_stats_not_found() -> Result<()> {
+        let sut = new_cri_service()?;
+        let layer = sut.snapshotter().prepare("layer-active", None).await?;
+        fs::write(layer.join("file"), "")?;
+        sut.snapshotter().commit("layer", "layer-active")?;
+
+        for id in ["missing", "layer"] {
+            let request = Request::new(ContainerStatsRequest {
+                container_id: id.into(),
+            });
+            let status = sut.handle_container_stats(request).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+        }
+        Ok(())
+    }
 }
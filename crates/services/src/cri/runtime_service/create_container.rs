@@ -1,36 +1,201 @@
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    fs,
+    path::{Path, PathBuf},
+};
 
 use crate::{
     cri::{
-        api::{CreateContainerRequest, CreateContainerResponse},
-        cri_service::{CRIService, OptionStatus, ResultStatus},
+        api::{
+            ContainerConfig, CreateContainerRequest, CreateContainerResponse, KeyValue,
+            LinuxContainerSecurityContext,
+        },
+        cri_service::{CRIService, OptionStatus, ResultStatus, IMAGE_ANNOTATION},
     },
     error::ServiceError,
+    server::parse_size,
 };
-use container::container::local::OCIContainerBuilder;
 use container::container::Container;
-use oci_spec::runtime::{LinuxBuilder, ProcessBuilder, RootBuilder, SpecBuilder, UserBuilder};
+use image::{index::ImageRecord, rootfs};
+use log::warn;
+use oci_spec::{
+    image::Config as ImageConfig,
+    runtime::{LinuxBuilder, ProcessBuilder, RootBuilder, SpecBuilder, User, UserBuilder},
+};
 use tonic::{Request, Response, Status};
 
 use crate::cri::api::Mount as CRIMount;
 use oci_spec::runtime::Mount as OCIMount;
 
+/// Annotation of the runtime spec containing the stop signal of the image configuration, as
+/// defined by the OCI image specification.
+const STOP_SIGNAL_ANNOTATION: &str = "org.opencontainers.image.stopSignal";
+
+/// Annotation of the container config overriding the default disk quota of the writable layer.
+pub const WRITABLE_LAYER_SIZE_ANNOTATION: &str = "io.containers.writable-layer-size";
+
+/// The `PATH` of container processes if neither the image nor the container config set one.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
 impl CRIService {
     /// handle_create_container creates a new container in specified PodSandbox.
     pub async fn handle_create_container(
         &self,
         request: Request<CreateContainerRequest>,
     ) -> Result<Response<CreateContainerResponse>, Status> {
-        let config = request
-            .into_inner()
+        let request = request.into_inner();
+        let mut config = request
             .config
-            .take()
             .ok_or_invalid("no container config provided")?;
 
         let metadata = config
             .metadata
+            .as_ref()
             .ok_or_invalid("no container metadata provided")?;
 
+        let image = config
+            .image
+            .as_ref()
+            .ok_or_invalid("no container image provided")?;
+
+        // The image content is only protected by the bundle annotation once the bundle exists
+        let pause = self.garbage_collector().pause().await;
+        let record = self
+            .find_image(&image.image)?
+            .ok_or_else(|| Status::not_found(format!("image {} not found", image.image)))?;
+        self.image_index()
+            .touch(record.id())
+            .map_internal("failed to update image usage")?;
+
+        let image_config = self.image_config(&record).await?;
+
+        let size_limit = self.writable_layer_size_limit(&config)?;
+        let id = format!("{}.{}", metadata.name, metadata.attempt);
+        config
+            .annotations
+            .insert(IMAGE_ANNOTATION.into(), record.id().to_string());
+        let rootfs = self.prepare_rootfs(&id, &record, size_limit).await?;
+
+        let cgroups_path = match request.sandbox_config.and_then(|c| c.linux) {
+            Some(linux) => Some(
+                self.cgroup_manager()
+                    .container_cgroups_path(
+                        Path::new(&linux.cgroup_parent),
+                        &request.pod_sandbox_id,
+                        &id,
+                    )
+                    .map_invalid("invalid pod cgroup parent")?,
+            ),
+            None => None,
+        };
+
+        let result = self
+            .create(&id, &rootfs, config, &image_config, cgroups_path)
+            .await;
+        drop(pause);
+        match result {
+            Ok(container_id) => {
+                let resp = CreateContainerResponse { container_id };
+                Ok(Response::new(resp))
+            }
+            Err(status) => {
+                if let Err(e) = self.bundles().remove(&id).await {
+                    warn!("Unable to remove bundle of container {}: {:#}", id, e)
+                }
+                if let Err(e) = self.snapshotter().remove(&id).await {
+                    warn!(
+                        "Unable to remove root file system of container {}: {:#}",
+                        id, e
+                    )
+                }
+                Err(status)
+            }
+        }
+    }
+
+    /// Read the execution parameters from the configuration of the image.
+    async fn image_config(&self, record: &ImageRecord) -> Result<ImageConfig, Status> {
+        let data = self
+            .puller()
+            .store()
+            .read(record.id())
+            .await
+            .map_internal("failed to read image config")?;
+        let mut configuration: serde_json::Value =
+            serde_json::from_slice(&data).map_internal("failed to parse image config")?;
+        match configuration["config"].take() {
+            serde_json::Value::Null => Ok(ImageConfig::default()),
+            config => serde_json::from_value(config).map_internal("failed to parse image config"),
+        }
+    }
+
+    /// The disk quota of the writable layer, which is either set by annotation or the default
+    /// of the service. The annotation cannot exceed the default.
+    #[allow(clippy::result_large_err)]
+    fn writable_layer_size_limit(&self, config: &ContainerConfig) -> Result<Option<u64>, Status> {
+        match config.annotations.get(WRITABLE_LAYER_SIZE_ANNOTATION) {
+            Some(size) => {
+                let size = parse_size(size).map_invalid(format!(
+                    "invalid {} annotation",
+                    WRITABLE_LAYER_SIZE_ANNOTATION
+                ))?;
+                limit_writable_layer_size(size, self.writable_layer_size()).map(Some)
+            }
+            None => Ok(self.writable_layer_size()),
+        }
+    }
+
+    /// Prepare and mount the writable root file system of the container on top of the image.
+    /// The size limit is only enforced if the snapshotter supports quotas.
+    async fn prepare_rootfs(
+        &self,
+        id: &str,
+        record: &ImageRecord,
+        size_limit: Option<u64>,
+    ) -> Result<PathBuf, Status> {
+        self.snapshotter()
+            .prepare(id, record.top_layer().as_deref())
+            .await
+            .map_internal("failed to prepare container root file system")?;
+        let limited = match (size_limit, self.snapshotter().quota()) {
+            (Some(bytes), Some(_)) => self.snapshotter().limit(id, bytes),
+            (Some(_), None) => {
+                warn!(
+                    "Unable to limit the writable layer of container {}: no quota support",
+                    id
+                );
+                Ok(())
+            }
+            (None, _) => Ok(()),
+        };
+        match limited.and_then(|()| self.snapshotter().mount(id)) {
+            Ok(rootfs) => Ok(rootfs),
+            Err(e) => {
+                if let Err(e) = self.snapshotter().remove(id).await {
+                    warn!(
+                        "Unable to remove root file system of container {}: {:#}",
+                        id, e
+                    )
+                }
+                Err(Status::internal(format!(
+                    "failed to set up container root file system: {}",
+                    e
+                )))
+            }
+        }
+    }
+
+    /// Build the runtime spec by merging the container config with the image config, then create
+    /// the container on the prepared root file system. The container cgroup is placed at the
+    /// cgroups path if provided.
+    async fn create(
+        &self,
+        id: &str,
+        rootfs: &Path,
+        config: ContainerConfig,
+        image_config: &ImageConfig,
+        cgroups_path: Option<String>,
+    ) -> Result<String, Status> {
         let linux_config = config
             .linux
             .ok_or_invalid("no container linux config provided")?;
@@ -39,72 +204,73 @@ impl CRIService {
             .security_context
             .ok_or_invalid("no container security context provided")?;
 
+        let args = process_args(config.command, config.args, image_config);
+        if args.is_empty() {
+            return Err(Status::invalid_argument(
+                "no command specified in container config or image",
+            ));
+        }
+
+        let (user, home) = process_user(rootfs, &security_context, image_config)?;
+
+        let resources = match linux_config.resources.as_ref() {
+            Some(resources) => Some(self.linux_resources(resources)?),
+            None => None,
+        };
+        let oom_score_adj = linux_config
+            .resources
+            .as_ref()
+            .map(|r| r.oom_score_adj)
+            .filter(|value| *value != 0)
+            .map(oom_score_adj)
+            .transpose()?;
+
+        create_volumes(rootfs, image_config, &config.mounts)
+            .map_internal("failed to create image volumes")?;
+
+        let mut annotations = config.annotations;
+        if let Some(stop_signal) = image_config.stop_signal() {
+            annotations
+                .entry(STOP_SIGNAL_ANNOTATION.into())
+                .or_insert_with(|| stop_signal.clone());
+        }
+
+        let mut process = ProcessBuilder::default()
+            .args(args)
+            .env(process_env(&config.envs, image_config, &home))
+            .cwd(process_cwd(config.working_dir, image_config))
+            .apparmor_profile(security_context.apparmor_profile)
+            .no_new_privileges(security_context.no_new_privs)
+            .user(user)
+            .terminal(config.tty);
+        if let Some(oom_score_adj) = oom_score_adj {
+            process = process.oom_score_adj(oom_score_adj);
+        }
+
+        let mut linux = LinuxBuilder::default()
+            .masked_paths(security_context.masked_paths)
+            .readonly_paths(security_context.readonly_paths);
+        if let Some(resources) = resources {
+            linux = linux.resources(resources);
+        }
+        if let Some(cgroups_path) = cgroups_path {
+            linux = linux.cgroups_path(cgroups_path);
+        }
+
         let spec = SpecBuilder::default()
             .process(
-                ProcessBuilder::default()
-                    .args(
-                        config
-                            .command
-                            .into_iter()
-                            .chain(config.args)
-                            .collect::<Vec<String>>(),
-                    )
-                    .env(
-                        config
-                            .envs
-                            .iter()
-                            .map(|kv| format!("{}={}", kv.key, kv.value))
-                            .collect::<Vec<String>>(),
-                    )
-                    .cwd(config.working_dir)
-                    .apparmor_profile(security_context.apparmor_profile)
-                    .no_new_privileges(security_context.no_new_privs)
-                    .user(
-                        UserBuilder::default()
-                            .uid(
-                                u32::try_from(
-                                    security_context
-                                        .run_as_user
-                                        .as_ref()
-                                        .map(|id| id.value)
-                                        .unwrap_or_default(),
-                                )
-                                .map_internal("failed to convert uid")?,
-                            )
-                            .gid(
-                                u32::try_from(
-                                    security_context
-                                        .run_as_group
-                                        .as_ref()
-                                        .map(|id| id.value)
-                                        .unwrap_or_default(),
-                                )
-                                .map_internal("failed to convert gid")?,
-                            )
-                            .additional_gids(
-                                security_context
-                                    .supplemental_groups
-                                    .iter()
-                                    .copied()
-                                    .map(u32::try_from)
-                                    .collect::<Result<Vec<u32>, _>>()
-                                    .map_internal("failed to convert supplemental groups")?,
-                            )
-                            .build()
-                            .map_internal("failed to build runtime spec user")?,
-                    )
+                process
                     .build()
                     .map_internal("failed to build runtime spec process")?,
             )
             .linux(
-                LinuxBuilder::default()
-                    .masked_paths(security_context.masked_paths)
-                    .readonly_paths(security_context.readonly_paths)
+                linux
                     .build()
                     .map_internal("failed to build runtime spec linux")?,
             )
             .root(
                 RootBuilder::default()
+                    .path(rootfs)
                     .readonly(security_context.readonly_rootfs)
                     .build()
                     .map_internal("failed to build")?,
@@ -113,13 +279,20 @@ impl CRIService {
                 prepare_mounts(&config.mounts)
                     .map_internal("failed to build oci runtime spec mounts")?,
             )
-            .annotations(config.annotations)
+            .annotations(annotations)
             .build()
             .map_internal("failed to create runtime spec")?;
 
-        let mut container = OCIContainerBuilder::default()
-            .id(format!("{}.{}", metadata.name, metadata.attempt))
+        let bundle = self
+            .bundles()
+            .create(id, &spec, rootfs)
+            .await
+            .map_internal("failed to create container bundle")?;
+        let mut container = self
+            .container_builder(bundle)
             .log_path(config.log_path)
+            .stdin(config.stdin)
+            .stdin_once(config.stdin_once)
             .spec(spec)
             .build()
             .map_internal("failed to build container")?;
@@ -129,12 +302,156 @@ impl CRIService {
             .await
             .map_internal("failed to create container")?;
 
-        let resp = CreateContainerResponse {
-            container_id: container.id().into(),
-        };
+        Ok(container.id().into())
+    }
+}
 
-        Ok(Response::new(resp))
+/// Clamp the annotated disk quota of the writable layer to the default. A quota of zero would
+/// disable the limit and is rejected.
+#[allow(clippy::result_large_err)]
+fn limit_writable_layer_size(size: u64, default: Option<u64>) -> Result<u64, Status> {
+    if size == 0 {
+        return Err(Status::invalid_argument(format!(
+            "{} annotation must not be zero",
+            WRITABLE_LAYER_SIZE_ANNOTATION
+        )));
     }
+    Ok(default.map_or(size, |default| size.min(default)))
+}
+
+/// Build the process arguments following the Kubernetes precedence rules: the command replaces
+/// the image entrypoint and the args replace the image cmd, whereas the image cmd is ignored if
+/// only the command is set.
+fn process_args(
+    command: Vec<String>,
+    args: Vec<String>,
+    image_config: &ImageConfig,
+) -> Vec<String> {
+    let args = match (command.is_empty(), args.is_empty()) {
+        (true, true) => image_config.cmd().clone().unwrap_or_default(),
+        (false, true) => vec![],
+        (_, false) => args,
+    };
+    let command = if command.is_empty() {
+        image_config.entrypoint().clone().unwrap_or_default()
+    } else {
+        command
+    };
+    command.into_iter().chain(args).collect()
+}
+
+/// Build the process environment from the image environment, which gets overridden by the
+/// variables of the container config. `PATH` and `HOME` get defaults if not set.
+fn process_env(envs: &[KeyValue], image_config: &ImageConfig, home: &str) -> Vec<String> {
+    let mut env: Vec<(String, String)> = vec![];
+    let image_env = image_config.env().iter().flatten().map(|kv| {
+        let (key, value) = kv.split_once('=').unwrap_or((kv, ""));
+        (key.to_string(), value.to_string())
+    });
+    let container_env = envs.iter().map(|kv| (kv.key.clone(), kv.value.clone()));
+    let defaults = [("PATH", DEFAULT_PATH), ("HOME", home)]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<Vec<_>>();
+
+    for (key, value) in image_env.chain(container_env) {
+        match env.iter_mut().find(|(k, _)| *k == key) {
+            Some(existing) => existing.1 = value,
+            None => env.push((key, value)),
+        }
+    }
+    for (key, value) in defaults {
+        if !env.iter().any(|(k, _)| *k == key) {
+            env.push((key, value));
+        }
+    }
+    env.into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect()
+}
+
+/// The working directory of the container config, otherwise the one of the image or `/`.
+fn process_cwd(working_dir: String, image_config: &ImageConfig) -> String {
+    if !working_dir.is_empty() {
+        return working_dir;
+    }
+    image_config
+        .working_dir()
+        .clone()
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| "/".into())
+}
+
+/// Resolve the process user and its home directory. The user and group of the security context
+/// take precedence over the user of the image, whereas names are resolved against the
+/// `/etc/passwd` and `/etc/group` files of the root file system.
+#[allow(clippy::result_large_err)]
+fn process_user(
+    rootfs: &Path,
+    security_context: &LinuxContainerSecurityContext,
+    image_config: &ImageConfig,
+) -> Result<(User, String), Status> {
+    let user = match (
+        &security_context.run_as_user,
+        security_context.run_as_username.as_str(),
+    ) {
+        (Some(uid), _) => uid.value.to_string(),
+        (None, name) if !name.is_empty() => name.into(),
+        _ => image_config.user().clone().unwrap_or_default(),
+    };
+    let resolved = rootfs::resolve_user(rootfs, &user).map_invalid("failed to resolve user")?;
+
+    let gid = match &security_context.run_as_group {
+        Some(gid) => u32::try_from(gid.value).map_invalid("failed to convert gid")?,
+        None => resolved.gid(),
+    };
+    let mut additional_gids = resolved.additional_gids().clone();
+    for group in &security_context.supplemental_groups {
+        let group = u32::try_from(*group).map_invalid("failed to convert supplemental groups")?;
+        if !additional_gids.contains(&group) {
+            additional_gids.push(group);
+        }
+    }
+
+    let user = UserBuilder::default()
+        .uid(resolved.uid())
+        .gid(gid)
+        .additional_gids(additional_gids)
+        .build()
+        .map_internal("failed to build runtime spec user")?;
+    Ok((user, resolved.home().clone()))
+}
+
+/// The OOM score adjustment of the container process, which has to be within `[-1000, 1000]`.
+#[allow(clippy::result_large_err)]
+fn oom_score_adj(value: i64) -> Result<i32, Status> {
+    match i32::try_from(value) {
+        Ok(value) if (-1000..=1000).contains(&value) => Ok(value),
+        _ => Err(Status::invalid_argument(format!(
+            "invalid oom score adjustment {}",
+            value
+        ))),
+    }
+}
+
+/// Create the directories for the volumes of the image inside the root file system, unless they
+/// are provided as mounts of the container config.
+fn create_volumes(
+    rootfs: &Path,
+    image_config: &ImageConfig,
+    mounts: &[CRIMount],
+) -> anyhow::Result<()> {
+    let mounted = mounts
+        .iter()
+        .map(|m| Path::new(&m.container_path))
+        .collect::<Vec<_>>();
+    for volume in image_config.volumes().iter().flatten() {
+        if mounted.contains(&Path::new(volume)) {
+            continue;
+        }
+        fs::create_dir_all(rootfs::resolve_path(rootfs, volume)?)?;
+    }
+    Ok(())
 }
 
 fn prepare_mounts(cri_mounts: &[CRIMount]) -> Result<Vec<OCIMount>, ServiceError> {
@@ -152,13 +469,16 @@ mod tests {
     use super::*;
     use crate::cri::{
         api::{
-            ContainerConfig, ContainerMetadata, CreateContainerRequest, Int64Value, KeyValue,
-            LinuxContainerConfig, LinuxContainerSecurityContext, Mount,
+            ContainerMetadata, ImageSpec, Int64Value, LinuxContainerConfig,
+            LinuxContainerResources, LinuxPodSandboxConfig, Mount, PodSandboxConfig,
         },
-        cri_service::tests::new_cri_service,
+        cri_service::tests::{add_image, add_image_with_config, new_cri_service},
     };
-    use anyhow::Result;
+    use anyhow::{Context, Result};
+    use image::{gc::GcPolicy, usage::DiskUsage};
+    use serde_json::json;
     use std::collections::HashMap;
+    use tonic::Code;
 
     fn create_request(config: Option<ContainerConfig>) -> Result<CreateContainerRequest> {
         let request = CreateContainerRequest {
@@ -186,7 +506,10 @@ mod tests {
                 name: "vicious_tuna".to_owned(),
                 attempt: 1,
             }),
-            image: None,
+            image: Some(ImageSpec {
+                image: "app:v1".to_owned(),
+                annotations: HashMap::new(),
+            }),
             command: vec!["sleep".to_owned()],
             args: vec!["9000".to_owned()],
             working_dir: "/var/run/containrs".to_owned(),
@@ -251,6 +574,7 @@ mod tests {
     #[tokio::test]
     async fn create_container_success() -> Result<()> {
         let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
         let security_context = create_security_context();
         let linux_config = create_linux(Some(security_context));
         let config = create_config(Some(linux_config))?;
@@ -258,6 +582,253 @@ mod tests {
 
         let response = sut.handle_create_container(Request::new(request)).await?;
         assert_eq!(response.get_ref().container_id, "vicious_tuna.1".to_owned());
+
+        let rootfs = sut.snapshotter().mount("vicious_tuna.1")?;
+        assert!(rootfs.join("file").exists());
+        let bundle = sut.bundles().get("vicious_tuna.1")?.context("no bundle")?;
+        assert_eq!(std::fs::read_link(bundle.rootfs())?, rootfs);
+        assert!(bundle.config_file().exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_touches_image() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
+        let unused = add_image(&sut, "other", &["other:v1"]).await?;
+        let config = create_config(Some(create_linux(Some(create_security_context()))))?;
+        sut.handle_create_container(Request::new(create_request(Some(config))?))
+            .await?;
+        sut.bundles().remove("vicious_tuna.1").await?;
+
+        // The image pulled earlier survives, because it got used more recently
+        let images = sut
+            .image_index()
+            .list()?
+            .iter()
+            .map(|record| record.gc_image(false))
+            .collect::<Result<Vec<_>>>()?;
+        let selected = GcPolicy::default().select(&images, DiskUsage::new(90, 100));
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].id(), unused.id());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_resources() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
+        let mut linux_config = create_linux(Some(create_security_context()));
+        linux_config.resources = Some(LinuxContainerResources {
+            cpu_shares: 512,
+            memory_limit_in_bytes: 1 << 30,
+            oom_score_adj: -100,
+            ..Default::default()
+        });
+        let config = create_config(Some(linux_config))?;
+        let mut request = create_request(Some(config))?;
+        request.sandbox_config = Some(PodSandboxConfig {
+            linux: Some(LinuxPodSandboxConfig {
+                cgroup_parent: "/kubepods/pod123".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        sut.handle_create_container(Request::new(request)).await?;
+        let spec = sut
+            .bundles()
+            .get("vicious_tuna.1")?
+            .context("no bundle")?
+            .spec()?;
+        let process = spec.process().clone().context("no process")?;
+        assert_eq!(process.oom_score_adj(), Some(-100));
+        let linux = spec.linux().clone().context("no linux")?;
+        assert_eq!(
+            linux.cgroups_path().as_deref(),
+            Some(Path::new("/kubepods/pod123/containrs-vicious_tuna.1"))
+        );
+        let resources = linux.resources().clone().context("no resources")?;
+        assert_eq!(resources.cpu().clone().and_then(|c| c.shares()), Some(512));
+        assert_eq!(resources.memory().and_then(|m| m.limit()), Some(1 << 30));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_fail_invalid_resources() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
+        let mut linux_config = create_linux(Some(create_security_context()));
+        linux_config.resources = Some(LinuxContainerResources {
+            oom_score_adj: 1001,
+            ..Default::default()
+        });
+        let config = create_config(Some(linux_config))?;
+        let request = create_request(Some(config))?;
+
+        let status = sut
+            .handle_create_container(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(sut.snapshotter().stat("vicious_tuna.1")?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_image_volumes() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image_with_config(
+            &sut,
+            json!({"Volumes": {"/data": {}, "/path/in/container": {}}}),
+            &[("file", b"content")],
+            &["app:v1"],
+        )
+        .await?;
+        let config = create_config(Some(create_linux(Some(create_security_context()))))?;
+        let request = create_request(Some(config))?;
+
+        sut.handle_create_container(Request::new(request)).await?;
+        let rootfs = sut.snapshotter().mount("vicious_tuna.1")?;
+        assert!(rootfs.join("data").is_dir());
+        assert!(!rootfs.join("path").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_fail_no_command() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
+        let mut config = create_config(Some(create_linux(Some(create_security_context()))))?;
+        config.command = vec![];
+        config.args = vec![];
+        let request = create_request(Some(config))?;
+
+        let status = sut
+            .handle_create_container(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(sut.snapshotter().stat("vicious_tuna.1")?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_writable_layer_size_unsupported() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
+        let mut config = create_config(Some(create_linux(Some(create_security_context()))))?;
+        config
+            .annotations
+            .insert(WRITABLE_LAYER_SIZE_ANNOTATION.into(), "1Gi".into());
+        let request = create_request(Some(config))?;
+
+        // The limit is not enforced without quota support
+        sut.handle_create_container(Request::new(request)).await?;
+        let info = sut
+            .snapshotter()
+            .stat("vicious_tuna.1")?
+            .context("no rootfs")?;
+        assert!(info.size_limit().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_fail_invalid_writable_layer_size() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
+        let mut config = create_config(Some(create_linux(Some(create_security_context()))))?;
+        config
+            .annotations
+            .insert(WRITABLE_LAYER_SIZE_ANNOTATION.into(), "lots".into());
+        let request = create_request(Some(config))?;
+
+        let status = sut
+            .handle_create_container(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(sut.snapshotter().stat("vicious_tuna.1")?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_fail_zero_writable_layer_size() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
+        let mut config = create_config(Some(create_linux(Some(create_security_context()))))?;
+        config
+            .annotations
+            .insert(WRITABLE_LAYER_SIZE_ANNOTATION.into(), "0".into());
+        let request = create_request(Some(config))?;
+
+        let status = sut
+            .handle_create_container(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(sut.snapshotter().stat("vicious_tuna.1")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn writable_layer_size_clamped_to_default() -> Result<()> {
+        assert_eq!(limit_writable_layer_size(1 << 20, None)?, 1 << 20);
+        assert_eq!(limit_writable_layer_size(1 << 20, Some(1 << 30))?, 1 << 20);
+        assert_eq!(limit_writable_layer_size(1 << 40, Some(1 << 30))?, 1 << 30);
+        assert_eq!(
+            limit_writable_layer_size(0, Some(1 << 30))
+                .unwrap_err()
+                .code(),
+            Code::InvalidArgument
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_fail_unknown_user() -> Result<()> {
+        let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
+        let mut security_context = create_security_context();
+        security_context.run_as_user = None;
+        let config = create_config(Some(create_linux(Some(security_context))))?;
+        let request = create_request(Some(config))?;
+
+        let status = sut
+            .handle_create_container(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(sut.snapshotter().stat("vicious_tuna.1")?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_fail_image_not_found() -> Result<()> {
+        let sut = new_cri_service()?;
+        let config = create_config(Some(create_linux(Some(create_security_context()))))?;
+        let request = create_request(Some(config))?;
+
+        let status = sut
+            .handle_create_container(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_container_fail_no_image() -> Result<()> {
+        let sut = new_cri_service()?;
+        let mut config = create_config(Some(create_linux(Some(create_security_context()))))?;
+        config.image = None;
+        let request = create_request(Some(config))?;
+
+        let status = sut
+            .handle_create_container(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
         Ok(())
     }
 
@@ -288,6 +859,7 @@ mod tests {
     #[tokio::test]
     async fn create_container_fail_no_security() -> Result<()> {
         let sut = new_cri_service()?;
+        add_image(&sut, "file", &["app:v1"]).await?;
         let linux_config = create_linux(None);
         let config = create_config(Some(linux_config))?;
         let request = create_request(Some(config))?;
@@ -296,4 +868,116 @@ mod tests {
         assert!(response.is_err());
         Ok(())
     }
+
+    fn image_config(config: serde_json::Value) -> Result<ImageConfig> {
+        Ok(serde_json::from_value(config)?)
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn process_args_precedence() -> Result<()> {
+        let image = image_config(json!({"Entrypoint": ["/entry"], "Cmd": ["cmd"]}))?;
+        let cases: [(&[&str], &[&str], &[&str]); 4] = [
+            (&[], &[], &["/entry", "cmd"]),
+            (&[], &["args"], &["/entry", "args"]),
+            (&["/command"], &[], &["/command"]),
+            (&["/command"], &["args"], &["/command", "args"]),
+        ];
+        for (command, args, expected) in cases.iter() {
+            assert_eq!(
+                process_args(strings(command), strings(args), &image),
+                strings(expected)
+            );
+        }
+
+        let image = image_config(json!({"Cmd": ["/cmd", "arg"]}))?;
+        assert_eq!(
+            process_args(vec![], vec![], &image),
+            strings(&["/cmd", "arg"])
+        );
+        assert!(process_args(vec![], vec![], &ImageConfig::default()).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn process_env_merge() -> Result<()> {
+        let image = image_config(json!({"Env": ["PATH=/bin", "A=image", "B=image=b", "EMPTY"]}))?;
+        let envs = vec![
+            KeyValue {
+                key: "A".to_owned(),
+                value: "container".to_owned(),
+            },
+            KeyValue {
+                key: "C".to_owned(),
+                value: "c".to_owned(),
+            },
+        ];
+        assert_eq!(
+            process_env(&envs, &image, "/home/app"),
+            strings(&[
+                "PATH=/bin",
+                "A=container",
+                "B=image=b",
+                "EMPTY=",
+                "C=c",
+                "HOME=/home/app"
+            ])
+        );
+        assert_eq!(
+            process_env(&[], &ImageConfig::default(), "/"),
+            vec![format!("PATH={}", DEFAULT_PATH), "HOME=/".to_owned()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn process_cwd_precedence() -> Result<()> {
+        let image = image_config(json!({"WorkingDir": "/app"}))?;
+        assert_eq!(process_cwd("/work".to_owned(), &image), "/work");
+        assert_eq!(process_cwd(String::new(), &image), "/app");
+        assert_eq!(process_cwd(String::new(), &ImageConfig::default()), "/");
+        Ok(())
+    }
+
+    #[test]
+    fn process_user_precedence() -> Result<()> {
+        let rootfs = tempfile::tempdir()?;
+        fs::create_dir(rootfs.path().join("etc"))?;
+        fs::write(
+            rootfs.path().join("etc/passwd"),
+            "app:x:1000:1000::/home/app:/bin/sh\nother:x:2000:2000::/home/other:/bin/sh\n",
+        )?;
+        fs::write(rootfs.path().join("etc/group"), "audio:x:29:app\n")?;
+        let image = image_config(json!({"User": "app"}))?;
+        let mut security_context = create_security_context();
+        security_context.run_as_user = None;
+        security_context.run_as_group = None;
+        security_context.run_as_username = String::new();
+        security_context.supplemental_groups = vec![29, 30];
+
+        let (user, home) = process_user(rootfs.path(), &security_context, &image)?;
+        assert_eq!(user.uid(), 1000);
+        assert_eq!(user.gid(), 1000);
+        assert_eq!(user.additional_gids(), &Some(vec![29, 30]));
+        assert_eq!(home, "/home/app");
+
+        security_context.run_as_username = "other".to_owned();
+        let (user, home) = process_user(rootfs.path(), &security_context, &image)?;
+        assert_eq!(user.uid(), 2000);
+        assert_eq!(home, "/home/other");
+
+        security_context.run_as_user = Some(Int64Value { value: 3000 });
+        security_context.run_as_group = Some(Int64Value { value: 3001 });
+        let (user, home) = process_user(rootfs.path(), &security_context, &image)?;
+        assert_eq!(user.uid(), 3000);
+        assert_eq!(user.gid(), 3001);
+        assert_eq!(home, "/");
+
+        security_context.run_as_user = Some(Int64Value { value: -1 });
+        assert!(process_user(rootfs.path(), &security_context, &image).is_err());
+        Ok(())
+    }
 }
@@ -1,16 +1,86 @@
 use crate::cri::{
     api::{ListContainerStatsRequest, ListContainerStatsResponse},
-    cri_service::CRIService,
+    cri_service::{CRIService, ResultStatus},
 };
+use image::snapshot::{SnapshotKind, EXTRACT_PREFIX};
 use tonic::{Request, Response, Status};
 
 impl CRIService {
     /// handle_list_container_stats returns stats of all running containers.
     pub async fn handle_list_container_stats(
         &self,
-        _request: Request<ListContainerStatsRequest>,
+        request: Request<ListContainerStatsRequest>,
     ) -> Result<Response<ListContainerStatsResponse>, Status> {
-        let resp = ListContainerStatsResponse { stats: vec![] };
+        let id_filter = request
+            .into_inner()
+            .filter
+            .map(|filter| filter.id)
+            .filter(|id| !id.is_empty());
+
+        let mut stats = vec![];
+        for info in self
+            .snapshotter()
+            .list()
+            .map_internal("failed to list writable layers")?
+        {
+            let id = info.key();
+            if info.kind() != SnapshotKind::Active
+                || id.starts_with(EXTRACT_PREFIX)
+                || id_filter.as_ref().is_some_and(|filter| filter != id)
+            {
+                continue;
+            }
+            // Removed concurrently otherwise
+            if let Some(container_stats) = self.container_stats(id).await? {
+                stats.push(container_stats);
+            }
+        }
+        let resp = ListContainerStatsResponse { stats };
         Ok(Response::new(resp))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::{api::ContainerStatsFilter, cri_service::tests::new_cri_service};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn list_container_stats() -> Result<()> {
+        let sut = new_cri_service()?;
+        sut.snapshotter().prepare("layer-active", None).await?;
+        sut.snapshotter().commit("layer", "layer-active")?;
+        for id in ["a", "b"] {
+            sut.snapshotter().prepare(id, Some("layer")).await?;
+        }
+        sut.snapshotter()
+            .prepare(&format!("{}layer", EXTRACT_PREFIX), None)
+            .await?;
+
+        let ids = |response: ListContainerStatsResponse| {
+            let mut ids = response
+                .stats
+                .into_iter()
+                .filter_map(|s| s.attributes.map(|a| a.id))
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let all = sut
+            .handle_list_container_stats(Request::new(ListContainerStatsRequest { filter: None }))
+            .await?;
+        assert_eq!(ids(all.into_inner()), ["a", "b"]);
+
+        let filtered = sut
+            .handle_list_container_stats(Request::new(ListContainerStatsRequest {
+                filter: Some(ContainerStatsFilter {
+                    id: "b".into(),
+                    ..Default::default()
+                }),
+            }))
+            .await?;
+        assert_eq!(ids(filtered.into_inner()), ["b"]);
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 use crate::cri::{
     api::{RemoveContainerRequest, RemoveContainerResponse},
-    cri_service::CRIService,
+    cri_service::{CRIService, ResultStatus},
 };
+use container::{container::Container, oci_runtime::RuntimeError};
+use log::warn;
 use tonic::{Request, Response, Status};
 
 impl CRIService {
@@ -10,9 +12,136 @@ impl CRIService {
     /// container has already been removed.
     pub async fn handle_remove_container(
         &self,
-        _request: Request<RemoveContainerRequest>,
+        request: Request<RemoveContainerRequest>,
     ) -> Result<Response<RemoveContainerResponse>, Status> {
+        let id = request.into_inner().container_id;
+
+        let bundle = self
+            .bundles()
+            .get(&id)
+            .map_internal("failed to get container bundle")?;
+        if let Some(bundle) = bundle {
+            let mut container = self
+                .container_builder(bundle)
+                .build()
+                .map_internal("failed to build container")?;
+            if let Err(e) = container.delete().await {
+                // The runtime does not know the container anymore, for example after a reboot
+                match container.state().await {
+                    Err(state) if state.downcast_ref::<RuntimeError>().is_some() => warn!(
+                        "Unable to delete container {}, assuming it is gone: {:#}",
+                        id, e
+                    ),
+                    _ => return Err(e).map_internal("failed to delete container"),
+                }
+            }
+            self.bundles()
+                .remove(&id)
+                .await
+                .map_internal("failed to remove container bundle")?;
+        }
+        self.snapshotter()
+            .remove(&id)
+            .await
+            .map_internal("failed to remove container root file system")?;
+
         let resp = RemoveContainerResponse {};
         Ok(Response::new(resp))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::cri_service::tests::{new_cri_service, new_cri_service_with, TestOptions};
+    use anyhow::Result;
+    use oci_spec::runtime::Spec;
+    use std::{fs, os::unix::fs::PermissionsExt};
+    use tempfile::TempDir;
+    use tonic::Code;
+
+    fn remove_request(id: &str) -> Request<RemoveContainerRequest> {
+        Request::new(RemoveContainerRequest {
+            container_id: id.into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn remove_container_success() -> Result<()> {
+        let sut = new_cri_service()?;
+        let rootfs = sut.snapshotter().prepare("id", None).await?;
+        let bundle = sut
+            .bundles()
+            .create("id", &Spec::default(), &rootfs)
+            .await?;
+
+        sut.handle_remove_container(remove_request("id")).await?;
+        assert!(sut.bundles().get("id")?.is_none());
+        assert!(!bundle.path().exists());
+        assert!(sut.snapshotter().stat("id")?.is_none());
+
+        sut.handle_remove_container(remove_request("id")).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_container_success_unknown_to_runtime() -> Result<()> {
+        let sut = new_cri_service_with(TestOptions {
+            runtime: Some(which::which("false")?),
+            ..Default::default()
+        })?;
+        let rootfs = sut.snapshotter().prepare("id", None).await?;
+        let bundle = sut
+            .bundles()
+            .create("id", &Spec::default(), &rootfs)
+            .await?;
+
+        sut.handle_remove_container(remove_request("id")).await?;
+        assert!(sut.bundles().get("id")?.is_none());
+        assert!(!bundle.path().exists());
+        assert!(sut.snapshotter().stat("id")?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_container_fail_delete() -> Result<()> {
+        let dir = TempDir::new()?;
+        let runtime = dir.path().join("runtime");
+        fs::write(
+            &runtime,
+            r#"#!/bin/sh
+case "$*" in
+    *" state "*) echo '{"id":"id","pid":1,"status":"running","bundle":"/b"}' ;;
+    *) exit 1 ;;
+esac
+"#,
+        )?;
+        fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755))?;
+        let sut = new_cri_service_with(TestOptions {
+            runtime: Some(runtime),
+            ..Default::default()
+        })?;
+        let rootfs = sut.snapshotter().prepare("id", None).await?;
+        sut.bundles()
+            .create("id", &Spec::default(), &rootfs)
+            .await?;
+
+        // The runtime still knows the container, so nothing gets removed
+        let status = sut
+            .handle_remove_container(remove_request("id"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        assert!(sut.bundles().get("id")?.is_some());
+        assert!(sut.snapshotter().stat("id")?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_container_not_found() -> Result<()> {
+        let sut = new_cri_service()?;
+        sut.handle_remove_container(remove_request("missing"))
+            .await?;
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
 use crate::cri::{
     api::{RemovePodSandboxRequest, RemovePodSandboxResponse},
-    cri_service::CRIService,
+    cri_service::{CRIService, ResultStatus},
 };
+use log::info;
 use tonic::{Request, Response, Status};
 
 impl CRIService {
@@ -10,9 +11,66 @@ impl CRIService {
     /// not return an error if the sandbox has already been removed.
     pub async fn handle_remove_pod_sandbox(
         &self,
-        _request: Request<RemovePodSandboxRequest>,
+        request: Request<RemovePodSandboxRequest>,
     ) -> Result<Response<RemovePodSandboxResponse>, Status> {
+        let id = request.into_inner().pod_sandbox_id;
+
+        let parent = self
+            .cgroup_parent(&id)
+            .map_internal("failed to get pod cgroup parent")?;
+        if let Some(parent) = parent {
+            self.cgroup_manager()
+                .remove_pod(&parent, &id)
+                .map_internal("failed to remove pod cgroup")?;
+            self.forget_cgroup_parent(&id)
+                .map_internal("failed to remove pod cgroup parent")?;
+            info!("Removed cgroup of pod sandbox {}", id);
+        }
+
         let reply = RemovePodSandboxResponse {};
         Ok(Response::new(reply))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::cri_service::tests::new_cri_service;
+    use anyhow::Result;
+    use std::path::Path;
+
+    fn remove_request(id: &str) -> Request<RemovePodSandboxRequest> {
+        Request::new(RemovePodSandboxRequest {
+            pod_sandbox_id: id.into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn remove_pod_sandbox_success() -> Result<()> {
+        let sut = new_cri_service()?;
+        let parent = Path::new("/kubepods/pod123");
+        let cgroup = sut.cgroup_manager().create_pod(parent, "123", None)?;
+        sut.record_cgroup_parent("123", parent)?;
+        let path = sut
+            .cgroup_manager()
+            .hierarchy()
+            .root()
+            .join(cgroup.strip_prefix("/")?);
+        assert!(path.exists());
+
+        sut.handle_remove_pod_sandbox(remove_request("123")).await?;
+        assert!(!path.exists());
+        assert!(sut.cgroup_parent("123")?.is_none());
+
+        sut.handle_remove_pod_sandbox(remove_request("123")).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_pod_sandbox_not_found() -> Result<()> {
+        let sut = new_cri_service()?;
+        sut.handle_remove_pod_sandbox(remove_request("missing"))
+            .await?;
+        Ok(())
+    }
+}
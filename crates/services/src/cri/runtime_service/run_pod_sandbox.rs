@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::cri::{
     api::{NamespaceMode, RunPodSandboxRequest, RunPodSandboxResponse},
@@ -70,7 +70,8 @@ impl CRIService {
                             .annotations(config.annotations)
                             .labels(config.labels)
                             .sysctls(linux_config.sysctls)
-                            .cgroup_parent(PathBuf::from(linux_config.cgroup_parent))
+                            .cgroup_parent(PathBuf::from(&linux_config.cgroup_parent))
+                            .cgroup_manager(self.cgroup_manager().clone())
                             .security(
                                 SecurityConfigBuilder::default()
                                     .run_as_user(security_context.run_as_user.map(|v| v.value))
@@ -93,6 +94,10 @@ impl CRIService {
 
         debug!("Created pod sandbox {:?}", sandbox);
 
+        // Record the cgroup parent to be able to remove the pod cgroup later on
+        self.record_cgroup_parent(sandbox.id(), Path::new(&linux_config.cgroup_parent))
+            .map_internal("record pod cgroup parent")?;
+
         // Run the sandbox
         sandbox.run().await.map_internal("run pod sandbox")?;
         info!("Started pod sandbox {}", sandbox);
@@ -53,12 +53,12 @@ mod tests {
             .context("no status")?
             .conditions;
         assert_eq!(conditions.len(), 2);
-        let runtime_condition = conditions.get(0).context("no runtime condition")?;
+        let runtime_condition = conditions.first().context("no runtime condition")?;
         let network_condition = conditions.get(1).context("no network condition")?;
         assert_eq!(runtime_condition.r#type, "RuntimeReady");
-        assert_eq!(runtime_condition.status, true);
+        assert!(runtime_condition.status);
         assert_eq!(network_condition.r#type, "NetworkReady");
-        assert_eq!(network_condition.status, true);
+        assert!(network_condition.status);
         Ok(())
     }
 }
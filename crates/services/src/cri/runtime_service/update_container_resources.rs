@@ -1,16 +1,227 @@
 use crate::cri::{
-    api::{UpdateContainerResourcesRequest, UpdateContainerResourcesResponse},
-    cri_service::CRIService,
+    api::{
+        LinuxContainerResources, UpdateContainerResourcesRequest, UpdateContainerResourcesResponse,
+    },
+    cri_service::{CRIService, OptionStatus, ResultStatus},
 };
+use container::container::Container;
+use log::info;
+use oci_spec::runtime::LinuxResources;
+use std::convert::TryFrom;
 use tonic::{Request, Response, Status};
 
 impl CRIService {
     /// handle_update_container_resources updates ContainerConfig of the container.
     pub async fn handle_update_container_resources(
         &self,
-        _request: Request<UpdateContainerResourcesRequest>,
+        request: Request<UpdateContainerResourcesRequest>,
     ) -> Result<Response<UpdateContainerResourcesResponse>, Status> {
+        let request = request.into_inner();
+        let resources = request
+            .linux
+            .as_ref()
+            .ok_or_invalid("no linux resources provided")?;
+        let resources = self.linux_resources(resources)?;
+
+        let bundle = self
+            .bundles()
+            .get(&request.container_id)
+            .map_internal("failed to get container bundle")?
+            .ok_or_else(|| {
+                Status::not_found(format!("container {} not found", request.container_id))
+            })?;
+        let spec = bundle.spec().map_internal("failed to read runtime spec")?;
+        let mut container = self
+            .container_builder(bundle)
+            .spec(spec)
+            .build()
+            .map_internal("failed to build container")?;
+        container
+            .update(&resources)
+            .await
+            .map_internal("failed to update container resources")?;
+        info!("Updated resources of container {}", request.container_id);
+
         let resp = UpdateContainerResourcesResponse {};
         Ok(Response::new(resp))
     }
+
+    /// Translate the CRI resources into the ones of the runtime spec, which have to be supported
+    /// by the cgroup version of the host.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn linux_resources(
+        &self,
+        resources: &LinuxContainerResources,
+    ) -> Result<LinuxResources, Status> {
+        let resources =
+            LinuxResources::try_from(resources).map_invalid("invalid container resources")?;
+        self.cgroup_manager()
+            .hierarchy()
+            .validate(&resources)
+            .map_invalid("unsupported container resources")?;
+        Ok(resources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::{api::HugepageLimit, cri_service::tests::new_cri_service};
+    use anyhow::Result;
+    use oci_spec::runtime::Spec;
+    use std::collections::HashMap;
+    use tonic::Code;
+
+    fn update_request(
+        id: &str,
+        linux: Option<LinuxContainerResources>,
+    ) -> Request<UpdateContainerResourcesRequest> {
+        Request::new(UpdateContainerResourcesRequest {
+            container_id: id.into(),
+            linux,
+        })
+    }
+
+    fn resources() -> LinuxContainerResources {
+        LinuxContainerResources {
+            cpu_period: 100_000,
+            cpu_quota: 50_000,
+            cpu_shares: 512,
+            memory_limit_in_bytes: 1 << 30,
+            oom_score_adj: 100,
+            cpuset_cpus: "0-1".into(),
+            cpuset_mems: "0".into(),
+            hugepage_limits: vec![HugepageLimit {
+                page_size: "2MB".into(),
+                limit: 1 << 21,
+            }],
+            unified: HashMap::from([("memory.high".into(), "512M".into())]),
+            memory_swap_limit_in_bytes: 2 << 30,
+        }
+    }
+
+    #[test]
+    fn linux_resources_mapping() -> Result<()> {
+        let sut = new_cri_service()?;
+        let resources = sut.linux_resources(&resources())?;
+
+        let cpu = resources.cpu().clone().unwrap_or_default();
+        assert_eq!(cpu.period(), Some(100_000));
+        assert_eq!(cpu.quota(), Some(50_000));
+        assert_eq!(cpu.shares(), Some(512));
+        assert_eq!(cpu.cpus().as_deref(), Some("0-1"));
+        assert_eq!(cpu.mems().as_deref(), Some("0"));
+
+        let memory = resources.memory().unwrap_or_default();
+        assert_eq!(memory.limit(), Some(1 << 30));
+        assert_eq!(memory.swap(), Some(2 << 30));
+
+        let hugepages = resources.hugepage_limits().clone().unwrap_or_default();
+        assert_eq!(hugepages.len(), 1);
+        assert_eq!(hugepages[0].page_size(), "2MB");
+        assert_eq!(hugepages[0].limit(), 1 << 21);
+
+        assert_eq!(
+            resources
+                .unified()
+                .as_ref()
+                .and_then(|u| u.get("memory.high")),
+            Some(&"512M".to_string())
+        );
+
+        let resources = sut.linux_resources(&LinuxContainerResources::default())?;
+        assert_eq!(resources.cpu().clone().unwrap_or_default().shares(), None);
+        assert_eq!(resources.memory().unwrap_or_default().limit(), None);
+        assert!(resources.hugepage_limits().is_none());
+        assert!(resources.unified().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn linux_resources_invalid() -> Result<()> {
+        let sut = new_cri_service()?;
+        let invalid = [
+            LinuxContainerResources {
+                cpu_shares: -1,
+                ..Default::default()
+            },
+            LinuxContainerResources {
+                memory_limit_in_bytes: -1,
+                ..Default::default()
+            },
+            LinuxContainerResources {
+                memory_swap_limit_in_bytes: -2,
+                ..Default::default()
+            },
+            LinuxContainerResources {
+                hugepage_limits: vec![HugepageLimit {
+                    page_size: "2XB".into(),
+                    limit: 1,
+                }],
+                ..Default::default()
+            },
+            // The swap limit includes the memory limit
+            LinuxContainerResources {
+                memory_limit_in_bytes: 1 << 30,
+                memory_swap_limit_in_bytes: 1 << 20,
+                ..Default::default()
+            },
+            LinuxContainerResources {
+                unified: HashMap::from([("invalid".into(), "1".into())]),
+                ..Default::default()
+            },
+        ];
+        for resources in invalid {
+            let status = sut.linux_resources(&resources).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument, "{:?}", resources);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_container_resources_success() -> Result<()> {
+        let sut = new_cri_service()?;
+        let rootfs = sut.snapshotter().prepare("id", None).await?;
+        let bundle = sut
+            .bundles()
+            .create("id", &Spec::default(), &rootfs)
+            .await?;
+
+        sut.handle_update_container_resources(update_request("id", Some(resources())))
+            .await?;
+        let written: LinuxResources =
+            serde_json::from_slice(&std::fs::read(bundle.resources_file())?)?;
+        assert_eq!(written, sut.linux_resources(&resources())?);
+
+        // The spec of the bundle reflects the update
+        let spec = bundle.spec()?;
+        assert_eq!(
+            spec.linux().as_ref().and_then(|l| l.resources().as_ref()),
+            Some(&written)
+        );
+        assert!(spec.root().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_container_resources_not_found() -> Result<()> {
+        let sut = new_cri_service()?;
+        let status = sut
+            .handle_update_container_resources(update_request("missing", Some(resources())))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_container_resources_fail_no_resources() -> Result<()> {
+        let sut = new_cri_service()?;
+        let status = sut
+            .handle_update_container_resources(update_request("id", None))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
 }
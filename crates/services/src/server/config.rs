@@ -1,7 +1,14 @@
 //! Configuration related structures
 use clap::{crate_name, crate_version, Parser};
+use common::cgroup::manager::CgroupDriver;
+use container::{conmon::DEFAULT_CONMON, container::local::DEFAULT_RUNTIME, monitor::Monitor};
 use derive_builder::Builder;
 use getset::{CopyGetters, Getters};
+use image::{
+    policy::{lookaside::DEFAULT_REGISTRIES_DIR, DEFAULT_POLICY_PATH},
+    pull::DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+    registry::config::DEFAULT_REGISTRIES_CONFIG,
+};
 use lazy_static::lazy_static;
 use nix::unistd::{self, Uid};
 use serde::{Deserialize, Serialize};
@@ -97,6 +104,179 @@ pub struct Config {
     )]
     /// The paths to the CNI plugin binaries, separated by the OS typic separator.
     cni_plugin_paths: String,
+
+    #[get = "pub"]
+    #[arg(
+        default_value(DEFAULT_REGISTRIES_CONFIG),
+        env("CRI_REGISTRIES_CONFIG"),
+        long("registries-config"),
+        value_name("PATH")
+    )]
+    /// The path to the registries.conf(5) file, which configures mirrors, insecure and blocked
+    /// registries as well as the resolution of short image names.
+    registries_config: PathBuf,
+
+    #[get = "pub"]
+    #[arg(
+        default_value(DEFAULT_POLICY_PATH),
+        env("CRI_SIGNATURE_POLICY"),
+        long("signature-policy"),
+        value_name("PATH")
+    )]
+    /// The path to the containers-policy.json(5) file, which defines the signatures required to
+    /// pull an image. All images are accepted if the file does not exist.
+    signature_policy: PathBuf,
+
+    #[get = "pub"]
+    #[arg(
+        default_value(DEFAULT_REGISTRIES_DIR),
+        env("CRI_REGISTRIES_DIR"),
+        long("registries-dir"),
+        value_name("PATH")
+    )]
+    /// The containers-registries.d(5) directory, which configures the lookaside storages of
+    /// image signatures.
+    registries_dir: PathBuf,
+
+    #[get = "pub"]
+    #[arg(
+        env("CRI_DECRYPTION_KEYS_PATH"),
+        long("decryption-keys-path"),
+        value_name("PATH")
+    )]
+    /// A directory containing the private keys used to decrypt encrypted image layers. Images
+    /// with encrypted layers cannot be unpacked without a matching key.
+    decryption_keys_path: Option<PathBuf>,
+
+    #[get_copy = "pub"]
+    #[arg(
+        default_value_t = DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+        env("CRI_MAX_CONCURRENT_DOWNLOADS"),
+        long("max-concurrent-downloads"),
+        value_name("COUNT")
+    )]
+    /// The maximum number of image layers downloaded at the same time across all pulls.
+    max_concurrent_downloads: usize,
+
+    #[get = "pub"]
+    #[arg(
+        env("CRI_PINNED_IMAGES"),
+        long("pinned-images"),
+        value_delimiter(','),
+        value_name("IMAGE")
+    )]
+    /// The image references which are pinned, for example the pause image. Pinned images are
+    /// reported as such to the kubelet and never removed by the garbage collection.
+    pinned_images: Vec<String>,
+
+    #[get_copy = "pub"]
+    #[arg(
+        env("CRI_IMAGE_GC_HIGH_THRESHOLD"),
+        long("image-gc-high-threshold"),
+        value_parser(clap::value_parser!(u8).range(0..=100)),
+        value_name("PERCENT")
+    )]
+    /// The disk usage in percent which triggers the removal of unused images after a pull. The
+    /// garbage collection of images is left to the kubelet if not set.
+    image_gc_high_threshold: Option<u8>,
+
+    #[get_copy = "pub"]
+    #[arg(
+        default_value("80"),
+        env("CRI_IMAGE_GC_LOW_THRESHOLD"),
+        long("image-gc-low-threshold"),
+        value_parser(clap::value_parser!(u8).range(0..=100)),
+        value_name("PERCENT")
+    )]
+    /// The disk usage in percent to which unused images get removed once the high threshold is
+    /// exceeded.
+    image_gc_low_threshold: u8,
+
+    #[get = "pub"]
+    #[arg(
+        env("CRI_IMAGE_IMPORT_DIR"),
+        long("image-import-dir"),
+        value_name("PATH")
+    )]
+    /// A directory containing docker archives and OCI image layouts, which get imported on
+    /// startup. This allows to pre-seed nodes without any registry access.
+    image_import_dir: Option<PathBuf>,
+
+    #[get_copy = "pub"]
+    #[arg(
+        env("CRI_WRITABLE_LAYER_SIZE"),
+        long("writable-layer-size"),
+        value_parser(parse_size),
+        value_name("SIZE")
+    )]
+    /// The default disk quota of the writable layer of containers, like `10G` or `512Mi`. It is
+    /// only enforced if the storage supports project quotas and can be overridden per container
+    /// by the `io.containers.writable-layer-size` annotation.
+    writable_layer_size: Option<u64>,
+
+    #[get = "pub"]
+    #[arg(
+        default_value(DEFAULT_RUNTIME),
+        env("CRI_RUNTIME"),
+        long("runtime"),
+        value_name("PATH")
+    )]
+    /// The OCI runtime binary used to run containers, like runc or crun. It gets looked up in
+    /// `$PATH` if not absolute.
+    runtime: PathBuf,
+
+    #[get = "pub"]
+    #[arg(env("CRI_RUNTIME_ROOT"), long("runtime-root"), value_name("PATH"))]
+    /// The root directory of the OCI runtime for the container state, which should be located
+    /// on a tmpfs. The default of the runtime is used if not set.
+    runtime_root: Option<PathBuf>,
+
+    #[get = "pub"]
+    #[arg(
+        default_value("conmon"),
+        env("CRI_MONITOR"),
+        long("monitor"),
+        value_parser([Monitor::Conmon.as_ref(), Monitor::Native.as_ref()]),
+        value_name("MONITOR")
+    )]
+    /// The monitor supervising the containers. The `conmon` monitor spawns the conmon binary per
+    /// container, whereas the `native` one supervises them from within the server, which means
+    /// that they do not outlive it.
+    monitor: String,
+
+    #[get = "pub"]
+    #[arg(
+        default_value(DEFAULT_CONMON),
+        env("CRI_CONMON"),
+        long("conmon"),
+        value_name("PATH")
+    )]
+    /// The conmon binary monitoring the containers, which records their exit codes and writes
+    /// their logs. It gets looked up in `$PATH` if not absolute.
+    conmon: PathBuf,
+
+    #[get_copy = "pub"]
+    #[arg(
+        env("CRI_LOG_SIZE_MAX"),
+        long("log-size-max"),
+        value_parser(parse_size),
+        value_name("SIZE")
+    )]
+    /// The maximum size of container log files, like `10Mi`. The logs are not limited if not
+    /// set.
+    log_size_max: Option<u64>,
+
+    #[get = "pub"]
+    #[arg(
+        default_value("cgroupfs"),
+        env("CRI_CGROUP_MANAGER"),
+        long("cgroup-manager"),
+        value_parser([CgroupDriver::Cgroupfs.as_ref(), CgroupDriver::Systemd.as_ref()]),
+        value_name("MANAGER")
+    )]
+    /// The cgroup manager of the pods and containers, which has to match the cgroup driver of
+    /// the kubelet. The `systemd` manager expects the cgroup parents to be slices.
+    cgroup_manager: String,
 }
 
 impl Config {
@@ -130,6 +310,33 @@ impl Default for Config {
     }
 }
 
+/// Parse a size in bytes, which can have a decimal (`k`, `M`, `G`, `T`) or binary (`Ki`, `Mi`,
+/// `Gi`, `Ti`) suffix as known from Kubernetes quantities.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let factor: u64 = match suffix {
+        "" => 1,
+        "k" | "K" => 1000,
+        "M" => 1000u64.pow(2),
+        "G" => 1000u64.pow(3),
+        "T" => 1000u64.pow(4),
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        "Ti" => 1 << 40,
+        _ => return Err(format!("invalid size suffix {:?}", suffix)),
+    };
+    number
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size {:?}: {}", value, e))?
+        .checked_mul(factor)
+        .ok_or_else(|| format!("size {:?} is too large", value))
+}
+
 #[derive(AsRefStr, Clone, Copy, Debug, Deserialize, Eq, EnumString, PartialEq, Serialize)]
 #[strum(serialize_all = "snake_case")]
 /// Defines the scope of the log level
@@ -153,6 +360,28 @@ pub mod tests {
         assert!(c.cni_default_network().is_none());
         assert_eq!(c.cni_config_paths().len(), 1);
         assert!(!c.cni_plugin_paths().is_empty());
+        assert_eq!(
+            c.registries_config(),
+            &PathBuf::from(DEFAULT_REGISTRIES_CONFIG)
+        );
+        assert_eq!(c.signature_policy(), &PathBuf::from(DEFAULT_POLICY_PATH));
+        assert_eq!(c.registries_dir(), &PathBuf::from(DEFAULT_REGISTRIES_DIR));
+        assert!(c.decryption_keys_path().is_none());
+        assert_eq!(
+            c.max_concurrent_downloads(),
+            DEFAULT_MAX_CONCURRENT_DOWNLOADS
+        );
+        assert!(c.pinned_images().is_empty());
+        assert!(c.image_gc_high_threshold().is_none());
+        assert_eq!(c.image_gc_low_threshold(), 80);
+        assert!(c.image_import_dir().is_none());
+        assert!(c.writable_layer_size().is_none());
+        assert_eq!(c.runtime(), &PathBuf::from(DEFAULT_RUNTIME));
+        assert!(c.runtime_root().is_none());
+        assert_eq!(c.monitor(), Monitor::Conmon.as_ref());
+        assert_eq!(c.conmon(), &PathBuf::from(DEFAULT_CONMON));
+        assert!(c.log_size_max().is_none());
+        assert_eq!(c.cgroup_manager(), CgroupDriver::Cgroupfs.as_ref());
     }
 
     #[test]
@@ -165,6 +394,22 @@ pub mod tests {
             .cni_plugin_paths("1:2:3")
             .log_scope(LogScope::Global.as_ref())
             .storage_path("/some/other/path")
+            .registries_config("/some/registries.conf")
+            .signature_policy("/some/policy.json")
+            .registries_dir("/some/registries.d")
+            .decryption_keys_path("/some/keys")
+            .max_concurrent_downloads(5usize)
+            .pinned_images(vec!["pause".to_string()])
+            .image_gc_high_threshold(90)
+            .image_gc_low_threshold(70)
+            .image_import_dir("/some/images")
+            .writable_layer_size(1024u64)
+            .runtime("/some/runtime")
+            .runtime_root("/some/runtime/root")
+            .monitor(Monitor::Native.as_ref())
+            .conmon("/some/conmon")
+            .log_size_max(2048u64)
+            .cgroup_manager(CgroupDriver::Systemd.as_ref())
             .build()?;
 
         assert_eq!(c.log_level(), "warn");
@@ -174,10 +419,42 @@ pub mod tests {
         assert_eq!(c.cni_default_network(), &Some("default-network".into()));
         assert_eq!(c.cni_config_paths().len(), 2);
         assert_eq!(c.cni_plugin_paths(), "1:2:3");
+        assert_eq!(
+            &c.registries_config().display().to_string(),
+            "/some/registries.conf"
+        );
+        assert_eq!(c.signature_policy(), &PathBuf::from("/some/policy.json"));
+        assert_eq!(c.registries_dir(), &PathBuf::from("/some/registries.d"));
+        assert_eq!(c.decryption_keys_path(), &Some("/some/keys".into()));
+        assert_eq!(c.max_concurrent_downloads(), 5);
+        assert_eq!(c.pinned_images(), &["pause"]);
+        assert_eq!(c.image_gc_high_threshold(), Some(90));
+        assert_eq!(c.image_gc_low_threshold(), 70);
+        assert_eq!(c.image_import_dir(), &Some("/some/images".into()));
+        assert_eq!(c.writable_layer_size(), Some(1024));
+        assert_eq!(c.runtime(), &PathBuf::from("/some/runtime"));
+        assert_eq!(c.runtime_root(), &Some("/some/runtime/root".into()));
+        assert_eq!(c.monitor(), Monitor::Native.as_ref());
+        assert_eq!(c.conmon(), &PathBuf::from("/some/conmon"));
+        assert_eq!(c.log_size_max(), Some(2048));
+        assert_eq!(c.cgroup_manager(), CgroupDriver::Systemd.as_ref());
 
         Ok(())
     }
 
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("10G"), Ok(10_000_000_000));
+        assert_eq!(parse_size("512Mi"), Ok(512 << 20));
+        assert_eq!(parse_size(" 1k "), Ok(1000));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("10Xi").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("99999999999Ti").is_err());
+    }
+
     #[test]
     fn default_run_path_root() {
         let uid = Uid::from_raw(0);
@@ -65,6 +65,23 @@ impl KeyValueStorage for DefaultKeyValueStorage {
         Ok(())
     }
 
+    fn scan_prefix<K, V>(&self, prefix: K) -> Result<Vec<(Vec<u8>, V)>>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned,
+    {
+        self.db()
+            .scan_prefix(prefix)
+            .map(|item| {
+                let (key, value) = item.context("failed to scan storage")?;
+                Ok((
+                    key.to_vec(),
+                    rmp_serde::from_slice(&value).context("deserialize value")?,
+                ))
+            })
+            .collect()
+    }
+
     fn remove<K>(&mut self, key: K) -> Result<()>
     where
         K: AsRef<[u8]>,
@@ -121,6 +138,26 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn scan_prefix() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut db = DefaultKeyValueStorage::open(dir.path())?;
+
+        db.insert("a/2", "two")?;
+        db.insert("a/1", "one")?;
+        db.insert("b/1", "other")?;
+        let res: Vec<(Vec<u8>, String)> = db.scan_prefix("a/")?;
+        assert_eq!(
+            res,
+            vec![
+                (b"a/1".to_vec(), "one".to_string()),
+                (b"a/2".to_vec(), "two".to_string())
+            ]
+        );
+        assert!(db.scan_prefix::<_, String>("c/")?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn persist() -> Result<()> {
         let dir = TempDir::new()?;
@@ -150,7 +187,7 @@ mod tests {
             v1
         );
 
-        db.insert(k2.clone(), v2.clone())?;
+        db.insert(k2.clone(), v2)?;
         assert_eq!(
             db.get::<_, String>(k2)?.context("value for k2 is none")?,
             v2
@@ -27,6 +27,12 @@ pub trait KeyValueStorage {
         K: AsRef<[u8]>,
         V: Serialize;
 
+    /// Get all items whose key starts with the provided prefix, ordered by their key.
+    fn scan_prefix<K, V>(&self, prefix: K) -> Result<Vec<(Vec<u8>, V)>>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned;
+
     /// Remove an item from the storage.
     fn remove<K>(&mut self, key: K) -> Result<()>
     where
@@ -39,6 +39,21 @@ impl KeyValueStorage for MemoryKeyValueStorage {
         Ok(())
     }
 
+    fn scan_prefix<K, V>(&self, prefix: K) -> Result<Vec<(Vec<u8>, V)>>
+    where
+        K: AsRef<[u8]>,
+        V: serde::de::DeserializeOwned,
+    {
+        let mut items = self
+            .db()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix.as_ref()))
+            .map(|(key, value)| Ok((key.clone(), rmp_serde::from_slice(value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(items)
+    }
+
     fn remove<K>(&mut self, key: K) -> Result<()>
     where
         K: AsRef<[u8]>,
@@ -97,6 +112,25 @@ mod tests {
         db.persist()
     }
 
+    #[test]
+    fn scan_prefix() -> Result<()> {
+        let mut db = MemoryKeyValueStorage::default();
+
+        db.insert("a/2", "two")?;
+        db.insert("a/1", "one")?;
+        db.insert("b/1", "other")?;
+        let res: Vec<(Vec<u8>, String)> = db.scan_prefix("a/")?;
+        assert_eq!(
+            res,
+            vec![
+                (b"a/1".to_vec(), "one".to_string()),
+                (b"a/2".to_vec(), "two".to_string())
+            ]
+        );
+        assert!(db.scan_prefix::<_, String>("c/")?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn insert_values() -> Result<()> {
         let mut db = MemoryKeyValueStorage::default();
@@ -116,7 +150,7 @@ mod tests {
             v1
         );
 
-        db.insert(k2.clone(), v2.clone())?;
+        db.insert(k2.clone(), v2)?;
         assert_eq!(
             db.get::<_, String>(k2)?.context("value for k2 is none")?,
             v2
@@ -190,7 +190,7 @@ impl Sut {
                 }
             }
         }
-        return Ok(success);
+        Ok(success)
     }
 
     fn wait_for_file_exists(file_path: &Path) -> Result<bool> {
@@ -204,6 +204,6 @@ impl Sut {
             }
         }
 
-        return Ok(success);
+        Ok(success)
     }
 }
@@ -39,7 +39,7 @@ async fn port_manager_ipv4() -> Result<()> {
     assert!(lines
         .iter()
         .any(|x| x.contains("-m multiport --dports 8080 -j")));
-    assert!(lines.get(0).context("no line 0")?.contains("-N"));
+    assert!(lines.first().context("no line 0")?.contains("-N"));
     assert!(lines.iter().any(|x| x.contains(
         "-s 127.0.0.0/8 -d 127.0.0.1/32 -p tcp -m tcp --dport 8080 -j CRI-HOSTPORT-SETMARK"
     )));
@@ -87,7 +87,7 @@ async fn port_manager_ipv6() -> Result<()> {
     // Verify
     let binary = which::which("ip6tables")?;
     let lines = test_iptables_std_output(&binary, &id).await?;
-    assert!(lines.get(0).context("no line 0")?.contains("-N"));
+    assert!(lines.first().context("no line 0")?.contains("-N"));
     assert_eq!(lines.len(), 4);
     assert!(lines
         .iter()
@@ -116,7 +116,7 @@ fn new_id() -> String {
 
 async fn test_iptables_std_output(binary: &Path, id: &str) -> Result<Vec<String>> {
     let output = Command::new(binary)
-        .args(&["--wait", "-t", "nat", "-S"])
+        .args(["--wait", "-t", "nat", "-S"])
         .output()
         .await?;
     assert!(output.status.success());